@@ -15,8 +15,15 @@ use kira::{
 use macroquad::prelude::*;
 
 use crate::{
+    audio_device::{backend_settings, list_output_devices, AudioDeviceInfo},
+    beat::Beat,
+    calibration::CalibrationPhase,
     config::AppConfig,
     consts::{TxMsg, UserHit, ALL_INSTRUMENTS, DEFAULT_BEATS_PER_LOOP, TICK_SCHEDULE_AHEAD},
+    gap_click::GapClickConfig,
+    midi_clock::{pulse_ticks_between, song_position_message, CLOCK, CONTINUE, STOP},
+    midi_output::{list_output_ports, velocity, MidiOutput, MidiOutputTarget},
+    time::{wall_time_seconds, ClockReading, ClockSync},
     voices::{Instrument, Voices},
 };
 
@@ -25,12 +32,22 @@ use crate::{
 /// These two responsibilities co-exist so that the audio player's subtle timing issues
 /// can be measured and corrected for.
 pub struct Audio {
-    manager: AudioManager<DefaultBackend>,
-    clock: ClockHandle,
+    playback: Playback,
+    // the clock restarts from 0 when the output device changes, so we track where it picked up from
+    clock_offset_ticks: f64,
+    // relates wall time to the clock, so hits can be placed at the time they happened
+    clock_sync: ClockSync,
+    // the reading clock_sync last observed, so it can be recorded
+    last_clock_reading: ClockReading,
+    // hits are placed relative to when the frame's input is processed
+    frame_wall_time_s: f64,
     last_scheduled_tick: f64,
     bpm: f64,
     beats_per_loop: usize,
     metronome_enabled: bool,
+    gap_click: GapClickConfig,
+    // while calibrating, only the cue for the current phase is played
+    calibration_phase: Option<CalibrationPhase>,
 
     sounds: HashMap<Instrument, StaticSoundData>,
     metronome_sound: Option<StaticSoundData>,
@@ -39,56 +56,126 @@ pub struct Audio {
     calibration_input: VecDeque<f64>,
     configured_audio_latency_seconds: f64,
 
+    output_device_name: Option<String>,
+    buffer_size: Option<u32>,
+    output_devices: Vec<AudioDeviceInfo>,
+
+    midi_output: Option<MidiOutput>,
+    midi_output_ports: Vec<String>,
+    midi_output_latency_s: f64,
+    midi_output_mutes_samples: bool,
+    // lead other gear, by sending midi clock and start/stop on the midi output
+    midi_clock_out: bool,
+    // midi notes are sent a short time ahead, separately from the samples
+    midi_last_scheduled_tick: f64,
+
     tx: Sender<TxMsg>,
 
     // debug only
     last_beat: i32,
 }
 
+/// What drives the clock: the audio output, or a recording being replayed (see replay.rs)
+enum Playback {
+    Output {
+        manager: Box<AudioManager<DefaultBackend>>,
+        clock: ClockHandle,
+    },
+    /// nothing is played. the clock reads whatever it read when the recording was made.
+    Replay(ClockReading),
+}
+
 const DEFAULT_BPM: f64 = 60.;
 const MIN_BPM: f64 = 40.;
 const MAX_BPM: f64 = 240.;
 
+/// how far ahead midi notes are queued. kept short, so that a pause or tempo change takes effect quickly
+const MIDI_SCHEDULE_AHEAD_S: f64 = 0.1;
+
+const POSITION_EPSILON_TICKS: f64 = 0.0001;
+
 impl Audio {
     pub fn new(conf: &AppConfig, tx: Sender<TxMsg>) -> Self {
         let mut manager =
-            AudioManager::<DefaultBackend>::new(AudioManagerSettings::default()).unwrap();
+            match new_manager(conf.audio_output_device.as_deref(), conf.audio_buffer_size) {
+                Ok(manager) => manager,
+                Err(e) => {
+                    log::warn!("unable to open configured audio output, using default. error: {e}");
+                    AudioManager::<DefaultBackend>::new(AudioManagerSettings::default()).unwrap()
+                }
+            };
         let clock = manager
             // TODO: investigate bpm * 2 stuff
             .add_clock(ClockSpeed::TicksPerMinute(DEFAULT_BPM * 2_f64))
             .unwrap();
 
+        let midi_output =
+            conf.midi_output
+                .as_ref()
+                .and_then(|target| match MidiOutput::connect(target) {
+                    Ok(output) => Some(output),
+                    Err(e) => {
+                        log::warn!("unable to open midi output {:?}: {e}", target.name());
+                        None
+                    }
+                });
+
+        let playback = Playback::Output {
+            manager: Box::new(manager),
+            clock,
+        };
+        let mut audio = Self::with_playback(conf, tx, playback);
+        audio.gap_click.seed = GapClickConfig::session_seed();
+        audio.output_devices = list_output_devices();
+        audio.midi_output = midi_output;
+        audio.midi_output_ports = list_output_ports();
+        audio
+    }
+
+    /// audio that only keeps time for a replay, without opening any output
+    pub fn new_replay(conf: &AppConfig, tx: Sender<TxMsg>) -> Self {
+        Self::with_playback(conf, tx, Playback::Replay(ClockReading::default()))
+    }
+
+    fn with_playback(conf: &AppConfig, tx: Sender<TxMsg>, playback: Playback) -> Self {
         tx.send(TxMsg::AudioNew).unwrap();
 
         Self {
-            manager,
-            clock,
+            playback,
+            clock_offset_ticks: 0.,
+            clock_sync: ClockSync::default(),
+            last_clock_reading: ClockReading::default(),
+            frame_wall_time_s: wall_time_seconds(),
             last_scheduled_tick: -1.,
             bpm: DEFAULT_BPM,
             beats_per_loop: DEFAULT_BEATS_PER_LOOP,
             metronome_enabled: false,
+            gap_click: GapClickConfig::default(),
+            calibration_phase: None,
 
             sounds: HashMap::new(),
             metronome_sound: None,
 
             user_hits: vec![],
             calibration_input: VecDeque::new(),
-            configured_audio_latency_seconds: conf.audio_latency_seconds,
+            configured_audio_latency_seconds: conf.output_latency_seconds(),
             last_beat: -1,
 
+            output_device_name: conf.audio_output_device.clone(),
+            buffer_size: conf.audio_buffer_size,
+            output_devices: vec![],
+
+            midi_output: None,
+            midi_output_ports: vec![],
+            midi_output_latency_s: conf.midi_output_latency_ms / 1000.,
+            midi_output_mutes_samples: conf.midi_output_mutes_samples,
+            midi_clock_out: conf.midi_clock_out,
+            midi_last_scheduled_tick: -1.,
+
             tx,
         }
     }
 
-    pub fn new_mock(conf: &AppConfig, tx: Sender<TxMsg>) -> Self {
-        let mut audio = Audio::new(conf, tx);
-        audio.user_hits = vec![UserHit {
-            instrument: Instrument::ClosedHihat,
-            clock_tick: 1.0,
-        }];
-        audio
-    }
-
     // initialize() loads required resources, like audio data
     // I've separated this from new() because it's async and it may error.
     pub async fn initialize(&mut self) -> Result<(), Box<dyn Error>> {
@@ -116,6 +203,117 @@ impl Audio {
         self.configured_audio_latency_seconds = latency;
     }
 
+    // audio output device
+    pub fn get_output_devices(&self) -> &[AudioDeviceInfo] {
+        &self.output_devices
+    }
+
+    pub fn refresh_output_devices(&mut self) {
+        self.output_devices = list_output_devices();
+    }
+
+    pub fn get_output_device_name(&self) -> Option<&str> {
+        self.output_device_name.as_deref()
+    }
+
+    pub fn get_buffer_size(&self) -> Option<u32> {
+        self.buffer_size
+    }
+
+    /// switches the audio output at runtime. The clock continues from the current position.
+    pub fn set_output(
+        &mut self,
+        device_name: Option<String>,
+        buffer_size: Option<u32>,
+    ) -> Result<(), Box<dyn Error>> {
+        if matches!(self.playback, Playback::Replay(_)) {
+            return Err("there's no audio output while replaying".into());
+        }
+        let current_tick = self.current_clock_tick();
+        let was_ticking = !self.is_paused();
+
+        let mut manager = new_manager(device_name.as_deref(), buffer_size)?;
+        let mut clock = manager.add_clock(ClockSpeed::TicksPerMinute(self.bpm * 2.))?;
+        if was_ticking {
+            clock.start();
+        }
+
+        // replacing the manager drops the old one, which stops its sounds
+        self.playback = Playback::Output {
+            manager: Box::new(manager),
+            clock,
+        };
+        self.clock_offset_ticks = current_tick;
+        self.last_scheduled_tick = current_tick;
+        self.output_device_name = device_name;
+        self.buffer_size = buffer_size;
+
+        Ok(())
+    }
+
+    // midi output
+    pub fn get_midi_output_ports(&self) -> &[String] {
+        &self.midi_output_ports
+    }
+
+    pub fn refresh_midi_output_ports(&mut self) {
+        self.midi_output_ports = list_output_ports();
+    }
+
+    pub fn get_midi_output(&self) -> Option<&MidiOutputTarget> {
+        self.midi_output.as_ref().map(|output| output.target())
+    }
+
+    pub fn set_midi_output(
+        &mut self,
+        target: Option<&MidiOutputTarget>,
+    ) -> Result<(), Box<dyn Error>> {
+        self.midi_output = match target {
+            Some(target) => Some(MidiOutput::connect(target)?),
+            None => None,
+        };
+        self.midi_last_scheduled_tick = self.current_clock_tick();
+        self.send_midi_continue();
+        Ok(())
+    }
+
+    pub fn is_midi_clock_out(&self) -> bool {
+        self.midi_clock_out
+    }
+
+    pub fn set_midi_clock_out(&mut self, enabled: bool) {
+        self.midi_clock_out = enabled;
+        self.send_midi_continue();
+    }
+
+    /// tells gear following our midi clock to play from the current position, if we're playing
+    fn send_midi_continue(&self) {
+        let Some(output) = self.midi_output.as_ref().filter(|_| self.midi_clock_out) else {
+            return;
+        };
+        if self.is_paused() {
+            return;
+        }
+        output.send_now(song_position_message(self.current_clock_tick()));
+        output.send_now(vec![CONTINUE]);
+    }
+
+    pub fn get_midi_output_latency_s(&self) -> f64 {
+        self.midi_output_latency_s
+    }
+
+    pub fn set_midi_output_latency_s(&mut self, latency: f64) {
+        self.midi_output_latency_s = latency;
+    }
+
+    pub fn get_midi_output_mutes_samples(&self) -> bool {
+        self.midi_output_mutes_samples
+    }
+
+    pub fn set_midi_output_mutes_samples(&mut self, mutes: bool) {
+        self.midi_output_mutes_samples = mutes;
+    }
+
     // beats per loop
     pub fn set_beats_per_loop(&mut self, val: usize) {
         self.beats_per_loop = val;
@@ -139,7 +337,8 @@ impl Audio {
     }
 
     /// schedule should be run within each game tick to schedule the audio
-    pub async fn schedule(&mut self, voices: &Voices) -> Result<(), Box<dyn Error>> {
+    pub fn schedule(&mut self, voices: &Voices) -> Result<(), Box<dyn Error>> {
+        self.sync_clock();
         self.check_if_new_beat_or_new_loop();
 
         let current = self.current_clock_tick();
@@ -155,7 +354,20 @@ impl Audio {
             tick_to_schedule
         );
 
-        for ins in ALL_INSTRUMENTS.iter() {
+        let (play_voices, play_metronome, gap_click) = match self.calibration_phase {
+            Some(CalibrationPhase::Visual) => (false, false, GapClickConfig::default()),
+            Some(CalibrationPhase::Audio) => (false, true, GapClickConfig::default()),
+            None => (true, self.is_metronome_enabled(), self.gap_click),
+        };
+
+        // a replay only keeps time
+        let Playback::Output { manager, clock } = &mut self.playback else {
+            return Ok(());
+        };
+
+        let play_samples =
+            play_voices && !(self.midi_output.is_some() && self.midi_output_mutes_samples);
+        for ins in ALL_INSTRUMENTS.iter().filter(|_| play_samples) {
             let notes = voices.get_instrument_beats(ins);
             // fetch sound data from hashmap and the clone() it to re-use
             let sound = self
@@ -168,16 +380,18 @@ impl Audio {
                 notes,
                 &sound,
                 get_volume(ins),
-                &mut self.manager,
-                &self.clock,
+                manager,
+                clock,
+                self.clock_offset_ticks,
                 self.last_scheduled_tick,
                 tick_to_schedule,
                 self.beats_per_loop as f64,
+                &gap_click,
             )?;
         }
 
-        if self.is_metronome_enabled() {
-            let metronome_notes = vec![0., 2., 4., 6., 8., 10., 12., 14.];
+        if play_metronome {
+            let metronome_notes: Vec<Beat> = (0..8).map(|i| Beat::from_beats(i * 2)).collect();
             let sound = self
                 .metronome_sound
                 .clone()
@@ -187,21 +401,133 @@ impl Audio {
                 &metronome_notes,
                 &sound,
                 volume,
-                &mut self.manager,
-                &self.clock,
+                manager,
+                clock,
+                self.clock_offset_ticks,
                 self.last_scheduled_tick,
                 tick_to_schedule,
                 self.beats_per_loop as f64,
+                &gap_click,
             )?;
         }
 
         self.last_scheduled_tick = tick_to_schedule;
 
+        if play_voices {
+            self.schedule_midi(voices, &gap_click);
+        }
+
         Ok(())
     }
 
-    fn current_clock_tick(&self) -> f64 {
-        self.clock.time().ticks as f64 + self.clock.time().fraction
+    /// queues the loop's notes on the midi output, a short time before they're due
+    fn schedule_midi(&mut self, voices: &Voices, gap_click: &GapClickConfig) {
+        let Some(output) = &self.midi_output else {
+            return;
+        };
+        if self.is_paused() {
+            return;
+        }
+
+        let current = self.current_clock_tick();
+        // notes are sent early when the latency offset is negative, so look further ahead
+        let ahead_s = MIDI_SCHEDULE_AHEAD_S + (-self.midi_output_latency_s).max(0.);
+        let tick_to_schedule = current + ahead_s / self.get_seconds_per_tick();
+        // skip notes that are already past, e.g. after a pause
+        let from_tick = self.midi_last_scheduled_tick.max(current);
+        if tick_to_schedule <= from_tick {
+            return;
+        }
+
+        for ins in ALL_INSTRUMENTS.iter() {
+            let notes = voices.get_instrument_beats(ins);
+            let window = note_ticks_in_window(
+                notes,
+                from_tick,
+                tick_to_schedule,
+                self.beats_per_loop as f64,
+            );
+            for (beat, note_tick) in window {
+                if gap_click.is_tick_muted(note_tick) {
+                    continue;
+                }
+                let Some(wall_time_s) = self.clock_sync.wall_time_at(note_tick) else {
+                    continue;
+                };
+                let due_s = wall_time_s + self.midi_output_latency_s;
+                let velocity = velocity(voices.get_dynamic(ins, beat));
+                output.send_note(ins, velocity, (due_s.max(0.) * 1_000_000.) as u128);
+            }
+        }
+
+        if self.midi_clock_out {
+            for pulse_tick in pulse_ticks_between(from_tick, tick_to_schedule) {
+                let Some(wall_time_s) = self.clock_sync.wall_time_at(pulse_tick) else {
+                    continue;
+                };
+                let due_s = wall_time_s + self.midi_output_latency_s;
+                output.send_at((due_s.max(0.) * 1_000_000.) as u128, vec![CLOCK]);
+            }
+        }
+
+        self.midi_last_scheduled_tick = tick_to_schedule;
+    }
+
+    fn sync_clock(&mut self) {
+        let reading = match &self.playback {
+            Playback::Output { clock, .. } => ClockReading {
+                wall_time_s: wall_time_seconds(),
+                tick: self.current_clock_tick(),
+                ticking: clock.ticking(),
+            },
+            Playback::Replay(reading) => *reading,
+        };
+        let ticks_per_second = if reading.ticking {
+            1. / self.get_seconds_per_tick()
+        } else {
+            0.
+        };
+        self.clock_sync
+            .observe(reading.wall_time_s, reading.tick, ticks_per_second);
+        self.last_clock_reading = reading;
+    }
+
+    /// the clock, as last read when scheduling
+    pub fn clock_reading(&self) -> ClockReading {
+        self.last_clock_reading
+    }
+
+    pub fn clock_sync(&self) -> ClockSync {
+        self.clock_sync
+    }
+
+    /// picks up timing from where a recording started
+    pub fn restore_clock(&mut self, clock_sync: ClockSync, reading: ClockReading) {
+        self.clock_sync = clock_sync;
+        self.last_clock_reading = reading;
+        self.replay_clock(reading);
+        self.last_beat = self.current_beat() as i32;
+    }
+
+    /// while replaying, sets what the clock reads
+    pub fn replay_clock(&mut self, reading: ClockReading) {
+        if let Playback::Replay(current) = &mut self.playback {
+            *current = reading;
+        }
+    }
+
+    /// marks the moment this frame's input is processed, which hits are timed back from
+    pub fn begin_frame(&mut self, wall_time_s: f64) {
+        self.frame_wall_time_s = wall_time_s;
+    }
+
+    pub fn current_clock_tick(&self) -> f64 {
+        match &self.playback {
+            Playback::Output { clock, .. } => {
+                self.clock_offset_ticks + clock.time().ticks as f64 + clock.time().fraction
+            }
+            Playback::Replay(reading) => reading.tick,
+        }
     }
 
     pub fn current_beat(&self) -> f64 {
@@ -222,20 +548,58 @@ impl Audio {
 
     pub fn set_bpm(&mut self, bpm: f64) {
         self.bpm = clamp(bpm, MIN_BPM, MAX_BPM);
-        self.clock
-            .set_speed(ClockSpeed::TicksPerMinute(bpm * 2.), Tween::default())
+        if let Playback::Output { clock, .. } = &mut self.playback {
+            clock.set_speed(ClockSpeed::TicksPerMinute(bpm * 2.), Tween::default())
+        }
     }
 
     pub fn toggle_pause(&mut self) {
-        if self.clock.ticking() {
-            self.clock.pause();
+        let was_ticking = !self.is_paused();
+        match &mut self.playback {
+            Playback::Output { clock, .. } if was_ticking => clock.pause(),
+            Playback::Output { clock, .. } => clock.start(),
+            Playback::Replay(reading) => reading.ticking = !was_ticking,
+        }
+        if was_ticking {
+            if let Some(output) = &self.midi_output {
+                output.clear();
+                if self.midi_clock_out {
+                    output.send_now(vec![STOP]);
+                }
+            }
         } else {
-            self.clock.start();
+            self.send_midi_continue();
         }
     }
 
+    /// moves playback to the given tick, e.g. to follow another device's transport.
+    /// sounds scheduled from the old position are dropped along with the old clock.
+    pub fn set_position(&mut self, tick: f64) -> Result<(), Box<dyn Error>> {
+        match &mut self.playback {
+            Playback::Output { manager, clock } => {
+                let mut new_clock = manager.add_clock(ClockSpeed::TicksPerMinute(self.bpm * 2.))?;
+                if clock.ticking() {
+                    new_clock.start();
+                }
+                *clock = new_clock;
+                self.clock_offset_ticks = tick;
+            }
+            Playback::Replay(reading) => reading.tick = tick,
+        }
+        // notes exactly at the new position should still play
+        self.last_scheduled_tick = tick - POSITION_EPSILON_TICKS;
+        self.midi_last_scheduled_tick = tick - POSITION_EPSILON_TICKS;
+        if let Some(output) = &self.midi_output {
+            output.clear();
+        }
+        Ok(())
+    }
+
     pub fn is_paused(&self) -> bool {
-        !self.clock.ticking()
+        match &self.playback {
+            Playback::Output { clock, .. } => !clock.ticking(),
+            Playback::Replay(reading) => !reading.ticking,
+        }
     }
 
     pub fn toggle_metronome(&mut self) {
@@ -246,18 +610,42 @@ impl Audio {
         self.metronome_enabled
     }
 
+    pub fn get_gap_click(&self) -> &GapClickConfig {
+        &self.gap_click
+    }
+
+    pub fn set_gap_click(&mut self, config: GapClickConfig) {
+        self.gap_click = config;
+    }
+
+    pub fn set_calibration_phase(&mut self, phase: Option<CalibrationPhase>) {
+        self.calibration_phase = phase;
+    }
+
     // TODO: Feels like this could be moved elsewhere, with a quick lookup against audio if needed (e.g. get_seconds_per_tick)
 
-    /// saves a user's hits, so they can be displayed and checked for accuracy
-    pub fn track_user_hit(&mut self, instrument: Instrument, processing_delay_s: f64) {
-        // convert processing delay to ticks, based on BPM
-        let ticks_per_second = 1. / self.get_seconds_per_tick();
-        let processing_delay_ticks = ticks_per_second * processing_delay_s;
+    /// the clock tick this long ago
+    pub fn clock_tick_before(&self, delay_s: f64) -> f64 {
+        match self.clock_sync.tick_at(self.frame_wall_time_s - delay_s) {
+            Some(tick) => tick,
+            None => {
+                // convert processing delay to ticks, based on BPM
+                let ticks_per_second = 1. / self.get_seconds_per_tick();
+                self.current_clock_tick() - ticks_per_second * delay_s
+            }
+        }
+    }
 
-        self.user_hits.push(UserHit::new(
-            instrument,
-            self.current_clock_tick() - processing_delay_ticks,
-        ));
+    /// saves a user's hits, so they can be displayed and checked for accuracy
+    pub fn track_user_hit(
+        &mut self,
+        instrument: Instrument,
+        processing_delay_s: f64,
+        velocity: Option<u8>,
+    ) {
+        let clock_tick = self.clock_tick_before(processing_delay_s);
+        self.user_hits
+            .push(UserHit::new(instrument, clock_tick).with_velocity(velocity));
 
         log::debug!(
             "Capture at beat = {}, clock = {}",
@@ -294,88 +682,96 @@ impl Audio {
 /// schedules notes for a single sound to be played between last_scheduled_tick and tick_to_schedule
 #[allow(clippy::too_many_arguments)]
 fn schedule_audio(
-    notes: &[f64],
+    notes: &[Beat],
     sound: &StaticSoundData,
     volume: f64,
     manager: &mut AudioManager,
     clock: &ClockHandle,
+    clock_offset_ticks: f64,
     last_scheduled_tick: f64,
     tick_to_schedule: f64,
     beats_per_loop: f64,
+    gap_click: &GapClickConfig,
 ) -> Result<(), Box<dyn Error>> {
+    for (_, note_tick) in
+        note_ticks_in_window(notes, last_scheduled_tick, tick_to_schedule, beats_per_loop)
+    {
+        schedule_note(
+            note_tick,
+            clock,
+            clock_offset_ticks,
+            manager,
+            sound,
+            volume,
+            gap_click,
+        )?;
+    }
+
+    Ok(())
+}
+
+/// the notes that fall after last_scheduled_tick and up to tick_to_schedule, with the tick each plays at
+fn note_ticks_in_window(
+    notes: &[Beat],
+    last_scheduled_tick: f64,
+    tick_to_schedule: f64,
+    beats_per_loop: f64,
+) -> Vec<(Beat, f64)> {
+    let mut out = vec![];
     let prev_beat = last_scheduled_tick % beats_per_loop;
     let next_beat = tick_to_schedule % beats_per_loop;
     let loop_num = (last_scheduled_tick / beats_per_loop) as i32; // floor
-    for note in notes.iter() {
-        if note > &prev_beat && note <= &next_beat {
-            schedule_note(
-                note,
-                loop_num,
-                clock,
-                manager,
-                sound,
-                volume,
-                beats_per_loop,
-            )?;
+    let note_tick = |note: f64, loop_num: i32| note + (loop_num as f64) * beats_per_loop;
+    for beat in notes.iter() {
+        let note = beat.as_f64();
+        if note > prev_beat && note <= next_beat {
+            out.push((*beat, note_tick(note, loop_num)));
         };
 
         // handle wrap-around case
         if next_beat < prev_beat {
             // from prev_beat to end of loop
-            if *note > prev_beat && *note <= beats_per_loop {
-                schedule_note(
-                    note,
-                    loop_num,
-                    clock,
-                    manager,
-                    sound,
-                    volume,
-                    beats_per_loop,
-                )?;
+            if note > prev_beat && note <= beats_per_loop {
+                out.push((*beat, note_tick(note, loop_num)));
             }
             // from start of loop to next beat
-            if *note >= 0. && *note <= next_beat {
-                schedule_note(
-                    note,
-                    loop_num + 1,
-                    clock,
-                    manager,
-                    sound,
-                    volume,
-                    beats_per_loop,
-                )?;
+            if note >= 0. && note <= next_beat {
+                out.push((*beat, note_tick(note, loop_num + 1)));
             }
         }
     }
-
-    Ok(())
+    out
 }
 
 /// schedules a single note to be played at a specific tick
+#[allow(clippy::too_many_arguments)]
 fn schedule_note(
-    note: &f64,
-    loop_num: i32,
+    note_tick: f64,
     clock: &ClockHandle,
+    clock_offset_ticks: f64,
     manager: &mut AudioManager,
     sound: &StaticSoundData,
     volume: f64,
-    beats_per_loop: f64,
+    gap_click: &GapClickConfig,
 ) -> Result<(), Box<dyn Error>> {
-    let note_tick = (*note + (loop_num as f64) * beats_per_loop) as u64;
-    log::debug!(
-        "\tScheduling {:?} ({}) at {}",
-        sound.settings,
-        note,
-        note_tick
-    );
-
-    // Set volume and timing
+    // during a gap, the user keeps time on their own
+    if gap_click.is_tick_muted(note_tick) {
+        return Ok(());
+    }
+
+    log::debug!("\tScheduling {:?} at {}", sound.settings, note_tick);
+
+    // Set volume and timing, relative to when the current clock started
+    let clock_tick = note_tick - clock_offset_ticks;
+    if clock_tick < 0. {
+        return Ok(());
+    }
     let settings = StaticSoundSettings::new()
         .volume(volume)
         .start_time(ClockTime {
             clock: clock.id(),
-            ticks: note_tick,
-            fraction: 0.,
+            ticks: clock_tick.floor() as u64,
+            fraction: clock_tick.fract(),
         });
 
     manager.play(sound.with_settings(settings))?;
@@ -383,6 +779,17 @@ fn schedule_note(
     Ok(())
 }
 
+fn new_manager(
+    device_name: Option<&str>,
+    buffer_size: Option<u32>,
+) -> Result<AudioManager<DefaultBackend>, Box<dyn Error>> {
+    let settings = AudioManagerSettings {
+        backend_settings: backend_settings(device_name, buffer_size),
+        ..Default::default()
+    };
+    Ok(AudioManager::<DefaultBackend>::new(settings)?)
+}
+
 fn get_volume(ins: &Instrument) -> f64 {
     match ins {
         Instrument::OpenHihat => 0.5,
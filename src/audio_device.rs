@@ -0,0 +1,84 @@
+/*
+  List and choose audio output devices.
+
+  Kira plays audio via cpal, so we use cpal directly to enumerate the devices and configure kira's backend.
+*/
+
+use cpal::{
+    traits::{DeviceTrait, HostTrait},
+    BufferSize, SupportedBufferSize,
+};
+use kira::manager::backend::cpal::CpalBackendSettings;
+
+/// buffer sizes (in frames) offered in the UI, if supported by the device
+pub const BUFFER_SIZE_OPTIONS: [u32; 6] = [32, 64, 128, 256, 512, 1024];
+
+#[derive(Debug, Clone, PartialEq)]
+pub struct AudioDeviceInfo {
+    pub name: String,
+    pub sample_rate: u32,
+    /// (min, max) buffer size in frames, if the device reports it
+    pub buffer_size_range: Option<(u32, u32)>,
+}
+
+impl AudioDeviceInfo {
+    pub fn supports_buffer_size(&self, frames: u32) -> bool {
+        match self.buffer_size_range {
+            Some((min, max)) => frames >= min && frames <= max,
+            None => true,
+        }
+    }
+}
+
+pub fn list_output_devices() -> Vec<AudioDeviceInfo> {
+    let host = cpal::default_host();
+    let devices = match host.output_devices() {
+        Ok(devices) => devices,
+        Err(e) => {
+            log::warn!("unable to list audio output devices: {e}");
+            return vec![];
+        }
+    };
+
+    devices
+        .filter_map(|device| {
+            let name = device.name().ok()?;
+            let config = device.default_output_config().ok()?;
+            let buffer_size_range = match config.buffer_size() {
+                SupportedBufferSize::Range { min, max } => Some((*min, *max)),
+                SupportedBufferSize::Unknown => None,
+            };
+            Some(AudioDeviceInfo {
+                name,
+                sample_rate: config.sample_rate().0,
+                buffer_size_range,
+            })
+        })
+        .collect()
+}
+
+/// settings for kira's backend. If the device isn't found, the default device is used.
+pub fn backend_settings(
+    device_name: Option<&str>,
+    buffer_size: Option<u32>,
+) -> CpalBackendSettings {
+    let device = device_name.and_then(|name| {
+        let host = cpal::default_host();
+        let found = host
+            .output_devices()
+            .ok()?
+            .find(|d| d.name().map(|n| n == name).unwrap_or(false));
+        if found.is_none() {
+            log::warn!("audio output device '{name}' not found, using default");
+        }
+        found
+    });
+
+    CpalBackendSettings {
+        device,
+        buffer_size: match buffer_size {
+            Some(frames) => BufferSize::Fixed(frames),
+            None => BufferSize::Default,
+        },
+    }
+}
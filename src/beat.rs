@@ -0,0 +1,179 @@
+/*
+  Exact positions of notes within a loop.
+
+  A position is stored as a whole number of ticks. There are enough ticks per beat that common subdivisions
+  (16ths, 32nds, triplets, quintuplets, sextuplets) land exactly on a tick, so equality and hashing are exact.
+*/
+
+use std::{
+    fmt,
+    ops::{Add, Neg, Sub},
+};
+
+use serde::{Deserialize, Deserializer, Serialize, Serializer};
+
+/// ticks per beat (8th note). 960 = 2^6 * 3 * 5
+pub const TICKS_PER_BEAT: i64 = 960;
+
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, Hash, PartialOrd, Ord)]
+pub struct Beat(i64);
+
+impl Beat {
+    pub const ZERO: Beat = Beat(0);
+
+    pub const fn from_ticks(ticks: i64) -> Self {
+        Self(ticks)
+    }
+
+    pub const fn from_beats(beats: i64) -> Self {
+        Self(beats * TICKS_PER_BEAT)
+    }
+
+    /// e.g. (2, 3) is two thirds of a beat. rounds to the nearest tick if it isn't exact.
+    pub fn from_ratio(numer: i64, denom: i64) -> Self {
+        Self((numer as f64 * TICKS_PER_BEAT as f64 / denom as f64).round() as i64)
+    }
+
+    /// rounds to the nearest tick
+    pub fn from_f64(beats: f64) -> Self {
+        Self((beats * TICKS_PER_BEAT as f64).round() as i64)
+    }
+
+    pub fn ticks(&self) -> i64 {
+        self.0
+    }
+
+    pub fn as_f64(&self) -> f64 {
+        self.0 as f64 / TICKS_PER_BEAT as f64
+    }
+
+    pub fn abs(self) -> Self {
+        Self(self.0.abs())
+    }
+}
+
+impl Add for Beat {
+    type Output = Beat;
+
+    fn add(self, rhs: Beat) -> Beat {
+        Beat(self.0 + rhs.0)
+    }
+}
+
+impl Sub for Beat {
+    type Output = Beat;
+
+    fn sub(self, rhs: Beat) -> Beat {
+        Beat(self.0 - rhs.0)
+    }
+}
+
+impl Neg for Beat {
+    type Output = Beat;
+
+    fn neg(self) -> Beat {
+        Beat(-self.0)
+    }
+}
+
+fn gcd(a: i64, b: i64) -> i64 {
+    if b == 0 {
+        a.abs()
+    } else {
+        gcd(b, a % b)
+    }
+}
+
+impl fmt::Display for Beat {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let divisor = gcd(self.0, TICKS_PER_BEAT);
+        let denom = TICKS_PER_BEAT / divisor;
+        if denom % 3 == 0 {
+            // no exact decimal, so write it as a fraction (e.g. "2/3")
+            write!(f, "{}/{}", self.0 / divisor, denom)
+        } else {
+            write!(f, "{}", self.as_f64())
+        }
+    }
+}
+
+// In loop files, a beat is a number (e.g. 1.5) or, for positions without an exact decimal, a fraction (e.g. "2/3").
+impl Serialize for Beat {
+    fn serialize<S: Serializer>(&self, serializer: S) -> Result<S::Ok, S::Error> {
+        let denom = TICKS_PER_BEAT / gcd(self.0, TICKS_PER_BEAT);
+        if denom % 3 == 0 {
+            serializer.serialize_str(&self.to_string())
+        } else {
+            serializer.serialize_f64(self.as_f64())
+        }
+    }
+}
+
+#[derive(Deserialize)]
+#[serde(untagged)]
+enum BeatInFile {
+    Number(f64),
+    Text(String),
+}
+
+impl<'de> Deserialize<'de> for Beat {
+    fn deserialize<D: Deserializer<'de>>(deserializer: D) -> Result<Self, D::Error> {
+        match BeatInFile::deserialize(deserializer)? {
+            BeatInFile::Number(beats) => Ok(Beat::from_f64(beats)),
+            BeatInFile::Text(text) => parse_beat(&text)
+                .ok_or_else(|| serde::de::Error::custom(format!("invalid beat: {text:?}"))),
+        }
+    }
+}
+
+fn parse_beat(text: &str) -> Option<Beat> {
+    match text.split_once('/') {
+        Some((numer, denom)) => {
+            let numer: i64 = numer.trim().parse().ok()?;
+            let denom: i64 = denom.trim().parse().ok()?;
+            if denom == 0 {
+                return None;
+            }
+            Some(Beat::from_ratio(numer, denom))
+        }
+        None => Some(Beat::from_f64(text.trim().parse().ok()?)),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::beat::{Beat, TICKS_PER_BEAT};
+
+    #[test]
+    fn it_represents_subdivisions_exactly() {
+        let triplet = Beat::from_ratio(2, 3);
+        assert_eq!(triplet + triplet + triplet, Beat::from_beats(2));
+
+        let quintuplet = Beat::from_ratio(2, 5);
+        assert_eq!(quintuplet, Beat::from_f64(0.4));
+
+        let thirty_second = Beat::from_f64(0.25);
+        assert_eq!(thirty_second.ticks(), TICKS_PER_BEAT / 4);
+        assert_eq!(Beat::from_beats(16) - thirty_second, Beat::from_f64(15.75));
+    }
+
+    #[test]
+    fn it_reads_and_writes_beats_in_loop_files() {
+        let beats: Vec<Beat> = serde_json::from_str(r#"[0, 1.5, "2/3", "7/3"]"#).unwrap();
+        assert_eq!(
+            beats,
+            vec![
+                Beat::ZERO,
+                Beat::from_f64(1.5),
+                Beat::from_ratio(2, 3),
+                Beat::from_ratio(7, 3)
+            ]
+        );
+        assert_eq!(
+            serde_json::to_string(&beats).unwrap(),
+            r#"[0.0,1.5,"2/3","7/3"]"#
+        );
+
+        assert!(serde_json::from_str::<Beat>(r#""1/0""#).is_err());
+    }
+}
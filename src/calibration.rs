@@ -0,0 +1,241 @@
+/*
+  Latency calibration wizard.
+
+  The user taps along with a steady pulse, first with a visual cue only (measures input latency)
+  and then with an audio click only (measures input + audio output latency).
+  The difference between the two phases is the audio output latency.
+*/
+
+pub const KEYBOARD_INPUT_NAME: &str = "keyboard";
+
+// taps are collected in a sliding window, until it's consistent enough to trust
+const MIN_TAPS: usize = 16;
+const MAX_TAPS_PER_ATTEMPT: usize = 48;
+const MAX_STD_DEV_S: f64 = 0.03;
+const TRIM_RATIO: f64 = 0.1;
+
+// the pulse to tap along with is every quarter note (i.e. every 2 beats, like the metronome)
+const BEATS_PER_PULSE: f64 = 2.;
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum CalibrationPhase {
+    Visual,
+    Audio,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct CalibrationStats {
+    pub num_taps: usize,
+    pub median: f64,
+    pub trimmed_mean: f64,
+    pub std_dev: f64,
+}
+
+impl CalibrationStats {
+    pub fn is_stable(&self) -> bool {
+        self.num_taps >= MIN_TAPS && self.std_dev <= MAX_STD_DEV_S
+    }
+}
+
+/// robust summary of tap offsets (in seconds). outliers are trimmed from both ends.
+pub fn compute_stats(offsets: &[f64]) -> Option<CalibrationStats> {
+    if offsets.is_empty() {
+        return None;
+    }
+
+    let mut sorted = offsets.to_vec();
+    sorted.sort_by(|a, b| a.total_cmp(b));
+
+    let n = sorted.len();
+    let median = if n % 2 == 0 {
+        (sorted[n / 2 - 1] + sorted[n / 2]) / 2.
+    } else {
+        sorted[n / 2]
+    };
+
+    let trim = (n as f64 * TRIM_RATIO) as usize;
+    let trimmed = &sorted[trim..n - trim];
+    let trimmed_mean = trimmed.iter().sum::<f64>() / trimmed.len() as f64;
+    let variance = trimmed
+        .iter()
+        .map(|x| (x - trimmed_mean).powi(2))
+        .sum::<f64>()
+        / trimmed.len() as f64;
+
+    Some(CalibrationStats {
+        num_taps: n,
+        median,
+        trimmed_mean,
+        std_dev: variance.sqrt(),
+    })
+}
+
+/// seconds between a tap and the nearest pulse. positive means the tap was late.
+pub fn tap_offset_seconds(beat: f64, bpm: f64) -> f64 {
+    let nearest_pulse = (beat / BEATS_PER_PULSE).round() * BEATS_PER_PULSE;
+    let seconds_per_beat = 60. / bpm / 2.;
+    (beat - nearest_pulse) * seconds_per_beat
+}
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct CalibrationResult {
+    pub input_latency_s: f64,
+    pub output_latency_s: f64,
+}
+
+#[derive(Debug, Clone)]
+pub struct CalibrationWizard {
+    pub input_device: String,
+    pub phase: Option<CalibrationPhase>,
+    taps: Vec<f64>,
+    taps_this_attempt: usize,
+    pub num_rejected_attempts: usize,
+    visual_stats: Option<CalibrationStats>,
+    pub result: Option<CalibrationResult>,
+}
+
+impl CalibrationWizard {
+    pub fn new() -> Self {
+        Self {
+            input_device: KEYBOARD_INPUT_NAME.to_string(),
+            phase: None,
+            taps: vec![],
+            taps_this_attempt: 0,
+            num_rejected_attempts: 0,
+            visual_stats: None,
+            result: None,
+        }
+    }
+
+    pub fn start(&mut self, input_device: &str) {
+        *self = Self::new();
+        self.input_device = input_device.to_string();
+        self.phase = Some(CalibrationPhase::Visual);
+    }
+
+    pub fn cancel(&mut self) {
+        *self = Self::new();
+    }
+
+    pub fn is_active(&self) -> bool {
+        self.phase.is_some()
+    }
+
+    pub fn current_stats(&self) -> Option<CalibrationStats> {
+        compute_stats(&self.taps)
+    }
+
+    /// records a tap. returns the result once both phases are complete.
+    pub fn tap(&mut self, offset_s: f64) -> Option<CalibrationResult> {
+        let phase = self.phase?;
+
+        // keep a sliding window, since the earliest taps are often while the user is still finding the pulse
+        self.taps.push(offset_s);
+        if self.taps.len() > MIN_TAPS {
+            self.taps.remove(0);
+        }
+        self.taps_this_attempt += 1;
+
+        let stats = compute_stats(&self.taps)?;
+        if !stats.is_stable() {
+            if self.taps_this_attempt >= MAX_TAPS_PER_ATTEMPT {
+                log::info!(
+                    "calibration run was unstable ({:?}), restarting phase",
+                    stats
+                );
+                self.taps.clear();
+                self.taps_this_attempt = 0;
+                self.num_rejected_attempts += 1;
+            }
+            return None;
+        }
+
+        self.taps.clear();
+        self.taps_this_attempt = 0;
+        self.num_rejected_attempts = 0;
+        match phase {
+            CalibrationPhase::Visual => {
+                self.visual_stats = Some(stats);
+                self.phase = Some(CalibrationPhase::Audio);
+                None
+            }
+            CalibrationPhase::Audio => {
+                let input_latency_s = self.visual_stats.map(|s| s.trimmed_mean).unwrap_or(0.);
+                let result = CalibrationResult {
+                    input_latency_s,
+                    output_latency_s: stats.trimmed_mean - input_latency_s,
+                };
+                self.phase = None;
+                self.result = Some(result);
+                Some(result)
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::calibration::{
+        compute_stats, tap_offset_seconds, CalibrationPhase, CalibrationWizard,
+    };
+
+    #[test]
+    fn it_computes_robust_stats() {
+        let mut offsets = vec![0.010; 18];
+        // a couple of wild outliers
+        offsets.push(0.5);
+        offsets.push(-0.5);
+
+        let stats = compute_stats(&offsets).unwrap();
+        assert_eq!(stats.num_taps, 20);
+        assert_eq!(stats.median, 0.010);
+        assert!((stats.trimmed_mean - 0.010).abs() < 1e-9);
+        assert!(stats.std_dev < 1e-9);
+
+        assert_eq!(compute_stats(&[]), None);
+    }
+
+    #[test]
+    fn it_computes_tap_offset_from_nearest_pulse() {
+        // at 60 bpm, a beat (8th note) is 0.5s
+        assert!((tap_offset_seconds(2.1, 60.) - 0.05).abs() < 1e-9);
+        assert!((tap_offset_seconds(1.9, 60.) + 0.05).abs() < 1e-9);
+        assert!((tap_offset_seconds(15.95, 60.) + 0.025).abs() < 1e-9);
+    }
+
+    #[test]
+    fn it_separates_input_and_output_latency() {
+        let mut wizard = CalibrationWizard::new();
+        wizard.start("TD-27");
+        assert_eq!(wizard.phase, Some(CalibrationPhase::Visual));
+
+        let jitter = |i: usize| if i % 2 == 0 { 0.005 } else { -0.005 };
+        for i in 0..16 {
+            assert_eq!(wizard.tap(0.020 + jitter(i)), None);
+        }
+        assert_eq!(wizard.phase, Some(CalibrationPhase::Audio));
+
+        let mut result = None;
+        for i in 0..16 {
+            result = wizard.tap(0.070 + jitter(i));
+        }
+        let result = result.unwrap();
+        assert!((result.input_latency_s - 0.020).abs() < 1e-9);
+        assert!((result.output_latency_s - 0.050).abs() < 1e-9);
+        assert!(!wizard.is_active());
+    }
+
+    #[test]
+    fn it_rejects_unstable_runs() {
+        let mut wizard = CalibrationWizard::new();
+        wizard.start("keyboard");
+
+        for i in 0..48 {
+            let offset = if i % 2 == 0 { 0.1 } else { -0.1 };
+            assert_eq!(wizard.tap(offset), None);
+        }
+        assert_eq!(wizard.phase, Some(CalibrationPhase::Visual));
+        assert_eq!(wizard.num_rejected_attempts, 1);
+        assert_eq!(wizard.current_stats(), None);
+    }
+}
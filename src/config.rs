@@ -1,10 +1,57 @@
+use std::{collections::HashMap, path::PathBuf};
+
+use directories::ProjectDirs;
 use serde::{Deserialize, Serialize};
 
+use crate::midi_output::MidiOutputTarget;
+
 #[derive(Default, Debug, Serialize, Deserialize)]
+#[serde(default)]
 pub struct AppConfig {
     pub audio_latency_seconds: f64,
+
+    // audio output. `None` uses the system default
+    pub audio_output_device: Option<String>,
+    pub audio_buffer_size: Option<u32>,
+    /// measured audio latency for each output device
+    pub output_latency_by_device: HashMap<String, f64>,
+    /// measured input latency for each input device (e.g. "keyboard" or a midi device name)
+    pub input_latency_by_device: HashMap<String, f64>,
+    /// name of the midi mapping chosen for each midi device
+    pub midi_mapping_by_device: HashMap<String, String>,
+    /// midi input ports to connect to, by name. `None` connects to the first available port
+    pub midi_input_ports: Option<Vec<String>>,
+    /// where to send the loop as midi notes. `None` doesn't send midi
+    pub midi_output: Option<MidiOutputTarget>,
+    /// added to when each midi note is sent, so the midi output lines up with the bundled sounds
+    pub midi_output_latency_ms: f64,
+    /// while sending midi, don't play the bundled sounds
+    pub midi_output_mutes_samples: bool,
+    /// send midi clock and start/stop on the midi output, so other gear follows our tempo
+    pub midi_clock_out: bool,
+    /// follow the midi clock and start/stop from this midi input port. `None` keeps our own tempo
+    pub midi_clock_source: Option<String>,
+    /// listen for hits on an acoustic kit with this audio input device. `None` doesn't listen
+    pub mic_input_device: Option<String>,
+}
+
+/// the same app folders confy uses for the AppConfig file, so everything the app saves is kept together
+fn project_dirs() -> Option<ProjectDirs> {
+    ProjectDirs::from("rs", "", "drum-break")
+}
+
+/// where user data (e.g. session journals) is kept, e.g. ~/.local/share/drum-break on linux
+pub fn data_dir() -> Option<PathBuf> {
+    project_dirs().map(|dirs| dirs.data_dir().to_path_buf())
+}
+
+/// where user settings are kept (the same folder as the AppConfig file), e.g. ~/.config/drum-break on linux
+pub fn config_dir() -> Option<PathBuf> {
+    project_dirs().map(|dirs| dirs.config_dir().to_path_buf())
 }
 
+const DEFAULT_OUTPUT_DEVICE_KEY: &str = "default";
+
 impl AppConfig {
     pub fn new() -> Self {
         // loads or initializes
@@ -19,4 +66,47 @@ impl AppConfig {
             _ => (),
         }
     }
+
+    fn output_device_key(&self) -> &str {
+        self.audio_output_device
+            .as_deref()
+            .unwrap_or(DEFAULT_OUTPUT_DEVICE_KEY)
+    }
+
+    /// the latency measured for the current output device, falling back to the last measured latency
+    pub fn output_latency_seconds(&self) -> f64 {
+        self.output_latency_by_device
+            .get(self.output_device_key())
+            .copied()
+            .unwrap_or(self.audio_latency_seconds)
+    }
+
+    pub fn set_output_latency_seconds(&mut self, latency: f64) {
+        self.audio_latency_seconds = latency;
+        let key = self.output_device_key().to_string();
+        self.output_latency_by_device.insert(key, latency);
+    }
+
+    pub fn input_latency_seconds(&self, input_device: &str) -> f64 {
+        self.input_latency_by_device
+            .get(input_device)
+            .copied()
+            .unwrap_or(0.)
+    }
+
+    /// `connected` are the ports currently in use, which become the starting choice if none was made yet
+    pub fn set_midi_input_port_enabled(&mut self, port: &str, enabled: bool, connected: &[String]) {
+        let ports = self
+            .midi_input_ports
+            .get_or_insert_with(|| connected.to_vec());
+        ports.retain(|p| p != port);
+        if enabled {
+            ports.push(port.to_string());
+        }
+    }
+
+    pub fn set_input_latency_seconds(&mut self, input_device: &str, latency: f64) {
+        self.input_latency_by_device
+            .insert(input_device.to_string(), latency);
+    }
 }
@@ -9,6 +9,9 @@ pub const WINDOW_HEIGHT: i32 = 720;
 
 pub const DEFAULT_BEATS_PER_LOOP: usize = 16;
 
+// A "beat" in a loop is an 8th note (the metronome clicks every 2 beats), so a bar of 4/4 is 8 beats
+pub const BEATS_PER_BAR: usize = 8;
+
 //
 // Audio
 //
@@ -43,6 +46,8 @@ pub enum TxMsg {
 pub struct UserHit {
     pub instrument: Instrument,
     pub clock_tick: f64,
+    /// how hard the hit was (0-127). only known for midi input.
+    pub velocity: Option<u8>,
 }
 
 impl UserHit {
@@ -50,9 +55,15 @@ impl UserHit {
         Self {
             instrument,
             clock_tick,
+            velocity: None,
         }
     }
 
+    pub fn with_velocity(mut self, velocity: Option<u8>) -> Self {
+        self.velocity = velocity;
+        self
+    }
+
     pub fn beat(&self, beats_per_loop: usize) -> f64 {
         self.clock_tick % (beats_per_loop as f64)
     }
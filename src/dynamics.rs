@@ -0,0 +1,282 @@
+/*
+  Dynamics scoring.
+
+  Compares how hard each hit was played (midi velocity) with the written accents and ghost notes,
+  and summarizes how consistent the user's velocities are for each instrument.
+*/
+
+use std::collections::HashMap;
+
+use serde::{Deserialize, Serialize};
+
+use crate::{
+    beat::Beat,
+    consts::UserHit,
+    score::TimingMargins,
+    voices::{Dynamic, Instrument, Voices},
+};
+
+#[derive(Debug, Clone, Copy, PartialEq, Serialize, Deserialize)]
+pub struct DynamicsConfig {
+    pub enabled: bool,
+    /// hits at or below this velocity count as ghost notes
+    pub ghost_max_velocity: u8,
+    /// hits at or above this velocity count as accents
+    pub accent_min_velocity: u8,
+}
+
+impl Default for DynamicsConfig {
+    fn default() -> Self {
+        Self {
+            enabled: false,
+            ghost_max_velocity: 50,
+            accent_min_velocity: 100,
+        }
+    }
+}
+
+impl DynamicsConfig {
+    pub fn classify(&self, velocity: u8) -> Dynamic {
+        if velocity <= self.ghost_max_velocity {
+            Dynamic::Ghost
+        } else if velocity >= self.accent_min_velocity {
+            Dynamic::Accent
+        } else {
+            Dynamic::Normal
+        }
+    }
+}
+
+/// A hit with a known velocity, paired with the written note it was aimed at
+#[derive(Debug, PartialEq, Clone, Copy)]
+pub struct VelocityHit {
+    pub instrument: Instrument,
+    pub velocity: u8,
+    pub written: Dynamic,
+}
+
+/// pairs each hit that has a velocity with the nearest written note on its instrument (within the miss margin)
+pub fn match_velocity_hits(
+    user_hits: &[UserHit],
+    desired_hits: &Voices,
+    audio_latency_beats: f64,
+    beats_per_loop: usize,
+    margins: &TimingMargins,
+) -> Vec<VelocityHit> {
+    let bpl = Beat::from_beats(beats_per_loop as i64);
+    user_hits
+        .iter()
+        .filter_map(|hit| {
+            let velocity = hit.velocity?;
+            let tick = (hit.clock_tick + audio_latency_beats).rem_euclid(beats_per_loop as f64);
+            let user_beat = Beat::from_f64(tick);
+            let (note, distance) = desired_hits
+                .get_instrument_beats(&hit.instrument)
+                .iter()
+                .map(|d| {
+                    let distance = [*d - bpl, *d, *d + bpl]
+                        .iter()
+                        .map(|d| (user_beat - *d).abs())
+                        .min()
+                        .unwrap_or(bpl);
+                    (*d, distance)
+                })
+                .min_by_key(|(_, distance)| *distance)?;
+            if distance > margins.miss {
+                return None;
+            }
+            Some(VelocityHit {
+                instrument: hit.instrument,
+                velocity,
+                written: desired_hits.get_dynamic(&hit.instrument, note),
+            })
+        })
+        .collect()
+}
+
+/// fraction (0 to 1) of hits played at their written dynamic. None if no hits had a velocity.
+pub fn compute_dynamics_score(hits: &[VelocityHit], config: &DynamicsConfig) -> Option<f64> {
+    if hits.is_empty() {
+        return None;
+    }
+    let matching = hits
+        .iter()
+        .filter(|h| config.classify(h.velocity) == h.written)
+        .count();
+    Some(matching as f64 / hits.len() as f64)
+}
+
+#[derive(Debug, PartialEq, Clone, Copy)]
+pub struct VelocityStats {
+    pub num_hits: usize,
+    pub mean: f64,
+    pub std_dev: f64,
+}
+
+impl VelocityStats {
+    fn from_velocities(velocities: &[u8]) -> Option<Self> {
+        if velocities.is_empty() {
+            return None;
+        }
+        let n = velocities.len() as f64;
+        let mean = velocities.iter().map(|v| *v as f64).sum::<f64>() / n;
+        let variance = velocities
+            .iter()
+            .map(|v| (*v as f64 - mean).powi(2))
+            .sum::<f64>()
+            / n;
+        Some(Self {
+            num_hits: velocities.len(),
+            mean,
+            std_dev: variance.sqrt(),
+        })
+    }
+}
+
+/// How evenly an instrument was played, grouped by the written dynamic of each note
+#[derive(Debug, PartialEq, Clone)]
+pub struct VelocityConsistency {
+    pub by_dynamic: HashMap<Dynamic, VelocityStats>,
+}
+
+impl VelocityConsistency {
+    pub fn from_hits(hits: &[VelocityHit], instrument: Instrument) -> Option<Self> {
+        let mut velocities: HashMap<Dynamic, Vec<u8>> = HashMap::new();
+        for hit in hits.iter().filter(|h| h.instrument == instrument) {
+            velocities
+                .entry(hit.written)
+                .or_default()
+                .push(hit.velocity);
+        }
+        let by_dynamic: HashMap<Dynamic, VelocityStats> = velocities
+            .iter()
+            .filter_map(|(d, v)| Some((*d, VelocityStats::from_velocities(v)?)))
+            .collect();
+        if by_dynamic.is_empty() {
+            return None;
+        }
+        Some(Self { by_dynamic })
+    }
+
+    /// difference between the average accent and average ghost note, if both were played
+    pub fn contrast(&self) -> Option<f64> {
+        let accent = self.by_dynamic.get(&Dynamic::Accent)?;
+        let ghost = self.by_dynamic.get(&Dynamic::Ghost)?;
+        Some(accent.mean - ghost.mean)
+    }
+
+    pub fn describe(&self) -> String {
+        let mut parts: Vec<String> = Dynamic::ALL
+            .iter()
+            .filter_map(|d| {
+                let stats = self.by_dynamic.get(d)?;
+                Some(format!(
+                    "{} {:.0}±{:.0} (n={})",
+                    d.name(),
+                    stats.mean,
+                    stats.std_dev,
+                    stats.num_hits
+                ))
+            })
+            .collect();
+        if let Some(contrast) = self.contrast() {
+            parts.push(format!("contrast {:.0}", contrast));
+        }
+        parts.join(", ")
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::{
+        beat::Beat,
+        consts::UserHit,
+        dynamics::{
+            compute_dynamics_score, match_velocity_hits, DynamicsConfig, VelocityConsistency,
+            VelocityHit,
+        },
+        score::TimingMargins,
+        voices::{Dynamic, Instrument, Loop, Voices},
+    };
+
+    const MARGINS: TimingMargins = TimingMargins {
+        correct: Beat::from_ticks(144),
+        miss: Beat::from_ticks(288),
+    };
+
+    #[test]
+    fn it_classifies_velocities() {
+        let config = DynamicsConfig::default();
+        assert_eq!(config.classify(30), Dynamic::Ghost);
+        assert_eq!(config.classify(50), Dynamic::Ghost);
+        assert_eq!(config.classify(80), Dynamic::Normal);
+        assert_eq!(config.classify(100), Dynamic::Accent);
+        assert_eq!(config.classify(127), Dynamic::Accent);
+    }
+
+    #[test]
+    fn it_scores_hits_against_written_accents_and_ghost_notes() {
+        let loop_data: Loop = serde_json::from_str(
+            r#"{
+                "bpm": 120,
+                "length_in_beats": 8,
+                "voices": { "snare": [0, 2, 4, 6] },
+                "accents": { "snare": [2, 6] },
+                "ghost_notes": { "snare": [4] }
+            }"#,
+        )
+        .unwrap();
+        let voices = Voices::new_from_loop(&loop_data);
+        assert_eq!(
+            voices.get_dynamic(&Instrument::Snare, Beat::from_beats(2)),
+            Dynamic::Accent
+        );
+        assert_eq!(
+            voices.get_dynamic(&Instrument::Snare, Beat::from_beats(4)),
+            Dynamic::Ghost
+        );
+        assert_eq!(
+            voices.get_dynamic(&Instrument::Snare, Beat::ZERO),
+            Dynamic::Normal
+        );
+
+        let user_hits = vec![
+            UserHit::new(Instrument::Snare, 0.05).with_velocity(Some(80)),
+            UserHit::new(Instrument::Snare, 1.95).with_velocity(Some(110)),
+            UserHit::new(Instrument::Snare, 4.).with_velocity(Some(90)), // ghost played too loud
+            UserHit::new(Instrument::Snare, 7.9).with_velocity(Some(120)), // accent played early
+            UserHit::new(Instrument::Snare, 5.),                         // no velocity (keyboard)
+            UserHit::new(Instrument::Kick, 0.).with_velocity(Some(100)), // no kick notes
+        ];
+        let hits = match_velocity_hits(&user_hits, &voices, 0., 8, &MARGINS);
+        assert_eq!(hits.len(), 4);
+        assert_eq!(hits[3].written, Dynamic::Normal); // wraps around to beat 0
+
+        let config = DynamicsConfig::default();
+        assert_eq!(compute_dynamics_score(&hits, &config), Some(0.5));
+        assert_eq!(compute_dynamics_score(&[], &config), None);
+    }
+
+    #[test]
+    fn it_summarizes_velocity_consistency() {
+        let hit = |velocity, written| VelocityHit {
+            instrument: Instrument::Snare,
+            velocity,
+            written,
+        };
+        let hits = vec![
+            hit(110, Dynamic::Accent),
+            hit(120, Dynamic::Accent),
+            hit(30, Dynamic::Ghost),
+            hit(40, Dynamic::Ghost),
+        ];
+        let consistency = VelocityConsistency::from_hits(&hits, Instrument::Snare).unwrap();
+        let accent = consistency.by_dynamic[&Dynamic::Accent];
+        assert_eq!(accent.num_hits, 2);
+        assert_eq!(accent.mean, 115.);
+        assert_eq!(accent.std_dev, 5.);
+        assert_eq!(consistency.contrast(), Some(80.));
+
+        assert!(VelocityConsistency::from_hits(&hits, Instrument::Kick).is_none());
+    }
+}
@@ -1,23 +1,37 @@
+use std::collections::BTreeMap;
+
 use egui::{
     self,
     emath::{self, RectTransform},
     pos2, CollapsingHeader, Color32, Shape, Widget,
 };
 
-use egui_plot::{Line, Plot};
+use egui_plot::{Bar, BarChart, Line, Plot};
 
 use log::info;
-use macroquad::color::{DARKBLUE, GREEN, LIGHTGRAY, ORANGE, PURPLE, RED};
+use macroquad::color::{DARKBLUE, GOLD, GREEN, LIGHTGRAY, MAGENTA, ORANGE, PURPLE, RED};
 
 use crate::{
+    audio_device::{AudioDeviceInfo, BUFFER_SIZE_OPTIONS},
+    beat::Beat,
+    calibration::{CalibrationPhase, CalibrationWizard, KEYBOARD_INPUT_NAME},
     consts::{UserHit, ALL_INSTRUMENTS},
+    dynamics::{compute_dynamics_score, match_velocity_hits, DynamicsConfig, VelocityConsistency},
     events::Events,
+    gap_click::GapClickConfig,
+    keymap::{Action, Command, Keymap},
+    kit_profile::LEARN_HITS_PER_INSTRUMENT,
+    midi_mapping::MidiMapping,
+    midi_output::{MidiOutputTarget, VIRTUAL_PORT_SUPPORTED},
+    progress::{LoopProgress, ProgressHistory},
     score::{
-        compute_accuracy_of_single_hit, compute_last_loop_summary,
-        compute_loop_performance_for_voice, get_hits_from_nth_loop,
-        get_user_hit_timings_by_instrument, Accuracy, MISS_MARGIN,
+        compute_accuracy_of_single_hit, compute_drift_per_bar, compute_hit_offsets_ms,
+        compute_last_loop_summary, compute_loop_summary, get_hits_from_nth_loop,
+        get_user_hit_timings_by_instrument, Accuracy, ConfusionSet, Difficulty, Grade,
+        GradeThresholds, TimingMargins, TimingTendency, TimingWindows,
     },
-    voices::{Instrument, Voices},
+    tempo_trainer::{RampMode, TempoLogEntry, TempoTrainerConfig},
+    voices::{Instrument, Voices, STRAIGHT_SWING},
 };
 
 // This resource holds information about the game:
@@ -28,6 +42,8 @@ pub struct UIState {
     is_playing: bool,
     bpm: f32,
     is_metronome_enabled: bool,
+    gap_click: GapClickConfig,
+    swing: f64,
     #[allow(dead_code)]
     volume_metronome: f32,
     #[allow(dead_code)]
@@ -39,6 +55,17 @@ pub struct UIState {
     beats_per_loop: usize,
 
     latency_offset_s: f32,
+    audio_output_devices: Vec<AudioDeviceInfo>,
+    audio_output_device_name: Option<String>,
+    audio_buffer_size: Option<u32>,
+
+    midi_output_ports: Vec<String>,
+    midi_output: Option<MidiOutputTarget>,
+    midi_output_latency_ms: f64,
+    midi_output_mutes_samples: bool,
+    midi_clock_out: bool,
+    midi_clock_source: Option<String>,
+    midi_clock_source_bpm: Option<f64>,
 
     user_hits: Vec<UserHit>,
     desired_hits: Voices,
@@ -47,11 +74,43 @@ pub struct UIState {
     are_side_panels_visible: bool,
 
     is_dev_tools_visible: bool,
-    correct_margin: f64,
-    miss_margin: f64,
+    /// whether input is being recorded for a replay
+    is_recording: bool,
+    timing_windows: TimingWindows,
+    confusion_set: ConfusionSet,
+    dynamics_config: DynamicsConfig,
+    grade_thresholds: GradeThresholds,
+    last_loop_grade: Option<Grade>,
+    progress: ProgressHistory,
 
     hide_empty_tracks: bool,
-    midi_device_name: String,
+    /// available midi input ports, and whether each is connected
+    midi_ports: Vec<(String, bool)>,
+    /// connected midi devices, and the mapping each uses
+    midi_devices: Vec<(String, MidiMapping)>,
+    midi_mapping_names: Vec<String>,
+    midi_learning_device: Option<String>,
+    midi_learn_target: Option<Instrument>,
+
+    mic_input_devices: Vec<String>,
+    /// the audio input device being listened to for hits
+    mic_input_device: Option<String>,
+    mic_level: f32,
+    /// the drums the kit profile can tell apart
+    kit_instruments: Vec<Instrument>,
+    is_kit_learning: bool,
+    /// the drum kit-learn is waiting for, and how many times it's been hit
+    kit_learn_target: Option<(Instrument, usize)>,
+
+    keymap: Keymap,
+    /// the action waiting for its new key
+    key_binding: Option<Action>,
+
+    is_calibration_visible: bool,
+    calibration: CalibrationWizard,
+
+    tempo_trainer_config: TempoTrainerConfig,
+    tempo_log: Vec<TempoLogEntry>,
     // user interaction state
     // is_dragging,
 }
@@ -77,10 +136,23 @@ impl Default for UIState {
             bpm: 120.,
 
             is_metronome_enabled: false,
+            gap_click: GapClickConfig::default(),
+            swing: STRAIGHT_SWING,
             volume_metronome: 0.75,
             volume_target_notes: 0.75,
 
             latency_offset_s: 0.,
+            audio_output_devices: vec![],
+            audio_output_device_name: None,
+            audio_buffer_size: None,
+
+            midi_output_ports: vec![],
+            midi_output: None,
+            midi_output_latency_ms: 0.,
+            midi_output_mutes_samples: false,
+            midi_clock_out: false,
+            midi_clock_source: None,
+            midi_clock_source_bpm: None,
 
             user_hits: vec![],
             desired_hits: Voices::new(),
@@ -89,12 +161,37 @@ impl Default for UIState {
             are_side_panels_visible: false,
 
             is_dev_tools_visible: false,
-            correct_margin: 0.,
-            miss_margin: 0.,
+            is_recording: false,
+            timing_windows: TimingWindows::default(),
+            confusion_set: ConfusionSet::default(),
+            dynamics_config: DynamicsConfig::default(),
+            grade_thresholds: GradeThresholds::default(),
+            last_loop_grade: None,
+            progress: ProgressHistory::default(),
 
             hide_empty_tracks: false,
 
-            midi_device_name: "".to_string(),
+            midi_ports: vec![],
+            midi_devices: vec![],
+            midi_mapping_names: vec![],
+            midi_learning_device: None,
+            midi_learn_target: None,
+
+            mic_input_devices: vec![],
+            mic_input_device: None,
+            mic_level: 0.,
+            kit_instruments: vec![],
+            is_kit_learning: false,
+            kit_learn_target: None,
+
+            keymap: Keymap::default(),
+            key_binding: None,
+
+            is_calibration_visible: false,
+            calibration: CalibrationWizard::new(),
+
+            tempo_trainer_config: TempoTrainerConfig::default(),
+            tempo_log: vec![],
         }
     }
 }
@@ -136,6 +233,36 @@ impl UIState {
         self.latency_offset_s = offset;
     }
 
+    pub fn set_audio_output(
+        &mut self,
+        devices: &[AudioDeviceInfo],
+        device_name: Option<&str>,
+        buffer_size: Option<u32>,
+    ) {
+        self.audio_output_devices = devices.to_vec();
+        self.audio_output_device_name = device_name.map(|n| n.to_owned());
+        self.audio_buffer_size = buffer_size;
+    }
+
+    pub fn set_midi_output(
+        &mut self,
+        ports: &[String],
+        target: Option<&MidiOutputTarget>,
+        latency_ms: f64,
+        mutes_samples: bool,
+    ) {
+        self.midi_output_ports = ports.to_vec();
+        self.midi_output = target.cloned();
+        self.midi_output_latency_ms = latency_ms;
+        self.midi_output_mutes_samples = mutes_samples;
+    }
+
+    pub fn set_midi_clock(&mut self, out: bool, source: Option<&str>, source_bpm: Option<f64>) {
+        self.midi_clock_out = out;
+        self.midi_clock_source = source.map(|s| s.to_string());
+        self.midi_clock_source_bpm = source_bpm;
+    }
+
     pub fn set_user_hits(&mut self, hits: &[UserHit]) {
         self.user_hits = hits.to_vec().clone();
     }
@@ -148,6 +275,14 @@ impl UIState {
         self.is_metronome_enabled = enabled;
     }
 
+    pub fn set_swing(&mut self, swing: f64) {
+        self.swing = swing;
+    }
+
+    pub fn set_gap_click(&mut self, config: &GapClickConfig) {
+        self.gap_click = *config;
+    }
+
     pub fn get_audio_latency_in_beats(&self) -> f32 {
         let beats_per_second = self.bpm / 60.;
         self.latency_offset_s * beats_per_second
@@ -161,24 +296,108 @@ impl UIState {
         self.is_dev_tools_visible = enabled;
     }
 
+    pub fn set_is_recording(&mut self, is_recording: bool) {
+        self.is_recording = is_recording;
+    }
+
     pub fn set_are_side_panels_visible(&mut self, visible: bool) {
         self.are_side_panels_visible = visible;
     }
 
-    pub fn set_correct_margin(&mut self, val: f64) {
-        self.correct_margin = val;
+    pub fn set_timing_windows(&mut self, val: &TimingWindows) {
+        self.timing_windows = *val;
     }
 
-    pub fn set_miss_margin(&mut self, val: f64) {
-        self.miss_margin = val;
+    pub fn set_confusion_set(&mut self, val: &ConfusionSet) {
+        self.confusion_set = val.clone();
+    }
+
+    pub fn set_dynamics_config(&mut self, val: &DynamicsConfig) {
+        self.dynamics_config = *val;
+    }
+
+    /// timing windows, in beats at the current tempo
+    fn margins(&self) -> TimingMargins {
+        self.timing_windows.margins(self.bpm as f64)
+    }
+
+    pub fn set_grading(&mut self, thresholds: &GradeThresholds, last_loop_grade: Option<Grade>) {
+        self.grade_thresholds = *thresholds;
+        self.last_loop_grade = last_loop_grade;
+    }
+
+    pub fn set_progress(&mut self, progress: &ProgressHistory) {
+        self.progress = progress.clone();
+    }
+
+    /// loop name, with the personal best if it's been played before
+    fn loop_label(&self, idx: usize) -> String {
+        let name = &self.selector_vec[idx];
+        match self.progress.get(name) {
+            Some(progress) => format!("{} ({})", name, progress.personal_best()),
+            None => name.to_string(),
+        }
+    }
+
+    fn selected_loop_progress(&self) -> Option<&LoopProgress> {
+        self.progress.get(self.selector_vec.get(self.selected_idx)?)
     }
 
     pub fn set_hide_empty_tracks(&mut self, val: bool) {
         self.hide_empty_tracks = val;
     }
 
-    pub fn set_midi_device_name(&mut self, val: &str) {
-        self.midi_device_name = val.to_owned();
+    pub fn set_midi(
+        &mut self,
+        ports: &[(String, bool)],
+        devices: &[(String, MidiMapping)],
+        mapping_names: &[String],
+        learning_device: Option<&str>,
+        learn_target: Option<Instrument>,
+    ) {
+        self.midi_ports = ports.to_vec();
+        self.midi_devices = devices.to_vec();
+        self.midi_mapping_names = mapping_names.to_vec();
+        self.midi_learning_device = learning_device.map(|d| d.to_string());
+        self.midi_learn_target = learn_target;
+    }
+
+    pub fn set_mic(
+        &mut self,
+        devices: &[String],
+        device: Option<&str>,
+        level: f32,
+        kit_instruments: &[Instrument],
+        is_kit_learning: bool,
+        kit_learn_target: Option<(Instrument, usize)>,
+    ) {
+        self.mic_input_devices = devices.to_vec();
+        self.mic_input_device = device.map(|d| d.to_string());
+        self.mic_level = level;
+        self.kit_instruments = kit_instruments.to_vec();
+        self.is_kit_learning = is_kit_learning;
+        self.kit_learn_target = kit_learn_target;
+    }
+
+    pub fn set_keymap(&mut self, keymap: &Keymap, binding: Option<Action>) {
+        self.keymap = keymap.clone();
+        self.key_binding = binding;
+    }
+
+    pub fn set_is_calibration_visible(&mut self, val: bool) {
+        self.is_calibration_visible = val;
+    }
+
+    pub fn set_calibration(&mut self, calibration: &CalibrationWizard) {
+        self.calibration = calibration.clone();
+    }
+
+    pub fn set_tempo_trainer_config(&mut self, config: &TempoTrainerConfig) {
+        self.tempo_trainer_config = *config;
+    }
+
+    pub fn set_tempo_log(&mut self, log: &[TempoLogEntry]) {
+        self.tempo_log = log.to_vec();
     }
 }
 
@@ -197,6 +416,8 @@ pub fn layout_ui(ctx: &egui::Context, ui_state: &UIState, events: &mut Vec<Event
     draw_central_panel(ctx, ui_state, events);
 
     help_window(ctx, ui_state);
+
+    calibration_window(ctx, ui_state, events);
 }
 
 fn help_window(ctx: &egui::Context, ui_state: &UIState) {
@@ -205,21 +426,111 @@ fn help_window(ctx: &egui::Context, ui_state: &UIState) {
     }
 
     egui::Window::new("Help").show(ctx, |ui| {
-        ui.horizontal(|ui| {
-            ui.label("Show Help");
-            ui.label("?");
-        });
-        ui.horizontal(|ui| {
-            ui.label("Show Dev Tools");
-            ui.label("a");
-        });
-        ui.horizontal(|ui| {
-            ui.label("Show FPS");
-            ui.label("z");
+        egui::Grid::new("help_keys").show(ui, |ui| {
+            for command in Command::ALL.iter() {
+                ui.label(command.name());
+                ui.label(ui_state.keymap.label(&Action::Command(*command)));
+                ui.end_row();
+            }
         });
     });
 }
 
+fn calibration_window(ctx: &egui::Context, ui_state: &UIState, events: &mut Vec<Events>) {
+    if !ui_state.is_calibration_visible {
+        return;
+    }
+
+    let calibration = &ui_state.calibration;
+    egui::Window::new("Latency Calibration").show(ctx, |ui| {
+        match calibration.phase {
+            None => {
+                ui.label("Tap along with a steady pulse, first watching and then listening.");
+                ui.label("Use the same input you'll play with.");
+                let tap_key = ui_state
+                    .keymap
+                    .label(&Action::Command(Command::CalibrationTap));
+                if ui
+                    .button(format!("Calibrate Keyboard ({})", tap_key))
+                    .clicked()
+                {
+                    events.push(Events::StartCalibration {
+                        input_device: KEYBOARD_INPUT_NAME.to_string(),
+                    });
+                }
+                if let Some(device) = &ui_state.mic_input_device {
+                    if ui
+                        .button(format!("Calibrate Microphone ({})", device))
+                        .clicked()
+                    {
+                        events.push(Events::StartCalibration {
+                            input_device: device.clone(),
+                        });
+                    }
+                }
+                for (device, _) in ui_state.midi_devices.iter() {
+                    let label = format!("Calibrate MIDI ({})", device);
+                    if ui.button(label).clicked() {
+                        events.push(Events::StartCalibration {
+                            input_device: device.clone(),
+                        });
+                    }
+                }
+                if let Some(result) = calibration.result {
+                    ui.separator();
+                    ui.label(format!(
+                        "Last result ({}): input {:.0} ms, audio output {:.0} ms",
+                        calibration.input_device,
+                        result.input_latency_s * 1000.,
+                        result.output_latency_s * 1000.
+                    ));
+                }
+                if ui.button("Close").clicked() {
+                    events.push(Events::ToggleCalibrationVisibility);
+                }
+            }
+            Some(phase) => {
+                match phase {
+                    CalibrationPhase::Visual => {
+                        ui.label("Step 1 of 2: tap along with the flashing circle (no sound).");
+                        // flash on each quarter note
+                        let is_flash = (ui_state.current_beat % 2.) < 0.25;
+                        let (rect, _) =
+                            ui.allocate_exact_size(egui::Vec2::new(40., 40.), egui::Sense::hover());
+                        let color = if is_flash {
+                            Color32::WHITE
+                        } else {
+                            Color32::DARK_GRAY
+                        };
+                        ui.painter().circle_filled(rect.center(), 18., color);
+                    }
+                    CalibrationPhase::Audio => {
+                        ui.label("Step 2 of 2: close your eyes and tap along with the click.");
+                    }
+                }
+
+                if let Some(stats) = calibration.current_stats() {
+                    ui.label(format!(
+                        "taps: {}  median: {:+.0} ms  spread: {:.0} ms",
+                        stats.num_taps,
+                        stats.median * 1000.,
+                        stats.std_dev * 1000.
+                    ));
+                }
+                if calibration.num_rejected_attempts > 0 {
+                    ui.label(format!(
+                        "Taps were too uneven, starting over (attempt {})",
+                        calibration.num_rejected_attempts + 1
+                    ));
+                }
+                if ui.button("Cancel").clicked() {
+                    events.push(Events::CancelCalibration);
+                }
+            }
+        }
+    });
+}
+
 fn dev_tools(ctx: &egui::Context, ui_state: &UIState, events: &mut Vec<Events>) {
     if !ui_state.is_dev_tools_visible {
         return;
@@ -229,26 +540,54 @@ fn dev_tools(ctx: &egui::Context, ui_state: &UIState, events: &mut Vec<Events>)
         ui.vertical_centered(|ui| {
             ui.heading("Dev Tools");
         });
-        CollapsingHeader::new("Accuracy")
-            .default_open(true)
+        ui.horizontal(|ui| {
+            if ui.button("Save Snapshot").clicked() {
+                events.push(Events::SaveSnapshot {
+                    include_user_hits: false,
+                });
+            }
+            if ui.button("Save Snapshot (with hits)").clicked() {
+                events.push(Events::SaveSnapshot {
+                    include_user_hits: true,
+                });
+            }
+            ui.separator();
+            if ui_state.is_recording {
+                if ui.button("Stop Recording").clicked() {
+                    events.push(Events::StopRecording);
+                }
+                ui.colored_label(Color32::RED, "recording input");
+            } else if ui.button("Record Input").clicked() {
+                events.push(Events::StartRecording);
+            }
+        });
+        CollapsingHeader::new("Grading")
+            .default_open(false)
             .show(ui, |ui| {
+                let mut thresholds = ui_state.grade_thresholds;
                 ui.horizontal(|ui| {
-                    ui.label("Correct Margin");
-                    let mut local_correct_margin = ui_state.correct_margin;
-                    let correct_margin_widget = egui::DragValue::new(&mut local_correct_margin);
-                    let correct_margin_widget_resp = correct_margin_widget.ui(ui);
-                    if correct_margin_widget_resp.changed() {
-                        events.push(Events::SetCorrectMargin(local_correct_margin));
-                    }
-
-                    ui.label("Miss Margin");
-                    let mut local_miss_margin = ui_state.miss_margin;
-                    let miss_margin_widget = egui::DragValue::new(&mut local_miss_margin);
-                    let miss_margin_widget_resp = miss_margin_widget.ui(ui);
-                    if miss_margin_widget_resp.changed() {
-                        events.push(Events::SetMissMargin(local_miss_margin));
-                    }
+                    ui.label("Great: min correct");
+                    ui.add(
+                        egui::DragValue::new(&mut thresholds.great_min_correct)
+                            .range(0.0..=1.0)
+                            .speed(0.01),
+                    );
+                    ui.label("max early/late");
+                    ui.add(
+                        egui::DragValue::new(&mut thresholds.great_max_early_late)
+                            .range(0.0..=1.0)
+                            .speed(0.01),
+                    );
+                    ui.label("Good: min score");
+                    ui.add(
+                        egui::DragValue::new(&mut thresholds.good_min_score)
+                            .range(0.0..=1.0)
+                            .speed(0.01),
+                    );
                 });
+                if thresholds != ui_state.grade_thresholds {
+                    events.push(Events::SetGradeThresholds(thresholds));
+                }
             });
     });
 }
@@ -284,19 +623,19 @@ fn draw_top_panel(ctx: &egui::Context, ui_state: &UIState, events: &mut Vec<Even
             ui.separator();
 
             let selector_text = if !ui_state.selector_vec.is_empty() {
-                &ui_state.selector_vec[ui_state.selected_idx]
+                ui_state.loop_label(ui_state.selected_idx)
             } else {
-                "No loops"
+                "No loops".to_string()
             };
             egui::ComboBox::from_label("")
-                .selected_text(selector_text.to_string())
+                .selected_text(selector_text)
                 .show_ui(ui, |ui| {
                     for i in 0..ui_state.selector_vec.len() {
                         let mut current_value = &ui_state.selector_vec[i];
                         let value = ui.selectable_value(
                             &mut current_value,
                             &ui_state.selector_vec[ui_state.selected_idx],
-                            &ui_state.selector_vec[i],
+                            ui_state.loop_label(i),
                         );
                         if value.clicked() {
                             events.push(Events::ChangeLoop(i));
@@ -306,6 +645,11 @@ fn draw_top_panel(ctx: &egui::Context, ui_state: &UIState, events: &mut Vec<Even
 
             ui.separator();
 
+            if let Some(grade) = ui_state.last_loop_grade {
+                ui.label(format!("{} {}", grade_emoji(grade), grade.name()));
+                ui.separator();
+            }
+
             ui.with_layout(egui::Layout::right_to_left(egui::Align::Center), |ui| {
                 if ui.button("Advanced View").clicked() {
                     events.push(Events::ToggleSidePanelVisibility);
@@ -344,6 +688,19 @@ fn draw_left_panel(ctx: &egui::Context, ui_state: &UIState, events: &mut Vec<Eve
 
             ui.separator();
 
+            ui.add(egui::Label::new("**Swing**"));
+            let mut local_swing_pct = ui_state.swing * 100.;
+            let swing_slider = egui::Slider::new(&mut local_swing_pct, 50.0..=75.0).suffix("%");
+            if swing_slider.ui(ui).changed() {
+                events.push(Events::SetSwing(local_swing_pct / 100.));
+            }
+
+            ui.separator();
+
+            gap_click(ui, ui_state, events);
+
+            ui.separator();
+
             ui.add(egui::Label::new("**Loop Status**"));
             ui.add(egui::Label::new("Current Loop"));
             ui.add(egui::Label::new(format!("{}", ui_state.current_loop)));
@@ -353,6 +710,14 @@ fn draw_left_panel(ctx: &egui::Context, ui_state: &UIState, events: &mut Vec<Eve
             ui.separator();
 
             gold_mode(ui, ui_state);
+
+            ui.separator();
+
+            progress(ui, ui_state);
+
+            ui.separator();
+
+            tempo_trainer(ui, ui_state, events);
         });
 }
 
@@ -370,6 +735,12 @@ fn draw_right_panel(ctx: &egui::Context, ui_state: &UIState, events: &mut Vec<Ev
                 ui.heading("Right Panel");
             });
 
+            timing_windows(ui, ui_state, events);
+
+            confusion_set(ui, ui_state, events);
+
+            ui.separator();
+
             ui.group(|ui| {
                 ui.add(egui::Label::new("Latency Offset"));
                 ui.label(format!("{:?}", ui_state.latency_offset_s));
@@ -379,8 +750,27 @@ fn draw_right_panel(ctx: &egui::Context, ui_state: &UIState, events: &mut Vec<Ev
                 if ui.button("+").clicked() {
                     events.push(Events::SetAudioLatency { delta_s: 0.1 });
                 }
+                if ui.button("Calibrate...").clicked() {
+                    events.push(Events::ToggleCalibrationVisibility);
+                }
             });
 
+            ui.separator();
+
+            audio_output(ui, ui_state, events);
+
+            ui.separator();
+
+            midi_output(ui, ui_state, events);
+
+            ui.separator();
+
+            timing_tendency(ui, ui_state);
+
+            ui.separator();
+
+            dynamics(ui, ui_state, events);
+
             ui.separator();
             egui::widgets::global_dark_light_mode_buttons(ui);
 
@@ -397,14 +787,19 @@ fn draw_right_panel(ctx: &egui::Context, ui_state: &UIState, events: &mut Vec<Ev
 
             ui.separator();
 
+            keyboard_controls(ui, ui_state, events);
+
+            ui.separator();
+
+            mic_input(ui, ui_state, events);
+
+            ui.separator();
+
             ui.group(|ui| {
                 ui.add(egui::Label::new("**MIDI**"));
-                ui.add(egui::Label::new(format!(
-                    "attached device: {}",
-                    ui_state.midi_device_name
-                )));
+                midi_devices(ui, ui_state, events);
 
-                if ui.button("Refresh Connected Midi Device").clicked() {
+                if ui.button("Refresh Midi Devices").clicked() {
                     events.push(Events::RefreshConnectedMidiDevice);
                 }
             });
@@ -495,7 +890,7 @@ fn draw_beat_grid(ui_state: &UIState, ui: &mut egui::Ui, events: &mut Vec<Events
                 };
                 events.push(Events::ToggleBeat {
                     ins: *ins,
-                    beat: col as f64,
+                    beat: Beat::from_beats(col as i64),
                 });
             }
         }
@@ -507,16 +902,26 @@ fn draw_beat_grid(ui_state: &UIState, ui: &mut egui::Ui, events: &mut Vec<Events
 
     draw_zebra_stripes(visible_rows, height_scale, to_screen, &mut shapes);
 
+    if let Some(target) = ui_state.midi_learn_target {
+        if let Some(row) = visible_instruments.iter().position(|ins| **ins == target) {
+            draw_highlighted_row(row, height_scale, to_screen, &mut shapes);
+        }
+    }
+
     draw_vertical_lines(visible_cols, width_scale, to_screen, &mut shapes);
 
     draw_horizontal_lines(visible_rows, height_scale, to_screen, &mut shapes);
 
+    draw_muted_bars(ui_state, width_scale, to_screen, &mut shapes);
+
     // Draw Note Successes
-    let loop_last_completed_beat = ui_state.current_beat - MISS_MARGIN as f32;
+    let margins = ui_state.margins();
+    let loop_last_completed_beat = ui_state.current_beat - margins.miss.as_f64() as f32;
     let current_loop_hits = get_hits_from_nth_loop(
         &ui_state.user_hits,
         ui_state.current_loop,
         ui_state.beats_per_loop,
+        &margins,
     );
     draw_note_successes(
         &current_loop_hits,
@@ -529,6 +934,8 @@ fn draw_beat_grid(ui_state: &UIState, ui: &mut egui::Ui, events: &mut Vec<Events
         height_scale,
         &visible_instruments,
         ui_state.beats_per_loop,
+        &margins,
+        &ui_state.confusion_set,
     );
 
     // Draw User Hits
@@ -553,18 +960,7 @@ fn draw_beat_grid(ui_state: &UIState, ui: &mut egui::Ui, events: &mut Vec<Events
 
     // add instrument names last, so they stay visible
     for (row, item) in visible_instruments.iter().enumerate().take(visible_rows) {
-        let name = match item {
-            Instrument::ClosedHihat => "Hi-hat",
-            Instrument::Snare => "Snare",
-            Instrument::Kick => "Kick",
-            Instrument::OpenHihat => "Open Hi-hat",
-            Instrument::Ride => "Ride",
-            Instrument::Crash => "Crash",
-            Instrument::Tom1 => "Tom1 (High)",
-            Instrument::Tom2 => "Tom2 (Med)",
-            Instrument::Tom3 => "Tom3 (Low)",
-            Instrument::PedalHiHat => "Pedal Hi-hat",
-        };
+        let name = instrument_name(item);
         // TODO: align text elsewhere
         let t_rect = rect_for_col_row(0., row, to_screen, width_scale, height_scale);
         let label = egui::Label::new(name);
@@ -572,6 +968,21 @@ fn draw_beat_grid(ui_state: &UIState, ui: &mut egui::Ui, events: &mut Vec<Events
     }
 }
 
+fn instrument_name(instrument: &Instrument) -> &'static str {
+    match instrument {
+        Instrument::ClosedHihat => "Hi-hat",
+        Instrument::Snare => "Snare",
+        Instrument::Kick => "Kick",
+        Instrument::OpenHihat => "Open Hi-hat",
+        Instrument::Ride => "Ride",
+        Instrument::Crash => "Crash",
+        Instrument::Tom1 => "Tom1 (High)",
+        Instrument::Tom2 => "Tom2 (Med)",
+        Instrument::Tom3 => "Tom3 (Low)",
+        Instrument::PedalHiHat => "Pedal Hi-hat",
+    }
+}
+
 fn draw_background(to_screen: RectTransform, shapes: &mut Vec<Shape>) {
     let bg_rect = egui::Shape::rect_filled(
         to_screen.transform_rect(egui::Rect {
@@ -610,6 +1021,25 @@ fn draw_zebra_stripes(
     }
 }
 
+/// e.g. the instrument that midi-learn is waiting for
+fn draw_highlighted_row(
+    row: usize,
+    height_scale: f32,
+    to_screen: RectTransform,
+    shapes: &mut Vec<Shape>,
+) {
+    let base_pos = pos2(0., (row as f32) * height_scale);
+    let start_pt = to_screen.transform_pos(base_pos);
+    let end_pt =
+        to_screen.transform_pos(base_pos + egui::Vec2::new(VIRTUAL_WIDTH, 1. * height_scale));
+    let shape = egui::Shape::rect_filled(
+        egui::Rect::from_two_pos(start_pt, end_pt),
+        egui::Rounding::default(),
+        Color32::from_rgb(255, 215, 120),
+    );
+    shapes.push(shape);
+}
+
 fn draw_vertical_lines(
     visible_cols: usize,
     width_scale: f32,
@@ -648,6 +1078,35 @@ fn draw_horizontal_lines(
     }
 }
 
+/// Shade the bars of the current loop where the guide track is silent (gap-click)
+fn draw_muted_bars(
+    ui_state: &UIState,
+    width_scale: f32,
+    to_screen: RectTransform,
+    shapes: &mut Vec<Shape>,
+) {
+    let loop_start_tick = (ui_state.current_loop * ui_state.beats_per_loop) as f64;
+    for col in 0..ui_state.beats_per_loop {
+        if !ui_state
+            .gap_click
+            .is_tick_muted(loop_start_tick + col as f64)
+        {
+            continue;
+        }
+
+        let base_pos = pos2(col as f32 * width_scale, 0.);
+        let shape = egui::Shape::rect_filled(
+            to_screen.transform_rect(egui::Rect {
+                min: base_pos,
+                max: base_pos + egui::Vec2::new(width_scale, VIRTUAL_HEIGHT),
+            }),
+            egui::Rounding::default(),
+            Color32::from_black_alpha(40),
+        );
+        shapes.push(shape);
+    }
+}
+
 fn rect_for_col_row(
     beat: f64,
     row: usize,
@@ -718,6 +1177,7 @@ fn draw_user_hits(
                 shapes,
                 height_scale,
                 ui_state.beats_per_loop,
+                &ui_state.margins(),
             );
         }
     }
@@ -728,16 +1188,21 @@ fn draw_user_hit(
     user_beat: f64,
     row: usize,
     audio_latency_beats: f64,
-    desired_hits: &[f64],
+    desired_hits: &[Beat],
     to_screen: RectTransform,
     shapes: &mut Vec<Shape>,
     height_scale: f32,
     beats_per_loop: usize,
+    margins: &TimingMargins,
 ) {
     let user_beat_with_latency = user_beat + audio_latency_beats;
 
-    let (acc, is_next_loop) =
-        compute_accuracy_of_single_hit(user_beat_with_latency, desired_hits, beats_per_loop);
+    let (acc, is_next_loop) = compute_accuracy_of_single_hit(
+        Beat::from_f64(user_beat_with_latency),
+        desired_hits,
+        beats_per_loop,
+        margins,
+    );
 
     // with audio latency and is_next_loop
     // TODO(bug): hit a note on every beat of 16. Then toggle on and off a note on only beat 1 for that instrument. it causes buggy display of hit timings where the 2nd half (beats 9-16) aren't shown .. bercause it's closer to beat 1 than any other beat, I guess?.
@@ -762,6 +1227,8 @@ fn draw_user_hit(
         Accuracy::Correct => GREEN,
         Accuracy::Miss => RED,
         Accuracy::Unknown => LIGHTGRAY,
+        Accuracy::Extra => MAGENTA,
+        Accuracy::WrongDrum => GOLD,
     };
     let bar_color_32 = Color32::from_rgb(
         (bar_color.r * 256.) as u8,
@@ -785,28 +1252,33 @@ fn draw_note_successes(
     height_scale: f32,
     visible_instruments: &[&Instrument],
     beats_per_loop: usize,
+    margins: &TimingMargins,
+    confusion_set: &ConfusionSet,
 ) {
-    for (instrument_idx, instrument) in visible_instruments.iter().enumerate() {
-        let actual = get_user_hit_timings_by_instrument(user_hits, **instrument, beats_per_loop);
-        // add audio_latency to each note
-        let actual_w_latency = actual
-            .iter()
-            .map(|note| note + audio_latency)
-            .collect::<Vec<f64>>();
+    // add audio_latency to each note
+    let hits_w_latency = user_hits
+        .iter()
+        .map(|hit| {
+            UserHit::new(hit.instrument, hit.clock_tick + audio_latency).with_velocity(hit.velocity)
+        })
+        .collect::<Vec<UserHit>>();
+    let loop_perf = compute_loop_summary(
+        &hits_w_latency,
+        desired_hits,
+        Beat::from_f64(loop_current_beat),
+        beats_per_loop,
+        margins,
+        confusion_set,
+    );
 
+    for (instrument_idx, instrument) in visible_instruments.iter().enumerate() {
         let desired = desired_hits.get_instrument_beats(instrument);
-
-        let loop_perf = compute_loop_performance_for_voice(
-            &actual_w_latency,
-            desired,
-            loop_current_beat,
-            beats_per_loop,
-        );
+        let accuracies = &loop_perf.get_score_tracker(instrument).accuracies;
         for (note_idx, note) in desired.iter().enumerate() {
             let shape = note_success_shape(
-                *note,
+                note.as_f64(),
                 instrument_idx,
-                loop_perf[note_idx],
+                accuracies[note_idx],
                 to_screen,
                 width_scale,
                 height_scale,
@@ -832,6 +1304,8 @@ fn note_success_shape(
         Accuracy::Correct => GREEN,
         Accuracy::Miss => RED,
         Accuracy::Unknown => DARKBLUE,
+        Accuracy::Extra => MAGENTA,
+        Accuracy::WrongDrum => GOLD,
     };
     let bar_color_32 = Color32::from_rgb(
         (bar_color.r * 256.) as u8,
@@ -842,6 +1316,15 @@ fn note_success_shape(
     egui::Shape::rect_filled(rect, egui::Rounding::default().at_least(5.0), bar_color_32)
 }
 
+fn grade_emoji(grade: Grade) -> char {
+    match grade {
+        Grade::Ace => '✅',
+        Grade::Great => '🟢',
+        Grade::Good => '🟡',
+        Grade::NeedsWork => '🔴',
+    }
+}
+
 fn gold_mode(ui: &mut egui::Ui, ui_state: &UIState) {
     ui.add(egui::Label::new("**Gold Mode**"));
 
@@ -849,36 +1332,44 @@ fn gold_mode(ui: &mut egui::Ui, ui_state: &UIState) {
     let mut s = String::new();
 
     let mut points: Vec<[f64; 2]> = vec![];
+    let margins = ui_state.margins();
+    let mut last_loop_totals = None;
     for i in 1..=5 {
         let nth_loop_hits = get_hits_from_nth_loop(
             &ui_state.user_hits,
             (ui_state.current_loop as i32 - i) as usize, // TODO: check for overflow
             ui_state.beats_per_loop,
+            &margins,
         );
         let summary_data = compute_last_loop_summary(
             &nth_loop_hits,
             &ui_state.desired_hits,
             ui_state.beats_per_loop,
+            &margins,
+            &ui_state.confusion_set,
         );
+        if i == 1 {
+            last_loop_totals = Some(summary_data.combined());
+        }
 
         // Simpler than chart.. TODO: support for colored emoji
-        // 🔴
-        // 🟠
-        // 🟡
-        // 🟢
-        // ✅
         let ratio = summary_data.combined().score();
-        s.push(if ratio == 1.0 {
-            '✅'
-        } else if ratio > 0.7 {
-            '🟢'
-        } else {
-            '🔴'
-        });
+        s.push(grade_emoji(summary_data.grade(&ui_state.grade_thresholds)));
         points.push([i as f64, ratio * 100_f64]);
     }
     ui.add(egui::Label::new(s));
 
+    if let Some(totals) = last_loop_totals {
+        ui.label(format!(
+            "last loop: {} correct, {} early/late, {} missed, {} wrong drum, {} extra",
+            totals.count(Accuracy::Correct),
+            totals.count(Accuracy::Early) + totals.count(Accuracy::Late),
+            totals.count(Accuracy::Miss),
+            totals.count(Accuracy::WrongDrum),
+            totals.count(Accuracy::Extra),
+        ));
+    }
+
     // PLOT
     let line = Line::new(points)
         .color(Color32::from_rgb(100, 200, 100))
@@ -896,3 +1387,887 @@ fn gold_mode(ui: &mut egui::Ui, ui_state: &UIState) {
         plot_ui.line(line);
     });
 }
+
+fn progress(ui: &mut egui::Ui, ui_state: &UIState) {
+    CollapsingHeader::new("Progress")
+        .default_open(false)
+        .show(ui, |ui| {
+            let Some(progress) = ui_state.selected_loop_progress() else {
+                ui.label("no history for this loop yet");
+                return;
+            };
+
+            ui.label(progress.personal_best());
+            ui.label(format!(
+                "{} attempts, {:.0} min played",
+                progress.attempts,
+                progress.time_spent_s / 60.
+            ));
+
+            // x axis is days since this loop was first played
+            let first_day = progress.by_day.keys().next().copied().unwrap_or_default();
+            let score_points: Vec<[f64; 2]> = progress
+                .by_day
+                .iter()
+                .map(|(day, p)| [(day - first_day) as f64, p.best_score * 100.])
+                .collect();
+            let bpm_points: Vec<[f64; 2]> = progress
+                .by_day
+                .iter()
+                .filter_map(|(day, p)| Some([(day - first_day) as f64, p.highest_aced_bpm?]))
+                .collect();
+
+            ui.label("Best score per day");
+            Plot::new("progress_score")
+                .height(100.)
+                .include_y(0.0)
+                .include_y(100.0)
+                .allow_drag(false)
+                .show(ui, |plot_ui| {
+                    plot_ui.line(Line::new(score_points).color(Color32::from_rgb(100, 200, 100)));
+                });
+
+            ui.label("Highest aced BPM per day");
+            Plot::new("progress_bpm")
+                .height(100.)
+                .allow_drag(false)
+                .show(ui, |plot_ui| {
+                    plot_ui.line(Line::new(bpm_points).color(Color32::from_rgb(200, 170, 50)));
+                });
+        });
+}
+
+fn tempo_trainer(ui: &mut egui::Ui, ui_state: &UIState, events: &mut Vec<Events>) {
+    CollapsingHeader::new("Tempo Trainer")
+        .default_open(false)
+        .show(ui, |ui| {
+            let mut config = ui_state.tempo_trainer_config;
+
+            ui.checkbox(&mut config.enabled, "Enabled");
+
+            let is_timed = matches!(config.mode, RampMode::Timed { .. });
+            egui::ComboBox::from_label("Ramp")
+                .selected_text(if is_timed { "Timed" } else { "Performance" })
+                .show_ui(ui, |ui| {
+                    if ui.selectable_label(!is_timed, "Performance").clicked() {
+                        config.mode = RampMode::Performance;
+                    }
+                    if ui.selectable_label(is_timed, "Timed").clicked() && !is_timed {
+                        config.mode = RampMode::Timed {
+                            seconds_per_step: 30.,
+                        };
+                    }
+                });
+
+            egui::Grid::new("tempo_trainer_grid").show(ui, |ui| {
+                ui.label("Start BPM");
+                ui.add(egui::DragValue::new(&mut config.start_bpm).range(40.0..=240.0));
+                ui.end_row();
+
+                ui.label("Target BPM");
+                ui.add(egui::DragValue::new(&mut config.target_bpm).range(40.0..=240.0));
+                ui.end_row();
+
+                ui.label("Step Up");
+                ui.add(egui::DragValue::new(&mut config.step_bpm).range(0.0..=20.0));
+                ui.end_row();
+
+                match &mut config.mode {
+                    RampMode::Performance => {
+                        ui.label("Success Grade");
+                        egui::ComboBox::from_id_source("tempo_trainer_success_grade")
+                            .selected_text(config.success_grade.name())
+                            .show_ui(ui, |ui| {
+                                for grade in Grade::ALL {
+                                    ui.selectable_value(
+                                        &mut config.success_grade,
+                                        grade,
+                                        grade.name(),
+                                    );
+                                }
+                            });
+                        ui.end_row();
+
+                        ui.label("Successes");
+                        ui.add(egui::DragValue::new(&mut config.success_takes).range(1..=20));
+                        ui.end_row();
+                    }
+                    RampMode::Timed { seconds_per_step } => {
+                        ui.label("Seconds / Step");
+                        ui.add(egui::DragValue::new(seconds_per_step).range(1.0..=600.0));
+                        ui.end_row();
+                    }
+                }
+
+                ui.label("Step Down");
+                ui.add(egui::DragValue::new(&mut config.step_down_bpm).range(0.0..=20.0));
+                ui.end_row();
+
+                ui.label("Failures");
+                ui.add(egui::DragValue::new(&mut config.failure_takes).range(1..=20));
+                ui.end_row();
+            });
+
+            if config != ui_state.tempo_trainer_config {
+                events.push(Events::SetTempoTrainerConfig(config));
+            }
+
+            if ui.button("Restart from Start BPM").clicked() {
+                events.push(Events::RestartTempoTrainer);
+            }
+
+            // tempo reached per loop, this session
+            let points: Vec<[f64; 2]> = ui_state
+                .tempo_log
+                .iter()
+                .map(|entry| [entry.loop_num as f64, entry.bpm])
+                .collect();
+            let line = Line::new(points)
+                .color(Color32::from_rgb(100, 150, 250))
+                .name("bpm");
+
+            let plot = Plot::new("Tempo Log")
+                .height(100.)
+                .show_axes([true, true])
+                .allow_drag(false);
+
+            plot.show(ui, |plot_ui| {
+                plot_ui.line(line);
+            });
+        });
+}
+
+fn keyboard_controls(ui: &mut egui::Ui, ui_state: &UIState, events: &mut Vec<Events>) {
+    CollapsingHeader::new("Keyboard Controls")
+        .default_open(false)
+        .show(ui, |ui| {
+            egui::ComboBox::from_id_source("keymap_preset")
+                .selected_text(ui_state.keymap.name.as_str())
+                .show_ui(ui, |ui| {
+                    for preset in Keymap::presets() {
+                        let selected = preset.name == ui_state.keymap.name;
+                        if ui.selectable_label(selected, &preset.name).clicked() && !selected {
+                            events.push(Events::SetKeymap(preset.name));
+                        }
+                    }
+                });
+
+            let conflicts = ui_state.keymap.conflicts();
+            egui::Grid::new("keymap_grid").show(ui, |ui| {
+                for action in Keymap::actions() {
+                    let name = match action {
+                        Action::Hit(ins) => instrument_name(&ins),
+                        Action::Command(command) => command.name(),
+                    };
+                    ui.label(name);
+
+                    if ui_state.key_binding == Some(action) {
+                        ui.strong("press a key... (Esc to cancel)");
+                    } else {
+                        let keys = ui_state.keymap.keys(&action);
+                        let labels: Vec<String> = keys.iter().map(|key| key.label()).collect();
+                        let is_conflict = conflicts
+                            .iter()
+                            .any(|(key, actions)| keys.contains(key) && actions.contains(&action));
+                        if is_conflict {
+                            ui.colored_label(Color32::RED, labels.join(", "));
+                        } else {
+                            ui.label(labels.join(", "));
+                        }
+                    }
+
+                    if ui.button("Rebind").clicked() {
+                        events.push(Events::StartKeyBinding(action));
+                    }
+                    if ui.button("Clear").clicked() {
+                        events.push(Events::ClearKeyBinding(action));
+                    }
+                    ui.end_row();
+                }
+            });
+
+            for (key, actions) in conflicts.iter() {
+                let names: Vec<&str> = actions
+                    .iter()
+                    .map(|action| match action {
+                        Action::Hit(ins) => instrument_name(ins),
+                        Action::Command(command) => command.name(),
+                    })
+                    .collect();
+                ui.colored_label(
+                    Color32::RED,
+                    format!("{} is bound to: {}", key.label(), names.join(", ")),
+                );
+            }
+        });
+}
+
+fn mic_input(ui: &mut egui::Ui, ui_state: &UIState, events: &mut Vec<Events>) {
+    ui.group(|ui| {
+        ui.add(egui::Label::new("**Microphone**"));
+
+        egui::ComboBox::from_id_source("mic_input_device")
+            .selected_text(ui_state.mic_input_device.as_deref().unwrap_or("Off"))
+            .show_ui(ui, |ui| {
+                let is_off = ui_state.mic_input_device.is_none();
+                if ui.selectable_label(is_off, "Off").clicked() && !is_off {
+                    events.push(Events::SetMicInputDevice(None));
+                }
+                for device in ui_state.mic_input_devices.iter() {
+                    let is_selected = ui_state.mic_input_device.as_ref() == Some(device);
+                    if ui.selectable_label(is_selected, device).clicked() && !is_selected {
+                        events.push(Events::SetMicInputDevice(Some(device.clone())));
+                    }
+                }
+            });
+        if ui.button("Refresh Input Devices").clicked() {
+            events.push(Events::RefreshMicInputDevices);
+        }
+
+        if ui_state.mic_input_device.is_none() {
+            return;
+        }
+        ui.add(egui::ProgressBar::new(ui_state.mic_level.sqrt()).desired_height(8.));
+
+        if ui_state.is_kit_learning {
+            if let Some((target, num_hits)) = ui_state.kit_learn_target {
+                ui.label(format!(
+                    "hit the {} ({}/{})",
+                    instrument_name(&target),
+                    num_hits,
+                    LEARN_HITS_PER_INSTRUMENT
+                ));
+            }
+            ui.horizontal(|ui| {
+                if ui.button("Skip").clicked() {
+                    events.push(Events::SkipKitLearnInstrument);
+                }
+                if ui.button("Save").clicked() {
+                    events.push(Events::FinishKitLearn);
+                }
+                if ui.button("Cancel").clicked() {
+                    events.push(Events::CancelKitLearn);
+                }
+            });
+            return;
+        }
+
+        if ui_state.kit_instruments.is_empty() {
+            ui.label("every hit counts as a snare hit");
+        } else {
+            let names: Vec<&str> = ui_state
+                .kit_instruments
+                .iter()
+                .map(instrument_name)
+                .collect();
+            ui.label(format!("hearing: {}", names.join(", ")));
+        }
+        ui.horizontal(|ui| {
+            if ui
+                .button("Learn Kit")
+                .on_hover_text("hit each drum a few times as its name is shown")
+                .clicked()
+            {
+                events.push(Events::StartKitLearn);
+            }
+            if !ui_state.kit_instruments.is_empty() && ui.button("Forget Kit").clicked() {
+                events.push(Events::ClearKitProfile);
+            }
+        });
+    });
+}
+
+fn midi_devices(ui: &mut egui::Ui, ui_state: &UIState, events: &mut Vec<Events>) {
+    if ui_state.midi_ports.is_empty() {
+        ui.label("no midi devices found");
+    }
+    for (port, connected) in ui_state.midi_ports.iter() {
+        let mut enabled = *connected;
+        if ui.checkbox(&mut enabled, port).changed() {
+            events.push(Events::SetMidiInputPortEnabled {
+                port: port.clone(),
+                enabled,
+            });
+        }
+    }
+
+    for (device, mapping) in ui_state.midi_devices.iter() {
+        midi_mapping(ui, ui_state, device, mapping, events);
+    }
+
+    midi_clock_source(ui, ui_state, events);
+}
+
+fn midi_clock_source(ui: &mut egui::Ui, ui_state: &UIState, events: &mut Vec<Events>) {
+    ui.horizontal(|ui| {
+        ui.label("follow MIDI clock");
+        egui::ComboBox::from_id_source("midi_clock_source")
+            .selected_text(ui_state.midi_clock_source.as_deref().unwrap_or("Off"))
+            .show_ui(ui, |ui| {
+                let is_off = ui_state.midi_clock_source.is_none();
+                if ui.selectable_label(is_off, "Off").clicked() && !is_off {
+                    events.push(Events::SetMidiClockSource(None));
+                }
+                for (device, _) in ui_state.midi_devices.iter() {
+                    let is_selected = ui_state.midi_clock_source.as_ref() == Some(device);
+                    if ui.selectable_label(is_selected, device).clicked() && !is_selected {
+                        events.push(Events::SetMidiClockSource(Some(device.clone())));
+                    }
+                }
+            });
+    });
+    if ui_state.midi_clock_source.is_some() {
+        match ui_state.midi_clock_source_bpm {
+            Some(bpm) => ui.label(format!("following at {:.1} BPM", bpm)),
+            None => ui.label("waiting for MIDI clock"),
+        };
+    }
+}
+
+fn midi_mapping(
+    ui: &mut egui::Ui,
+    ui_state: &UIState,
+    device: &str,
+    mapping: &MidiMapping,
+    events: &mut Vec<Events>,
+) {
+    CollapsingHeader::new(format!("Mapping: {}", device))
+        .id_source(("midi_mapping", device))
+        .default_open(false)
+        .show(ui, |ui| {
+            egui::ComboBox::from_id_source(("midi_mapping_combo", device))
+                .selected_text(mapping.name.as_str())
+                .show_ui(ui, |ui| {
+                    for name in ui_state.midi_mapping_names.iter() {
+                        let selected = *name == mapping.name;
+                        if ui.selectable_label(selected, name).clicked() && !selected {
+                            events.push(Events::SetMidiMapping {
+                                device: device.to_string(),
+                                mapping: name.clone(),
+                            });
+                        }
+                    }
+                });
+
+            let is_learning = ui_state.midi_learning_device.as_deref() == Some(device);
+            egui::Grid::new(("midi_mapping_grid", device)).show(ui, |ui| {
+                for ins in ALL_INSTRUMENTS.iter() {
+                    let notes = mapping.get_notes(ins);
+                    let name = instrument_name(ins);
+                    if is_learning && ui_state.midi_learn_target == Some(*ins) {
+                        ui.strong(format!("▶ {}", name));
+                    } else {
+                        ui.label(name);
+                    }
+                    let notes: Vec<String> = notes.iter().map(|n| n.to_string()).collect();
+                    ui.label(notes.join(", "));
+                    ui.end_row();
+                }
+            });
+            if let Some(pedal) = mapping.hihat_pedal {
+                ui.label(format!(
+                    "hi-hat pedal: CC{} (closed at {}+, open at {} or less)",
+                    pedal.controller, pedal.closed_min, pedal.open_max
+                ));
+            }
+
+            if !is_learning {
+                if ui
+                    .button("MIDI Learn")
+                    .on_hover_text("hit each pad as its instrument is highlighted")
+                    .clicked()
+                {
+                    events.push(Events::StartMidiLearn {
+                        device: device.to_string(),
+                    });
+                }
+                return;
+            }
+
+            if let Some(target) = ui_state.midi_learn_target {
+                ui.label(format!("hit the pad for: {}", instrument_name(&target)));
+            }
+            ui.horizontal(|ui| {
+                if ui.button("Skip").clicked() {
+                    events.push(Events::SkipMidiLearnInstrument);
+                }
+                if ui.button("Save").clicked() {
+                    events.push(Events::FinishMidiLearn);
+                }
+                if ui.button("Cancel").clicked() {
+                    events.push(Events::CancelMidiLearn);
+                }
+            });
+        });
+}
+
+fn dynamics(ui: &mut egui::Ui, ui_state: &UIState, events: &mut Vec<Events>) {
+    CollapsingHeader::new("Dynamics")
+        .default_open(false)
+        .show(ui, |ui| {
+            let mut config = ui_state.dynamics_config;
+
+            ui.checkbox(&mut config.enabled, "Score accents and ghost notes");
+            egui::Grid::new("dynamics_grid").show(ui, |ui| {
+                ui.label("Ghost at or below");
+                ui.add(
+                    egui::DragValue::new(&mut config.ghost_max_velocity)
+                        .range(1..=config.accent_min_velocity - 1),
+                );
+                ui.end_row();
+
+                ui.label("Accent at or above");
+                ui.add(
+                    egui::DragValue::new(&mut config.accent_min_velocity)
+                        .range(config.ghost_max_velocity + 1..=127),
+                );
+                ui.end_row();
+            });
+
+            if config != ui_state.dynamics_config {
+                events.push(Events::SetDynamicsConfig(config));
+            }
+
+            if !config.enabled {
+                return;
+            }
+
+            // hits from recent loops, by the loop they were played in
+            let latency = ui_state.get_audio_latency_in_beats() as f64;
+            let bpl = ui_state.beats_per_loop as f64;
+            let hits_in_loops = |first: usize, last: usize| -> Vec<UserHit> {
+                ui_state
+                    .user_hits
+                    .iter()
+                    .filter(|hit| {
+                        let loop_num = ((hit.clock_tick + latency) / bpl).floor();
+                        loop_num >= first as f64 && loop_num <= last as f64
+                    })
+                    .cloned()
+                    .collect()
+            };
+            let match_hits = |hits: &[UserHit]| {
+                match_velocity_hits(
+                    hits,
+                    &ui_state.desired_hits,
+                    latency,
+                    ui_state.beats_per_loop,
+                    &ui_state.margins(),
+                )
+            };
+
+            if ui_state.current_loop > 0 {
+                let last_loop = ui_state.current_loop - 1;
+                let hits = match_hits(&hits_in_loops(last_loop, last_loop));
+                match compute_dynamics_score(&hits, &config) {
+                    Some(score) => ui.label(format!(
+                        "last loop: {:.0}% at the written dynamic",
+                        score * 100.
+                    )),
+                    None => ui.label("last loop: no hits with velocity (midi only)"),
+                };
+            }
+
+            let first_loop = ui_state.current_loop.saturating_sub(TENDENCY_RECENT_LOOPS);
+            let recent_hits = match_hits(&hits_in_loops(first_loop, ui_state.current_loop));
+            ui.add(egui::Label::new("Velocity consistency"));
+            for instrument in ALL_INSTRUMENTS.iter() {
+                if let Some(consistency) = VelocityConsistency::from_hits(&recent_hits, *instrument)
+                {
+                    ui.label(format!(
+                        "{} {}",
+                        instrument_name(instrument).to_lowercase(),
+                        consistency.describe()
+                    ));
+                }
+            }
+        });
+}
+
+fn gap_click(ui: &mut egui::Ui, ui_state: &UIState, events: &mut Vec<Events>) {
+    CollapsingHeader::new("Gap Click")
+        .default_open(false)
+        .show(ui, |ui| {
+            let mut config = ui_state.gap_click;
+
+            ui.checkbox(&mut config.enabled, "Enabled");
+            egui::Grid::new("gap_click_grid").show(ui, |ui| {
+                ui.label("Play Bars");
+                ui.add(egui::DragValue::new(&mut config.play_bars).range(1..=16));
+                ui.end_row();
+
+                ui.label("Mute Bars");
+                ui.add(egui::DragValue::new(&mut config.mute_bars).range(0..=16));
+                ui.end_row();
+
+                ui.label("Random Dropout %");
+                let mut pct = config.random_dropout * 100.;
+                if ui
+                    .add(egui::DragValue::new(&mut pct).range(0.0..=100.0))
+                    .changed()
+                {
+                    config.random_dropout = pct / 100.;
+                }
+                ui.end_row();
+            });
+
+            if config != ui_state.gap_click {
+                events.push(Events::SetGapClick(config));
+            }
+
+            if !config.enabled {
+                return;
+            }
+
+            // per-bar drift during the silent bars, most recent first
+            ui.add(egui::Label::new("Drift (silent bars)"));
+            let drifts = compute_drift_per_bar(
+                &ui_state.user_hits,
+                &ui_state.desired_hits,
+                ui_state.get_audio_latency_in_beats() as f64,
+                ui_state.beats_per_loop,
+            );
+            let seconds_per_beat = 60. / ui_state.bpm as f64 / 2.;
+            for drift in drifts
+                .iter()
+                .rev()
+                .filter(|d| config.is_bar_muted(d.bar))
+                .take(8)
+            {
+                let ms = drift.mean_offset * seconds_per_beat * 1000.;
+                ui.label(format!(
+                    "bar {}: {:+.0} ms ({} hits)",
+                    drift.bar + 1,
+                    ms,
+                    drift.num_hits
+                ));
+            }
+        });
+}
+
+fn audio_output(ui: &mut egui::Ui, ui_state: &UIState, events: &mut Vec<Events>) {
+    ui.group(|ui| {
+        ui.add(egui::Label::new("**Audio Output**"));
+
+        let selected_name = ui_state
+            .audio_output_device_name
+            .clone()
+            .unwrap_or("Default".to_string());
+        egui::ComboBox::from_id_source("audio_output_device")
+            .selected_text(selected_name)
+            .show_ui(ui, |ui| {
+                let is_default = ui_state.audio_output_device_name.is_none();
+                if ui.selectable_label(is_default, "Default").clicked() && !is_default {
+                    events.push(Events::SetAudioOutputDevice(None));
+                }
+                for device in &ui_state.audio_output_devices {
+                    let is_selected =
+                        ui_state.audio_output_device_name.as_deref() == Some(&device.name);
+                    let label = format!("{} ({} Hz)", device.name, device.sample_rate);
+                    if ui.selectable_label(is_selected, label).clicked() && !is_selected {
+                        events.push(Events::SetAudioOutputDevice(Some(device.name.clone())));
+                    }
+                }
+            });
+
+        let selected_device = ui_state
+            .audio_output_devices
+            .iter()
+            .find(|d| ui_state.audio_output_device_name.as_deref() == Some(&d.name));
+        if let Some(AudioDeviceInfo {
+            buffer_size_range: Some((min, max)),
+            ..
+        }) = selected_device
+        {
+            ui.label(format!("buffer: {} - {} frames", min, max));
+        }
+
+        let buffer_text = match ui_state.audio_buffer_size {
+            Some(frames) => format!("{} frames", frames),
+            None => "Default".to_string(),
+        };
+        egui::ComboBox::from_id_source("audio_buffer_size")
+            .selected_text(buffer_text)
+            .show_ui(ui, |ui| {
+                let is_default = ui_state.audio_buffer_size.is_none();
+                if ui.selectable_label(is_default, "Default").clicked() && !is_default {
+                    events.push(Events::SetAudioBufferSize(None));
+                }
+                for frames in BUFFER_SIZE_OPTIONS {
+                    if selected_device.is_some_and(|d| !d.supports_buffer_size(frames)) {
+                        continue;
+                    }
+                    let is_selected = ui_state.audio_buffer_size == Some(frames);
+                    if ui
+                        .selectable_label(is_selected, format!("{} frames", frames))
+                        .clicked()
+                        && !is_selected
+                    {
+                        events.push(Events::SetAudioBufferSize(Some(frames)));
+                    }
+                }
+            });
+
+        if ui.button("Refresh Audio Devices").clicked() {
+            events.push(Events::RefreshAudioOutputDevices);
+        }
+    });
+}
+
+fn midi_output(ui: &mut egui::Ui, ui_state: &UIState, events: &mut Vec<Events>) {
+    ui.group(|ui| {
+        ui.add(egui::Label::new("**MIDI Output**"));
+
+        let selected_text = match &ui_state.midi_output {
+            Some(MidiOutputTarget::Virtual) => format!("Virtual ({})", MidiOutputTarget::Virtual.name()),
+            Some(target) => target.name().to_string(),
+            None => "Off".to_string(),
+        };
+        egui::ComboBox::from_id_source("midi_output")
+            .selected_text(selected_text)
+            .show_ui(ui, |ui| {
+                let mut options = vec![None];
+                if VIRTUAL_PORT_SUPPORTED {
+                    options.push(Some(MidiOutputTarget::Virtual));
+                }
+                options.extend(
+                    ui_state
+                        .midi_output_ports
+                        .iter()
+                        .map(|port| Some(MidiOutputTarget::Port(port.clone()))),
+                );
+                for option in options {
+                    let label = match &option {
+                        Some(MidiOutputTarget::Virtual) => "Virtual".to_string(),
+                        Some(target) => target.name().to_string(),
+                        None => "Off".to_string(),
+                    };
+                    let is_selected = ui_state.midi_output == option;
+                    if ui.selectable_label(is_selected, label).clicked() && !is_selected {
+                        events.push(Events::SetMidiOutput(option));
+                    }
+                }
+            });
+
+        if ui_state.midi_output.is_some() {
+            let mut latency_ms = ui_state.midi_output_latency_ms;
+            ui.horizontal(|ui| {
+                ui.label("latency offset");
+                if ui
+                    .add(
+                        egui::DragValue::new(&mut latency_ms)
+                            .range(-200.0..=200.0)
+                            .suffix(" ms"),
+                    )
+                    .on_hover_text("shifts midi notes later (or earlier, if negative) to line up with the bundled sounds")
+                    .changed()
+                {
+                    events.push(Events::SetMidiOutputLatency { latency_ms });
+                }
+            });
+
+            let mut mutes = ui_state.midi_output_mutes_samples;
+            if ui.checkbox(&mut mutes, "mute bundled sounds").changed() {
+                events.push(Events::SetMidiOutputMutesSamples(mutes));
+            }
+
+            let mut clock_out = ui_state.midi_clock_out;
+            if ui
+                .checkbox(&mut clock_out, "send MIDI clock")
+                .on_hover_text("other gear follows our tempo and start/stop")
+                .changed()
+            {
+                events.push(Events::SetMidiClockOut(clock_out));
+            }
+        }
+
+        if ui.button("Refresh MIDI Outputs").clicked() {
+            events.push(Events::RefreshMidiOutputPorts);
+        }
+    });
+}
+
+fn timing_windows(ui: &mut egui::Ui, ui_state: &UIState, events: &mut Vec<Events>) {
+    ui.add(egui::Label::new("**Difficulty**"));
+
+    let mut windows = ui_state.timing_windows;
+    let selected_text = match windows.difficulty() {
+        Some(difficulty) => difficulty.name().to_string(),
+        None => "Custom".to_string(),
+    };
+    egui::ComboBox::from_id_source("difficulty")
+        .selected_text(selected_text)
+        .show_ui(ui, |ui| {
+            for difficulty in Difficulty::ALL {
+                if ui
+                    .selectable_label(windows.difficulty() == Some(difficulty), difficulty.name())
+                    .clicked()
+                {
+                    windows = difficulty.timing_windows();
+                }
+            }
+        });
+
+    CollapsingHeader::new("Timing Windows")
+        .default_open(false)
+        .show(ui, |ui| {
+            egui::Grid::new("timing_windows_grid").show(ui, |ui| {
+                ui.label("Correct");
+                ui.add(
+                    egui::DragValue::new(&mut windows.correct_ms)
+                        .range(1.0..=windows.miss_ms)
+                        .suffix(" ms"),
+                );
+                ui.end_row();
+
+                ui.label("Miss");
+                ui.add(
+                    egui::DragValue::new(&mut windows.miss_ms)
+                        .range(windows.correct_ms..=500.0)
+                        .suffix(" ms"),
+                );
+                ui.end_row();
+
+                let mut is_capped = windows.max_miss_beats.is_some();
+                ui.checkbox(&mut is_capped, "Max beats");
+                let mut max_miss_beats = windows.max_miss_beats.unwrap_or(0.5);
+                ui.add_enabled(
+                    is_capped,
+                    egui::DragValue::new(&mut max_miss_beats)
+                        .range(0.05..=1.0)
+                        .speed(0.01),
+                );
+                windows.max_miss_beats = is_capped.then_some(max_miss_beats);
+                ui.end_row();
+            });
+
+            let margins = ui_state.margins();
+            ui.label(format!(
+                "at {} BPM: ±{:.2} / ±{:.2} beats",
+                ui_state.bpm, margins.correct, margins.miss
+            ));
+        });
+
+    if windows != ui_state.timing_windows {
+        events.push(Events::SetTimingWindows(windows));
+    }
+}
+
+fn confusion_set(ui: &mut egui::Ui, ui_state: &UIState, events: &mut Vec<Events>) {
+    CollapsingHeader::new("Wrong Drum")
+        .default_open(false)
+        .show(ui, |ui| {
+            ui.label("Hits on one of these instead of the other count as \"wrong drum\"");
+
+            let mut confusion_set = ui_state.confusion_set.clone();
+            let mut to_remove = None;
+            for (idx, (a, b)) in confusion_set.pairs.iter().enumerate() {
+                ui.horizontal(|ui| {
+                    ui.label(format!("{} / {}", instrument_name(a), instrument_name(b)));
+                    if ui.small_button("x").clicked() {
+                        to_remove = Some(idx);
+                    }
+                });
+            }
+            if let Some(idx) = to_remove {
+                confusion_set.pairs.remove(idx);
+            }
+
+            // new pairs are picked in the UI, then added
+            let id = ui.make_persistent_id("new_confusion_pair");
+            let (mut a, mut b) =
+                ui.data_mut(|d| *d.get_temp_mut_or(id, (Instrument::Snare, Instrument::Tom1)));
+            ui.horizontal(|ui| {
+                for (side, ins) in [("a", &mut a), ("b", &mut b)] {
+                    egui::ComboBox::from_id_source(("new_confusion_pair", side))
+                        .selected_text(instrument_name(ins))
+                        .show_ui(ui, |ui| {
+                            for option in ALL_INSTRUMENTS.iter() {
+                                ui.selectable_value(ins, *option, instrument_name(option));
+                            }
+                        });
+                }
+                let can_add = a != b && !confusion_set.are_confusable(a, b);
+                if ui.add_enabled(can_add, egui::Button::new("Add")).clicked() {
+                    confusion_set.pairs.push((a, b));
+                }
+            });
+            ui.data_mut(|d| d.insert_temp(id, (a, b)));
+
+            if confusion_set != ui_state.confusion_set {
+                events.push(Events::SetConfusionSet(confusion_set));
+            }
+        });
+}
+
+// how many of the most recent loops to consider when looking for timing tendencies
+const TENDENCY_RECENT_LOOPS: usize = 8;
+const TENDENCY_HISTOGRAM_BIN_MS: f64 = 5.;
+
+fn timing_tendency(ui: &mut egui::Ui, ui_state: &UIState) {
+    CollapsingHeader::new("Timing Tendency")
+        .default_open(false)
+        .show(ui, |ui| {
+            let first_loop = ui_state.current_loop.saturating_sub(TENDENCY_RECENT_LOOPS);
+            let mut all_offsets = vec![];
+            for instrument in ALL_INSTRUMENTS.iter() {
+                let offsets: Vec<(usize, f64)> = compute_hit_offsets_ms(
+                    &ui_state.user_hits,
+                    &ui_state.desired_hits,
+                    *instrument,
+                    ui_state.get_audio_latency_in_beats() as f64,
+                    ui_state.bpm as f64,
+                    ui_state.beats_per_loop,
+                    &ui_state.margins(),
+                )
+                .into_iter()
+                .filter(|(loop_num, _)| *loop_num >= first_loop)
+                .collect();
+
+                let Some(tendency) = TimingTendency::from_offsets(&offsets) else {
+                    continue;
+                };
+                ui.label(format!(
+                    "{} {}",
+                    instrument_name(instrument).to_lowercase(),
+                    tendency.describe()
+                ))
+                .on_hover_text(format!(
+                    "{} hits, spread ±{:.0} ms, trend {:+.1} ms/loop",
+                    tendency.num_hits, tendency.std_dev_ms, tendency.trend_ms_per_loop
+                ));
+                all_offsets.extend(offsets);
+            }
+
+            let Some(overall) = TimingTendency::from_offsets(&all_offsets) else {
+                ui.label("no hits yet");
+                return;
+            };
+            ui.label(format!(
+                "overall {}, spread ±{:.0} ms, trend {:+.1} ms/loop",
+                overall.describe(),
+                overall.std_dev_ms,
+                overall.trend_ms_per_loop
+            ));
+
+            // histogram of offsets. negative is early, positive is late.
+            let mut bins: BTreeMap<i64, usize> = BTreeMap::new();
+            for (_, offset) in all_offsets.iter() {
+                let bin = (offset / TENDENCY_HISTOGRAM_BIN_MS).round() as i64;
+                *bins.entry(bin).or_default() += 1;
+            }
+            let bars: Vec<Bar> = bins
+                .iter()
+                .map(|(bin, count)| {
+                    Bar::new(*bin as f64 * TENDENCY_HISTOGRAM_BIN_MS, *count as f64)
+                        .width(TENDENCY_HISTOGRAM_BIN_MS)
+                })
+                .collect();
+            Plot::new("timing_tendency_histogram")
+                .height(100.)
+                .allow_zoom(false)
+                .allow_drag(false)
+                .allow_scroll(false)
+                .show(ui, |plot_ui| plot_ui.bar_chart(BarChart::new(bars)));
+        });
+}
@@ -1,10 +1,23 @@
-use crate::voices::Instrument;
+use crate::{
+    beat::Beat,
+    dynamics::DynamicsConfig,
+    gap_click::GapClickConfig,
+    keymap::{Action, Key},
+    midi_clock::Transport,
+    midi_output::MidiOutputTarget,
+    score::{ConfusionSet, GradeThresholds, TimingWindows},
+    tempo_trainer::TempoTrainerConfig,
+    voices::Instrument,
+};
 
 #[derive(Clone, Debug)]
 pub enum Events {
     UserHit {
         instrument: Instrument,
         processing_delay: f64,
+        velocity: Option<u8>,
+        /// the input device it came from, as named for its calibrated latency
+        device: String,
     },
     Pause,
     ChangeBPM {
@@ -14,26 +27,95 @@ pub enum Events {
     Quit,
     ResetHits,
     SaveLoop,
+    SaveSnapshot {
+        include_user_hits: bool,
+    },
+    StartRecording,
+    StopRecording,
     ToggleBeat {
         ins: Instrument,
-        beat: f64,
+        beat: Beat,
     },
     TrackForCalibration,
+    StartCalibration {
+        input_device: String,
+    },
+    CancelCalibration,
     SetAudioLatency {
         delta_s: f64,
     },
+    SetAudioOutputDevice(Option<String>),
+    SetAudioBufferSize(Option<u32>),
+    RefreshAudioOutputDevices,
+    SetMidiOutput(Option<MidiOutputTarget>),
+    SetMidiOutputLatency {
+        latency_ms: f64,
+    },
+    SetMidiOutputMutesSamples(bool),
+    RefreshMidiOutputPorts,
+    SetMidiClockOut(bool),
+    SetMidiClockSource(Option<String>),
+    MidiTransport(Transport),
+    /// the followed midi clock's latest pulse, which arrived processing_delay seconds ago
+    SyncToMidiClock {
+        bpm: Option<f64>,
+        position_ticks: f64,
+        processing_delay: f64,
+    },
     ToggleMetronome,
+    SetSwing(f64),
+    SetGapClick(GapClickConfig),
     ChangeLoop(usize), // loop idx
 
     ToggleHelpVisibility,
     ToggleEmptyTrackVisibility,
     ToggleSidePanelVisibility,
+    ToggleCalibrationVisibility,
 
     RefreshConnectedMidiDevice,
+    SetMidiInputPortEnabled {
+        port: String,
+        enabled: bool,
+    },
+    SetMidiMapping {
+        device: String,
+        mapping: String,
+    },
+    StartMidiLearn {
+        device: String,
+    },
+    SkipMidiLearnInstrument,
+    CancelMidiLearn,
+    FinishMidiLearn,
+
+    SetMicInputDevice(Option<String>),
+    RefreshMicInputDevices,
+    StartKitLearn,
+    SkipKitLearnInstrument,
+    CancelKitLearn,
+    FinishKitLearn,
+    ClearKitProfile,
+
+    /// the next key pressed is bound to this action
+    StartKeyBinding(Action),
+    CancelKeyBinding,
+    BindKey {
+        action: Action,
+        key: Key,
+    },
+    ClearKeyBinding(Action),
+    /// switch to a preset keymap, by name
+    SetKeymap(String),
+
+    SetTimingWindows(TimingWindows),
+    SetConfusionSet(ConfusionSet),
+    SetDynamicsConfig(DynamicsConfig),
+
+    SetTempoTrainerConfig(TempoTrainerConfig),
+    RestartTempoTrainer,
 
     // Dev Tools
     ToggleDebugMode,
     ToggleDevToolsVisibility,
-    SetCorrectMargin(f64),
-    SetMissMargin(f64),
+    SetGradeThresholds(GradeThresholds),
 }
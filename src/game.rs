@@ -1,39 +1,43 @@
 use std::error::Error;
-use std::{env, fs};
 
 use std::sync::mpsc::Receiver;
 
 use crate::audio::Audio;
+use crate::calibration::{tap_offset_seconds, CalibrationWizard, KEYBOARD_INPUT_NAME};
 use crate::config::AppConfig;
 use crate::consts::{TxMsg, DEFAULT_BEATS_PER_LOOP};
+use crate::dynamics::DynamicsConfig;
 use crate::egui_ui::UIState;
+use crate::journal::{JournalHit, JournalNote, LoopAttempt, SessionJournal};
+use crate::keyboard_input_handler::KeyboardInputHandler;
+use crate::mic_input_handler::MicInputHandler;
+use crate::midi_clock::{loop_offset, Transport, FOLLOW_BPM_TOLERANCE, FOLLOW_MAX_DRIFT_TICKS};
 use crate::midi_input_handler::MidiInputHandler;
+use crate::midi_mapping::{save_user_mapping, MidiMapping};
+use crate::progress::ProgressHistory;
+use crate::replay::{new_recording_path, InputRecorder};
 use crate::score::{
-    compute_last_loop_summary, get_hits_from_nth_loop, CORRECT_MARGIN, MISS_MARGIN,
+    compute_last_loop_summary, get_hits_from_nth_loop, ConfusionSet, Grade, GradeThresholds,
+    TimingWindows,
 };
-use crate::time::current_time_millis;
-use crate::voices::{Voices, VoicesFromJSON};
+use crate::snapshot::{autosave_path, new_snapshot_path, GameSnapshot};
+use crate::tempo_trainer::{TempoTrainer, TempoTrainerConfig};
+use crate::voices::{Voices, STRAIGHT_SWING};
 
 use log::info;
 use macroquad::prelude::*;
-use serde::Serialize;
+use serde::{Deserialize, Serialize};
 
 use crate::{events::Events, voices::Loop};
 
-const GOLD_MODE_BPM_STEP: f64 = 2.;
-const GOLD_MODE_CORRECT_TAKES: i32 = 3;
-
-pub struct GoldMode {
-    pub correct_takes: i32,
-    pub was_gold: bool,
-}
-
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
 pub struct Flags {
     pub ui_debug_mode: bool,
     pub dev_tools_visible: bool,
     pub help_visible: bool,
     pub hide_empty_tracks: bool,
     pub side_panels_visible: bool,
+    pub calibration_visible: bool,
 }
 
 impl Flags {
@@ -44,6 +48,7 @@ impl Flags {
             help_visible: false,
             hide_empty_tracks: false,
             side_panels_visible: false,
+            calibration_visible: false,
         }
     }
 }
@@ -52,60 +57,66 @@ pub type Loops = Vec<(String, Loop)>;
 
 pub struct GameState {
     pub voices: Voices,
-    pub gold_mode: GoldMode,
+    pub tempo_trainer: TempoTrainer,
     pub selected_loop_idx: usize,
     pub loops: Loops,
     pub flags: Flags,
-    pub correct_margin: f64,
-    pub miss_margin: f64,
+    pub timing_windows: TimingWindows,
+    pub confusion_set: ConfusionSet,
+    pub dynamics_config: DynamicsConfig,
+    pub grade_thresholds: GradeThresholds,
+    pub last_loop_grade: Option<Grade>,
     pub beats_per_loop: usize,
+    /// the user's swing setting, used by loops that don't set their own
+    pub global_swing: f64,
+    /// the current loop's own swing, if it has one
+    pub loop_swing: Option<f64>,
+    pub calibration: CalibrationWizard,
+    pub progress: ProgressHistory,
 }
 
 impl GameState {
     pub fn new(loops: Loops) -> Self {
         Self {
             voices: Voices::new(),
-            gold_mode: GoldMode {
-                correct_takes: 0,
-                was_gold: false,
-            },
+            tempo_trainer: TempoTrainer::new(TempoTrainerConfig::default()),
             selected_loop_idx: 0,
             loops,
             flags: Flags::new(),
-            correct_margin: CORRECT_MARGIN,
-            miss_margin: MISS_MARGIN,
+            timing_windows: TimingWindows::default(),
+            confusion_set: ConfusionSet::default(),
+            dynamics_config: DynamicsConfig::default(),
+            grade_thresholds: GradeThresholds::default(),
+            last_loop_grade: None,
             beats_per_loop: DEFAULT_BEATS_PER_LOOP,
+            global_swing: STRAIGHT_SWING,
+            loop_swing: None,
+            calibration: CalibrationWizard::new(),
+            progress: ProgressHistory::default(),
         }
     }
 
-    pub fn new_mock_game_state() -> Self {
-        let voices_from_json = VoicesFromJSON::new_mock();
-        let voices = Voices::new_from_voices_old_model(&voices_from_json);
-        Self {
-            voices,
-            gold_mode: GoldMode {
-                correct_takes: 0,
-                was_gold: false,
-            },
-            selected_loop_idx: 0,
-            loops: vec![(
-                "Foo".to_string(),
-                Loop {
-                    bpm: 112,
-                    length_in_beats: 16,
-                    voices: voices_from_json,
-                },
-            )],
-            flags: Flags::new(),
-            correct_margin: CORRECT_MARGIN,
-            miss_margin: MISS_MARGIN,
-            beats_per_loop: DEFAULT_BEATS_PER_LOOP,
-        }
+    pub fn selected_loop_name(&self) -> &str {
+        self.loops
+            .get(self.selected_loop_idx)
+            .map_or("", |(name, _)| name.as_str())
+    }
+
+    /// the swing the current loop is played and scored with
+    pub fn swing(&self) -> f64 {
+        self.loop_swing.unwrap_or(self.global_swing)
     }
 }
 
 // TODO: simplify how we init this.. I don't think all the mutability and helper fns are needed
-pub fn compute_ui_state(gs: &GameState, audio: &Audio, midi_device_name: &str) -> UIState {
+pub fn compute_ui_state(
+    gs: &GameState,
+    audio: &Audio,
+    midi_input: &MidiInputHandler,
+    mic_input: &MicInputHandler,
+    keyboard_input: &KeyboardInputHandler,
+    is_recording: bool,
+) -> UIState {
     let selector_vec = gs.loops.iter().map(|(name, _)| name.to_string()).collect();
     let mut ui_state = UIState::default().selector_vec(&selector_vec);
     ui_state.set_selected_idx(gs.selected_loop_idx);
@@ -115,35 +126,157 @@ pub fn compute_ui_state(gs: &GameState, audio: &Audio, midi_device_name: &str) -
     ui_state.set_bpm(audio.get_bpm() as f32);
     ui_state.set_beats_per_loop(gs.beats_per_loop);
     ui_state.set_audio_latency_s(audio.get_configured_audio_latency_seconds() as f32);
+    ui_state.set_audio_output(
+        audio.get_output_devices(),
+        audio.get_output_device_name(),
+        audio.get_buffer_size(),
+    );
+    ui_state.set_midi_output(
+        audio.get_midi_output_ports(),
+        audio.get_midi_output(),
+        audio.get_midi_output_latency_s() * 1000.,
+        audio.get_midi_output_mutes_samples(),
+    );
+    ui_state.set_midi_clock(
+        audio.is_midi_clock_out(),
+        midi_input.clock_source(),
+        midi_input.clock_source_bpm(),
+    );
     ui_state.set_user_hits(&audio.user_hits);
-    ui_state.set_desired_hits(&gs.voices);
+    ui_state.set_desired_hits(&gs.voices.swung(gs.swing()));
+    ui_state.set_swing(gs.swing());
     ui_state.set_are_side_panels_visible(gs.flags.side_panels_visible);
     ui_state.set_metronome_enabled(audio.is_metronome_enabled());
+    ui_state.set_gap_click(audio.get_gap_click());
 
     ui_state.set_is_dev_tools_visible(gs.flags.dev_tools_visible);
-    ui_state.set_correct_margin(gs.correct_margin);
-    ui_state.set_miss_margin(gs.miss_margin);
+    ui_state.set_is_recording(is_recording);
+    ui_state.set_timing_windows(&gs.timing_windows);
+    ui_state.set_confusion_set(&gs.confusion_set);
+    ui_state.set_dynamics_config(&gs.dynamics_config);
+    ui_state.set_grading(&gs.grade_thresholds, gs.last_loop_grade);
+    ui_state.set_progress(&gs.progress);
     ui_state.set_is_help_visible(gs.flags.help_visible);
     ui_state.set_hide_empty_tracks(gs.flags.hide_empty_tracks);
-    ui_state.set_midi_device_name(midi_device_name);
+    let midi_devices: Vec<(String, MidiMapping)> = midi_input
+        .connected_device_names()
+        .into_iter()
+        .filter_map(|name| {
+            let mapping = midi_input.mapping(&name)?.clone();
+            Some((name, mapping))
+        })
+        .collect();
+    ui_state.set_midi(
+        &midi_input.ports(),
+        &midi_devices,
+        &midi_input.mapping_names(),
+        midi_input.learning_device(),
+        midi_input.learn_target(),
+    );
+    ui_state.set_mic(
+        mic_input.devices(),
+        mic_input.device_name(),
+        mic_input.level(),
+        &mic_input.profile_instruments(),
+        mic_input.is_learning(),
+        mic_input.learn_target(),
+    );
+    ui_state.set_keymap(keyboard_input.keymap(), keyboard_input.binding());
+    ui_state.set_is_calibration_visible(gs.flags.calibration_visible);
+    ui_state.set_calibration(&gs.calibration);
+    ui_state.set_tempo_trainer_config(&gs.tempo_trainer.config);
+    ui_state.set_tempo_log(&gs.tempo_trainer.log);
     ui_state
 }
 
-#[derive(Debug, Serialize)]
-struct UserMetric {
-    // TODO: Add loop name / id
-    system_time_ms: u128,
-    score: f64,
-    bpm: f64,
+/// Everything that happens in a frame between reading input and rendering: loops that just finished are scored,
+/// the frame's events are applied, and audio is scheduled ahead. Live play and replays both go through here.
+/// Returns the loops that were scored.
+#[allow(clippy::too_many_arguments)]
+pub fn update(
+    gs: &mut GameState,
+    audio: &mut Audio,
+    rx: &Receiver<TxMsg>,
+    events: &Vec<Events>,
+    frame_time_s: f64,
+    conf: &mut AppConfig,
+    midi_input: &mut MidiInputHandler,
+    mic_input: &mut MicInputHandler,
+    keyboard_input: &mut KeyboardInputHandler,
+    journal: Option<&mut SessionJournal>,
+) -> Result<Vec<LoopAttempt>, Box<dyn Error>> {
+    let swing = gs.swing();
+    let attempts = process_system_events(
+        rx,
+        audio,
+        &gs.voices,
+        &mut gs.tempo_trainer,
+        &gs.timing_windows,
+        &gs.confusion_set,
+        &gs.grade_thresholds,
+        &mut gs.last_loop_grade,
+        gs.beats_per_loop,
+        swing,
+        gs.loops
+            .get(gs.selected_loop_idx)
+            .map_or("", |(name, _)| name.as_str()),
+        conf,
+        (frame_time_s * 1000.) as u128,
+        journal,
+        &mut gs.progress,
+    );
+    audio.begin_frame(frame_time_s);
+    process_user_events(
+        &mut gs.voices,
+        audio,
+        &mut gs.flags,
+        &gs.loops,
+        &mut gs.selected_loop_idx,
+        events,
+        &mut gs.timing_windows,
+        &mut gs.confusion_set,
+        &mut gs.dynamics_config,
+        midi_input,
+        mic_input,
+        keyboard_input,
+        &mut gs.beats_per_loop,
+        &mut gs.tempo_trainer,
+        &mut gs.global_swing,
+        &mut gs.loop_swing,
+        conf,
+        &mut gs.calibration,
+        &mut gs.grade_thresholds,
+    )?;
+
+    // apply the latest calibrated input latency (midi devices look up their own as hits come in)
+    keyboard_input.set_input_latency_s(conf.input_latency_seconds(KEYBOARD_INPUT_NAME));
+
+    audio.schedule(&gs.voices.swung(gs.swing()))?;
+    Ok(attempts)
 }
 
+/// scores each loop as it finishes, against the swung voices. the journal keeps the notes as written, along with
+/// the swing, so it isn't applied twice. replays aren't journaled.
+#[allow(clippy::too_many_arguments)]
 pub fn process_system_events(
     rx: &Receiver<TxMsg>,
     audio: &mut Audio,
     voices: &Voices,
-    gold_mode: &mut GoldMode,
+    tempo_trainer: &mut TempoTrainer,
+    timing_windows: &TimingWindows,
+    confusion_set: &ConfusionSet,
+    grade_thresholds: &GradeThresholds,
+    last_loop_grade: &mut Option<Grade>,
     beats_per_loop: usize,
-) {
+    swing: f64,
+    loop_name: &str,
+    conf: &AppConfig,
+    now_ms: u128,
+    mut journal: Option<&mut SessionJournal>,
+    progress: &mut ProgressHistory,
+) -> Vec<LoopAttempt> {
+    let mut attempts = vec![];
+
     // read events
 
     while let Ok(msg) = rx.try_recv() {
@@ -151,58 +284,136 @@ pub fn process_system_events(
         match msg {
             TxMsg::AudioNew => (),
             TxMsg::StartingLoop(loop_num) => {
+                let margins = timing_windows.margins(audio.get_bpm());
                 let last_loop_hits = get_hits_from_nth_loop(
                     &audio.user_hits,
                     (audio.current_loop() - 1) as usize,
                     beats_per_loop,
+                    &margins,
+                );
+                let summary_data = compute_last_loop_summary(
+                    &last_loop_hits,
+                    &voices.swung(swing),
+                    beats_per_loop,
+                    &margins,
+                    confusion_set,
                 );
-                let summary_data =
-                    compute_last_loop_summary(&last_loop_hits, voices, beats_per_loop);
                 info!("last loop summary = {:?}", summary_data);
                 let totals = summary_data.combined();
+                let grade = totals.grade(grade_thresholds);
+                info!("last loop grade = {:?}", grade);
+                if loop_num > 0 {
+                    *last_loop_grade = Some(grade);
+                }
 
                 if loop_num > 0 {
-                    // Log user metric to a file, for eventual data analysis
-                    let user_metric = UserMetric {
-                        system_time_ms: current_time_millis(),
+                    // Record the attempt in the session journal, for eventual data analysis
+                    let attempt = LoopAttempt {
+                        system_time_ms: now_ms as u64,
+                        loop_num: (loop_num - 1) as usize,
+                        loop_name: loop_name.to_string(),
                         bpm: audio.get_bpm(),
+                        beats_per_loop,
+                        swing,
+                        timing_windows: *timing_windows,
+                        margins,
+                        audio_latency_s: audio.get_configured_audio_latency_seconds(),
+                        input_latency_by_device_s: conf.input_latency_by_device.clone(),
+                        desired_notes: JournalNote::all_from_voices(voices),
+                        user_hits: last_loop_hits.iter().map(JournalHit::from).collect(),
                         score: totals.score(),
+                        grade,
                     };
-                    let log_result = log_user_metric(&user_metric);
-                    if let Err(e) = log_result {
-                        log::error!("error logging user_metric. error was: {e}")
+                    progress.add_attempt(&attempt);
+                    if let Some(journal) = journal.as_deref_mut() {
+                        if let Err(e) = journal.log_loop_attempt(attempt.clone()) {
+                            log::error!("error writing session journal. error was: {e}")
+                        }
                     }
+                    attempts.push(attempt);
                 }
 
-                gold_mode.was_gold = false;
-                if totals.score() == 1. {
-                    gold_mode.correct_takes += 1;
-                } else {
-                    gold_mode.correct_takes = 0;
-                }
-
-                if gold_mode.correct_takes == GOLD_MODE_CORRECT_TAKES {
-                    audio.set_bpm(audio.get_bpm() + GOLD_MODE_BPM_STEP);
-                    gold_mode.correct_takes = 0;
-                    gold_mode.was_gold = true;
+                let new_bpm = tempo_trainer.on_loop_completed(
+                    loop_num - 1,
+                    totals.score(),
+                    grade,
+                    audio.get_bpm(),
+                    now_ms,
+                );
+                if let Some(new_bpm) = new_bpm {
+                    audio.set_bpm(new_bpm);
                     // TODO: schedule a 1-off "success!" SFX to play
                     // TOOD: Maybe -- clear existing noise from mistaken notes
                 }
             }
         }
     }
+
+    attempts
 }
 
-fn log_user_metric(user_metric: &UserMetric) -> Result<(), Box<dyn Error>> {
-    log::info!("user_metric = {:?}", user_metric);
-    let data = serde_json::to_string(&user_metric)?;
-    let current_dir = env::current_dir()?;
-    log::info!("user_metric (json) = {}", data);
-    let dir = current_dir.join("log");
-    fs::create_dir_all(&dir)?;
-    let fpath = dir.join(format!("user_metric-{}.json", user_metric.system_time_ms));
-    fs::write(fpath, data)?;
-    Ok(())
+/// save snapshots of the game state. on quit, the state is saved so it can be restored at startup.
+pub fn process_snapshot_events(gs: &GameState, audio: &Audio, events: &Vec<Events>) {
+    for event in events {
+        match event {
+            Events::SaveSnapshot { include_user_hits } => {
+                let snapshot = GameSnapshot::capture(gs, audio, *include_user_hits);
+                let Some(path) = new_snapshot_path() else {
+                    log::warn!("no data directory to save the snapshot to");
+                    continue;
+                };
+                match snapshot.save(&path) {
+                    Ok(()) => log::info!("saved snapshot to {:?}", path),
+                    Err(e) => log::error!("error saving snapshot. error was: {e}"),
+                }
+            }
+            Events::Quit => {
+                if let Some(path) = autosave_path() {
+                    if let Err(e) = GameSnapshot::capture(gs, audio, false).save(&path) {
+                        log::error!("error saving snapshot on quit. error was: {e}");
+                    }
+                }
+                std::process::exit(0);
+            }
+            _ => (),
+        }
+    }
+}
+
+/// record the session's input, so it can be replayed. the recording is saved when it stops, or on quit.
+pub fn process_recording_events(
+    recorder: &mut Option<InputRecorder>,
+    gs: &GameState,
+    audio: &Audio,
+    events: &Vec<Events>,
+    app_version: &str,
+) {
+    for event in events {
+        match event {
+            Events::StartRecording if recorder.is_none() => {
+                log::info!("recording input");
+                *recorder = Some(InputRecorder::start(gs, audio, app_version));
+            }
+            Events::StopRecording | Events::Quit => {
+                let Some(recording) = recorder.take().map(|r| r.finish(audio)) else {
+                    continue;
+                };
+                let Some(path) = new_recording_path() else {
+                    log::warn!("no data directory to save the recording to");
+                    continue;
+                };
+                match recording.save(&path) {
+                    Ok(()) => log::info!(
+                        "saved recording of {} inputs to {:?}",
+                        recording.num_inputs(),
+                        path
+                    ),
+                    Err(e) => log::error!("error saving recording. error was: {e}"),
+                }
+            }
+            _ => (),
+        }
+    }
 }
 
 /// update application state based on events (that came from user input)
@@ -214,19 +425,42 @@ pub fn process_user_events(
     loops: &Vec<(String, Loop)>,
     selected_loop_idx: &mut usize,
     events: &Vec<Events>,
-    correct_margin: &mut f64,
-    miss_margin: &mut f64,
+    timing_windows: &mut TimingWindows,
+    confusion_set: &mut ConfusionSet,
+    dynamics_config: &mut DynamicsConfig,
     midi_input: &mut MidiInputHandler,
+    mic_input: &mut MicInputHandler,
+    keyboard_input: &mut KeyboardInputHandler,
     beats_per_loop: &mut usize,
+    tempo_trainer: &mut TempoTrainer,
+    global_swing: &mut f64,
+    loop_swing: &mut Option<f64>,
+    conf: &mut AppConfig,
+    calibration: &mut CalibrationWizard,
+    grade_thresholds: &mut GradeThresholds,
 ) -> Result<(), Box<dyn Error>> {
     for event in events {
-        info!("[user event] {:?}", event);
+        // clock sync happens every frame, so isn't logged
+        if !matches!(event, Events::SyncToMidiClock { .. }) {
+            info!("[user event] {:?}", event);
+        }
         match event {
             Events::UserHit {
                 instrument,
                 processing_delay,
+                velocity,
+                device,
             } => {
-                audio.track_user_hit(*instrument, *processing_delay);
+                if calibration.is_active() {
+                    // only the device being calibrated counts, e.g. not a mic that picks up the taps
+                    if *device == calibration.input_device {
+                        // time the tap like a hit, but without the latency being calibrated
+                        let delay_s = processing_delay - conf.input_latency_seconds(device);
+                        track_calibration_tap(calibration, audio, conf, delay_s);
+                    }
+                    continue;
+                }
+                audio.track_user_hit(*instrument, *processing_delay, *velocity);
             }
             Events::Pause => {
                 audio.toggle_pause();
@@ -237,8 +471,11 @@ pub fn process_user_events(
             Events::SetBPM(val) => {
                 audio.set_bpm(*val);
             }
-            Events::Quit => {
-                std::process::exit(0);
+            Events::Quit | Events::SaveSnapshot { .. } => {
+                // handled by process_snapshot_events(), which needs the whole game state
+            }
+            Events::StartRecording | Events::StopRecording => {
+                // handled by process_recording_events(), which needs the whole game state
             }
             Events::ResetHits => {
                 audio.user_hits = vec![];
@@ -250,23 +487,131 @@ pub fn process_user_events(
                 info!("toggling beat: {:?} {:?}", *ins, *beat);
                 voices.toggle_beat(*ins, *beat);
             }
+            Events::TrackForCalibration if calibration.is_active() => {
+                // the tap key is on the keyboard
+                if calibration.input_device == KEYBOARD_INPUT_NAME {
+                    track_calibration_tap(calibration, audio, conf, 0.);
+                }
+            }
             Events::TrackForCalibration => {
                 let updated_val = audio.track_for_calibration();
                 audio.set_configured_audio_latency_seconds(updated_val);
 
-                let cfg = AppConfig {
-                    audio_latency_seconds: updated_val,
-                };
-                cfg.save();
+                conf.set_output_latency_seconds(updated_val);
+                conf.save();
             }
             Events::SetAudioLatency { delta_s: delta } => {
                 let updated_val = audio.get_configured_audio_latency_seconds() + delta;
                 audio.set_configured_audio_latency_seconds(updated_val);
 
-                let cfg = AppConfig {
-                    audio_latency_seconds: updated_val,
-                };
-                cfg.save();
+                conf.set_output_latency_seconds(updated_val);
+                conf.save();
+            }
+            Events::StartCalibration { input_device } => {
+                calibration.start(input_device);
+                audio.set_calibration_phase(calibration.phase);
+                if audio.is_paused() {
+                    audio.toggle_pause();
+                }
+            }
+            Events::CancelCalibration => {
+                calibration.cancel();
+                audio.set_calibration_phase(calibration.phase);
+            }
+            Events::SetAudioOutputDevice(device_name) => {
+                if let Err(e) = audio.set_output(device_name.clone(), conf.audio_buffer_size) {
+                    log::error!("unable to switch audio output device. error was: {e}");
+                    continue;
+                }
+                conf.audio_output_device = device_name.clone();
+                // each device has its own latency
+                audio.set_configured_audio_latency_seconds(conf.output_latency_seconds());
+                conf.save();
+            }
+            Events::SetAudioBufferSize(buffer_size) => {
+                let device_name = conf.audio_output_device.clone();
+                if let Err(e) = audio.set_output(device_name, *buffer_size) {
+                    log::error!("unable to change audio buffer size. error was: {e}");
+                    continue;
+                }
+                conf.audio_buffer_size = *buffer_size;
+                conf.save();
+            }
+            Events::RefreshAudioOutputDevices => {
+                audio.refresh_output_devices();
+            }
+            Events::SetMidiOutput(target) => {
+                if let Err(e) = audio.set_midi_output(target.as_ref()) {
+                    log::error!("unable to switch midi output. error was: {e}");
+                    continue;
+                }
+                conf.midi_output = target.clone();
+                conf.save();
+            }
+            Events::SetMidiOutputLatency { latency_ms } => {
+                audio.set_midi_output_latency_s(latency_ms / 1000.);
+                conf.midi_output_latency_ms = *latency_ms;
+                conf.save();
+            }
+            Events::SetMidiOutputMutesSamples(mutes) => {
+                audio.set_midi_output_mutes_samples(*mutes);
+                conf.midi_output_mutes_samples = *mutes;
+                conf.save();
+            }
+            Events::RefreshMidiOutputPorts => {
+                audio.refresh_midi_output_ports();
+            }
+            Events::SetMidiClockOut(enabled) => {
+                audio.set_midi_clock_out(*enabled);
+                conf.midi_clock_out = *enabled;
+                conf.save();
+            }
+            Events::SetMidiClockSource(device) => {
+                conf.midi_clock_source = device.clone();
+                conf.save();
+            }
+            Events::MidiTransport(transport) => match transport {
+                Transport::Start => {
+                    // start from the top of the next loop, so the current loop's hits stay together
+                    let bpl = *beats_per_loop as f64;
+                    let next_loop = (audio.current_clock_tick() / bpl).ceil() * bpl;
+                    if let Err(e) = audio.set_position(next_loop) {
+                        log::warn!("unable to follow midi start. error was: {e}");
+                    }
+                    if audio.is_paused() {
+                        audio.toggle_pause();
+                    }
+                }
+                Transport::Continue => {
+                    if audio.is_paused() {
+                        audio.toggle_pause();
+                    }
+                }
+                Transport::Stop => {
+                    if !audio.is_paused() {
+                        audio.toggle_pause();
+                    }
+                }
+            },
+            Events::SyncToMidiClock {
+                bpm,
+                position_ticks,
+                processing_delay,
+            } => {
+                if let Some(bpm) = bpm {
+                    let bpm = (bpm * 10.).round() / 10.;
+                    if (bpm - audio.get_bpm()).abs() > FOLLOW_BPM_TOLERANCE {
+                        audio.set_bpm(bpm);
+                    }
+                }
+                let tick = audio.clock_tick_before(*processing_delay);
+                let drift = loop_offset(tick, *position_ticks, *beats_per_loop as f64);
+                if drift.abs() > FOLLOW_MAX_DRIFT_TICKS {
+                    log::info!("following midi clock: moving {:.3} ticks", drift);
+                    if let Err(e) = audio.set_position(audio.current_clock_tick() + drift) {
+                        log::warn!("unable to follow midi clock. error was: {e}");
+                    }
+                }
             }
             Events::ToggleDebugMode => {
                 flags.ui_debug_mode = !flags.ui_debug_mode;
@@ -274,24 +619,41 @@ pub fn process_user_events(
             Events::ToggleMetronome => {
                 audio.toggle_metronome();
             }
+            Events::SetSwing(val) => {
+                // a loop with its own swing is edited like its notes; otherwise this is the global setting
+                match loop_swing {
+                    Some(swing) => *swing = *val,
+                    None => *global_swing = *val,
+                }
+            }
+            Events::SetGapClick(config) => {
+                audio.set_gap_click(*config);
+            }
             Events::ChangeLoop(loop_num) => {
                 // voices_options.iter().for_each(|(name, new_loop)| {
                 // if ui.button(None, format!("{:?} ({:?})", name.as_str(), new_loop.bpm)) {
                 let new_loop = loops.as_slice()[*loop_num].clone().1;
-                *voices = Voices::new_from_voices_old_model(&new_loop.voices);
+                *voices = Voices::new_from_loop(&new_loop);
                 audio.set_bpm(new_loop.bpm as f64);
                 *beats_per_loop = new_loop.length_in_beats;
+                *loop_swing = new_loop.swing;
                 audio.set_beats_per_loop(new_loop.length_in_beats);
                 *selected_loop_idx = *loop_num;
             }
             Events::ToggleDevToolsVisibility => {
                 flags.dev_tools_visible = !flags.dev_tools_visible;
             }
-            Events::SetCorrectMargin(val) => {
-                *correct_margin = *val;
+            Events::SetTimingWindows(val) => {
+                *timing_windows = *val;
             }
-            Events::SetMissMargin(val) => {
-                *miss_margin = *val;
+            Events::SetConfusionSet(val) => {
+                *confusion_set = val.clone();
+            }
+            Events::SetDynamicsConfig(val) => {
+                *dynamics_config = *val;
+            }
+            Events::SetGradeThresholds(val) => {
+                *grade_thresholds = *val;
             }
             Events::ToggleHelpVisibility => {
                 flags.help_visible = !flags.help_visible;
@@ -302,11 +664,249 @@ pub fn process_user_events(
             Events::ToggleSidePanelVisibility => {
                 flags.side_panels_visible = !flags.side_panels_visible;
             }
+            Events::ToggleCalibrationVisibility => {
+                flags.calibration_visible = !flags.calibration_visible;
+            }
             Events::RefreshConnectedMidiDevice => {
-                midi_input.refresh_connected_device();
+                midi_input.refresh_ports(conf);
+            }
+            Events::SetMidiInputPortEnabled { port, enabled } => {
+                conf.set_midi_input_port_enabled(
+                    port,
+                    *enabled,
+                    &midi_input.connected_device_names(),
+                );
+                conf.save();
+                midi_input.refresh_ports(conf);
+            }
+            Events::SetMidiMapping { device, mapping } => {
+                midi_input.set_mapping(device, mapping);
+                conf.midi_mapping_by_device
+                    .insert(device.clone(), mapping.clone());
+                conf.save();
+            }
+            Events::StartMidiLearn { device } => {
+                midi_input.start_learn(device);
+            }
+            Events::SkipMidiLearnInstrument => {
+                midi_input.skip_learn_instrument();
+                if midi_input.learning_device().is_some() && midi_input.learn_target().is_none() {
+                    finish_midi_learn(midi_input, conf);
+                }
+            }
+            Events::CancelMidiLearn => {
+                midi_input.cancel_learn();
+            }
+            Events::FinishMidiLearn => {
+                finish_midi_learn(midi_input, conf);
+            }
+            Events::SetMicInputDevice(device) => {
+                conf.mic_input_device = device.clone();
+                conf.save();
+            }
+            Events::RefreshMicInputDevices => {
+                mic_input.refresh_devices();
+            }
+            Events::StartKitLearn => {
+                mic_input.start_learn();
+            }
+            Events::SkipKitLearnInstrument => {
+                mic_input.skip_learn_instrument();
+                if mic_input.is_learning() && mic_input.learn_target().is_none() {
+                    mic_input.finish_learn();
+                }
+            }
+            Events::CancelKitLearn => {
+                mic_input.cancel_learn();
+            }
+            Events::FinishKitLearn => {
+                mic_input.finish_learn();
+            }
+            Events::ClearKitProfile => {
+                mic_input.clear_profile();
+            }
+            Events::StartKeyBinding(action) => {
+                keyboard_input.start_binding(*action);
+            }
+            Events::CancelKeyBinding => {
+                keyboard_input.cancel_binding();
+            }
+            Events::BindKey { action, key } => {
+                keyboard_input.bind(action, *key);
+            }
+            Events::ClearKeyBinding(action) => {
+                keyboard_input.clear_binding(action);
+            }
+            Events::SetKeymap(name) => {
+                keyboard_input.set_keymap(name);
+            }
+            Events::SetTempoTrainerConfig(config) => {
+                tempo_trainer.set_config(*config);
+            }
+            Events::RestartTempoTrainer => {
+                audio.set_bpm(tempo_trainer.restart());
             }
         }
     }
 
     Ok(())
 }
+
+/// records a tap, made `delay_s` before this frame, for the calibration wizard,
+/// and saves the measured latencies once it's complete
+fn track_calibration_tap(
+    calibration: &mut CalibrationWizard,
+    audio: &mut Audio,
+    conf: &mut AppConfig,
+    delay_s: f64,
+) {
+    let offset_s = tap_offset_seconds(audio.clock_tick_before(delay_s), audio.get_bpm());
+    if let Some(result) = calibration.tap(offset_s) {
+        info!("calibration result: {:?}", result);
+        audio.set_configured_audio_latency_seconds(result.output_latency_s);
+
+        conf.set_input_latency_seconds(&calibration.input_device, result.input_latency_s);
+        conf.set_output_latency_seconds(result.output_latency_s);
+        conf.save();
+    }
+    audio.set_calibration_phase(calibration.phase);
+}
+
+/// saves the learned midi mapping, and remembers it for the attached device
+fn finish_midi_learn(midi_input: &mut MidiInputHandler, conf: &mut AppConfig) {
+    let Some((device_name, mapping)) = midi_input.finish_learn() else {
+        return;
+    };
+    if let Err(e) = save_user_mapping(&mapping) {
+        log::error!("error saving midi mapping. error was: {e}");
+    }
+    conf.midi_mapping_by_device
+        .insert(device_name, mapping.name);
+    conf.save();
+}
+
+#[cfg(test)]
+mod tests {
+    use std::sync::mpsc;
+
+    use crate::{
+        audio::Audio,
+        calibration::KEYBOARD_INPUT_NAME,
+        events::Events,
+        game::{update, GameState},
+        keyboard_input_handler::KeyboardInputHandler,
+        mic_input_handler::MicInputHandler,
+        midi_input_handler::MidiInputHandler,
+        replay::replay_config,
+        time::{ClockReading, ClockSync},
+        voices::{Instrument, Loop, STRAIGHT_SWING},
+    };
+
+    fn test_loop(swing: Option<f64>) -> Loop {
+        let mut l: Loop = serde_json::from_str(
+            r#"{"bpm": 100, "length_in_beats": 16, "voices": {"closed_hihat": [], "snare": [], "kick": [], "open_hihat": [], "ride": [], "crash": []}}"#,
+        )
+        .unwrap();
+        l.swing = swing;
+        l
+    }
+
+    #[test]
+    fn it_keeps_the_global_swing_when_a_loop_sets_its_own() {
+        let (tx, rx) = mpsc::channel();
+        let mut conf = replay_config();
+        let mut audio = Audio::new_replay(&conf, tx);
+        let mut midi_input = MidiInputHandler::new(&conf);
+        let mut mic_input = MicInputHandler::new(&conf);
+        let mut keyboard_input = KeyboardInputHandler::new();
+        let mut gs = GameState::new(vec![
+            ("straight".to_string(), test_loop(None)),
+            ("shuffle".to_string(), test_loop(Some(0.66))),
+        ]);
+
+        let mut play = |gs: &mut GameState, events: Vec<Events>| {
+            update(
+                gs,
+                &mut audio,
+                &rx,
+                &events,
+                1_700_000_000.,
+                &mut conf,
+                &mut midi_input,
+                &mut mic_input,
+                &mut keyboard_input,
+                None,
+            )
+            .unwrap();
+        };
+
+        play(&mut gs, vec![Events::SetSwing(0.55)]);
+        play(&mut gs, vec![Events::ChangeLoop(1)]);
+        assert_eq!(gs.swing(), 0.66);
+
+        // adjusting swing edits the loop's own, not the global setting
+        play(&mut gs, vec![Events::SetSwing(0.7)]);
+        assert_eq!(gs.swing(), 0.7);
+        assert_eq!(gs.global_swing, 0.55);
+
+        play(&mut gs, vec![Events::ChangeLoop(0)]);
+        assert_eq!(gs.swing(), 0.55);
+        assert_ne!(gs.swing(), STRAIGHT_SWING);
+    }
+
+    #[test]
+    fn it_times_calibration_taps_like_hits() {
+        let (tx, rx) = mpsc::channel();
+        let mut conf = replay_config();
+        conf.set_input_latency_seconds("pad", 0.01);
+        let mut audio = Audio::new_replay(&conf, tx);
+        audio.set_bpm(120.);
+        let mut midi_input = MidiInputHandler::new(&conf);
+        let mut mic_input = MicInputHandler::new(&conf);
+        let mut keyboard_input = KeyboardInputHandler::new();
+        let mut gs = GameState::new(vec![]);
+        gs.calibration.start("pad");
+
+        // a pulse at t0, and a tap 20ms after it that reaches this frame 30ms later
+        let t0 = 1_700_000_000.;
+        let mut clock_sync = ClockSync::default();
+        clock_sync.observe(t0, 0., 4.);
+        let reading = ClockReading {
+            wall_time_s: t0 + 0.05,
+            tick: 0.2,
+            ticking: true,
+        };
+        audio.restore_clock(clock_sync, reading);
+        let tap = Events::UserHit {
+            instrument: Instrument::Snare,
+            processing_delay: 0.03 + 0.01,
+            velocity: Some(100),
+            device: "pad".to_string(),
+        };
+        // only the device being calibrated taps. a key press (or the tap key) at the same time is ignored.
+        let key_press = Events::UserHit {
+            instrument: Instrument::Snare,
+            processing_delay: 0.,
+            velocity: None,
+            device: KEYBOARD_INPUT_NAME.to_string(),
+        };
+        update(
+            &mut gs,
+            &mut audio,
+            &rx,
+            &vec![tap, key_press, Events::TrackForCalibration],
+            t0 + 0.05,
+            &mut conf,
+            &mut midi_input,
+            &mut mic_input,
+            &mut keyboard_input,
+            None,
+        )
+        .unwrap();
+
+        let stats = gs.calibration.current_stats().unwrap();
+        assert_eq!(stats.num_taps, 1);
+        assert!((stats.median - 0.02).abs() < 1e-6, "{:?}", stats);
+        assert!(audio.user_hits.is_empty());
+    }
+}
@@ -0,0 +1,120 @@
+/*
+  Gap-click (dropout) training.
+
+  The metronome and guide track play for some bars, then go silent for some bars while the user keeps time.
+  Random dropouts are seeded once per session, so they differ from one session to the next.
+*/
+
+use serde::{Deserialize, Serialize};
+
+use crate::{consts::BEATS_PER_BAR, time::current_time_millis};
+
+#[derive(Debug, Clone, Copy, PartialEq, Serialize, Deserialize)]
+pub struct GapClickConfig {
+    pub enabled: bool,
+    pub play_bars: usize,
+    pub mute_bars: usize,
+    /// chance (0 to 1) that an otherwise audible bar is muted
+    pub random_dropout: f64,
+    /// which bars randomly drop out. fixed by default, for tests; a session picks its own (see session_seed())
+    pub seed: u64,
+}
+
+impl Default for GapClickConfig {
+    fn default() -> Self {
+        Self {
+            enabled: false,
+            play_bars: 2,
+            mute_bars: 2,
+            random_dropout: 0.,
+            seed: 0,
+        }
+    }
+}
+
+impl GapClickConfig {
+    /// a seed for a new session's random dropouts
+    pub fn session_seed() -> u64 {
+        current_time_millis() as u64
+    }
+
+    /// bar index for an absolute clock tick
+    pub fn bar_for_tick(tick: f64) -> i64 {
+        (tick / BEATS_PER_BAR as f64).floor() as i64
+    }
+
+    pub fn is_tick_muted(&self, tick: f64) -> bool {
+        self.is_bar_muted(Self::bar_for_tick(tick))
+    }
+
+    /// whether the guide track and metronome are silent during the given bar.
+    /// random dropouts are derived from the bar index, so they're stable between scheduling and scoring.
+    pub fn is_bar_muted(&self, bar: i64) -> bool {
+        if !self.enabled || bar < 0 {
+            return false;
+        }
+
+        let cycle = self.play_bars + self.mute_bars;
+        if cycle > 0 && (bar as usize % cycle) >= self.play_bars {
+            return true;
+        }
+
+        self.random_dropout > 0. && pseudo_random(bar as u64, self.seed) < self.random_dropout
+    }
+}
+
+/// deterministic value from 0 to 1 for a bar (splitmix64)
+fn pseudo_random(bar: u64, seed: u64) -> f64 {
+    let mut z = bar.wrapping_add(seed).wrapping_add(0x9E3779B97F4A7C15);
+    z = (z ^ (z >> 30)).wrapping_mul(0xBF58476D1CE4E5B9);
+    z = (z ^ (z >> 27)).wrapping_mul(0x94D049BB133111EB);
+    z ^= z >> 31;
+    (z >> 11) as f64 / (1u64 << 53) as f64
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::{consts::BEATS_PER_BAR, gap_click::GapClickConfig};
+
+    #[test]
+    fn it_alternates_play_and_mute_bars() {
+        let gc = GapClickConfig {
+            enabled: true,
+            play_bars: 2,
+            mute_bars: 1,
+            ..Default::default()
+        };
+        let muted: Vec<bool> = (0..6).map(|bar| gc.is_bar_muted(bar)).collect();
+        assert_eq!(muted, vec![false, false, true, false, false, true]);
+
+        let bar_len = BEATS_PER_BAR as f64;
+        assert!(!gc.is_tick_muted(2. * bar_len - 0.5));
+        assert!(gc.is_tick_muted(2. * bar_len));
+    }
+
+    #[test]
+    fn it_never_mutes_when_disabled() {
+        let gc = GapClickConfig {
+            random_dropout: 1.,
+            ..Default::default()
+        };
+        assert!((0..10).all(|bar| !gc.is_bar_muted(bar)));
+    }
+
+    #[test]
+    fn it_drops_out_random_bars_deterministically() {
+        let gc = GapClickConfig {
+            enabled: true,
+            play_bars: 1,
+            mute_bars: 0,
+            random_dropout: 0.5,
+            seed: 42,
+        };
+        let first: Vec<bool> = (0..100).map(|bar| gc.is_bar_muted(bar)).collect();
+        let second: Vec<bool> = (0..100).map(|bar| gc.is_bar_muted(bar)).collect();
+        assert_eq!(first, second);
+
+        let num_muted = first.iter().filter(|m| **m).count();
+        assert!(num_muted > 25 && num_muted < 75);
+    }
+}
@@ -0,0 +1,376 @@
+/*
+  Practice session journal.
+
+  Every loop the user plays is appended to a journal file, with everything needed to re-score it later:
+  the loop, tempo, timing windows, latency settings, the desired notes and all of the user's hits.
+
+  There's one file per session, in JSON Lines format (one record per line). Records are only ever appended,
+  and each one carries a schema version so old sessions can still be read as the format evolves.
+*/
+
+use std::{
+    collections::HashMap,
+    error::Error,
+    fs::{self, File, OpenOptions},
+    io::Write,
+    path::{Path, PathBuf},
+};
+
+use serde::{Deserialize, Serialize};
+
+use crate::{
+    beat::Beat,
+    config::data_dir,
+    consts::{UserHit, ALL_INSTRUMENTS},
+    score::{Grade, TimingMargins, TimingWindows},
+    voices::{Dynamic, Instrument, Voices},
+};
+
+/// bump this when the meaning of a record changes
+pub const JOURNAL_VERSION: u32 = 1;
+
+#[derive(Debug, PartialEq, Serialize, Deserialize)]
+pub struct JournalRecord {
+    pub version: u32,
+    #[serde(flatten)]
+    pub entry: JournalEntry,
+}
+
+#[derive(Debug, PartialEq, Serialize, Deserialize)]
+#[serde(tag = "type", rename_all = "snake_case")]
+pub enum JournalEntry {
+    SessionStart {
+        started_at_ms: u64,
+        app_version: String,
+    },
+    LoopAttempt(LoopAttempt),
+}
+
+/// One pass through a loop
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct LoopAttempt {
+    pub system_time_ms: u64,
+    /// nth time through the loop in this session
+    pub loop_num: usize,
+    pub loop_name: String,
+    pub bpm: f64,
+    pub beats_per_loop: usize,
+    pub swing: f64,
+    pub timing_windows: TimingWindows,
+    pub margins: TimingMargins,
+    pub audio_latency_s: f64,
+    pub input_latency_by_device_s: HashMap<String, f64>,
+    /// the notes as written, before swing is applied
+    pub desired_notes: Vec<JournalNote>,
+    /// hits are on the absolute clock, in beats, with input latency already removed
+    pub user_hits: Vec<JournalHit>,
+    pub score: f64,
+    pub grade: Grade,
+}
+
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct JournalNote {
+    pub instrument: Instrument,
+    pub beat: Beat,
+    pub dynamic: Dynamic,
+}
+
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct JournalHit {
+    pub instrument: Instrument,
+    pub clock_tick: f64,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub velocity: Option<u8>,
+}
+
+impl JournalNote {
+    pub fn all_from_voices(voices: &Voices) -> Vec<Self> {
+        let mut notes = vec![];
+        for instrument in ALL_INSTRUMENTS.iter() {
+            for beat in voices.get_instrument_beats(instrument) {
+                notes.push(JournalNote {
+                    instrument: *instrument,
+                    beat: *beat,
+                    dynamic: voices.get_dynamic(instrument, *beat),
+                });
+            }
+        }
+        notes
+    }
+}
+
+impl JournalHit {
+    pub fn to_user_hit(&self) -> UserHit {
+        UserHit::new(self.instrument, self.clock_tick).with_velocity(self.velocity)
+    }
+}
+
+impl From<&UserHit> for JournalHit {
+    fn from(hit: &UserHit) -> Self {
+        Self {
+            instrument: hit.instrument,
+            clock_tick: hit.clock_tick,
+            velocity: hit.velocity,
+        }
+    }
+}
+
+/// where session journals are kept
+pub fn sessions_dir() -> Option<PathBuf> {
+    data_dir().map(|dir| dir.join("sessions"))
+}
+
+/// Appends records to the current session's journal file
+pub struct SessionJournal {
+    dir: Option<PathBuf>,
+    started_at_ms: u64,
+    app_version: String,
+    file: Option<File>,
+}
+
+impl SessionJournal {
+    /// the file isn't created until the first loop attempt, so idle sessions don't leave empty journals behind
+    pub fn new(dir: Option<PathBuf>, started_at_ms: u64, app_version: &str) -> Self {
+        Self {
+            dir,
+            started_at_ms,
+            app_version: app_version.trim().to_string(),
+            file: None,
+        }
+    }
+
+    pub fn path(&self) -> Option<PathBuf> {
+        self.dir
+            .as_ref()
+            .map(|dir| dir.join(format!("session-{}.jsonl", self.started_at_ms)))
+    }
+
+    pub fn log_loop_attempt(&mut self, attempt: LoopAttempt) -> Result<(), Box<dyn Error>> {
+        if self.file.is_none() {
+            let Some(path) = self.path() else {
+                return Err("no data directory for session journals".into());
+            };
+            if let Some(dir) = path.parent() {
+                fs::create_dir_all(dir)?;
+            }
+            self.file = Some(OpenOptions::new().create(true).append(true).open(&path)?);
+            log::info!("writing session journal to {:?}", path);
+            self.append(JournalEntry::SessionStart {
+                started_at_ms: self.started_at_ms,
+                app_version: self.app_version.clone(),
+            })?;
+        }
+        self.append(JournalEntry::LoopAttempt(attempt))
+    }
+
+    fn append(&mut self, entry: JournalEntry) -> Result<(), Box<dyn Error>> {
+        let Some(file) = self.file.as_mut() else {
+            return Err("session journal isn't open".into());
+        };
+        let record = JournalRecord {
+            version: JOURNAL_VERSION,
+            entry,
+        };
+        let mut line = serde_json::to_string(&record)?;
+        line.push('\n');
+        // write each record in one call, so a crash can't leave half a line behind another record
+        file.write_all(line.as_bytes())?;
+        file.flush()?;
+        Ok(())
+    }
+}
+
+#[derive(Deserialize)]
+struct RecordVersion {
+    version: u32,
+}
+
+/// reads every record of a session journal. a line that can't be read is skipped, so it doesn't take the rest
+/// of the session with it.
+pub fn read_journal(path: &Path) -> Result<Vec<JournalRecord>, Box<dyn Error>> {
+    let data = fs::read_to_string(path)?;
+    let mut records = vec![];
+    for (i, line) in data.lines().enumerate() {
+        if line.trim().is_empty() {
+            continue;
+        }
+        // check the version first, since newer records may not parse
+        if let Ok(RecordVersion { version }) = serde_json::from_str(line) {
+            if version > JOURNAL_VERSION {
+                return Err(format!(
+                    "journal version {} is newer than supported version {}",
+                    version, JOURNAL_VERSION
+                )
+                .into());
+            }
+        }
+        match serde_json::from_str(line) {
+            Ok(record) => records.push(record),
+            Err(e) => log::warn!("skipping line {} of {:?}: {}", i + 1, path, e),
+        }
+    }
+    Ok(records)
+}
+
+#[cfg(test)]
+mod tests {
+    use std::{collections::HashMap, fs};
+
+    use crate::{
+        beat::Beat,
+        consts::UserHit,
+        journal::{
+            read_journal, JournalEntry, JournalHit, JournalNote, JournalRecord, LoopAttempt,
+            SessionJournal, JOURNAL_VERSION,
+        },
+        progress::ProgressHistory,
+        score::{compute_last_loop_summary, ConfusionSet, Difficulty, Grade, GradeThresholds},
+        voices::{Dynamic, Instrument, Voices},
+    };
+
+    fn attempt(loop_num: usize) -> LoopAttempt {
+        let mut voices = Voices::new();
+        voices.toggle_beat(Instrument::Kick, Beat::ZERO);
+        voices.toggle_beat(Instrument::Snare, Beat::from_ratio(2, 3));
+        let timing_windows = Difficulty::Normal.timing_windows();
+        LoopAttempt {
+            system_time_ms: 1_700_000_000_000,
+            loop_num,
+            loop_name: "Rock".to_string(),
+            bpm: 120.,
+            beats_per_loop: 16,
+            swing: 0.5,
+            timing_windows,
+            margins: timing_windows.margins(120.),
+            audio_latency_s: 0.05,
+            input_latency_by_device_s: HashMap::from([("keyboard".to_string(), 0.02)]),
+            desired_notes: JournalNote::all_from_voices(&voices),
+            user_hits: vec![
+                JournalHit::from(&UserHit::new(Instrument::Kick, 16.01)),
+                JournalHit::from(&UserHit::new(Instrument::Snare, 16.7).with_velocity(Some(90))),
+            ],
+            score: 1.,
+            grade: Grade::Ace,
+        }
+    }
+
+    #[test]
+    fn it_appends_versioned_records_to_a_session_journal() {
+        let dir = std::env::temp_dir().join(format!("drum-break-journal-{}", std::process::id()));
+        let mut journal = SessionJournal::new(Some(dir.clone()), 42, "0.1.0\n");
+        let path = journal.path().unwrap();
+        assert!(!path.exists());
+
+        journal.log_loop_attempt(attempt(1)).unwrap();
+        journal.log_loop_attempt(attempt(2)).unwrap();
+
+        let records = read_journal(&path).unwrap();
+        fs::remove_dir_all(&dir).unwrap();
+
+        assert_eq!(records.len(), 3);
+        assert!(records.iter().all(|r| r.version == JOURNAL_VERSION));
+        assert_eq!(
+            records[0].entry,
+            JournalEntry::SessionStart {
+                started_at_ms: 42,
+                app_version: "0.1.0".to_string()
+            }
+        );
+        assert_eq!(records[2].entry, JournalEntry::LoopAttempt(attempt(2)));
+
+        let JournalEntry::LoopAttempt(first) = &records[1].entry else {
+            panic!("expected a loop attempt");
+        };
+        assert_eq!(first.desired_notes.len(), 2);
+        let snare = first
+            .desired_notes
+            .iter()
+            .find(|n| n.instrument == Instrument::Snare)
+            .unwrap();
+        assert_eq!(snare.beat, Beat::from_ratio(2, 3));
+        assert_eq!(snare.dynamic, Dynamic::Normal);
+    }
+
+    #[test]
+    fn it_reads_back_an_attempt_at_an_empty_loop() {
+        let dir =
+            std::env::temp_dir().join(format!("drum-break-journal-empty-{}", std::process::id()));
+        let mut journal = SessionJournal::new(Some(dir.clone()), 42, "0.1.0");
+        let path = journal.path().unwrap();
+
+        let mut empty = attempt(1);
+        let summary = compute_last_loop_summary(
+            &[],
+            &Voices::new(),
+            16,
+            &empty.margins,
+            &ConfusionSet::default(),
+        );
+        empty.desired_notes = vec![];
+        empty.user_hits = vec![];
+        empty.score = summary.combined().score();
+        empty.grade = summary.combined().grade(&GradeThresholds::default());
+        journal.log_loop_attempt(empty).unwrap();
+
+        let records = read_journal(&path).unwrap();
+        fs::remove_dir_all(&dir).unwrap();
+        let JournalEntry::LoopAttempt(read) = &records[1].entry else {
+            panic!("expected a loop attempt");
+        };
+        assert_eq!(read.score, 0.);
+        assert_eq!(read.grade, Grade::NeedsWork);
+    }
+
+    #[test]
+    fn it_skips_lines_it_cant_read() {
+        let dir = std::env::temp_dir().join(format!(
+            "drum-break-journal-bad-line-{}",
+            std::process::id()
+        ));
+        let mut journal = SessionJournal::new(Some(dir.clone()), 42, "0.1.0");
+        let path = journal.path().unwrap();
+        journal.log_loop_attempt(attempt(1)).unwrap();
+        // e.g. a NaN score, which was written as null
+        let bad_line = serde_json::to_string(&JournalRecord {
+            version: JOURNAL_VERSION,
+            entry: JournalEntry::LoopAttempt(attempt(2)),
+        })
+        .unwrap()
+        .replace(r#""score":1.0"#, r#""score":null"#);
+        let mut data = fs::read_to_string(&path).unwrap();
+        data.push_str(&bad_line);
+        data.push('\n');
+        fs::write(&path, data).unwrap();
+        journal.log_loop_attempt(attempt(3)).unwrap();
+
+        let records = read_journal(&path).unwrap();
+        let progress = ProgressHistory::load(Some(&dir));
+        fs::remove_dir_all(&dir).unwrap();
+
+        let loop_nums: Vec<usize> = records
+            .iter()
+            .filter_map(|r| match &r.entry {
+                JournalEntry::LoopAttempt(attempt) => Some(attempt.loop_num),
+                _ => None,
+            })
+            .collect();
+        assert_eq!(loop_nums, vec![1, 3]);
+        assert_eq!(progress.get("Rock").unwrap().attempts, 2);
+    }
+
+    #[test]
+    fn it_rejects_journals_from_newer_versions() {
+        let path = std::env::temp_dir().join(format!(
+            "drum-break-journal-newer-{}.jsonl",
+            std::process::id()
+        ));
+        fs::write(
+            &path,
+            r#"{"version":999,"type":"session_start","started_at_ms":1,"app_version":"9.0.0"}"#,
+        )
+        .unwrap();
+        let result = read_journal(&path);
+        fs::remove_file(&path).unwrap();
+        assert!(result.is_err());
+    }
+}
@@ -4,121 +4,150 @@
 
 use macroquad::prelude::*;
 
-use crate::{consts::*, events::Events};
-
-pub struct KeyboardInputHandler {}
+use crate::{
+    calibration::KEYBOARD_INPUT_NAME,
+    events::Events,
+    keymap::{Action, Command, Key, Keymap},
+};
+
+pub struct KeyboardInputHandler {
+    input_latency_s: f64,
+    keymap: Keymap,
+    /// the action waiting for the user to press its new key
+    binding: Option<Action>,
+}
 
 impl KeyboardInputHandler {
     pub fn new() -> Self {
-        Self {}
-    }
-
-    /// convert any user input from the last frame into Events
-    pub fn process(&self) -> Vec<Events> {
-        let mut events: Vec<Events> = vec![];
-
-        // Playing the drums //
-        let processing_delay = 0.; // TODO: solve this for keyboard input, too.
-                                   // Right now we don't know the delay between key press and frame start .. we could improve by guessing midway through the previous frame (1/2 frame duration) without any knowledge
-
-        for (idx, ins) in ALL_INSTRUMENTS.iter().enumerate() {
-            let key_code = match idx {
-                0 => KeyCode::Key1,
-                1 => KeyCode::Key2,
-                2 => KeyCode::Key3,
-                3 => KeyCode::Key4,
-                4 => KeyCode::Key5,
-                5 => KeyCode::Key6,
-                6 => KeyCode::Key7,
-                7 => KeyCode::Key8,
-                8 => KeyCode::Key9,
-                9 => KeyCode::Key0,
-                _ => panic!("more than hard-coded num instruments, failed to map key codes"),
-            };
-            if is_key_pressed(key_code) {
-                events.push(Events::UserHit {
-                    instrument: *ins,
-                    processing_delay,
-                });
-            }
-        }
-
-        if is_key_pressed(KeyCode::Space) {
-            events.push(Events::Pause)
-        }
-
-        if is_key_pressed(KeyCode::Equal) {
-            events.push(Events::TrackForCalibration);
+        Self {
+            input_latency_s: 0.,
+            keymap: Keymap::load(),
+            binding: None,
         }
+    }
 
-        if is_key_pressed(KeyCode::LeftBracket) {
-            let mut multiplier = 1.;
-            if is_key_down(KeyCode::LeftShift) || is_key_down(KeyCode::RightShift) {
-                multiplier = 100.;
-            }
-            events.push(Events::SetAudioLatency {
-                delta_s: -0.001 * multiplier,
-            });
-        }
+    /// input latency, as measured by calibration
+    pub fn set_input_latency_s(&mut self, latency: f64) {
+        self.input_latency_s = latency;
+    }
 
-        if is_key_pressed(KeyCode::RightBracket) {
-            let mut multiplier = 1.;
-            if is_key_down(KeyCode::LeftShift) || is_key_down(KeyCode::RightShift) {
-                multiplier = 100.;
-            }
-            events.push(Events::SetAudioLatency {
-                delta_s: 0.001 * multiplier,
-            });
-        }
+    pub fn keymap(&self) -> &Keymap {
+        &self.keymap
+    }
 
-        // Improve UX here
-        // Check if down < 0.5s then go fast? (then can use same key incr.. "Up")
-        if is_key_pressed(KeyCode::Up) {
-            events.push(Events::ChangeBPM { delta: 1. });
-        }
-        if is_key_down(KeyCode::Right) {
-            events.push(Events::ChangeBPM { delta: 1. });
-        }
+    pub fn binding(&self) -> Option<Action> {
+        self.binding
+    }
 
-        if is_key_pressed(KeyCode::Down) {
-            events.push(Events::ChangeBPM { delta: -1. });
-        }
+    /// the next key pressed is bound to this action
+    pub fn start_binding(&mut self, action: Action) {
+        self.binding = Some(action);
+    }
 
-        if is_key_down(KeyCode::Left) {
-            events.push(Events::ChangeBPM { delta: -1. });
-        }
+    pub fn cancel_binding(&mut self) {
+        self.binding = None;
+    }
 
-        if is_key_pressed(KeyCode::M) {
-            events.push(Events::ToggleMetronome);
-        }
+    pub fn bind(&mut self, action: &Action, key: Key) {
+        self.binding = None;
+        self.keymap.bind(action, key);
+        self.save_keymap();
+    }
 
-        if is_key_pressed(KeyCode::Z) {
-            events.push(Events::ToggleDebugMode);
-        }
+    pub fn clear_binding(&mut self, action: &Action) {
+        self.keymap.clear(action);
+        self.save_keymap();
+    }
 
-        if is_key_pressed(KeyCode::A) {
-            events.push(Events::ToggleDevToolsVisibility);
-        }
+    /// switches to one of the preset keymaps, by name
+    pub fn set_keymap(&mut self, name: &str) {
+        let Some(keymap) = Keymap::presets().into_iter().find(|k| k.name == name) else {
+            log::warn!("unknown keymap: {}", name);
+            return;
+        };
+        self.keymap = keymap;
+        self.save_keymap();
+    }
 
-        if is_key_pressed(KeyCode::Q) {
-            events.push(Events::Quit)
+    fn save_keymap(&self) {
+        if let Err(e) = self.keymap.save() {
+            log::warn!("unable to save keymap: {}", e);
         }
+    }
 
-        if is_key_pressed(KeyCode::R) {
-            events.push(Events::ResetHits)
-        }
+    /// convert any user input from the last frame into Events
+    pub fn process(&self) -> Vec<Events> {
+        let mut events: Vec<Events> = vec![];
 
-        if is_key_pressed(KeyCode::X) {
-            events.push(Events::SaveLoop);
+        // while rebinding, the next key is captured rather than played
+        if let Some(action) = self.binding {
+            if is_key_pressed(KeyCode::Escape) {
+                events.push(Events::CancelKeyBinding);
+            } else if let Some(key_code) = get_last_key_pressed() {
+                events.push(Events::BindKey {
+                    action,
+                    key: Key(key_code),
+                });
+            }
+            return events;
         }
 
-        // TODO(bug):
-        // On MacOS.. KeyCode::Slash seems to map to '/' .. isn't that Backslash?
-        // and KeyCode::Backslash doesn't seem to match anything
-        if is_key_pressed(KeyCode::Slash) {
-            events.push(Events::ToggleHelpVisibility);
+        // Playing the drums //
+        // key presses aren't timestamped, so a hit is timed from the frame start, less the calibrated latency
+        let processing_delay = self.input_latency_s;
+
+        let is_shift_down = is_key_down(KeyCode::LeftShift) || is_key_down(KeyCode::RightShift);
+        for action in Keymap::actions() {
+            let keys = self.keymap.keys(&action);
+            match action {
+                Action::Hit(instrument) => {
+                    if keys.iter().any(|key| is_key_pressed(key.0)) {
+                        events.push(Events::UserHit {
+                            instrument,
+                            processing_delay,
+                            velocity: None,
+                            device: KEYBOARD_INPUT_NAME.to_string(),
+                        });
+                    }
+                }
+                Action::Command(command) => {
+                    let is_triggered = if command.is_held() {
+                        keys.iter().any(|key| is_key_down(key.0))
+                    } else {
+                        keys.iter().any(|key| is_key_pressed(key.0))
+                    };
+                    if is_triggered {
+                        events.push(command_event(command, is_shift_down));
+                    }
+                }
+            }
         }
 
         events
     }
 }
+
+fn command_event(command: Command, is_shift_down: bool) -> Events {
+    let latency_step = if is_shift_down { 0.1 } else { 0.001 };
+    match command {
+        Command::Pause => Events::Pause,
+        Command::CalibrationTap => Events::TrackForCalibration,
+        Command::DecreaseLatency => Events::SetAudioLatency {
+            delta_s: -latency_step,
+        },
+        Command::IncreaseLatency => Events::SetAudioLatency {
+            delta_s: latency_step,
+        },
+        // Improve UX here
+        // Check if down < 0.5s then go fast? (then can use same key incr.. "Up")
+        Command::IncreaseBpm | Command::IncreaseBpmHeld => Events::ChangeBPM { delta: 1. },
+        Command::DecreaseBpm | Command::DecreaseBpmHeld => Events::ChangeBPM { delta: -1. },
+        Command::ToggleMetronome => Events::ToggleMetronome,
+        Command::ToggleHelp => Events::ToggleHelpVisibility,
+        Command::ToggleDevTools => Events::ToggleDevToolsVisibility,
+        Command::ToggleDebugMode => Events::ToggleDebugMode,
+        Command::ResetHits => Events::ResetHits,
+        Command::SaveLoop => Events::SaveLoop,
+        Command::Quit => Events::Quit,
+    }
+}
@@ -0,0 +1,451 @@
+/*
+  Which keyboard keys play each instrument and run each command.
+
+  Presets ship with the app: the number row, and a two-handed layout with the hats under the left hand, the
+  snare under the right, and the kick on space. The user's own bindings are saved to keymap.json in the
+  config directory.
+
+  Keys are stored by the key code the platform reports, so rebinding by pressing a key also works for
+  non-US layouts.
+*/
+
+use std::{collections::BTreeMap, error::Error, fs, path::PathBuf};
+
+use macroquad::input::KeyCode;
+use serde::{Deserialize, Serialize};
+
+use crate::{config::config_dir, consts::ALL_INSTRUMENTS, voices::Instrument};
+
+const DEFAULT_KEYMAP_NAME: &str = "Number row";
+
+/// the name of a keymap once any binding has changed from its preset
+const CUSTOM_KEYMAP_NAME: &str = "Custom";
+
+/// Something a key does, other than playing an instrument
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash, PartialOrd, Ord, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum Command {
+    Pause,
+    CalibrationTap,
+    DecreaseLatency,
+    IncreaseLatency,
+    IncreaseBpm,
+    DecreaseBpm,
+    /// repeats every frame while the key is held
+    IncreaseBpmHeld,
+    /// repeats every frame while the key is held
+    DecreaseBpmHeld,
+    ToggleMetronome,
+    ToggleHelp,
+    ToggleDevTools,
+    ToggleDebugMode,
+    ResetHits,
+    SaveLoop,
+    Quit,
+}
+
+impl Command {
+    pub const ALL: [Command; 15] = [
+        Command::Pause,
+        Command::CalibrationTap,
+        Command::DecreaseLatency,
+        Command::IncreaseLatency,
+        Command::IncreaseBpm,
+        Command::DecreaseBpm,
+        Command::IncreaseBpmHeld,
+        Command::DecreaseBpmHeld,
+        Command::ToggleMetronome,
+        Command::ToggleHelp,
+        Command::ToggleDevTools,
+        Command::ToggleDebugMode,
+        Command::ResetHits,
+        Command::SaveLoop,
+        Command::Quit,
+    ];
+
+    pub fn name(&self) -> &'static str {
+        match self {
+            Command::Pause => "Play / Pause",
+            Command::CalibrationTap => "Calibration Tap",
+            Command::DecreaseLatency => "Latency -1ms (shift: -100ms)",
+            Command::IncreaseLatency => "Latency +1ms (shift: +100ms)",
+            Command::IncreaseBpm => "BPM +1",
+            Command::DecreaseBpm => "BPM -1",
+            Command::IncreaseBpmHeld => "BPM + (hold)",
+            Command::DecreaseBpmHeld => "BPM - (hold)",
+            Command::ToggleMetronome => "Toggle Metronome",
+            Command::ToggleHelp => "Show Help",
+            Command::ToggleDevTools => "Show Dev Tools",
+            Command::ToggleDebugMode => "Show FPS",
+            Command::ResetHits => "Reset Hits",
+            Command::SaveLoop => "Save Loop",
+            Command::Quit => "Quit",
+        }
+    }
+
+    /// whether the command repeats while its key is held, rather than once per press
+    pub fn is_held(&self) -> bool {
+        matches!(self, Command::IncreaseBpmHeld | Command::DecreaseBpmHeld)
+    }
+}
+
+/// What a key is bound to
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash, PartialOrd, Ord)]
+pub enum Action {
+    Hit(Instrument),
+    Command(Command),
+}
+
+macro_rules! bindable_keys {
+    ($($key:ident),* $(,)?) => {
+        /// every key that can be bound. keys are saved by name, e.g. "Slash" or "Key1"
+        const BINDABLE_KEYS: &[(KeyCode, &str)] = &[$((KeyCode::$key, stringify!($key))),*];
+    };
+}
+
+bindable_keys! {
+    Space, Apostrophe, Comma, Minus, Period, Slash, Key0, Key1, Key2, Key3, Key4, Key5, Key6, Key7,
+    Key8, Key9, Semicolon, Equal, A, B, C, D, E, F, G, H, I, J, K, L, M, N, O, P, Q, R, S, T, U, V,
+    W, X, Y, Z, LeftBracket, Backslash, RightBracket, GraveAccent, World1, World2, Escape, Enter,
+    Tab, Backspace, Insert, Delete, Right, Left, Down, Up, PageUp, PageDown, Home, End, CapsLock,
+    ScrollLock, NumLock, PrintScreen, Pause, F1, F2, F3, F4, F5, F6, F7, F8, F9, F10, F11, F12, Kp0,
+    Kp1, Kp2, Kp3, Kp4, Kp5, Kp6, Kp7, Kp8, Kp9, KpDecimal, KpDivide, KpMultiply, KpSubtract, KpAdd,
+    KpEnter, KpEqual, LeftShift, LeftControl, LeftAlt, LeftSuper, RightShift, RightControl,
+    RightAlt, RightSuper, Menu,
+}
+
+/// A keyboard key, saved by name
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash, Serialize, Deserialize)]
+#[serde(try_from = "String", into = "String")]
+pub struct Key(pub KeyCode);
+
+impl Key {
+    pub fn from_name(name: &str) -> Option<Self> {
+        BINDABLE_KEYS
+            .iter()
+            .find(|(_, n)| *n == name)
+            .map(|(code, _)| Key(*code))
+    }
+
+    pub fn name(&self) -> &'static str {
+        BINDABLE_KEYS
+            .iter()
+            .find(|(code, _)| *code == self.0)
+            .map_or("Unknown", |(_, name)| name)
+    }
+
+    /// a short label for the UI, e.g. "1" rather than "Key1"
+    pub fn label(&self) -> String {
+        let name = self.name();
+        let symbol = match self.0 {
+            KeyCode::Apostrophe => "'",
+            KeyCode::Comma => ",",
+            KeyCode::Minus => "-",
+            KeyCode::Period => ".",
+            KeyCode::Slash => "/",
+            KeyCode::Semicolon => ";",
+            KeyCode::Equal => "=",
+            KeyCode::LeftBracket => "[",
+            KeyCode::Backslash => "\\",
+            KeyCode::RightBracket => "]",
+            KeyCode::GraveAccent => "`",
+            _ => "",
+        };
+        if !symbol.is_empty() {
+            symbol.to_string()
+        } else if let Some(digit) = name.strip_prefix("Key") {
+            digit.to_string()
+        } else if name.len() == 1 {
+            name.to_lowercase()
+        } else {
+            name.to_string()
+        }
+    }
+}
+
+impl TryFrom<String> for Key {
+    type Error = String;
+
+    fn try_from(name: String) -> Result<Self, Self::Error> {
+        Key::from_name(&name).ok_or(format!("unknown key: {}", name))
+    }
+}
+
+impl From<Key> for String {
+    fn from(key: Key) -> Self {
+        key.name().to_string()
+    }
+}
+
+#[derive(Debug, PartialEq, Clone, Serialize, Deserialize)]
+pub struct Keymap {
+    pub name: String,
+    instruments: BTreeMap<Instrument, Vec<Key>>,
+    commands: BTreeMap<Command, Vec<Key>>,
+}
+
+impl Default for Keymap {
+    fn default() -> Self {
+        Self::number_row()
+    }
+}
+
+impl Keymap {
+    fn from_bindings(
+        name: &str,
+        instruments: &[(Instrument, KeyCode)],
+        commands: &[(Command, KeyCode)],
+    ) -> Self {
+        Self {
+            name: name.to_string(),
+            instruments: instruments
+                .iter()
+                .map(|(ins, code)| (*ins, vec![Key(*code)]))
+                .collect(),
+            commands: commands
+                .iter()
+                .map(|(command, code)| (*command, vec![Key(*code)]))
+                .collect(),
+        }
+    }
+
+    /// instruments in order along the number row
+    fn number_row() -> Self {
+        let digits = [
+            KeyCode::Key1,
+            KeyCode::Key2,
+            KeyCode::Key3,
+            KeyCode::Key4,
+            KeyCode::Key5,
+            KeyCode::Key6,
+            KeyCode::Key7,
+            KeyCode::Key8,
+            KeyCode::Key9,
+            KeyCode::Key0,
+        ];
+        let instruments: Vec<(Instrument, KeyCode)> = ALL_INSTRUMENTS
+            .iter()
+            .copied()
+            .zip(digits.iter().copied())
+            .collect();
+        Self::from_bindings(
+            DEFAULT_KEYMAP_NAME,
+            &instruments,
+            &default_commands(KeyCode::Space),
+        )
+    }
+
+    /// hats under the left hand, snare and toms under the right, kick on space
+    fn two_handed() -> Self {
+        Self::from_bindings(
+            "Two-handed (F/J)",
+            &[
+                (Instrument::PedalHiHat, KeyCode::S),
+                (Instrument::OpenHihat, KeyCode::D),
+                (Instrument::ClosedHihat, KeyCode::F),
+                (Instrument::Crash, KeyCode::E),
+                (Instrument::Snare, KeyCode::J),
+                (Instrument::Tom1, KeyCode::U),
+                (Instrument::Tom2, KeyCode::I),
+                (Instrument::Tom3, KeyCode::K),
+                (Instrument::Ride, KeyCode::O),
+                (Instrument::Kick, KeyCode::Space),
+            ],
+            // space is the kick, so play/pause moves
+            &default_commands(KeyCode::Enter),
+        )
+    }
+
+    pub fn presets() -> Vec<Keymap> {
+        vec![Self::number_row(), Self::two_handed()]
+    }
+
+    /// the user's keymap, otherwise the default
+    pub fn load() -> Self {
+        let Some(path) = keymap_path() else {
+            return Self::default();
+        };
+        let Ok(data) = fs::read_to_string(&path) else {
+            return Self::default();
+        };
+        match serde_json::from_str::<Keymap>(&data) {
+            Ok(mut keymap) => {
+                keymap.fill_missing(&Self::default());
+                keymap
+            }
+            Err(e) => {
+                log::warn!("invalid keymap {:?}, using the default: {}", path, e);
+                Self::default()
+            }
+        }
+    }
+
+    pub fn save(&self) -> Result<(), Box<dyn Error>> {
+        let path = keymap_path().ok_or("no config directory for the keymap")?;
+        if let Some(dir) = path.parent() {
+            fs::create_dir_all(dir)?;
+        }
+        fs::write(&path, serde_json::to_string_pretty(self)?)?;
+        log::info!("saved keymap to {:?}", path);
+        Ok(())
+    }
+
+    /// actions missing from a saved keymap (e.g. added in a newer version) use the other keymap's keys.
+    /// actions the user cleared are kept cleared.
+    fn fill_missing(&mut self, other: &Keymap) {
+        for (ins, keys) in other.instruments.iter() {
+            self.instruments.entry(*ins).or_insert_with(|| keys.clone());
+        }
+        for (command, keys) in other.commands.iter() {
+            self.commands
+                .entry(*command)
+                .or_insert_with(|| keys.clone());
+        }
+    }
+
+    /// every action, in the order they're shown: instruments, then commands
+    pub fn actions() -> Vec<Action> {
+        ALL_INSTRUMENTS
+            .iter()
+            .map(|ins| Action::Hit(*ins))
+            .chain(Command::ALL.iter().map(|c| Action::Command(*c)))
+            .collect()
+    }
+
+    pub fn keys(&self, action: &Action) -> &[Key] {
+        let keys = match action {
+            Action::Hit(ins) => self.instruments.get(ins),
+            Action::Command(command) => self.commands.get(command),
+        };
+        keys.map_or(&[], |keys| keys.as_slice())
+    }
+
+    /// the first key for an action, as shown in the UI, e.g. "?" in the help window
+    pub fn label(&self, action: &Action) -> String {
+        self.keys(action)
+            .first()
+            .map_or("unbound".to_string(), |key| key.label())
+    }
+
+    /// binds the action to this key only
+    pub fn bind(&mut self, action: &Action, key: Key) {
+        self.set_keys(action, vec![key]);
+    }
+
+    pub fn clear(&mut self, action: &Action) {
+        self.set_keys(action, vec![]);
+    }
+
+    fn set_keys(&mut self, action: &Action, keys: Vec<Key>) {
+        self.name = CUSTOM_KEYMAP_NAME.to_string();
+        match action {
+            Action::Hit(ins) => self.instruments.insert(*ins, keys),
+            Action::Command(command) => self.commands.insert(*command, keys),
+        };
+    }
+
+    /// keys that are bound to more than one action, with those actions
+    pub fn conflicts(&self) -> Vec<(Key, Vec<Action>)> {
+        let mut actions_by_key: Vec<(Key, Vec<Action>)> = vec![];
+        for action in Self::actions() {
+            for key in self.keys(&action) {
+                match actions_by_key.iter_mut().find(|(k, _)| k == key) {
+                    Some((_, actions)) => actions.push(action),
+                    None => actions_by_key.push((*key, vec![action])),
+                }
+            }
+        }
+        actions_by_key.retain(|(_, actions)| actions.len() > 1);
+        actions_by_key
+    }
+}
+
+fn default_commands(pause_key: KeyCode) -> [(Command, KeyCode); 15] {
+    [
+        (Command::Pause, pause_key),
+        (Command::CalibrationTap, KeyCode::Equal),
+        (Command::DecreaseLatency, KeyCode::LeftBracket),
+        (Command::IncreaseLatency, KeyCode::RightBracket),
+        (Command::IncreaseBpm, KeyCode::Up),
+        (Command::DecreaseBpm, KeyCode::Down),
+        (Command::IncreaseBpmHeld, KeyCode::Right),
+        (Command::DecreaseBpmHeld, KeyCode::Left),
+        (Command::ToggleMetronome, KeyCode::M),
+        // the "?" key on a US layout
+        (Command::ToggleHelp, KeyCode::Slash),
+        (Command::ToggleDevTools, KeyCode::A),
+        (Command::ToggleDebugMode, KeyCode::Z),
+        (Command::ResetHits, KeyCode::R),
+        (Command::SaveLoop, KeyCode::X),
+        (Command::Quit, KeyCode::Q),
+    ]
+}
+
+fn keymap_path() -> Option<PathBuf> {
+    config_dir().map(|dir| dir.join("keymap.json"))
+}
+
+#[cfg(test)]
+mod tests {
+    use macroquad::input::KeyCode;
+
+    use crate::{
+        keymap::{Action, Command, Key, Keymap},
+        voices::Instrument,
+    };
+
+    #[test]
+    fn it_has_presets_that_bind_everything_without_conflicts() {
+        for keymap in Keymap::presets() {
+            assert_eq!(keymap.conflicts(), vec![], "{}", keymap.name);
+            for action in Keymap::actions() {
+                assert_eq!(
+                    keymap.keys(&action).len(),
+                    1,
+                    "{}: {:?}",
+                    keymap.name,
+                    action
+                );
+            }
+        }
+        let two_handed = &Keymap::presets()[1];
+        assert_eq!(
+            two_handed.keys(&Action::Hit(Instrument::Kick)),
+            &[Key(KeyCode::Space)]
+        );
+    }
+
+    #[test]
+    fn it_finds_conflicting_bindings() {
+        let mut keymap = Keymap::default();
+        let snare = Action::Hit(Instrument::Snare);
+        let metronome = Action::Command(Command::ToggleMetronome);
+        keymap.bind(&snare, Key(KeyCode::M));
+        assert_eq!(
+            keymap.conflicts(),
+            vec![(Key(KeyCode::M), vec![snare, metronome])]
+        );
+
+        keymap.clear(&metronome);
+        assert_eq!(keymap.conflicts(), vec![]);
+        assert_eq!(keymap.label(&metronome), "unbound");
+        assert_eq!(keymap.label(&snare), "m");
+    }
+
+    #[test]
+    fn it_saves_keys_by_name() {
+        let mut keymap = Keymap::default();
+        keymap.clear(&Action::Command(Command::Quit));
+        let json = serde_json::to_string(&keymap).unwrap();
+        assert!(json.contains(r#""snare":["Key8"]"#), "{}", json);
+        assert!(json.contains(r#""quit":[]"#), "{}", json);
+
+        let mut loaded: Keymap = serde_json::from_str(&json).unwrap();
+        loaded.fill_missing(&Keymap::default());
+        assert_eq!(loaded, keymap);
+        assert!(serde_json::from_str::<Key>(r#""NotAKey""#).is_err());
+        assert_eq!(Key(KeyCode::Slash).label(), "/");
+        assert_eq!(Key(KeyCode::Key0).label(), "0");
+        assert_eq!(Key(KeyCode::Space).label(), "Space");
+    }
+}
@@ -0,0 +1,216 @@
+/*
+  Tell apart the drums of an acoustic kit by their sound, as heard through a microphone.
+
+  Every kit (and room, and mic placement) sounds different, so there are no presets. Instead the user records
+  a few hits of each drum, and the profile remembers the average sound of each. A new hit counts as whichever
+  drum it sounds closest to. Profiles are saved per input device, in kit_profiles.json in the config directory.
+*/
+
+use std::{
+    collections::{BTreeMap, HashMap},
+    error::Error,
+    fs,
+    path::PathBuf,
+};
+
+use serde::{Deserialize, Serialize};
+
+use crate::{config::config_dir, consts::ALL_INSTRUMENTS, onset::NUM_BANDS, voices::Instrument};
+
+/// hits recorded for each drum while learning a kit
+pub const LEARN_HITS_PER_INSTRUMENT: usize = 4;
+
+#[derive(Debug, Default, PartialEq, Clone, Serialize, Deserialize)]
+pub struct KitProfile {
+    /// the average sound of each drum
+    sounds: BTreeMap<Instrument, [f32; NUM_BANDS]>,
+}
+
+impl KitProfile {
+    pub fn instruments(&self) -> Vec<Instrument> {
+        self.sounds.keys().copied().collect()
+    }
+
+    /// the drum that sounds most like this hit, if any have been learned
+    pub fn classify(&self, features: &[f32; NUM_BANDS]) -> Option<Instrument> {
+        self.sounds
+            .iter()
+            .map(|(ins, sound)| (*ins, distance(sound, features)))
+            .min_by(|(_, a), (_, b)| a.total_cmp(b))
+            .map(|(ins, _)| ins)
+    }
+}
+
+fn distance(a: &[f32; NUM_BANDS], b: &[f32; NUM_BANDS]) -> f32 {
+    a.iter().zip(b.iter()).map(|(x, y)| (x - y) * (x - y)).sum()
+}
+
+/// Kit-learn: the user hits each drum a few times, while its name is highlighted
+#[derive(Debug, Clone)]
+pub struct KitLearn {
+    profile: KitProfile,
+    next_idx: usize,
+    /// the sounds recorded for the current drum so far
+    hits: Vec<[f32; NUM_BANDS]>,
+}
+
+impl KitLearn {
+    /// starts from an existing profile, so drums that are skipped keep their sound
+    pub fn new(from: &KitProfile) -> Self {
+        Self {
+            profile: from.clone(),
+            next_idx: 0,
+            hits: vec![],
+        }
+    }
+
+    /// the drum waiting to be hit, or None when every drum has been learned
+    pub fn target(&self) -> Option<Instrument> {
+        ALL_INSTRUMENTS.get(self.next_idx).copied()
+    }
+
+    /// how many times the current drum has been hit so far
+    pub fn num_hits(&self) -> usize {
+        self.hits.len()
+    }
+
+    pub fn learn(&mut self, features: &[f32; NUM_BANDS]) {
+        let Some(ins) = self.target() else {
+            return;
+        };
+        self.hits.push(*features);
+        if self.hits.len() < LEARN_HITS_PER_INSTRUMENT {
+            return;
+        }
+        let mut average = [0.; NUM_BANDS];
+        for hit in self.hits.iter() {
+            for (sum, x) in average.iter_mut().zip(hit.iter()) {
+                *sum += x / self.hits.len() as f32;
+            }
+        }
+        self.profile.sounds.insert(ins, average);
+        self.skip();
+    }
+
+    pub fn skip(&mut self) {
+        self.next_idx += 1;
+        self.hits.clear();
+    }
+
+    pub fn is_done(&self) -> bool {
+        self.target().is_none()
+    }
+
+    pub fn profile(&self) -> &KitProfile {
+        &self.profile
+    }
+}
+
+fn kit_profiles_path() -> Option<PathBuf> {
+    config_dir().map(|dir| dir.join("kit_profiles.json"))
+}
+
+/// the saved profile for each input device
+pub fn load_kit_profiles() -> HashMap<String, KitProfile> {
+    let Some(path) = kit_profiles_path() else {
+        return HashMap::new();
+    };
+    let Ok(data) = fs::read_to_string(&path) else {
+        return HashMap::new();
+    };
+    serde_json::from_str(&data).unwrap_or_else(|e| {
+        log::warn!("invalid kit profiles {:?}: {}", path, e);
+        HashMap::new()
+    })
+}
+
+pub fn save_kit_profiles(profiles: &HashMap<String, KitProfile>) -> Result<(), Box<dyn Error>> {
+    let path = kit_profiles_path().ok_or("no config directory for kit profiles")?;
+    if let Some(dir) = path.parent() {
+        fs::create_dir_all(dir)?;
+    }
+    fs::write(&path, serde_json::to_string_pretty(profiles)?)?;
+    log::info!("saved kit profiles to {:?}", path);
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::{
+        consts::ALL_INSTRUMENTS,
+        kit_profile::{KitLearn, KitProfile, LEARN_HITS_PER_INSTRUMENT},
+        onset::tests::{detect, mix, read_sound},
+        voices::Instrument,
+    };
+
+    #[test]
+    fn it_learns_a_kit_and_tells_its_drums_apart() {
+        let kick = read_sound("kick.wav");
+        let snare = read_sound("snare.wav");
+        let hihat = read_sound("closed-hihat.wav");
+        let sounds: Vec<(Instrument, &[f32])> = vec![
+            (Instrument::Kick, &kick),
+            (Instrument::Snare, &snare),
+            (Instrument::ClosedHihat, &hihat),
+        ];
+
+        // calibration: each drum a few times, at different strengths, a quarter note apart at 100 bpm
+        let mut calibration = vec![];
+        let mut order = vec![];
+        for ins in ALL_INSTRUMENTS.iter() {
+            let Some((_, sound)) = sounds.iter().find(|(i, _)| i == ins) else {
+                continue;
+            };
+            for n in 0..LEARN_HITS_PER_INSTRUMENT {
+                let at = 4_800 + (order.len() * LEARN_HITS_PER_INSTRUMENT + n) * 28_800;
+                calibration.push((*sound, at, 1. - n as f32 * 0.2));
+            }
+            order.push(*ins);
+        }
+        let track = mix(4_800 + calibration.len() * 28_800, &calibration);
+
+        let mut learn = KitLearn::new(&KitProfile::default());
+        for onset in detect(&track, 48000) {
+            // skip the drums that weren't recorded
+            while !order.contains(&learn.target().unwrap()) {
+                learn.skip();
+            }
+            learn.learn(&onset.features);
+        }
+        while !learn.is_done() {
+            learn.skip();
+        }
+        let profile = learn.profile().clone();
+        assert_eq!(
+            profile.instruments(),
+            vec![Instrument::ClosedHihat, Instrument::Snare, Instrument::Kick]
+        );
+
+        // a groove, played softer and louder than the calibration
+        let groove: Vec<(&[f32], usize, f32)> = vec![
+            (&kick, 4_800, 0.5),
+            (&hihat, 19_200, 0.3),
+            (&snare, 33_600, 0.9),
+            (&hihat, 48_000, 1.),
+            (&kick, 62_400, 1.),
+            (&snare, 76_800, 0.2),
+        ];
+        let onsets = detect(&mix(96_000, &groove), 48000);
+        let heard: Vec<Option<Instrument>> = onsets
+            .iter()
+            .map(|o| profile.classify(&o.features))
+            .collect();
+        assert_eq!(
+            heard,
+            vec![
+                Some(Instrument::Kick),
+                Some(Instrument::ClosedHihat),
+                Some(Instrument::Snare),
+                Some(Instrument::ClosedHihat),
+                Some(Instrument::Kick),
+                Some(Instrument::Snare),
+            ]
+        );
+        assert_eq!(KitProfile::default().classify(&onsets[0].features), None);
+    }
+}
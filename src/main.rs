@@ -1,18 +1,36 @@
 mod audio;
+mod audio_device;
+mod beat;
+mod calibration;
 mod config;
 mod consts;
+mod dynamics;
 mod egui_ui;
 mod events;
 mod fps;
 mod game;
+mod gap_click;
+mod journal;
 mod keyboard_input_handler;
+mod keymap;
+mod kit_profile;
 
+mod mic_input_handler;
 mod midi;
+mod midi_clock;
 mod midi_input_handler;
+mod midi_mapping;
+mod midi_output;
+mod onset;
+mod progress;
+mod replay;
 use cvars_console_macroquad::MacroquadConsole;
+use mic_input_handler::MicInputHandler;
 use midi_input_handler::MidiInputHandler;
 
 mod score;
+mod snapshot;
+mod tempo_trainer;
 mod time;
 mod ui;
 mod voices;
@@ -26,8 +44,16 @@ use crate::ui::*;
 
 use audio::Audio;
 use consts::{WINDOW_HEIGHT, WINDOW_WIDTH};
-use game::{compute_ui_state, process_system_events, process_user_events, GameState, Loops};
+use events::Events;
+use game::{
+    compute_ui_state, process_recording_events, process_snapshot_events, update, GameState, Loops,
+};
+use journal::{sessions_dir, SessionJournal};
 use keyboard_input_handler::KeyboardInputHandler;
+use progress::ProgressHistory;
+use replay::{replay_config, startup_recording, InputRecorder, InputSource, Replay};
+use snapshot::startup_snapshot;
+use time::{current_time_millis, wall_time_seconds};
 
 use macroquad::prelude::*;
 use voices::Loop;
@@ -67,9 +93,6 @@ impl Cvars {
     }
 }
 
-// TOOD: move this to an env var controlled flag or similar
-const MOCK_INITIAL_STATE: bool = false;
-
 #[macroquad::main(window_conf)]
 async fn main() -> Result<(), Box<dyn Error>> {
     #[cfg(not(target_arch = "wasm32"))]
@@ -85,71 +108,116 @@ async fn main() -> Result<(), Box<dyn Error>> {
 
     // Setup game state
     let loops: Loops = read_loops().await?;
-    let keyboard_input = KeyboardInputHandler::new();
-    let mut midi_input = MidiInputHandler::new();
+    let mut keyboard_input = KeyboardInputHandler::new();
 
-    let mut gs = if MOCK_INITIAL_STATE {
-        GameState::new_mock_game_state()
-    } else {
-        GameState::new(loops)
-    };
+    let mut gs = GameState::new(loops);
+    gs.progress = ProgressHistory::load(sessions_dir().as_deref());
 
     // Setup audio, which runs on a separate thread and passes messages back.
     // TODO: Get rid of the shared state here (see how we compute_ui_state()), and just use message passing to update the GameState
     let (tx, rx) = mpsc::channel();
+
+    // replay a recording instead of playing, e.g. one attached to a bug report
+    let recording = startup_recording();
+    let mut replay = recording.as_ref().map(Replay::new);
+
     // let conf = AppConfig::new()?; // TODO: Get rid of conf lib for now to simplify? This is the only usage
-    let conf = AppConfig::new();
+    let mut conf = match replay {
+        Some(_) => replay_config(),
+        None => AppConfig::new(),
+    };
     log::debug!("App Config: {:?}", &conf);
 
-    let mut audio = if MOCK_INITIAL_STATE {
-        Audio::new_mock(&conf, tx.clone())
-    } else {
-        Audio::new(&conf, tx.clone())
+    let mut midi_input = MidiInputHandler::new(&conf);
+    let mut mic_input = MicInputHandler::new(&conf);
+
+    let mut audio = match replay {
+        Some(_) => Audio::new_replay(&conf, tx.clone()),
+        None => Audio::new(&conf, tx.clone()),
     };
     audio.initialize().await?;
 
+    match &replay {
+        Some(replay) => replay.start(&mut gs, &mut audio),
+        None => {
+            // pick up where the last session left off (or from a snapshot attached to a bug report)
+            if let Some(snapshot) = startup_snapshot() {
+                snapshot.restore(&mut gs, &mut audio);
+            }
+            // closing the window is handled like any other quit, so the game state can be saved first.
+            // a replay just closes, and leaves the saved state alone.
+            prevent_quit();
+        }
+    }
+
+    let mut journal = SessionJournal::new(sessions_dir(), current_time_millis() as u64, version);
+    let mut recorder: Option<InputRecorder> = None;
+
     // debug
     let mut fps_tracker = Fps::new();
 
     let mut ui = UI::new();
     loop {
+        let mut frame_time_s = wall_time_seconds();
+        let mut inputs: Vec<(InputSource, Vec<Events>)> = vec![];
         let mut events = Vec::new();
-        // read user's input and translate to events
-        if !macroquad_console.is_open() {
-            events.extend(keyboard_input.process());
+        if let Some(replay) = &mut replay {
+            // the recording's input is played, and the user's is ignored
+            ui.flush_events();
+            if let Some((recorded_time_s, recorded_events)) =
+                replay.next_frame(&mut audio, &gs.loops)
+            {
+                frame_time_s = recorded_time_s;
+                events = recorded_events;
+            }
+        } else {
+            // read user's input and translate to events
+            if !macroquad_console.is_open() {
+                inputs.push((InputSource::Keyboard, keyboard_input.process()));
+            }
+            inputs.push((InputSource::Ui, ui.flush_events()));
+
+            inputs.push((InputSource::Midi, midi_input.process(&conf)));
+            inputs.push((InputSource::Mic, mic_input.process(&conf)));
+            events.extend(inputs.iter().flat_map(|(_, e)| e.iter().cloned()));
+            if is_quit_requested() {
+                events.push(Events::Quit);
+            }
         }
-        events.extend(ui.flush_events());
-
-        events.extend(midi_input.process());
 
         // change game state
-        process_system_events(
-            &rx,
-            &mut audio,
-            &gs.voices,
-            &mut gs.gold_mode,
-            gs.beats_per_loop,
-        );
-        process_user_events(
-            &mut gs.voices,
+        let attempts = update(
+            &mut gs,
             &mut audio,
-            &mut gs.flags,
-            &gs.loops,
-            &mut gs.selected_loop_idx,
+            &rx,
             &events,
-            &mut gs.correct_margin,
-            &mut gs.miss_margin,
+            frame_time_s,
+            &mut conf,
             &mut midi_input,
-            &mut gs.beats_per_loop,
+            &mut mic_input,
+            &mut keyboard_input,
+            match replay {
+                Some(_) => None,
+                None => Some(&mut journal),
+            },
         )?;
-
-        audio.schedule(&gs.voices).await?;
+        if let Some(replay) = &mut replay {
+            replay.end_frame(&attempts, &audio);
+        }
+        if let Some(recorder) = &mut recorder {
+            recorder.record_frame(frame_time_s, &audio, &gs.loops, &inputs, &attempts);
+        }
+        process_recording_events(&mut recorder, &gs, &audio, &events, version);
+        process_snapshot_events(&gs, &audio, &events);
 
         // render UI
         ui.render(&compute_ui_state(
             &gs,
             &audio,
-            midi_input.attached_device_name(),
+            &midi_input,
+            &mic_input,
+            &keyboard_input,
+            recorder.is_some(),
         ));
 
         macroquad_console.update(&mut my_cvars);
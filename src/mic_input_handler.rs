@@ -0,0 +1,331 @@
+/*
+  Capture hits on an acoustic kit from a microphone, and convert them into events.
+
+  Audio arrives from the input device in buffers, on its own thread. Each buffer is stamped with the wall time
+  its first sample was captured, so every hit can be timed from its exact sample (see onset.rs), rather than
+  from when the buffer happened to be read.
+
+  Without a kit profile every hit counts as a snare hit, e.g. when practicing on a pad.
+  With one (see kit_profile.rs), each hit counts as the drum it sounds most like.
+*/
+
+use std::{
+    collections::HashMap,
+    error::Error,
+    sync::{Arc, Mutex},
+};
+
+use cpal::{
+    traits::{DeviceTrait, HostTrait, StreamTrait},
+    FromSample, SampleFormat, SizedSample,
+};
+
+use crate::{
+    config::AppConfig,
+    events::Events,
+    kit_profile::{load_kit_profiles, save_kit_profiles, KitLearn, KitProfile},
+    onset::OnsetDetector,
+    time::{current_time_micros, TimestampSync},
+    voices::Instrument,
+};
+
+/// what every hit counts as, until the kit is learned
+const DEFAULT_INSTRUMENT: Instrument = Instrument::Snare;
+
+/// how much the level meter falls each frame
+const LEVEL_DECAY: f32 = 0.9;
+
+/// Mono audio from the input device, as captured
+struct AudioBuffer {
+    /// position of the first sample in the stream
+    first_sample: u64,
+    /// wall time when the first sample was captured
+    captured_us: u128,
+    samples: Vec<f32>,
+}
+
+struct MicInput {
+    device_name: String,
+    sample_rate: u32,
+    // audio stops when the stream is dropped
+    _stream: cpal::Stream,
+    // every buffer received since the last take_buffers(), in order
+    queue: Arc<Mutex<Vec<AudioBuffer>>>,
+    detector: OnsetDetector,
+    timestamps: TimestampSync,
+}
+
+impl MicInput {
+    fn connect(device_name: &str) -> Result<Self, Box<dyn Error>> {
+        let device = cpal::default_host()
+            .input_devices()?
+            .find(|d| d.name().is_ok_and(|n| n == device_name))
+            .ok_or(format!("audio input device not found: {}", device_name))?;
+        let config = device.default_input_config()?;
+        let queue = Arc::new(Mutex::new(vec![]));
+        let stream = match config.sample_format() {
+            SampleFormat::F32 => build_stream::<f32>(&device, &config.config(), queue.clone())?,
+            SampleFormat::I16 => build_stream::<i16>(&device, &config.config(), queue.clone())?,
+            SampleFormat::U16 => build_stream::<u16>(&device, &config.config(), queue.clone())?,
+            SampleFormat::I32 => build_stream::<i32>(&device, &config.config(), queue.clone())?,
+            format => return Err(format!("unsupported sample format: {}", format).into()),
+        };
+        stream.play()?;
+        let sample_rate = config.sample_rate().0;
+        Ok(Self {
+            device_name: device_name.to_string(),
+            sample_rate,
+            _stream: stream,
+            queue,
+            detector: OnsetDetector::new(sample_rate),
+            timestamps: TimestampSync::default(),
+        })
+    }
+
+    fn take_buffers(&self) -> Vec<AudioBuffer> {
+        match self.queue.lock() {
+            Ok(mut queue) => std::mem::take(&mut *queue),
+            Err(_) => vec![],
+        }
+    }
+}
+
+fn build_stream<T>(
+    device: &cpal::Device,
+    config: &cpal::StreamConfig,
+    queue: Arc<Mutex<Vec<AudioBuffer>>>,
+) -> Result<cpal::Stream, Box<dyn Error>>
+where
+    T: SizedSample,
+    f32: FromSample<T>,
+{
+    let channels = config.channels as usize;
+    let mut next_sample: u64 = 0;
+    let stream = device.build_input_stream(
+        config,
+        move |data: &[T], info: &cpal::InputCallbackInfo| {
+            // the first sample was captured some time before this callback
+            let timestamp = info.timestamp();
+            let capture_delay_us = timestamp
+                .callback
+                .duration_since(&timestamp.capture)
+                .map_or(0, |d| d.as_micros());
+            let samples: Vec<f32> = data
+                .chunks(channels)
+                .map(|frame| {
+                    frame.iter().map(|x| f32::from_sample_(*x)).sum::<f32>() / channels as f32
+                })
+                .collect();
+            let buffer = AudioBuffer {
+                first_sample: next_sample,
+                captured_us: current_time_micros().saturating_sub(capture_delay_us),
+                samples,
+            };
+            next_sample += buffer.samples.len() as u64;
+            if let Ok(mut queue) = queue.lock() {
+                queue.push(buffer);
+            }
+        },
+        |e| log::warn!("audio input error: {}", e),
+        None,
+    )?;
+    Ok(stream)
+}
+
+/// names of the audio input devices that are currently available
+pub fn list_input_devices() -> Vec<String> {
+    match cpal::default_host().input_devices() {
+        Ok(devices) => devices.filter_map(|d| d.name().ok()).collect(),
+        Err(e) => {
+            log::warn!("unable to list audio input devices: {e}");
+            vec![]
+        }
+    }
+}
+
+pub struct MicInputHandler {
+    input: Option<MicInput>,
+    /// the device chosen when we last connected
+    chosen_device: Option<String>,
+    available_devices: Vec<String>,
+    profiles: HashMap<String, KitProfile>,
+    learn: Option<KitLearn>,
+    /// the loudest recent sample, for a level meter
+    level: f32,
+}
+
+impl MicInputHandler {
+    pub fn new(conf: &AppConfig) -> Self {
+        let mut handler = Self {
+            input: None,
+            chosen_device: None,
+            available_devices: list_input_devices(),
+            profiles: load_kit_profiles(),
+            learn: None,
+            level: 0.,
+        };
+        handler.connect(conf.mic_input_device.as_deref());
+        handler
+    }
+
+    fn connect(&mut self, device_name: Option<&str>) {
+        self.chosen_device = device_name.map(|name| name.to_string());
+        self.input = None;
+        self.learn = None;
+        let Some(device_name) = device_name else {
+            return;
+        };
+        match MicInput::connect(device_name) {
+            Ok(input) => {
+                log::info!(
+                    "listening for hits on {:?} at {}Hz",
+                    device_name,
+                    input.sample_rate
+                );
+                self.input = Some(input);
+            }
+            Err(e) => log::warn!("unable to listen to {:?}: {}", device_name, e),
+        }
+    }
+
+    pub fn refresh_devices(&mut self) {
+        self.available_devices = list_input_devices();
+    }
+
+    pub fn devices(&self) -> &[String] {
+        &self.available_devices
+    }
+
+    /// the device being listened to
+    pub fn device_name(&self) -> Option<&str> {
+        self.input.as_ref().map(|input| input.device_name.as_str())
+    }
+
+    pub fn level(&self) -> f32 {
+        self.level
+    }
+
+    fn profile(&self) -> Option<&KitProfile> {
+        self.profiles.get(self.device_name()?)
+    }
+
+    /// the drums the current device's kit profile can tell apart
+    pub fn profile_instruments(&self) -> Vec<Instrument> {
+        self.profile()
+            .map_or(vec![], |profile| profile.instruments())
+    }
+
+    /// kit-learn starts from the device's current profile
+    pub fn start_learn(&mut self) {
+        if self.input.is_some() {
+            self.learn = Some(KitLearn::new(&self.profile().cloned().unwrap_or_default()));
+        }
+    }
+
+    pub fn skip_learn_instrument(&mut self) {
+        if let Some(learn) = &mut self.learn {
+            learn.skip();
+        }
+    }
+
+    pub fn cancel_learn(&mut self) {
+        self.learn = None;
+    }
+
+    /// stops kit-learn, and uses the learned profile for the device from now on
+    pub fn finish_learn(&mut self) {
+        let Some(learn) = self.learn.take() else {
+            return;
+        };
+        let Some(device_name) = self.device_name().map(|name| name.to_string()) else {
+            return;
+        };
+        self.profiles.insert(device_name, learn.profile().clone());
+        if let Err(e) = save_kit_profiles(&self.profiles) {
+            log::error!("error saving kit profiles. error was: {e}");
+        }
+    }
+
+    /// forgets the current device's kit, so every hit counts as the default instrument again
+    pub fn clear_profile(&mut self) {
+        let Some(device_name) = self.device_name().map(|name| name.to_string()) else {
+            return;
+        };
+        self.profiles.remove(&device_name);
+        if let Err(e) = save_kit_profiles(&self.profiles) {
+            log::error!("error saving kit profiles. error was: {e}");
+        }
+    }
+
+    /// the drum kit-learn is waiting for, and how many times it's been hit so far
+    pub fn learn_target(&self) -> Option<(Instrument, usize)> {
+        let learn = self.learn.as_ref()?;
+        Some((learn.target()?, learn.num_hits()))
+    }
+
+    pub fn is_learning(&self) -> bool {
+        self.learn.is_some()
+    }
+
+    /// convert any hits since the last frame into Events
+    pub fn process(&mut self, conf: &AppConfig) -> Vec<Events> {
+        let mut events: Vec<Events> = vec![];
+
+        if conf.mic_input_device != self.chosen_device {
+            self.connect(conf.mic_input_device.as_deref());
+        }
+        self.level *= LEVEL_DECAY;
+        let Some(input) = &mut self.input else {
+            return events;
+        };
+
+        let now_us = current_time_micros();
+        let sample_rate = input.sample_rate;
+        let input_latency_s = conf.input_latency_seconds(&input.device_name);
+        for buffer in input.take_buffers() {
+            input.timestamps.observe(
+                stamp_us(buffer.first_sample, sample_rate),
+                buffer.captured_us,
+            );
+            for x in buffer.samples.iter() {
+                self.level = self.level.max(x.abs());
+            }
+
+            for onset in input.detector.process(&buffer.samples) {
+                if let Some(learn) = &mut self.learn {
+                    // while learning, hits record the drum's sound rather than being played
+                    let was_done = learn.is_done();
+                    learn.learn(&onset.features);
+                    if learn.is_done() && !was_done {
+                        events.push(Events::FinishKitLearn);
+                    }
+                    continue;
+                }
+
+                let instrument = self
+                    .profiles
+                    .get(&input.device_name)
+                    .and_then(|profile| profile.classify(&onset.features))
+                    .unwrap_or(DEFAULT_INSTRUMENT);
+                // the hit happened when its first sample was captured, less the input latency
+                let captured_us = input
+                    .timestamps
+                    .wall_time_us(stamp_us(onset.sample, sample_rate), now_us);
+                let processing_delay_s = now_us.saturating_sub(captured_us) as f64 / 1_000_000.;
+                events.push(Events::UserHit {
+                    instrument,
+                    processing_delay: processing_delay_s + input_latency_s,
+                    velocity: Some(onset.velocity()),
+                    device: input.device_name.clone(),
+                });
+            }
+        }
+
+        events
+    }
+}
+
+/// the position of a sample in the stream, in microseconds
+fn stamp_us(sample: u64, sample_rate: u32) -> u64 {
+    (sample as u128 * 1_000_000 / sample_rate.max(1) as u128) as u64
+}
@@ -5,11 +5,14 @@
 */
 
 use log::info;
-use std::collections::HashMap;
+use std::error::Error;
 use std::string::*;
+use std::sync::mpsc::{self, Receiver};
 use std::sync::{Arc, Mutex};
+use std::thread;
+use std::time::Duration;
 
-use crate::time::current_time_millis;
+use crate::time::current_time_micros;
 
 pub struct MidiInput {
     input_port: midir::MidiInputPort,
@@ -18,115 +21,156 @@ pub struct MidiInput {
     midi_input: Option<midir::MidiInput>,
     connection: Option<midir::MidiInputConnection<()>>,
 
-    raw_inputs: Arc<Mutex<HashMap<u8, MidiInputDataRaw>>>,
-    previous_raw_inputs: Arc<Mutex<HashMap<u8, MidiInputDataRaw>>>,
+    // every message received since the last take_messages(), in the order they arrived
+    queue: Arc<Mutex<Vec<MidiInputDataRaw>>>,
 }
 
 #[derive(Eq, Clone, Debug, Copy, PartialEq)]
 pub struct MidiInputDataRaw {
     pub note_number: u8,
+    /// when the device sent the message, in microseconds from an arbitrary start
     pub timestamp: u64,
-    pub non_midi_timestamp_ms: u128,
+    /// when the message arrived, in wall time
+    pub non_midi_timestamp_us: u128,
     // https://www.logosfoundation.org/kursus/1075.html
     status: u8,
-    note_velocity: u8,
+    pub note_velocity: u8,
 }
 
 impl MidiInputDataRaw {
+    pub fn new(message: &[u8], timestamp: u64, non_midi_timestamp_us: u128) -> Self {
+        Self {
+            note_number: message.get(1).copied().unwrap_or(0),
+            timestamp,
+            non_midi_timestamp_us,
+            status: message.first().copied().unwrap_or(0),
+            note_velocity: message.get(2).copied().unwrap_or(0),
+        }
+    }
+
+    pub fn status(&self) -> u8 {
+        self.status
+    }
+
     pub fn is_note_on(&self) -> bool {
-        self.status >= 144 && self.status <= 159
+        // a note on with velocity 0 is how many devices send a note off
+        self.status >= 144 && self.status <= 159 && self.note_velocity > 0
+    }
+
+    /// for a control change, note_number is the controller and note_velocity is its value
+    pub fn is_control_change(&self) -> bool {
+        self.status >= 176 && self.status <= 191
+    }
+}
+
+fn new_client() -> Option<midir::MidiInput> {
+    let client_name = format!("midi_input_{}", macroquad::rand::gen_range(0, 1000000));
+    let mut midi_input = midir::MidiInput::new(client_name.as_str()).ok()?;
+    // keep timing messages, for midi clock sync
+    midi_input.ignore(midir::Ignore::None);
+    Some(midi_input)
+}
+
+/// how often to look for midi devices that were plugged in or removed
+const PORT_SCAN_INTERVAL: Duration = Duration::from_secs(1);
+
+fn port_names(midi_input: &midir::MidiInput) -> Vec<String> {
+    midi_input
+        .ports()
+        .iter()
+        .filter_map(|port| midi_input.port_name(port).ok())
+        .collect()
+}
+
+/// Lists the midi input ports every so often on a background thread, since a scan can be slow
+/// enough to hold up a frame. One client is reused for every scan.
+pub struct InputPortScanner {
+    rx: Receiver<Vec<String>>,
+}
+
+impl InputPortScanner {
+    /// starts scanning, and returns the ports that are available now
+    pub fn start() -> (Self, Vec<String>) {
+        let (tx, rx) = mpsc::channel();
+        let Some(midi_input) = new_client() else {
+            return (Self { rx }, vec![]);
+        };
+        let ports = port_names(&midi_input);
+        thread::spawn(move || loop {
+            thread::sleep(PORT_SCAN_INTERVAL);
+            // stops once the scanner is dropped
+            if tx.send(port_names(&midi_input)).is_err() {
+                break;
+            }
+        });
+        (Self { rx }, ports)
+    }
+
+    /// the names of the available ports from the latest scan, if there's been one since the last call
+    pub fn latest(&self) -> Option<Vec<String>> {
+        self.rx.try_iter().last()
     }
 }
 
 impl MidiInput {
-    pub fn new() -> Option<Self> {
-        log::info!("MidiInput::new()");
-        let client_name = format!("midi_input_{}", macroquad::rand::gen_range(0, 1000000));
-        let midi_input = midir::MidiInput::new(client_name.as_str());
-        if midi_input.is_err() {
-            return None;
-        }
-        let midi_input = midi_input.unwrap();
+    /// opens the input port with this name, if it's available
+    pub fn new(port_name: &str) -> Option<Self> {
+        log::info!("MidiInput::new({})", port_name);
+        let midi_input = new_client()?;
 
         log::info!("port count {}", midi_input.port_count());
-        // grab first device
-        let input_port = midi_input.ports().into_iter().next()?;
-
-        let device_name = midi_input
-            .port_name(&input_port)
-            .expect("can't get name of port");
+        let input_port = midi_input.ports().into_iter().find(|port| {
+            midi_input
+                .port_name(port)
+                .is_ok_and(|name| name == port_name)
+        })?;
+        let device_name = port_name.to_string();
 
         Some(Self {
             midi_input: Some(midi_input),
             input_port,
             device_name,
             connection: None,
-            raw_inputs: Arc::new(Mutex::new(HashMap::with_capacity(16))),
-            previous_raw_inputs: Arc::new(Mutex::new(HashMap::with_capacity(16))),
+            queue: Arc::new(Mutex::new(Vec::with_capacity(16))),
         })
     }
 
-    pub fn get_pressed_buttons(&self) -> Vec<MidiInputDataRaw> {
-        let mut pressed = Vec::new();
-        let mut raw_inputs = self.raw_inputs.lock().unwrap();
-        for (_id, raw_input) in raw_inputs.iter_mut() {
-            if raw_input.is_note_on() {
-                pressed.push(*raw_input);
-            }
-        }
-        if !pressed.is_empty() {
-            log::info!("Pressed midi: {:?}", pressed);
-        }
-        pressed
+    /// removes and returns every message received since the last call, oldest first
+    pub fn take_messages(&self) -> Vec<MidiInputDataRaw> {
+        std::mem::take(&mut *self.queue.lock().unwrap())
     }
 
-    // clear all inputs, update previous values
-    pub fn flush(&mut self) {
-        let mut prev_raw_inputs = self.previous_raw_inputs.lock().unwrap();
-        let mut raw_inputs = self.raw_inputs.lock().unwrap();
-        // store latests values as previous
-        for (id, raw_input) in raw_inputs.iter_mut() {
-            if let Some(prev_raw) = prev_raw_inputs.get_mut(id) {
-                *prev_raw = *raw_input;
-            } else {
-                prev_raw_inputs.insert(*id, *raw_input);
-            }
-        }
-        raw_inputs.clear();
-    }
-
-    pub fn connect(&mut self) {
+    pub fn connect(&mut self) -> Result<(), Box<dyn Error>> {
         log::info!("Connecting to midi device: {}", self.device_name);
-        let raw_inputs = self.raw_inputs.clone();
+        let queue = self.queue.clone();
         self.connection = Some(
             self.midi_input
                 .take() // consume midi_input because it will be sent to thread
-                .unwrap()
+                .ok_or("already connected")?
                 .connect(
                     &self.input_port,
                     self.device_name.as_str(),
                     move |stamp, message, _| {
                         // get timestamp
-                        let non_midi_timestamp_ms = current_time_millis();
-                        let midi_function = message[0];
-                        let note_number = message[1];
-                        let v = MidiInputDataRaw {
-                            note_number,
-                            timestamp: stamp,
-                            non_midi_timestamp_ms,
-                            status: midi_function,
-                            note_velocity: message[2],
-                        };
-                        info!("{}: {:?} (len = {})", stamp, v, message.len());
-                        info!("{}", MIDI_FUNCTION_NAMES[midi_function as usize - 128]);
-                        let mut rw: std::sync::MutexGuard<HashMap<u8, MidiInputDataRaw>> =
-                            raw_inputs.lock().unwrap();
-                        rw.insert(note_number, v);
+                        let non_midi_timestamp_us = current_time_micros();
+                        let v = MidiInputDataRaw::new(message, stamp, non_midi_timestamp_us);
+                        // clock pulses arrive dozens of times per second, so aren't logged
+                        if v.status != MIDI_CLOCK_PULSE {
+                            info!("{}: {:?} (len = {})", stamp, v, message.len());
+                            if let Some(name) = (v.status as usize)
+                                .checked_sub(128)
+                                .and_then(|i| MIDI_FUNCTION_NAMES.get(i))
+                            {
+                                info!("{}", name);
+                            }
+                        }
+                        queue.lock().unwrap().push(v);
                     },
                     (),
                 )
-                .expect("can't connect to midi device"),
+                .map_err(|e| format!("can't connect to midi device: {e}"))?,
         );
+        Ok(())
     }
 
     pub fn get_device_name(&self) -> &str {
@@ -136,6 +180,8 @@ impl MidiInput {
 
 // Midi Spec
 
+const MIDI_CLOCK_PULSE: u8 = 0xF8;
+
 // from 128-255, these are the functions corresponding to a Midi Note's 1st byte
 const MIDI_FUNCTION_NAMES: [&str; 128] = [
     "Chan 1 Note off",
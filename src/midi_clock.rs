@@ -0,0 +1,221 @@
+/*
+ Midi clock and transport: follow another device's tempo and start/stop (e.g. a DAW), or lead other gear.
+
+ Midi clock is 24 pulses per quarter note. A tick of the audio clock is an 8th note, so there are 12 pulses per tick.
+ Pulses arrive with some jitter, so the tempo is smoothed over many pulses.
+*/
+
+use serde::{Deserialize, Serialize};
+
+use crate::midi::MidiInputDataRaw;
+
+pub const PULSES_PER_TICK: f64 = 12.;
+
+// system real-time and common messages
+pub const CLOCK: u8 = 0xF8;
+pub const START: u8 = 0xFA;
+pub const CONTINUE: u8 = 0xFB;
+pub const STOP: u8 = 0xFC;
+pub const SONG_POSITION: u8 = 0xF2;
+
+/// how much each pulse moves the estimated time between pulses
+const PULSE_SMOOTHING: f64 = 0.05;
+
+/// a gap this many times longer than expected means the clock stopped, rather than jitter
+const MAX_PULSE_GAP: f64 = 3.;
+
+/// while following, tempo changes smaller than this are ignored
+pub const FOLLOW_BPM_TOLERANCE: f64 = 0.1;
+
+/// while following, playback is moved if it's further than this from the leader's position
+pub const FOLLOW_MAX_DRIFT_TICKS: f64 = 0.05;
+
+/// Start, stop or continue playback
+#[derive(Debug, Clone, Copy, PartialEq, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum Transport {
+    /// play from the start
+    Start,
+    /// play from the current song position
+    Continue,
+    Stop,
+}
+
+/// Follows the clock and transport messages from another device
+#[derive(Debug, Clone, Default)]
+pub struct MidiClockFollower {
+    running: bool,
+    /// pulses since song position 0
+    pulses: u64,
+    /// song position and wall time (in microseconds) of the latest pulse
+    last_pulse: Option<(u64, u128)>,
+    /// smoothed time between pulses
+    pulse_interval_us: Option<f64>,
+}
+
+impl MidiClockFollower {
+    /// updates from a message that arrived at the given wall time. returns any change to the transport.
+    pub fn on_message(&mut self, midi: &MidiInputDataRaw, wall_time_us: u128) -> Option<Transport> {
+        match midi.status() {
+            START => {
+                self.running = true;
+                self.pulses = 0;
+                Some(Transport::Start)
+            }
+            CONTINUE => {
+                self.running = true;
+                Some(Transport::Continue)
+            }
+            STOP => {
+                self.running = false;
+                Some(Transport::Stop)
+            }
+            SONG_POSITION => {
+                // in 16th notes, as 14 bits
+                let sixteenths = midi.note_number as u64 | (midi.note_velocity as u64) << 7;
+                self.pulses = sixteenths * 6;
+                None
+            }
+            CLOCK => {
+                self.on_pulse(wall_time_us);
+                None
+            }
+            _ => None,
+        }
+    }
+
+    fn on_pulse(&mut self, wall_time_us: u128) {
+        if let Some((_, last_us)) = self.last_pulse {
+            let interval = wall_time_us.saturating_sub(last_us) as f64;
+            self.pulse_interval_us = match self.pulse_interval_us {
+                Some(prev) if interval < prev * MAX_PULSE_GAP => {
+                    Some(prev + (interval - prev) * PULSE_SMOOTHING)
+                }
+                // first interval, or the clock stopped for a while
+                _ => Some(interval),
+            };
+        }
+        self.last_pulse = Some((self.pulses, wall_time_us));
+        // the first pulse after a start is song position 0
+        if self.running {
+            self.pulses += 1;
+        }
+    }
+
+    pub fn is_running(&self) -> bool {
+        self.running
+    }
+
+    /// the leader's tempo, in quarter notes per minute
+    pub fn bpm(&self) -> Option<f64> {
+        let interval_us = self.pulse_interval_us?;
+        (interval_us > 0.).then(|| 60_000_000. / (interval_us * PULSES_PER_TICK * 2.))
+    }
+
+    /// the song position of the latest pulse, in ticks, and the wall time (in microseconds) it arrived
+    pub fn last_pulse_ticks(&self) -> Option<(f64, u128)> {
+        let (pulses, wall_time_us) = self.last_pulse?;
+        Some((pulses as f64 / PULSES_PER_TICK, wall_time_us))
+    }
+}
+
+/// the clock pulses after from_tick, up to and including to_tick, as ticks
+pub fn pulse_ticks_between(from_tick: f64, to_tick: f64) -> Vec<f64> {
+    let first = (from_tick * PULSES_PER_TICK).floor() as i64 + 1;
+    let last = (to_tick * PULSES_PER_TICK).floor() as i64;
+    (first.max(0)..=last)
+        .map(|pulse| pulse as f64 / PULSES_PER_TICK)
+        .collect()
+}
+
+/// a song position pointer message for the given tick, rounded down to a 16th note
+pub fn song_position_message(tick: f64) -> Vec<u8> {
+    let sixteenths = ((tick.max(0.) * 2.).floor() as u64).min(0x3FFF);
+    vec![
+        SONG_POSITION,
+        (sixteenths & 0x7F) as u8,
+        (sixteenths >> 7) as u8,
+    ]
+}
+
+/// the smallest shift that moves a position within the loop onto the target, e.g. -1 rather than 15 in a 16 beat loop
+pub fn loop_offset(from_tick: f64, to_tick: f64, beats_per_loop: f64) -> f64 {
+    let diff = (to_tick - from_tick).rem_euclid(beats_per_loop);
+    if diff > beats_per_loop / 2. {
+        diff - beats_per_loop
+    } else {
+        diff
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::{
+        midi::MidiInputDataRaw,
+        midi_clock::{
+            loop_offset, pulse_ticks_between, song_position_message, MidiClockFollower, Transport,
+            CLOCK, CONTINUE, SONG_POSITION, START, STOP,
+        },
+    };
+
+    fn msg(bytes: &[u8]) -> MidiInputDataRaw {
+        MidiInputDataRaw::new(bytes, 0, 0)
+    }
+
+    #[test]
+    fn it_follows_tempo_and_position_despite_jitter() {
+        let mut follower = MidiClockFollower::default();
+        assert_eq!(follower.bpm(), None);
+        assert_eq!(
+            follower.on_message(&msg(&[START]), 0),
+            Some(Transport::Start)
+        );
+
+        // 120 bpm is 48 pulses per second, so ~20833us apart. each arrives up to 2ms late.
+        let interval_us = 60_000_000. / (120. * 24.);
+        let mut t: u128 = 0;
+        for pulse in 0..480 {
+            let jitter_us = [0, 2_000, 500, 1_500][pulse % 4];
+            t = (pulse as f64 * interval_us) as u128 + jitter_us;
+            follower.on_message(&msg(&[CLOCK]), t);
+        }
+        let bpm = follower.bpm().unwrap();
+        assert!((bpm - 120.).abs() < 1., "bpm: {}", bpm);
+        // 479 pulses after the first is 39.9 ticks (8th notes)
+        assert_eq!(follower.last_pulse_ticks(), Some((479. / 12., t)));
+
+        assert_eq!(follower.on_message(&msg(&[STOP]), t), Some(Transport::Stop));
+        // a stopped clock keeps pulsing, but doesn't move the song position
+        follower.on_message(&msg(&[CLOCK]), t + 20_000);
+        assert_eq!(follower.last_pulse_ticks().unwrap().0, 480. / 12.);
+
+        // move to bar 3 (32 16ths) and continue
+        follower.on_message(&msg(&[SONG_POSITION, 32, 0]), t);
+        assert_eq!(
+            follower.on_message(&msg(&[CONTINUE]), t),
+            Some(Transport::Continue)
+        );
+        follower.on_message(&msg(&[CLOCK]), t + 40_000);
+        assert_eq!(follower.last_pulse_ticks().unwrap().0, 16.);
+        assert!(follower.is_running());
+    }
+
+    #[test]
+    fn it_sends_pulses_and_positions() {
+        assert_eq!(
+            pulse_ticks_between(0., 0.25),
+            vec![1. / 12., 2. / 12., 3. / 12.]
+        );
+        assert!(pulse_ticks_between(0.25, 0.3).is_empty());
+        assert_eq!(pulse_ticks_between(-1., 0.), vec![0.]);
+        assert_eq!(song_position_message(16.7), vec![SONG_POSITION, 33, 0]);
+        assert_eq!(song_position_message(100.), vec![SONG_POSITION, 72, 1]);
+    }
+
+    #[test]
+    fn it_finds_the_nearest_loop_offset() {
+        assert_eq!(loop_offset(15.5, 16.5, 16.), 1.);
+        assert_eq!(loop_offset(33., 0., 16.), -1.);
+        assert_eq!(loop_offset(40.25, 8., 16.), -0.25);
+    }
+}
@@ -1,196 +1,439 @@
 /*
   Capture user input from midi. convert it into events.
 
-  This is stateful because it depends on setting up a connection to a midi input,
+  This is stateful because it depends on setting up connections to midi inputs,
   and flushing the internally stored events after the have been consumed via process().
-*/
 
-use std::collections::HashSet;
+  Several devices can be connected at once (e.g. a drum module and a keyboard), each with its own mapping.
+  Ports are re-scanned every so often (off the render loop), so devices plugged in mid-session are picked up.
+  The latest value of each controller (e.g. the hi-hat pedal) is tracked per device, since it can change what a hit means.
+  One device can also be followed for midi clock and start/stop.
+*/
 
-use macroquad::prelude::*;
+use std::collections::HashMap;
 
 use crate::{
-    consts::*, events::Events, midi::MidiInput, time::current_time_millis, voices::Instrument,
+    config::AppConfig,
+    events::Events,
+    midi::{InputPortScanner, MidiInput, MidiInputDataRaw},
+    midi_clock::{MidiClockFollower, CLOCK},
+    midi_mapping::{MidiLearn, MidiMapping, MidiMappings},
+    time::{current_time_micros, TimestampSync},
+    voices::Instrument,
 };
 
+struct MidiDevice {
+    input: MidiInput,
+    mapping: MidiMapping,
+    timestamps: TimestampSync,
+    /// latest value of each controller
+    controllers: HashMap<u8, u8>,
+}
+
 pub struct MidiInputHandler {
-    midi_input: Option<MidiInput>,
+    devices: Vec<MidiDevice>,
+    available_ports: Vec<String>,
+    port_scanner: InputPortScanner,
+    mappings: MidiMappings,
+    /// device name, and the learn in progress for it
+    learn: Option<(String, MidiLearn)>,
+    /// the device whose midi clock is followed
+    clock_source: Option<String>,
+    clock_follower: MidiClockFollower,
 }
 
 impl MidiInputHandler {
-    pub fn new() -> Self {
-        let mut midi_input = MidiInput::new();
-        match midi_input {
-            Some(ref mut midi_input) => {
-                midi_input.connect();
+    pub fn new(conf: &AppConfig) -> Self {
+        let (port_scanner, available_ports) = InputPortScanner::start();
+        let mut handler = Self {
+            devices: vec![],
+            available_ports,
+            port_scanner,
+            mappings: MidiMappings::load(),
+            learn: None,
+            clock_source: conf.midi_clock_source.clone(),
+            clock_follower: MidiClockFollower::default(),
+        };
+        handler.refresh_ports(conf);
+        if handler.devices.is_empty() {
+            log::warn!("warning: no midi input device found");
+        }
+        handler
+    }
+
+    /// connects to the chosen ports that are available, and drops any that were removed
+    pub fn refresh_ports(&mut self, conf: &AppConfig) {
+        if let Some(ports) = self.port_scanner.latest() {
+            self.available_ports = ports;
+        }
+        let wanted = ports_to_connect(&self.available_ports, conf.midi_input_ports.as_deref());
+
+        self.devices
+            .retain(|d| wanted.contains(&d.input.get_device_name().to_string()));
+        if let Some((device_name, _)) = &self.learn {
+            if !wanted.contains(device_name) {
+                self.learn = None;
             }
-            None => log::warn!("warning: no midi input device found"),
         }
 
-        Self { midi_input }
+        for port in wanted {
+            if self
+                .devices
+                .iter()
+                .any(|d| d.input.get_device_name() == port)
+            {
+                continue;
+            }
+            let Some(mut input) = MidiInput::new(&port) else {
+                continue;
+            };
+            if let Err(e) = input.connect() {
+                log::warn!("unable to connect to midi device {:?}: {}", port, e);
+                continue;
+            }
+            let mapping = self
+                .mappings
+                .for_device(&port, &conf.midi_mapping_by_device);
+            log::info!(
+                "connected to midi device {:?} using {:?}",
+                port,
+                mapping.name
+            );
+            self.devices.push(MidiDevice {
+                input,
+                mapping,
+                timestamps: TimestampSync::default(),
+                controllers: HashMap::new(),
+            });
+        }
     }
 
-    pub fn refresh_connected_device(&mut self) {
-        let mut midi_input = MidiInput::new();
-        match midi_input {
-            Some(ref mut midi_input) => {
-                midi_input.connect();
-            }
-            None => log::warn!("warning: no midi input device found"),
+    /// names of the available midi input ports, and whether each is connected
+    pub fn ports(&self) -> Vec<(String, bool)> {
+        self.available_ports
+            .iter()
+            .map(|port| (port.clone(), self.device(port).is_some()))
+            .collect()
+    }
+
+    pub fn connected_device_names(&self) -> Vec<String> {
+        self.devices
+            .iter()
+            .map(|d| d.input.get_device_name().to_string())
+            .collect()
+    }
+
+    fn device(&self, device_name: &str) -> Option<&MidiDevice> {
+        self.devices
+            .iter()
+            .find(|d| d.input.get_device_name() == device_name)
+    }
+
+    fn device_mut(&mut self, device_name: &str) -> Option<&mut MidiDevice> {
+        self.devices
+            .iter_mut()
+            .find(|d| d.input.get_device_name() == device_name)
+    }
+
+    pub fn set_mapping(&mut self, device_name: &str, mapping_name: &str) {
+        let Some(mapping) = self.mappings.get(mapping_name).cloned() else {
+            log::warn!("unknown midi mapping: {}", mapping_name);
+            return;
+        };
+        if let Some(device) = self.device_mut(device_name) {
+            device.mapping = mapping;
         }
+    }
+
+    pub fn mapping(&self, device_name: &str) -> Option<&MidiMapping> {
+        Some(&self.device(device_name)?.mapping)
+    }
 
-        self.midi_input = midi_input;
+    pub fn mapping_names(&self) -> Vec<String> {
+        self.mappings.names()
     }
 
-    pub fn attached_device_name(&self) -> &str {
-        if let Some(midi_input) = &self.midi_input {
-            midi_input.get_device_name()
-        } else {
-            "none"
+    /// midi-learn starts from the device's current mapping
+    pub fn start_learn(&mut self, device_name: &str) {
+        if let Some(device) = self.device(device_name) {
+            let learn = MidiLearn::new(&device.mapping, device_name);
+            self.learn = Some((device_name.to_string(), learn));
         }
     }
 
+    pub fn skip_learn_instrument(&mut self) {
+        if let Some((_, learn)) = &mut self.learn {
+            learn.skip();
+        }
+    }
+
+    pub fn cancel_learn(&mut self) {
+        self.learn = None;
+    }
+
+    /// stops midi-learn, and uses the learned mapping for that device from now on.
+    /// returns the device name and its new mapping.
+    pub fn finish_learn(&mut self) -> Option<(String, MidiMapping)> {
+        let (device_name, learn) = self.learn.take()?;
+        self.mappings.insert(learn.mapping.clone());
+        if let Some(device) = self.device_mut(&device_name) {
+            device.mapping = learn.mapping.clone();
+        }
+        Some((device_name, learn.mapping))
+    }
+
+    pub fn learning_device(&self) -> Option<&str> {
+        self.learn
+            .as_ref()
+            .map(|(device_name, _)| device_name.as_str())
+    }
+
+    pub fn clock_source(&self) -> Option<&str> {
+        self.clock_source.as_deref()
+    }
+
+    /// the followed midi clock's tempo, once it's known
+    pub fn clock_source_bpm(&self) -> Option<f64> {
+        self.clock_follower.bpm()
+    }
+
+    /// the instrument midi-learn is waiting for, if learning
+    pub fn learn_target(&self) -> Option<Instrument> {
+        self.learn.as_ref()?.1.target()
+    }
+
     /// convert any user input from the last frame into Events
-    pub fn process(&mut self) -> Vec<Events> {
+    pub fn process(&mut self, conf: &AppConfig) -> Vec<Events> {
         let mut events: Vec<Events> = vec![];
 
-        // TODO(future): get the current clock time AND audio clock time at the start of a frame, and use that for all downstream calcs
-        let _now_ms = current_time_millis();
-        if let Some(midi_input) = &mut self.midi_input {
-            let hits = get_midi_as_user_hits(midi_input);
+        if let Some(ports) = self.port_scanner.latest() {
+            if ports != self.available_ports {
+                self.available_ports = ports;
+                self.refresh_ports(conf);
+            }
+        }
+        let now_us = current_time_micros();
+        if conf.midi_clock_source != self.clock_source {
+            self.clock_source = conf.midi_clock_source.clone();
+            self.clock_follower = MidiClockFollower::default();
+        }
+
+        for device in self.devices.iter_mut() {
+            let device_name = device.input.get_device_name().to_string();
+            let messages = device.input.take_messages();
+            for midi in messages.iter() {
+                device
+                    .timestamps
+                    .observe(midi.timestamp, midi.non_midi_timestamp_us);
+            }
 
-            // for each hit, calculate the processing delay and correct the clock time
-            for hit in &hits {
-                // let processing_delay_ms = now_ms - hit.clock_tick as u128;
-                //// TODO: needs work
-                let processing_delay_ms = 0;
-                events.push(Events::UserHit {
-                    instrument: hit.instrument,
-                    processing_delay: processing_delay_ms as f64 / 1000.,
-                })
+            if self.clock_source.as_deref() == Some(device_name.as_str()) {
+                events.extend(follow_midi_clock(
+                    &mut self.clock_follower,
+                    &messages,
+                    &device.timestamps,
+                    now_us,
+                ));
             }
 
-            // let processing_delay = now - ; // is this better called "input latency"?
-            // let corrected_clock_time = current_clock_time - processing_delay;
+            if let Some((learn_device, learn)) = &mut self.learn {
+                if *learn_device == device_name {
+                    // while learning, pad hits assign notes rather than being played
+                    let was_done = learn.is_done();
+                    for midi in messages.iter().filter(|m| m.is_note_on()) {
+                        learn.learn(midi.note_number);
+                    }
+                    if learn.is_done() && !was_done {
+                        events.push(Events::FinishMidiLearn);
+                    }
+                    continue;
+                }
+            }
 
-            midi_input.flush();
+            let input_latency_s = conf.input_latency_seconds(&device_name);
+
+            // each hit happened some time before this frame: when the device sent it, less its input latency
+            for (instrument, midi) in
+                hits_from_messages(&messages, &device.mapping, &mut device.controllers)
+            {
+                let sent_us = device
+                    .timestamps
+                    .wall_time_us(midi.timestamp, midi.non_midi_timestamp_us);
+                let processing_delay_s = now_us.saturating_sub(sent_us) as f64 / 1_000_000.;
+                events.push(Events::UserHit {
+                    instrument,
+                    processing_delay: processing_delay_s + input_latency_s,
+                    velocity: Some(midi.note_velocity),
+                    device: device_name.clone(),
+                })
+            }
         }
 
         events
     }
 }
 
-struct InputConfigMidi {
-    kick: HashSet<u8>,
-    snare: HashSet<u8>,
-    closed_hi_hat: HashSet<u8>,
-    open_hi_hat: HashSet<u8>,
-    ride: HashSet<u8>,
-    crash: HashSet<u8>,
-    tom_1: HashSet<u8>,
-    tom_2: HashSet<u8>,
-    tom_3: HashSet<u8>,
-    pedal_hihat: HashSet<u8>,
+/// the ports to connect to: the ones the user chose, or the first available port if they haven't chosen yet
+fn ports_to_connect(available: &[String], chosen: Option<&[String]>) -> Vec<String> {
+    match chosen {
+        Some(chosen) => available
+            .iter()
+            .filter(|port| chosen.contains(port))
+            .cloned()
+            .collect(),
+        None => available.iter().take(1).cloned().collect(),
+    }
 }
 
-impl InputConfigMidi {
-    pub fn get_note_numbers(&self, ins: &Instrument) -> &HashSet<u8> {
-        match ins {
-            Instrument::ClosedHihat => &self.closed_hi_hat,
-            Instrument::Snare => &self.snare,
-            Instrument::Kick => &self.kick,
-            Instrument::OpenHihat => &self.open_hi_hat,
-            Instrument::Ride => &self.ride,
-            Instrument::Crash => &self.crash,
-            Instrument::Tom1 => &self.tom_1,
-            Instrument::Tom2 => &self.tom_2,
-            Instrument::Tom3 => &self.tom_3,
-            Instrument::PedalHiHat => &self.pedal_hihat,
+/// transport changes, in order, then where the latest clock pulse puts the song
+fn follow_midi_clock(
+    follower: &mut MidiClockFollower,
+    messages: &[MidiInputDataRaw],
+    timestamps: &TimestampSync,
+    now_us: u128,
+) -> Vec<Events> {
+    let mut events = vec![];
+    let mut pulsed = false;
+    for midi in messages {
+        let sent_us = timestamps.wall_time_us(midi.timestamp, midi.non_midi_timestamp_us);
+        pulsed |= midi.status() == CLOCK;
+        if let Some(transport) = follower.on_message(midi, sent_us) {
+            events.push(Events::MidiTransport(transport));
         }
     }
+
+    if let Some((position_ticks, sent_us)) = follower.last_pulse_ticks() {
+        if pulsed && follower.is_running() {
+            events.push(Events::SyncToMidiClock {
+                bpm: follower.bpm(),
+                position_ticks,
+                processing_delay: now_us.saturating_sub(sent_us) as f64 / 1_000_000.,
+            });
+        }
+    }
+    events
 }
 
-fn get_midi_as_user_hits(midi_input: &MidiInput) -> Vec<UserHit> {
-    let mut out: Vec<UserHit> = vec![];
-
-    // midi device: "MPK Mini Mk II"
-    let mpk_mini_mk_ii = InputConfigMidi {
-        closed_hi_hat: HashSet::from_iter(vec![44, 48]),
-        snare: HashSet::from_iter(vec![45, 49]),
-        kick: HashSet::from_iter(vec![46, 50]),
-        open_hi_hat: HashSet::from_iter(vec![47, 51]),
-        ride: HashSet::from_iter(vec![]),
-        crash: HashSet::from_iter(vec![]),
-        tom_1: HashSet::from_iter(vec![]),
-        tom_2: HashSet::from_iter(vec![]),
-        tom_3: HashSet::from_iter(vec![]),
-        pedal_hihat: HashSet::from_iter(vec![]),
-    };
+/// every hit on an instrument, in the order they were received.
+/// repeated strokes on the same pad (flams, rolls) are each their own hit.
+/// controller values are updated as they're seen, so each hit uses the pedal position at the time.
+fn hits_from_messages(
+    messages: &[MidiInputDataRaw],
+    mapping: &MidiMapping,
+    controllers: &mut HashMap<u8, u8>,
+) -> Vec<(Instrument, MidiInputDataRaw)> {
+    let mut out = vec![];
+    for midi in messages {
+        if midi.is_control_change() {
+            controllers.insert(midi.note_number, midi.note_velocity);
+            if let Some(ins) =
+                mapping.instrument_for_control_change(midi.note_number, midi.note_velocity)
+            {
+                out.push((ins, *midi));
+            }
+        } else if midi.is_note_on() {
+            for ins in mapping.instruments_for_hit(midi.note_number, controllers) {
+                out.push((ins, *midi));
+            }
+        }
+    }
+    out
+}
 
-    // https://support.roland.com/hc/en-us/articles/360005173411-TD-17-Default-Factory-MIDI-Note-Map
-    let td17 = InputConfigMidi {
-        closed_hi_hat: HashSet::from_iter(vec![42, 22]),
-        snare: HashSet::from_iter(vec![38, 40, 37]),
-        kick: HashSet::from_iter(vec![36]),
-        open_hi_hat: HashSet::from_iter(vec![46, 26]),
-        ride: HashSet::from_iter(vec![51, 53, 59]),
-        crash: HashSet::from_iter(vec![49, 55, 57, 52]),
-        tom_1: HashSet::from_iter(vec![50, 48]),
-        tom_2: HashSet::from_iter(vec![47, 45]),
-        tom_3: HashSet::from_iter(vec![58, 43]),
-        pedal_hihat: HashSet::from_iter(vec![44]),
-    };
+#[cfg(test)]
+mod tests {
+    use std::collections::HashMap;
 
-    // https://support.roland.com/hc/en-us/articles/4407474950811-TD-27-Default-MIDI-Note-Map
-    let td27 = InputConfigMidi {
-        closed_hi_hat: HashSet::from_iter(vec![42, 22]),
-        snare: HashSet::from_iter(vec![38, 40, 37]),
-        kick: HashSet::from_iter(vec![36]),
-        open_hi_hat: HashSet::from_iter(vec![46, 26]),
-        ride: HashSet::from_iter(vec![51, 53, 59]),
-        crash: HashSet::from_iter(vec![49, 55, 57, 52]),
-        tom_1: HashSet::from_iter(vec![50, 48]),
-        tom_2: HashSet::from_iter(vec![47, 45]),
-        tom_3: HashSet::from_iter(vec![58, 43]),
-        pedal_hihat: HashSet::from_iter(vec![44]),
+    use crate::{
+        midi::MidiInputDataRaw,
+        midi_input_handler::{hits_from_messages, ports_to_connect},
+        midi_mapping::{HiHatPedal, MidiMapping},
+        voices::Instrument,
     };
 
-    let alesis_nitro = InputConfigMidi {
-        closed_hi_hat: HashSet::from_iter(vec![42]),
-        snare: HashSet::from_iter(vec![38]),
-        kick: HashSet::from_iter(vec![36]),
-        open_hi_hat: HashSet::from_iter(vec![46, 23]),
-        ride: HashSet::from_iter(vec![]),
-        crash: HashSet::from_iter(vec![]),
-        tom_1: HashSet::from_iter(vec![]),
-        tom_2: HashSet::from_iter(vec![]),
-        tom_3: HashSet::from_iter(vec![]),
-        pedal_hihat: HashSet::from_iter(vec![]),
-    };
+    #[test]
+    fn it_connects_to_chosen_ports_that_are_available() {
+        let available = vec![
+            "USB Keyboard".to_string(),
+            "TD-27".to_string(),
+            "Nitro".to_string(),
+        ];
 
-    let ic_midi = match midi_input.get_device_name() {
-        "MPK Mini Mk II" => mpk_mini_mk_ii,
-        s if s.contains("TD-17") => td17,
-        s if s.contains("TD-27") => td27,
-        s if s.contains("Nitro") => alesis_nitro,
-        _ => {
-            log::warn!("warning: unknown midi device, using default of 'td27'");
-            td27
-        }
-    };
+        assert_eq!(ports_to_connect(&available, None), vec!["USB Keyboard"]);
+        assert!(ports_to_connect(&[], None).is_empty());
 
-    let pressed_midi = midi_input.get_pressed_buttons();
+        let chosen = vec![
+            "Nitro".to_string(),
+            "TD-27".to_string(),
+            "Unplugged".to_string(),
+        ];
+        assert_eq!(
+            ports_to_connect(&available, Some(&chosen)),
+            vec!["TD-27", "Nitro"]
+        );
+        assert!(ports_to_connect(&available, Some(&[])).is_empty());
+    }
 
-    // for each pressed_midi, check if it's in the ic_midi and then add to out as a proper UserHit if so
-    for midi in pressed_midi {
-        log::debug!("midi: {:?}", midi); // TODO: compare timestamps
-        let timestamp = midi.timestamp as f64;
-        for ins in ALL_INSTRUMENTS.iter() {
-            if ic_midi.get_note_numbers(ins).contains(&midi.note_number) {
-                out.push(UserHit::new(*ins, timestamp));
-            }
-        }
+    #[test]
+    fn it_keeps_every_stroke_of_fast_repeated_hits() {
+        let mut mapping = MidiMapping::new("kit");
+        mapping.notes.insert(Instrument::Snare, vec![38]);
+        mapping.notes.insert(Instrument::Kick, vec![36]);
+
+        let messages = vec![
+            // a flam on the snare
+            MidiInputDataRaw::new(&[153, 38, 40], 1_000, 0),
+            MidiInputDataRaw::new(&[153, 38, 110], 16_000, 0),
+            // note offs, in both forms
+            MidiInputDataRaw::new(&[137, 38, 0], 20_000, 0),
+            MidiInputDataRaw::new(&[153, 38, 0], 21_000, 0),
+            // a fast double on the kick, and an unmapped note between
+            MidiInputDataRaw::new(&[153, 36, 90], 30_000, 0),
+            MidiInputDataRaw::new(&[153, 99, 90], 32_000, 0),
+            MidiInputDataRaw::new(&[153, 36, 95], 40_000, 0),
+        ];
+
+        let hits: Vec<(Instrument, u64, u8)> =
+            hits_from_messages(&messages, &mapping, &mut HashMap::new())
+                .iter()
+                .map(|(ins, midi)| (*ins, midi.timestamp, midi.note_velocity))
+                .collect();
+        assert_eq!(
+            hits,
+            vec![
+                (Instrument::Snare, 1_000, 40),
+                (Instrument::Snare, 16_000, 110),
+                (Instrument::Kick, 30_000, 90),
+                (Instrument::Kick, 40_000, 95),
+            ]
+        );
     }
 
-    out
+    #[test]
+    fn it_uses_the_pedal_position_at_the_time_of_each_hit() {
+        let mut mapping = MidiMapping::new("kit");
+        mapping.notes.insert(Instrument::OpenHihat, vec![46]);
+        mapping.hihat_pedal = Some(HiHatPedal {
+            controller: 4,
+            closed_min: 90,
+            open_max: 30,
+            half_open: Instrument::OpenHihat,
+            foot_splash: None,
+        });
+
+        let messages = vec![
+            MidiInputDataRaw::new(&[185, 4, 127], 1_000, 0),
+            MidiInputDataRaw::new(&[153, 46, 80], 2_000, 0),
+            // the pedal opens between two hits in the same frame
+            MidiInputDataRaw::new(&[185, 4, 0], 3_000, 0),
+            MidiInputDataRaw::new(&[153, 46, 80], 4_000, 0),
+        ];
+        let mut controllers = HashMap::new();
+        let hits: Vec<Instrument> = hits_from_messages(&messages, &mapping, &mut controllers)
+            .iter()
+            .map(|(ins, _)| *ins)
+            .collect();
+        assert_eq!(hits, vec![Instrument::ClosedHihat, Instrument::OpenHihat]);
+        assert_eq!(controllers.get(&4), Some(&0));
+    }
 }
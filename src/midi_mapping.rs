@@ -0,0 +1,422 @@
+/*
+  Which midi notes trigger which instrument, for each kind of midi device.
+
+  Presets for common kits ship with the app (assets/midi_mappings). User mappings, e.g. made with midi-learn,
+  are saved as files in the config directory and replace a preset with the same name.
+
+  Electronic kits report how far the hi-hat pedal is pressed as a control change (CC), rather than
+  sending different notes. A mapping can say which controller that is, so hi-hat hits are counted as
+  open or closed based on where the pedal was at the time of the hit.
+*/
+
+use std::{
+    collections::{BTreeMap, HashMap},
+    error::Error,
+    fs,
+    path::{Path, PathBuf},
+};
+
+use include_dir::{include_dir, Dir};
+use serde::{Deserialize, Serialize};
+
+use crate::{config::config_dir, consts::ALL_INSTRUMENTS, voices::Instrument};
+
+const PRESETS_DIR: Dir = include_dir!("./assets/midi_mappings");
+
+/// used for devices that don't match any mapping
+const DEFAULT_MAPPING_NAME: &str = "Roland TD-27";
+
+#[derive(Debug, PartialEq, Clone, Serialize, Deserialize)]
+pub struct MidiMapping {
+    pub name: String,
+    /// the mapping is picked for devices whose name contains any of these
+    #[serde(default)]
+    pub device_match: Vec<String>,
+    pub notes: BTreeMap<Instrument, Vec<u8>>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub hihat_pedal: Option<HiHatPedal>,
+}
+
+/// How far open the hi-hat is
+#[derive(Debug, PartialEq, Clone, Copy)]
+pub enum HiHatOpenness {
+    Closed,
+    HalfOpen,
+    Open,
+}
+
+/// The hi-hat pedal, as reported by a controller. Higher values mean the pedal is pressed further (more closed).
+#[derive(Debug, PartialEq, Clone, Copy, Serialize, Deserialize)]
+pub struct HiHatPedal {
+    pub controller: u8,
+    /// at or above this, the hi-hat is closed
+    pub closed_min: u8,
+    /// at or below this, the hi-hat is open. in between, it's half-open.
+    pub open_max: u8,
+    /// half-open hits are counted as this instrument
+    #[serde(default = "default_half_open_instrument")]
+    pub half_open: Instrument,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub foot_splash: Option<FootSplash>,
+}
+
+fn default_half_open_instrument() -> Instrument {
+    Instrument::OpenHihat
+}
+
+/// Kits that detect a foot splash (the pedal closed and quickly released) report it as a controller.
+/// It's counted as an open hi-hat.
+#[derive(Debug, PartialEq, Clone, Copy, Serialize, Deserialize)]
+pub struct FootSplash {
+    pub controller: u8,
+    /// values below this are ignored
+    pub min_value: u8,
+}
+
+impl HiHatPedal {
+    pub fn openness(&self, value: u8) -> HiHatOpenness {
+        if value >= self.closed_min {
+            HiHatOpenness::Closed
+        } else if value <= self.open_max {
+            HiHatOpenness::Open
+        } else {
+            HiHatOpenness::HalfOpen
+        }
+    }
+
+    fn instrument(&self, value: u8) -> Instrument {
+        match self.openness(value) {
+            HiHatOpenness::Closed => Instrument::ClosedHihat,
+            HiHatOpenness::HalfOpen => self.half_open,
+            HiHatOpenness::Open => Instrument::OpenHihat,
+        }
+    }
+}
+
+impl MidiMapping {
+    pub fn new(name: &str) -> Self {
+        Self {
+            name: name.to_string(),
+            device_match: vec![],
+            notes: BTreeMap::new(),
+            hihat_pedal: None,
+        }
+    }
+
+    /// the instruments a note triggers, given the latest value of each controller.
+    /// a hi-hat note is open or closed depending on the pedal, once the pedal has reported its position.
+    pub fn instruments_for_hit(&self, note: u8, controllers: &HashMap<u8, u8>) -> Vec<Instrument> {
+        let instruments = self.instruments_for_note(note);
+        let pedal_value = self
+            .hihat_pedal
+            .and_then(|pedal| Some((pedal, *controllers.get(&pedal.controller)?)));
+        let Some((pedal, value)) = pedal_value else {
+            return instruments;
+        };
+
+        let is_hihat =
+            |ins: &Instrument| matches!(ins, Instrument::ClosedHihat | Instrument::OpenHihat);
+        let mut out: Vec<Instrument> = instruments.into_iter().filter(|i| !is_hihat(i)).collect();
+        if self.instruments_for_note(note).iter().any(is_hihat) {
+            out.push(pedal.instrument(value));
+        }
+        out
+    }
+
+    /// the instrument a control change triggers, if it's a foot splash
+    pub fn instrument_for_control_change(&self, controller: u8, value: u8) -> Option<Instrument> {
+        let splash = self.hihat_pedal?.foot_splash?;
+        (splash.controller == controller && value >= splash.min_value)
+            .then_some(Instrument::OpenHihat)
+    }
+
+    pub fn instruments_for_note(&self, note: u8) -> Vec<Instrument> {
+        ALL_INSTRUMENTS
+            .iter()
+            .filter(|ins| self.get_notes(ins).contains(&note))
+            .copied()
+            .collect()
+    }
+
+    pub fn get_notes(&self, ins: &Instrument) -> &[u8] {
+        self.notes.get(ins).map_or(&[], |notes| notes.as_slice())
+    }
+
+    /// the note now triggers only this instrument, and this instrument is triggered only by the note
+    pub fn assign(&mut self, ins: Instrument, note: u8) {
+        for notes in self.notes.values_mut() {
+            notes.retain(|n| *n != note);
+        }
+        self.notes.insert(ins, vec![note]);
+        self.notes.retain(|_, notes| !notes.is_empty());
+    }
+
+    fn matches_device(&self, device_name: &str) -> bool {
+        self.device_match.iter().any(|m| device_name.contains(m))
+    }
+}
+
+/// All known mappings: the shipped presets, plus the user's own
+#[derive(Debug, Clone)]
+pub struct MidiMappings {
+    mappings: Vec<MidiMapping>,
+}
+
+impl MidiMappings {
+    pub fn load() -> Self {
+        let mut mappings = Self::presets();
+        if let Some(dir) = user_mappings_dir() {
+            for mapping in read_user_mappings(&dir) {
+                mappings.insert(mapping);
+            }
+        }
+        mappings
+    }
+
+    fn presets() -> Self {
+        let mut mappings = vec![];
+        for file in PRESETS_DIR.files() {
+            match serde_json::from_slice::<MidiMapping>(file.contents()) {
+                Ok(mapping) => mappings.push(mapping),
+                Err(e) => log::error!("invalid midi mapping preset {:?}: {}", file.path(), e),
+            }
+        }
+        mappings.sort_by(|a, b| a.name.cmp(&b.name));
+        Self { mappings }
+    }
+
+    /// adds a mapping, replacing any with the same name
+    pub fn insert(&mut self, mapping: MidiMapping) {
+        match self.mappings.iter_mut().find(|m| m.name == mapping.name) {
+            Some(existing) => *existing = mapping,
+            None => self.mappings.push(mapping),
+        }
+    }
+
+    pub fn names(&self) -> Vec<String> {
+        self.mappings.iter().map(|m| m.name.clone()).collect()
+    }
+
+    pub fn get(&self, name: &str) -> Option<&MidiMapping> {
+        self.mappings.iter().find(|m| m.name == name)
+    }
+
+    /// the mapping chosen for this device, otherwise the first that matches its name, otherwise a default
+    pub fn for_device(&self, device_name: &str, chosen: &HashMap<String, String>) -> MidiMapping {
+        if let Some(mapping) = chosen.get(device_name).and_then(|name| self.get(name)) {
+            return mapping.clone();
+        }
+        if let Some(mapping) = self.mappings.iter().find(|m| m.matches_device(device_name)) {
+            return mapping.clone();
+        }
+        log::warn!(
+            "unknown midi device {:?}, using the {:?} mapping",
+            device_name,
+            DEFAULT_MAPPING_NAME
+        );
+        self.get(DEFAULT_MAPPING_NAME)
+            .cloned()
+            .unwrap_or_else(|| MidiMapping::new(DEFAULT_MAPPING_NAME))
+    }
+}
+
+fn user_mappings_dir() -> Option<PathBuf> {
+    config_dir().map(|dir| dir.join("midi_mappings"))
+}
+
+fn read_user_mappings(dir: &Path) -> Vec<MidiMapping> {
+    let Ok(entries) = fs::read_dir(dir) else {
+        return vec![];
+    };
+    let mut mappings = vec![];
+    for path in entries.filter_map(|e| e.ok()).map(|e| e.path()) {
+        if !path.extension().is_some_and(|ext| ext == "json") {
+            continue;
+        }
+        let mapping = fs::read_to_string(&path)
+            .map_err(|e| e.to_string())
+            .and_then(|data| serde_json::from_str(&data).map_err(|e| e.to_string()));
+        match mapping {
+            Ok(mapping) => mappings.push(mapping),
+            Err(e) => log::warn!("skipping midi mapping {:?}: {}", path, e),
+        }
+    }
+    mappings
+}
+
+/// saves a mapping to the user's mappings, so it's available next time
+pub fn save_user_mapping(mapping: &MidiMapping) -> Result<(), Box<dyn Error>> {
+    let dir = user_mappings_dir().ok_or("no config directory for midi mappings")?;
+    fs::create_dir_all(&dir)?;
+    let file_name: String = mapping
+        .name
+        .chars()
+        .map(|c| if c.is_ascii_alphanumeric() { c } else { '_' })
+        .collect();
+    let path = dir.join(format!("{}.json", file_name));
+    fs::write(&path, serde_json::to_string_pretty(mapping)?)?;
+    log::info!("saved midi mapping to {:?}", path);
+    Ok(())
+}
+
+/// Midi-learn: the user hits each pad in turn, while the matching instrument is highlighted
+#[derive(Debug, Clone)]
+pub struct MidiLearn {
+    pub mapping: MidiMapping,
+    next_idx: usize,
+}
+
+impl MidiLearn {
+    /// starts from an existing mapping, so instruments that are skipped keep their notes
+    pub fn new(from: &MidiMapping, device_name: &str) -> Self {
+        let mut mapping = from.clone();
+        mapping.name = format!("{} (custom)", device_name);
+        mapping.device_match = vec![device_name.to_string()];
+        Self {
+            mapping,
+            next_idx: 0,
+        }
+    }
+
+    /// the instrument waiting for a pad hit, or None when every instrument has been learned
+    pub fn target(&self) -> Option<Instrument> {
+        ALL_INSTRUMENTS.get(self.next_idx).copied()
+    }
+
+    pub fn learn(&mut self, note: u8) {
+        if let Some(ins) = self.target() {
+            self.mapping.assign(ins, note);
+            self.skip();
+        }
+    }
+
+    pub fn skip(&mut self) {
+        self.next_idx += 1;
+    }
+
+    pub fn is_done(&self) -> bool {
+        self.target().is_none()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::collections::HashMap;
+
+    use crate::{
+        consts::ALL_INSTRUMENTS,
+        midi_mapping::{FootSplash, HiHatOpenness, MidiLearn, MidiMapping, MidiMappings},
+        voices::Instrument,
+    };
+
+    #[test]
+    fn it_loads_shipped_presets() {
+        let mappings = MidiMappings::presets();
+        assert_eq!(
+            mappings.names(),
+            vec![
+                "Alesis Nitro",
+                "MPK Mini Mk II",
+                "Roland TD-17",
+                "Roland TD-27"
+            ]
+        );
+        let td27 = mappings.get("Roland TD-27").unwrap();
+        assert_eq!(td27.instruments_for_note(38), vec![Instrument::Snare]);
+        assert_eq!(td27.get_notes(&Instrument::PedalHiHat), &[44]);
+        assert!(td27.instruments_for_note(0).is_empty());
+    }
+
+    #[test]
+    fn it_picks_a_mapping_for_each_device() {
+        let mappings = MidiMappings::presets();
+        let no_choices = HashMap::new();
+        assert_eq!(
+            mappings.for_device("TD-17 MIDI 1", &no_choices).name,
+            "Roland TD-17"
+        );
+        assert_eq!(
+            mappings.for_device("Some Other Kit", &no_choices).name,
+            "Roland TD-27"
+        );
+
+        let chosen = HashMap::from([("TD-17 MIDI 1".to_string(), "Alesis Nitro".to_string())]);
+        assert_eq!(
+            mappings.for_device("TD-17 MIDI 1", &chosen).name,
+            "Alesis Nitro"
+        );
+    }
+
+    #[test]
+    fn it_learns_a_note_for_each_instrument() {
+        let mut from = MidiMapping::new("kit");
+        from.notes.insert(Instrument::Kick, vec![60]);
+        from.notes.insert(Instrument::Ride, vec![51]);
+
+        let mut learn = MidiLearn::new(&from, "My Kit");
+        assert_eq!(learn.target(), Some(ALL_INSTRUMENTS[0]));
+        for (i, _) in ALL_INSTRUMENTS.iter().enumerate() {
+            match ALL_INSTRUMENTS[i] {
+                Instrument::Ride => learn.skip(),
+                // the kick pad's note was previously assigned to the ride
+                Instrument::Kick => learn.learn(51),
+                _ => learn.learn(70 + i as u8),
+            }
+        }
+        assert!(learn.is_done());
+
+        let mapping = learn.mapping;
+        assert_eq!(mapping.name, "My Kit (custom)");
+        assert_eq!(mapping.get_notes(&Instrument::Kick), &[51]);
+        assert!(mapping.get_notes(&Instrument::Ride).is_empty());
+        assert_eq!(mapping.instruments_for_note(60), vec![]);
+        assert_eq!(mapping.instruments_for_note(70), vec![ALL_INSTRUMENTS[0]]);
+    }
+
+    #[test]
+    fn it_opens_and_closes_the_hihat_from_the_pedal() {
+        let mut td27 = MidiMappings::presets().get("Roland TD-27").unwrap().clone();
+        let pedal = td27.hihat_pedal.unwrap();
+        assert_eq!(pedal.openness(127), HiHatOpenness::Closed);
+        assert_eq!(pedal.openness(60), HiHatOpenness::HalfOpen);
+        assert_eq!(pedal.openness(0), HiHatOpenness::Open);
+
+        // before the pedal reports, notes are used as-is
+        let mut controllers = HashMap::new();
+        assert_eq!(
+            td27.instruments_for_hit(46, &controllers),
+            vec![Instrument::OpenHihat]
+        );
+
+        controllers.insert(4, 127);
+        assert_eq!(
+            td27.instruments_for_hit(46, &controllers),
+            vec![Instrument::ClosedHihat]
+        );
+        assert_eq!(
+            td27.instruments_for_hit(38, &controllers),
+            vec![Instrument::Snare]
+        );
+        controllers.insert(4, 60);
+        assert_eq!(
+            td27.instruments_for_hit(42, &controllers),
+            vec![pedal.half_open]
+        );
+        controllers.insert(4, 0);
+        assert_eq!(
+            td27.instruments_for_hit(42, &controllers),
+            vec![Instrument::OpenHihat]
+        );
+
+        assert_eq!(td27.instrument_for_control_change(4, 100), None);
+        td27.hihat_pedal.as_mut().unwrap().foot_splash = Some(FootSplash {
+            controller: 16,
+            min_value: 10,
+        });
+        assert_eq!(td27.instrument_for_control_change(16, 5), None);
+        assert_eq!(
+            td27.instrument_for_control_change(16, 80),
+            Some(Instrument::OpenHihat)
+        );
+    }
+}
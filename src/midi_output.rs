@@ -0,0 +1,261 @@
+/*
+ Send the loop as midi notes, e.g. to play it through a drum module's own sounds or a DAW's drum plugin.
+
+ Notes go out on channel 10 (general midi drums). Midi has no scheduling of its own, so each note is queued
+ with the wall time it's due, and a background thread sends it when that time comes.
+*/
+
+use std::{
+    cmp::Reverse,
+    collections::BinaryHeap,
+    error::Error,
+    sync::mpsc::{self, Receiver, RecvTimeoutError, Sender},
+    thread,
+    time::Duration,
+};
+
+use serde::{Deserialize, Serialize};
+
+use crate::{
+    time::current_time_micros,
+    voices::{Dynamic, Instrument},
+};
+
+/// the name other apps see for the virtual port
+pub const VIRTUAL_PORT_NAME: &str = "drum-break";
+
+/// virtual ports are only available with ALSA and CoreMIDI
+pub const VIRTUAL_PORT_SUPPORTED: bool = cfg!(unix);
+
+// note on / note off, on channel 10
+const NOTE_ON: u8 = 0x99;
+const NOTE_OFF: u8 = 0x89;
+
+/// how long each note is held before its note off
+const NOTE_LENGTH_US: u128 = 50_000;
+
+/// how long the sending thread sleeps when nothing is queued
+const IDLE_WAIT: Duration = Duration::from_millis(100);
+
+/// Where midi notes are sent
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub enum MidiOutputTarget {
+    /// an existing output port, by name
+    Port(String),
+    /// a port that other apps (e.g. a DAW) can connect to
+    Virtual,
+}
+
+impl MidiOutputTarget {
+    pub fn name(&self) -> &str {
+        match self {
+            MidiOutputTarget::Port(name) => name,
+            MidiOutputTarget::Virtual => VIRTUAL_PORT_NAME,
+        }
+    }
+}
+
+/// general midi drum note for each instrument
+pub fn gm_note(ins: &Instrument) -> u8 {
+    match ins {
+        Instrument::Kick => 36,
+        Instrument::Snare => 38,
+        Instrument::ClosedHihat => 42,
+        Instrument::PedalHiHat => 44,
+        Instrument::OpenHihat => 46,
+        Instrument::Tom3 => 43,
+        Instrument::Tom2 => 47,
+        Instrument::Tom1 => 50,
+        Instrument::Crash => 49,
+        Instrument::Ride => 51,
+    }
+}
+
+pub fn velocity(dynamic: Dynamic) -> u8 {
+    match dynamic {
+        Dynamic::Ghost => 40,
+        Dynamic::Normal => 90,
+        Dynamic::Accent => 127,
+    }
+}
+
+/// names of the midi output ports that are currently available
+pub fn list_output_ports() -> Vec<String> {
+    let Ok(midi_output) = midir::MidiOutput::new("drum-break") else {
+        return vec![];
+    };
+    midi_output
+        .ports()
+        .iter()
+        .filter_map(|port| midi_output.port_name(port).ok())
+        .collect()
+}
+
+enum Command {
+    Send { due_us: u128, message: Vec<u8> },
+    Clear,
+}
+
+pub struct MidiOutput {
+    target: MidiOutputTarget,
+    tx: Sender<Command>,
+}
+
+impl MidiOutput {
+    pub fn connect(target: &MidiOutputTarget) -> Result<Self, Box<dyn Error>> {
+        let connection = open_connection(target)?;
+        let (tx, rx) = mpsc::channel();
+        thread::spawn(move || run_sender(connection, rx));
+        log::info!("sending midi to {:?}", target.name());
+        Ok(Self {
+            target: target.clone(),
+            tx,
+        })
+    }
+
+    pub fn target(&self) -> &MidiOutputTarget {
+        &self.target
+    }
+
+    /// queues a note (and its note off) to be sent at the given wall time
+    pub fn send_note(&self, ins: &Instrument, velocity: u8, due_us: u128) {
+        let note = gm_note(ins);
+        self.send_at(due_us, vec![NOTE_ON, note, velocity]);
+        self.send_at(due_us + NOTE_LENGTH_US, vec![NOTE_OFF, note, 0]);
+    }
+
+    /// queues a message to be sent at the given wall time
+    pub fn send_at(&self, due_us: u128, message: Vec<u8>) {
+        // the thread only stops if the connection broke, which has already been logged
+        let _ = self.tx.send(Command::Send { due_us, message });
+    }
+
+    pub fn send_now(&self, message: Vec<u8>) {
+        self.send_at(0, message);
+    }
+
+    /// drops any notes that haven't been sent yet, e.g. on pause
+    pub fn clear(&self) {
+        let _ = self.tx.send(Command::Clear);
+    }
+}
+
+fn open_connection(
+    target: &MidiOutputTarget,
+) -> Result<midir::MidiOutputConnection, Box<dyn Error>> {
+    let midi_output = midir::MidiOutput::new("drum-break")?;
+    match target {
+        MidiOutputTarget::Port(port_name) => {
+            let port = midi_output
+                .ports()
+                .into_iter()
+                .find(|port| midi_output.port_name(port).is_ok_and(|n| n == *port_name))
+                .ok_or(format!("midi output port not found: {}", port_name))?;
+            Ok(midi_output
+                .connect(&port, "drum-break")
+                .map_err(|e| format!("can't connect to midi output: {e}"))?)
+        }
+        #[cfg(unix)]
+        MidiOutputTarget::Virtual => {
+            use midir::os::unix::VirtualOutput;
+            Ok(midi_output
+                .create_virtual(VIRTUAL_PORT_NAME)
+                .map_err(|e| format!("can't create virtual midi output: {e}"))?)
+        }
+        #[cfg(not(unix))]
+        MidiOutputTarget::Virtual => {
+            Err("virtual midi ports aren't supported on this platform".into())
+        }
+    }
+}
+
+/// sends each queued message when it's due, until the MidiOutput is dropped
+fn run_sender(mut connection: midir::MidiOutputConnection, rx: Receiver<Command>) {
+    let mut queue = MessageQueue::default();
+    loop {
+        let wait = match queue.next_due() {
+            Some(due_us) => {
+                Duration::from_micros(due_us.saturating_sub(current_time_micros()) as u64)
+            }
+            None => IDLE_WAIT,
+        };
+        match rx.recv_timeout(wait) {
+            Ok(Command::Send { due_us, message }) => queue.push(due_us, message),
+            Ok(Command::Clear) => queue.clear(),
+            Err(RecvTimeoutError::Timeout) => {}
+            Err(RecvTimeoutError::Disconnected) => break,
+        }
+
+        for message in queue.pop_due(current_time_micros()) {
+            if let Err(e) = connection.send(&message) {
+                log::warn!("unable to send midi: {}", e);
+            }
+        }
+    }
+    // don't leave any notes hanging
+    for message in queue.pop_due(u128::MAX) {
+        let _ = connection.send(&message);
+    }
+}
+
+/// Messages waiting to be sent, soonest first
+#[derive(Default)]
+struct MessageQueue {
+    // the sequence number keeps messages that are due at the same time in the order they were queued
+    pending: BinaryHeap<Reverse<(u128, u64, Vec<u8>)>>,
+    next_seq: u64,
+}
+
+impl MessageQueue {
+    fn push(&mut self, due_us: u128, message: Vec<u8>) {
+        self.pending.push(Reverse((due_us, self.next_seq, message)));
+        self.next_seq += 1;
+    }
+
+    fn next_due(&self) -> Option<u128> {
+        self.pending.peek().map(|Reverse((due_us, _, _))| *due_us)
+    }
+
+    fn pop_due(&mut self, now_us: u128) -> Vec<Vec<u8>> {
+        let mut out = vec![];
+        while self.next_due().is_some_and(|due_us| due_us <= now_us) {
+            if let Some(Reverse((_, _, message))) = self.pending.pop() {
+                out.push(message);
+            }
+        }
+        out
+    }
+
+    /// drops the messages that haven't been sent. note offs are kept, so no note is left hanging.
+    fn clear(&mut self) {
+        self.pending
+            .retain(|Reverse((_, _, message))| message[0] == NOTE_OFF);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::midi_output::{MessageQueue, NOTE_OFF, NOTE_ON};
+
+    #[test]
+    fn it_sends_queued_notes_when_due() {
+        let mut queue = MessageQueue::default();
+        queue.push(3_000, vec![NOTE_ON, 38, 90]);
+        queue.push(1_000, vec![NOTE_ON, 36, 90]);
+        queue.push(1_000, vec![NOTE_ON, 42, 40]);
+        queue.push(2_000, vec![NOTE_OFF, 36, 0]);
+        queue.push(2_500, vec![0xF8]);
+        assert_eq!(queue.next_due(), Some(1_000));
+
+        assert!(queue.pop_due(999).is_empty());
+        assert_eq!(
+            queue.pop_due(1_500),
+            vec![vec![NOTE_ON, 36, 90], vec![NOTE_ON, 42, 40]]
+        );
+
+        // the snare and clock pulse haven't been sent, so they're dropped. the kick's note off is still sent.
+        queue.clear();
+        assert_eq!(queue.pop_due(u128::MAX), vec![vec![NOTE_OFF, 36, 0]]);
+        assert_eq!(queue.next_due(), None);
+    }
+}
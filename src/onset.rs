@@ -0,0 +1,393 @@
+/*
+  Find drum hits (onsets) in an audio stream, e.g. from a microphone in front of an acoustic kit.
+
+  The signal is measured in short hops. A hit is a hop that's much louder than the background noise, and than
+  the envelope of the sound before it. The envelope holds each peak and fades slowly, so the ringing of a low
+  drum isn't mistaken for new hits. The hit's exact sample is then found by looking back for where its
+  attack begins.
+
+  Recordings (e.g. the bundled drum samples) can be fed through the detector in tests, as if from a microphone.
+
+  The audio just after each hit is kept to describe its sound: how its energy is spread across frequency bands.
+  That's independent of how hard the drum was hit, so hits can be told apart (see kit_profile.rs).
+*/
+
+use std::{collections::VecDeque, f64::consts::PI};
+
+/// samples per hop. ~3ms at 44.1kHz
+const HOP: usize = 128;
+
+/// samples after an onset that describe its sound. ~12ms at 44.1kHz
+const FEATURE_WINDOW: usize = 512;
+
+/// hops with peaks quieter than this (-40 dBFS) are never a hit
+const MIN_LEVEL: f32 = 0.01;
+
+/// a hit is this many times louder than the background noise
+const NOISE_FLOOR_RATIO: f32 = 4.;
+
+/// a hit is this many times louder than the envelope of the sound before it
+const RISE_RATIO: f32 = 2.;
+
+/// how long the envelope takes to fade to about a third
+const ENVELOPE_RELEASE_S: f64 = 0.03;
+
+/// how much each quiet hop moves the estimated background noise
+const NOISE_FLOOR_SMOOTHING: f32 = 0.01;
+
+/// after a hit, the drum rings for a while. a new hit can't start sooner than this.
+const MIN_ONSET_GAP_S: f64 = 0.03;
+
+/// the attack begins at the first sample louder than this fraction of the hit's first hop
+const ATTACK_FRACTION: f32 = 0.25;
+
+/// boundaries between the frequency bands that describe a hit's sound
+const BAND_EDGES_HZ: [f64; 6] = [150., 400., 1000., 2500., 5000., 10000.];
+pub const NUM_BANDS: usize = BAND_EDGES_HZ.len() + 1;
+
+/// A drum hit found in the audio
+#[derive(Debug, Clone, PartialEq)]
+pub struct Onset {
+    /// position in the stream, in samples from its start
+    pub sample: u64,
+    /// the loudest sample just after the onset, from 0 to 1
+    pub peak: f32,
+    /// share of the hit's energy in each frequency band
+    pub features: [f32; NUM_BANDS],
+}
+
+impl Onset {
+    /// how hard the drum was hit, as a midi velocity
+    pub fn velocity(&self) -> u8 {
+        (self.peak.clamp(0., 1.).sqrt() * 127.).round().max(1.) as u8
+    }
+}
+
+pub struct OnsetDetector {
+    sample_rate: u32,
+    /// recent samples, the first of which is at position buffer_start in the stream
+    buffer: Vec<f32>,
+    buffer_start: u64,
+    /// position of the next hop to measure
+    next_hop: u64,
+    /// the loudest recent peak, faded by how long ago it was
+    envelope: f32,
+    /// how much the envelope fades each hop
+    envelope_decay: f32,
+    noise_floor: f32,
+    last_onset: Option<u64>,
+    /// onsets waiting for enough audio to describe their sound
+    pending: VecDeque<u64>,
+}
+
+impl OnsetDetector {
+    pub fn new(sample_rate: u32) -> Self {
+        Self {
+            sample_rate,
+            buffer: vec![],
+            buffer_start: 0,
+            next_hop: 0,
+            envelope: 0.,
+            envelope_decay: (-(HOP as f64) / (ENVELOPE_RELEASE_S * sample_rate as f64)).exp()
+                as f32,
+            noise_floor: 0.,
+            last_onset: None,
+            pending: VecDeque::new(),
+        }
+    }
+
+    /// takes the next samples of the (mono) stream. returns the hits that can be described so far.
+    pub fn process(&mut self, samples: &[f32]) -> Vec<Onset> {
+        self.buffer.extend_from_slice(samples);
+        let buffer_end = self.buffer_start + self.buffer.len() as u64;
+
+        while self.next_hop + HOP as u64 <= buffer_end {
+            self.measure_hop();
+            self.next_hop += HOP as u64;
+        }
+
+        let mut onsets = vec![];
+        while let Some(&sample) = self.pending.front() {
+            if sample + FEATURE_WINDOW as u64 > buffer_end {
+                break;
+            }
+            self.pending.pop_front();
+            let window = self.samples(sample, FEATURE_WINDOW);
+            onsets.push(Onset {
+                sample,
+                peak: window.iter().fold(0., |peak, x| x.abs().max(peak)),
+                features: band_features(window, self.sample_rate),
+            });
+        }
+
+        // keep the previous hop, to look back for an attack, and any audio still to be described
+        let keep_from = self
+            .pending
+            .front()
+            .copied()
+            .unwrap_or(u64::MAX)
+            .min(self.next_hop.saturating_sub(HOP as u64))
+            .max(self.buffer_start);
+        self.buffer
+            .drain(..(keep_from - self.buffer_start) as usize);
+        self.buffer_start = keep_from;
+
+        onsets
+    }
+
+    fn samples(&self, from: u64, len: usize) -> &[f32] {
+        let start = (from - self.buffer_start) as usize;
+        &self.buffer[start..start + len]
+    }
+
+    fn measure_hop(&mut self) {
+        let hop = self.samples(self.next_hop, HOP);
+        let peak = hop.iter().fold(0., |peak: f32, x| x.abs().max(peak));
+        let threshold = (self.noise_floor * NOISE_FLOOR_RATIO).max(MIN_LEVEL);
+        let min_gap = (MIN_ONSET_GAP_S * self.sample_rate as f64) as u64;
+        let is_after_gap = self
+            .last_onset
+            .map_or(true, |last| self.next_hop >= last + min_gap);
+
+        if peak > threshold && peak > self.envelope * RISE_RATIO && is_after_gap {
+            let onset = self.find_attack(peak);
+            self.pending.push_back(onset);
+            self.last_onset = Some(onset);
+        } else if peak < threshold {
+            self.noise_floor += (peak - self.noise_floor) * NOISE_FLOOR_SMOOTHING;
+        }
+
+        self.envelope = peak.max(self.envelope * self.envelope_decay);
+    }
+
+    /// the first sample of the attack, looking back as far as the previous hop
+    fn find_attack(&self, peak: f32) -> u64 {
+        // louder than the sound before it, so the attack begins after any ringing from a previous hit
+        let level = (peak * ATTACK_FRACTION).max(self.envelope);
+
+        let mut from = self
+            .next_hop
+            .saturating_sub(HOP as u64)
+            .max(self.buffer_start);
+        if let Some(last) = self.last_onset {
+            from = from.max(last + 1);
+        }
+        let search = self.samples(from, (self.next_hop - from) as usize + HOP);
+        let offset = search.iter().position(|x| x.abs() >= level).unwrap_or(0);
+        from + offset as u64
+    }
+}
+
+/// share of the window's energy in each frequency band
+fn band_features(window: &[f32], sample_rate: u32) -> [f32; NUM_BANDS] {
+    let n = window.len();
+    // hann window, so energy doesn't leak between bands
+    let windowed: Vec<f64> = window
+        .iter()
+        .enumerate()
+        .map(|(i, x)| *x as f64 * (0.5 - 0.5 * (2. * PI * i as f64 / n as f64).cos()))
+        .collect();
+
+    let mut bands = [0.; NUM_BANDS];
+    for k in 1..n / 2 {
+        let freq_hz = k as f64 * sample_rate as f64 / n as f64;
+        let band = BAND_EDGES_HZ
+            .iter()
+            .filter(|edge| freq_hz >= **edge)
+            .count();
+        // a plain DFT is quick enough, since it only runs once per hit
+        let step = -2. * PI * k as f64 / n as f64;
+        let (mut re, mut im) = (0., 0.);
+        for (i, x) in windowed.iter().enumerate() {
+            let phase = step * i as f64;
+            re += x * phase.cos();
+            im += x * phase.sin();
+        }
+        bands[band] += re * re + im * im;
+    }
+
+    let total: f64 = bands.iter().sum();
+    let mut features = [0.; NUM_BANDS];
+    if total > 0. {
+        for (feature, energy) in features.iter_mut().zip(bands.iter()) {
+            *feature = (energy / total) as f32;
+        }
+    }
+    features
+}
+
+#[cfg(test)]
+pub mod tests {
+    use std::{error::Error, fs::File, path::Path};
+
+    use symphonia::core::{
+        audio::SampleBuffer, codecs::DecoderOptions, errors::Error as SymphoniaError,
+        formats::FormatOptions, io::MediaSourceStream, meta::MetadataOptions, probe::Hint,
+    };
+
+    use crate::onset::{Onset, OnsetDetector};
+
+    /// reads a recording as mono samples, with its sample rate
+    pub fn read_wav(path: &Path) -> Result<(Vec<f32>, u32), Box<dyn Error>> {
+        let source = MediaSourceStream::new(Box::new(File::open(path)?), Default::default());
+        let mut hint = Hint::new();
+        hint.with_extension("wav");
+        let mut format = symphonia::default::get_probe()
+            .format(
+                &hint,
+                source,
+                &FormatOptions::default(),
+                &MetadataOptions::default(),
+            )?
+            .format;
+        let track = format.default_track().ok_or("no audio track")?;
+        let track_id = track.id;
+        let sample_rate = track
+            .codec_params
+            .sample_rate
+            .ok_or("unknown sample rate")?;
+        let mut decoder = symphonia::default::get_codecs()
+            .make(&track.codec_params, &DecoderOptions::default())?;
+
+        let mut samples = vec![];
+        loop {
+            let packet = match format.next_packet() {
+                Ok(packet) => packet,
+                Err(SymphoniaError::IoError(e))
+                    if e.kind() == std::io::ErrorKind::UnexpectedEof =>
+                {
+                    break
+                }
+                Err(e) => return Err(e.into()),
+            };
+            if packet.track_id() != track_id {
+                continue;
+            }
+            let decoded = decoder.decode(&packet)?;
+            let channels = decoded.spec().channels.count();
+            let mut buffer = SampleBuffer::<f32>::new(decoded.capacity() as u64, *decoded.spec());
+            buffer.copy_interleaved_ref(decoded);
+            for frame in buffer.samples().chunks(channels) {
+                samples.push(frame.iter().sum::<f32>() / channels as f32);
+            }
+        }
+        Ok((samples, sample_rate))
+    }
+
+    /// quiet background noise, as from a microphone in a room
+    pub fn noise(len: usize, level: f32) -> Vec<f32> {
+        let mut seed: u32 = 12345;
+        (0..len)
+            .map(|_| {
+                seed = seed.wrapping_mul(1664525).wrapping_add(1013904223);
+                (seed as f32 / u32::MAX as f32 * 2. - 1.) * level
+            })
+            .collect()
+    }
+
+    /// a recording of each sound played at the given positions (in samples), over background noise
+    pub fn mix(len: usize, hits: &[(&[f32], usize, f32)]) -> Vec<f32> {
+        let mut track = noise(len, 0.001);
+        for (sound, at, gain) in hits {
+            for (i, x) in sound.iter().enumerate() {
+                if let Some(out) = track.get_mut(at + i) {
+                    *out += x * gain;
+                }
+            }
+        }
+        track
+    }
+
+    pub fn read_sound(name: &str) -> Vec<f32> {
+        let (samples, sample_rate) = read_wav(&Path::new("assets/sounds").join(name)).unwrap();
+        assert_eq!(sample_rate, 48000);
+        samples
+    }
+
+    /// feeds the recording in buffers of varying size, as an audio device would
+    pub fn detect(track: &[f32], sample_rate: u32) -> Vec<Onset> {
+        let mut detector = OnsetDetector::new(sample_rate);
+        let mut onsets = vec![];
+        let mut pos = 0;
+        for size in [64, 441, 512, 1000].iter().cycle() {
+            if pos >= track.len() {
+                break;
+            }
+            let end = (pos + size).min(track.len());
+            onsets.extend(detector.process(&track[pos..end]));
+            pos = end;
+        }
+        onsets
+    }
+
+    #[test]
+    fn it_finds_the_sample_where_each_hit_starts() {
+        // decaying tones with a sharp attack, the last two only 40ms apart
+        let sample_rate = 44100;
+        let tone: Vec<f32> = (0..8000)
+            .map(|i| (i as f32 * 0.05).sin() * (-(i as f32) / 1500.).exp())
+            .collect();
+        let starts = [10_000, 30_000, 31_764];
+        let track = mix(
+            50_000,
+            &[
+                (&tone, starts[0], 0.8),
+                (&tone, starts[1], 0.1),
+                (&tone, starts[2], 0.5),
+            ],
+        );
+
+        let onsets = detect(&track, sample_rate);
+        let found: Vec<u64> = onsets.iter().map(|o| o.sample).collect();
+        assert_eq!(found.len(), starts.len(), "onsets: {:?}", found);
+        for (onset, start) in found.iter().zip(starts.iter()) {
+            // within 0.5ms
+            assert!(
+                onset.abs_diff(*start as u64) <= 22,
+                "{} vs {}",
+                onset,
+                start
+            );
+        }
+        assert!(onsets[0].velocity() > onsets[2].velocity());
+        assert!(onsets[2].velocity() > onsets[1].velocity());
+    }
+
+    #[test]
+    fn it_finds_hits_in_recorded_drums() {
+        let kick = read_sound("kick.wav");
+        let snare = read_sound("snare.wav");
+        let hihat = read_sound("closed-hihat.wav");
+        // an 8th note groove at 100 bpm: 14400 samples apart
+        let hits: Vec<(&[f32], usize, f32)> = vec![
+            (&kick, 0, 1.),
+            (&hihat, 0, 0.7),
+            (&hihat, 14_400, 0.5),
+            (&snare, 28_800, 1.),
+            (&hihat, 28_800, 0.7),
+            (&hihat, 43_200, 0.5),
+            (&kick, 57_600, 0.6),
+            (&snare, 72_000, 0.3),
+        ];
+        let starts: Vec<usize> = vec![0, 14_400, 28_800, 43_200, 57_600, 72_000];
+        let lead_in = 4_800;
+        let hits: Vec<_> = hits
+            .into_iter()
+            .map(|(sound, at, gain)| (sound, at + lead_in, gain))
+            .collect();
+        let track = mix(90_000, &hits);
+
+        let found: Vec<u64> = detect(&track, 48000).iter().map(|o| o.sample).collect();
+        assert_eq!(found.len(), starts.len(), "onsets: {:?}", found);
+        for (onset, start) in found.iter().zip(starts.iter()) {
+            // the recordings begin within 3ms of their attack
+            let start = (start + lead_in) as u64;
+            assert!(
+                *onset >= start && *onset <= start + 144,
+                "{} vs {}",
+                onset,
+                start
+            );
+        }
+    }
+}
@@ -0,0 +1,181 @@
+/*
+  Progress over days and weeks, derived from the session journals.
+
+  For each loop: the number of attempts, time spent, best score, and the highest BPM it was aced at.
+*/
+
+use std::{collections::BTreeMap, collections::HashMap, fs, path::Path};
+
+use crate::{
+    journal::{read_journal, JournalEntry, LoopAttempt},
+    score::Grade,
+};
+
+const MS_PER_DAY: u64 = 24 * 60 * 60 * 1000;
+
+/// Progress on a loop during a single day
+#[derive(Debug, PartialEq, Clone, Copy, Default)]
+pub struct DayProgress {
+    pub attempts: usize,
+    pub best_score: f64,
+    pub highest_aced_bpm: Option<f64>,
+}
+
+#[derive(Debug, PartialEq, Clone, Default)]
+pub struct LoopProgress {
+    pub attempts: usize,
+    pub time_spent_s: f64,
+    pub best_score: f64,
+    pub highest_aced_bpm: Option<f64>,
+    /// keyed by days since the unix epoch (UTC)
+    pub by_day: BTreeMap<u64, DayProgress>,
+}
+
+impl LoopProgress {
+    fn add_attempt(&mut self, attempt: &LoopAttempt) {
+        let aced_bpm = (attempt.grade == Grade::Ace).then_some(attempt.bpm);
+
+        self.attempts += 1;
+        // a beat is an 8th note, so there are bpm * 2 beats per minute
+        self.time_spent_s += attempt.beats_per_loop as f64 / (attempt.bpm * 2. / 60.);
+        self.best_score = self.best_score.max(attempt.score);
+        self.highest_aced_bpm = max_bpm(self.highest_aced_bpm, aced_bpm);
+
+        let day = self
+            .by_day
+            .entry(attempt.system_time_ms / MS_PER_DAY)
+            .or_default();
+        day.attempts += 1;
+        day.best_score = day.best_score.max(attempt.score);
+        day.highest_aced_bpm = max_bpm(day.highest_aced_bpm, aced_bpm);
+    }
+
+    /// short summary, e.g. "best 92%, aced at 110 BPM"
+    pub fn personal_best(&self) -> String {
+        match self.highest_aced_bpm {
+            Some(bpm) => format!(
+                "best {:.0}%, aced at {:.0} BPM",
+                self.best_score * 100.,
+                bpm
+            ),
+            None => format!("best {:.0}%", self.best_score * 100.),
+        }
+    }
+}
+
+fn max_bpm(a: Option<f64>, b: Option<f64>) -> Option<f64> {
+    match (a, b) {
+        (Some(a), Some(b)) => Some(a.max(b)),
+        (a, b) => a.or(b),
+    }
+}
+
+/// Progress on every loop that's been played, keyed by loop name
+#[derive(Debug, PartialEq, Clone, Default)]
+pub struct ProgressHistory {
+    by_loop: HashMap<String, LoopProgress>,
+}
+
+impl ProgressHistory {
+    /// reads every session journal in the directory. unreadable journals, and unreadable lines within them, are skipped.
+    pub fn load(sessions_dir: Option<&Path>) -> Self {
+        let mut history = Self::default();
+        let Some(dir) = sessions_dir else {
+            return history;
+        };
+        let Ok(entries) = fs::read_dir(dir) else {
+            // nothing has been recorded yet
+            return history;
+        };
+
+        let mut paths: Vec<_> = entries
+            .filter_map(|e| e.ok())
+            .map(|e| e.path())
+            .filter(|p| p.extension().is_some_and(|ext| ext == "jsonl"))
+            .collect();
+        paths.sort();
+        for path in paths {
+            match read_journal(&path) {
+                Ok(records) => {
+                    for record in records {
+                        if let JournalEntry::LoopAttempt(attempt) = record.entry {
+                            history.add_attempt(&attempt);
+                        }
+                    }
+                }
+                Err(e) => log::warn!("skipping session journal {:?}: {}", path, e),
+            }
+        }
+        history
+    }
+
+    pub fn add_attempt(&mut self, attempt: &LoopAttempt) {
+        self.by_loop
+            .entry(attempt.loop_name.clone())
+            .or_default()
+            .add_attempt(attempt);
+    }
+
+    pub fn get(&self, loop_name: &str) -> Option<&LoopProgress> {
+        self.by_loop.get(loop_name)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::collections::HashMap;
+
+    use crate::{
+        journal::LoopAttempt,
+        progress::{ProgressHistory, MS_PER_DAY},
+        score::{Difficulty, Grade},
+    };
+
+    fn attempt(loop_name: &str, day: u64, bpm: f64, score: f64, grade: Grade) -> LoopAttempt {
+        let timing_windows = Difficulty::Normal.timing_windows();
+        LoopAttempt {
+            system_time_ms: day * MS_PER_DAY + 1000,
+            loop_num: 0,
+            loop_name: loop_name.to_string(),
+            bpm,
+            beats_per_loop: 16,
+            swing: 0.5,
+            timing_windows,
+            margins: timing_windows.margins(bpm),
+            audio_latency_s: 0.,
+            input_latency_by_device_s: HashMap::new(),
+            desired_notes: vec![],
+            user_hits: vec![],
+            score,
+            grade,
+        }
+    }
+
+    #[test]
+    fn it_tracks_personal_bests_per_loop() {
+        let mut history = ProgressHistory::default();
+        history.add_attempt(&attempt("Rock", 20_000, 120., 0.8, Grade::Good));
+        history.add_attempt(&attempt("Rock", 20_000, 100., 1., Grade::Ace));
+        history.add_attempt(&attempt("Rock", 20_001, 110., 0.95, Grade::Ace));
+        history.add_attempt(&attempt("Samba", 20_001, 90., 0.5, Grade::NeedsWork));
+
+        let rock = history.get("Rock").unwrap();
+        assert_eq!(rock.attempts, 3);
+        assert_eq!(rock.best_score, 1.);
+        assert_eq!(rock.highest_aced_bpm, Some(110.));
+        // 16 beats at 120, 100 and 110 BPM
+        assert!((rock.time_spent_s - (4. + 4.8 + 16. / (110. * 2. / 60.))).abs() < 1e-9);
+        assert_eq!(rock.personal_best(), "best 100%, aced at 110 BPM");
+
+        assert_eq!(rock.by_day.len(), 2);
+        assert_eq!(rock.by_day[&20_000].attempts, 2);
+        assert_eq!(rock.by_day[&20_000].highest_aced_bpm, Some(100.));
+        assert_eq!(rock.by_day[&20_001].best_score, 0.95);
+
+        let samba = history.get("Samba").unwrap();
+        assert_eq!(samba.highest_aced_bpm, None);
+        assert_eq!(samba.personal_best(), "best 50%");
+
+        assert!(history.get("Breakbeat").is_none());
+    }
+}
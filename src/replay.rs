@@ -0,0 +1,777 @@
+/*
+  Record a session's input, and replay it.
+
+  Reproducing a scoring or timing bug shouldn't need a drummer. A recording starts from a snapshot of the loop and
+  settings (see snapshot.rs), then holds every frame: the input events that change what's played or how it's
+  scored, where each one came from, and how the audio clock read that frame.
+
+  Replaying feeds the same events back through game::update(), against a clock that reads whatever was recorded,
+  so hits land on the same ticks and loops get the same scores. What the live session produced is saved with the
+  recording, so a replay can be checked against it, e.g. from a test.
+
+  Anything else that changes the tempo is recorded too: a followed midi clock's pulses and start/stop are replayed
+  like hits, and the tempo trainer's progress is part of the starting snapshot (with the time of its last step,
+  which a snapshot leaves out), so it ramps up at the same loops.
+*/
+
+use std::{
+    error::Error,
+    fs,
+    path::{Path, PathBuf},
+};
+
+use serde::{Deserialize, Serialize};
+
+use crate::{
+    audio::Audio,
+    beat::Beat,
+    config::{data_dir, AppConfig},
+    dynamics::DynamicsConfig,
+    events::Events,
+    game::{GameState, Loops},
+    gap_click::GapClickConfig,
+    journal::{JournalHit, LoopAttempt},
+    midi_clock::Transport,
+    score::{ConfusionSet, Grade, GradeThresholds, TimingWindows},
+    snapshot::GameSnapshot,
+    tempo_trainer::TempoTrainerConfig,
+    time::{current_time_millis, ClockReading, ClockSync},
+    voices::Instrument,
+};
+
+/// bump this when the meaning of a field changes
+pub const RECORDING_VERSION: u32 = 1;
+
+/// set this to a recording file to replay it, instead of playing
+pub const REPLAY_ENV_VAR: &str = "DRUM_BREAK_REPLAY";
+
+/// how far a replayed hit may land from where it was recorded. timestamps can lose their last bit when saved.
+const TICK_TOLERANCE: f64 = 1e-6;
+const SCORE_TOLERANCE: f64 = 1e-6;
+const BPM_TOLERANCE: f64 = 1e-6;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum InputSource {
+    Keyboard,
+    Ui,
+    Midi,
+    Mic,
+}
+
+/// An input event that changes what's played or how it's scored.
+/// The rest (e.g. device settings, or which windows are open) don't matter to a replay, and aren't recorded.
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+#[serde(tag = "event", rename_all = "snake_case")]
+pub enum RecordedEvent {
+    /// wall_time_s is when the hit happened, with input latency and processing delay already taken off
+    Hit {
+        instrument: Instrument,
+        wall_time_s: f64,
+        velocity: Option<u8>,
+        #[serde(default)]
+        device: String,
+    },
+    Pause,
+    ChangeBpm {
+        delta: f64,
+    },
+    SetBpm {
+        bpm: f64,
+    },
+    ResetHits,
+    ToggleBeat {
+        instrument: Instrument,
+        beat: Beat,
+    },
+    /// by name, so a recording still replays after loops are added or removed
+    ChangeLoop {
+        name: String,
+    },
+    SetSwing {
+        swing: f64,
+    },
+    SetGapClick {
+        config: GapClickConfig,
+    },
+    SetTimingWindows {
+        windows: TimingWindows,
+    },
+    SetConfusionSet {
+        set: ConfusionSet,
+    },
+    SetDynamicsConfig {
+        config: DynamicsConfig,
+    },
+    SetGradeThresholds {
+        thresholds: GradeThresholds,
+    },
+    SetTempoTrainerConfig {
+        config: TempoTrainerConfig,
+    },
+    RestartTempoTrainer,
+    ToggleMetronome,
+    MidiTransport {
+        transport: Transport,
+    },
+    /// wall_time_s is when the followed midi clock's latest pulse arrived
+    SyncToMidiClock {
+        bpm: Option<f64>,
+        position_ticks: f64,
+        wall_time_s: f64,
+    },
+}
+
+impl RecordedEvent {
+    /// the recorded form of an event processed at frame_time_s, if it matters to a replay
+    fn from_event(event: &Events, frame_time_s: f64, loops: &Loops) -> Option<Self> {
+        let recorded = match event {
+            Events::UserHit {
+                instrument,
+                processing_delay,
+                velocity,
+                device,
+            } => Self::Hit {
+                instrument: *instrument,
+                wall_time_s: frame_time_s - processing_delay,
+                velocity: *velocity,
+                device: device.clone(),
+            },
+            Events::Pause => Self::Pause,
+            Events::ChangeBPM { delta } => Self::ChangeBpm { delta: *delta },
+            Events::SetBPM(bpm) => Self::SetBpm { bpm: *bpm },
+            Events::ResetHits => Self::ResetHits,
+            Events::ToggleBeat { ins, beat } => Self::ToggleBeat {
+                instrument: *ins,
+                beat: *beat,
+            },
+            Events::ChangeLoop(idx) => Self::ChangeLoop {
+                name: loops.get(*idx)?.0.clone(),
+            },
+            Events::SetSwing(swing) => Self::SetSwing { swing: *swing },
+            Events::SetGapClick(config) => Self::SetGapClick { config: *config },
+            Events::SetTimingWindows(windows) => Self::SetTimingWindows { windows: *windows },
+            Events::SetConfusionSet(set) => Self::SetConfusionSet { set: set.clone() },
+            Events::SetDynamicsConfig(config) => Self::SetDynamicsConfig { config: *config },
+            Events::SetGradeThresholds(thresholds) => Self::SetGradeThresholds {
+                thresholds: *thresholds,
+            },
+            Events::SetTempoTrainerConfig(config) => {
+                Self::SetTempoTrainerConfig { config: *config }
+            }
+            Events::RestartTempoTrainer => Self::RestartTempoTrainer,
+            Events::ToggleMetronome => Self::ToggleMetronome,
+            Events::MidiTransport(transport) => Self::MidiTransport {
+                transport: *transport,
+            },
+            Events::SyncToMidiClock {
+                bpm,
+                position_ticks,
+                processing_delay,
+            } => Self::SyncToMidiClock {
+                bpm: *bpm,
+                position_ticks: *position_ticks,
+                wall_time_s: frame_time_s - processing_delay,
+            },
+            _ => return None,
+        };
+        Some(recorded)
+    }
+
+    /// the event to process at frame_time_s. None if it's for a loop that doesn't exist.
+    fn to_event(&self, frame_time_s: f64, loops: &Loops) -> Option<Events> {
+        let event = match self {
+            Self::Hit {
+                instrument,
+                wall_time_s,
+                velocity,
+                device,
+            } => Events::UserHit {
+                instrument: *instrument,
+                processing_delay: frame_time_s - wall_time_s,
+                velocity: *velocity,
+                device: device.clone(),
+            },
+            Self::Pause => Events::Pause,
+            Self::ChangeBpm { delta } => Events::ChangeBPM { delta: *delta },
+            Self::SetBpm { bpm } => Events::SetBPM(*bpm),
+            Self::ResetHits => Events::ResetHits,
+            Self::ToggleBeat { instrument, beat } => Events::ToggleBeat {
+                ins: *instrument,
+                beat: *beat,
+            },
+            Self::ChangeLoop { name } => {
+                let Some(idx) = loops.iter().position(|(n, _)| n == name) else {
+                    log::warn!(
+                        "the recording changes to loop {:?}, which doesn't exist",
+                        name
+                    );
+                    return None;
+                };
+                Events::ChangeLoop(idx)
+            }
+            Self::SetSwing { swing } => Events::SetSwing(*swing),
+            Self::SetGapClick { config } => Events::SetGapClick(*config),
+            Self::SetTimingWindows { windows } => Events::SetTimingWindows(*windows),
+            Self::SetConfusionSet { set } => Events::SetConfusionSet(set.clone()),
+            Self::SetDynamicsConfig { config } => Events::SetDynamicsConfig(*config),
+            Self::SetGradeThresholds { thresholds } => Events::SetGradeThresholds(*thresholds),
+            Self::SetTempoTrainerConfig { config } => Events::SetTempoTrainerConfig(*config),
+            Self::RestartTempoTrainer => Events::RestartTempoTrainer,
+            Self::ToggleMetronome => Events::ToggleMetronome,
+            Self::MidiTransport { transport } => Events::MidiTransport(*transport),
+            Self::SyncToMidiClock {
+                bpm,
+                position_ticks,
+                wall_time_s,
+            } => Events::SyncToMidiClock {
+                bpm: *bpm,
+                position_ticks: *position_ticks,
+                processing_delay: frame_time_s - wall_time_s,
+            },
+        };
+        Some(event)
+    }
+}
+
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct RecordedInput {
+    pub source: InputSource,
+    #[serde(flatten)]
+    pub event: RecordedEvent,
+}
+
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct RecordedFrame {
+    /// when the frame's input was processed
+    pub wall_time_s: f64,
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    pub inputs: Vec<RecordedInput>,
+    /// the audio clock, as read when the frame's audio was scheduled
+    pub clock: ClockReading,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Serialize, Deserialize)]
+pub struct ScoredLoop {
+    pub loop_num: usize,
+    pub bpm: f64,
+    pub score: f64,
+    pub grade: Grade,
+}
+
+impl From<&LoopAttempt> for ScoredLoop {
+    fn from(attempt: &LoopAttempt) -> Self {
+        Self {
+            loop_num: attempt.loop_num,
+            bpm: attempt.bpm,
+            score: attempt.score,
+            grade: attempt.grade,
+        }
+    }
+}
+
+/// What a session produced: each loop's score, and the grid of hits
+#[derive(Debug, Clone, Default, PartialEq, Serialize, Deserialize)]
+pub struct ReplayOutcome {
+    pub loops: Vec<ScoredLoop>,
+    pub user_hits: Vec<JournalHit>,
+}
+
+impl ReplayOutcome {
+    /// how a replay's outcome differs from this one, described for a bug report. empty when they match.
+    pub fn differences(&self, replayed: &ReplayOutcome) -> Vec<String> {
+        let mut out = vec![];
+        if replayed.loops.len() != self.loops.len() {
+            out.push(format!(
+                "{} loops were scored, instead of {}",
+                replayed.loops.len(),
+                self.loops.len()
+            ));
+        }
+        for (expected, actual) in self.loops.iter().zip(replayed.loops.iter()) {
+            if actual.loop_num != expected.loop_num
+                || actual.grade != expected.grade
+                || (actual.score - expected.score).abs() > SCORE_TOLERANCE
+            {
+                out.push(format!(
+                    "loop {} scored {:.4} ({:?}), instead of loop {} scoring {:.4} ({:?})",
+                    actual.loop_num,
+                    actual.score,
+                    actual.grade,
+                    expected.loop_num,
+                    expected.score,
+                    expected.grade
+                ));
+            }
+            if (actual.bpm - expected.bpm).abs() > BPM_TOLERANCE {
+                out.push(format!(
+                    "loop {} was played at {} bpm, instead of {} bpm",
+                    actual.loop_num, actual.bpm, expected.bpm
+                ));
+            }
+        }
+
+        if replayed.user_hits.len() != self.user_hits.len() {
+            out.push(format!(
+                "{} hits were placed, instead of {}",
+                replayed.user_hits.len(),
+                self.user_hits.len()
+            ));
+        }
+        for (i, (expected, actual)) in self.user_hits.iter().zip(&replayed.user_hits).enumerate() {
+            if actual.instrument != expected.instrument
+                || actual.velocity != expected.velocity
+                || (actual.clock_tick - expected.clock_tick).abs() > TICK_TOLERANCE
+            {
+                out.push(format!(
+                    "hit {} was {:?} at tick {:.6}, instead of {:?} at tick {:.6}",
+                    i,
+                    actual.instrument,
+                    actual.clock_tick,
+                    expected.instrument,
+                    expected.clock_tick
+                ));
+            }
+        }
+        out
+    }
+}
+
+#[derive(Debug, PartialEq, Serialize, Deserialize)]
+pub struct InputRecording {
+    pub version: u32,
+    pub app_version: String,
+    /// the loop and settings, and the hits so far, when recording started
+    pub start: GameSnapshot,
+    /// how wall time related to the audio clock when recording started
+    pub clock_sync: ClockSync,
+    pub clock: ClockReading,
+    /// when the tempo trainer last stepped, on the recorded clock
+    #[serde(default)]
+    pub tempo_trainer_last_step_ms: Option<u128>,
+    pub frames: Vec<RecordedFrame>,
+    /// what the live session produced
+    pub outcome: ReplayOutcome,
+}
+
+impl InputRecording {
+    pub fn num_inputs(&self) -> usize {
+        self.frames.iter().map(|frame| frame.inputs.len()).sum()
+    }
+
+    pub fn from_json(data: &str) -> Result<Self, Box<dyn Error>> {
+        // check the version first, since newer recordings may not parse
+        let RecordingVersion { version } = serde_json::from_str(data)?;
+        if version > RECORDING_VERSION {
+            return Err(format!(
+                "recording version {} is newer than supported version {}",
+                version, RECORDING_VERSION
+            )
+            .into());
+        }
+        Ok(serde_json::from_str(data)?)
+    }
+
+    pub fn load(path: &Path) -> Result<Self, Box<dyn Error>> {
+        Self::from_json(&fs::read_to_string(path)?)
+    }
+
+    pub fn save(&self, path: &Path) -> Result<(), Box<dyn Error>> {
+        if let Some(dir) = path.parent() {
+            fs::create_dir_all(dir)?;
+        }
+        fs::write(path, serde_json::to_string(self)?)?;
+        Ok(())
+    }
+}
+
+#[derive(Deserialize)]
+struct RecordingVersion {
+    version: u32,
+}
+
+/// Records every frame of a live session
+pub struct InputRecorder {
+    recording: InputRecording,
+}
+
+impl InputRecorder {
+    /// starts from the current state, so should be called once a frame's audio is scheduled
+    pub fn start(gs: &GameState, audio: &Audio, app_version: &str) -> Self {
+        Self {
+            recording: InputRecording {
+                version: RECORDING_VERSION,
+                app_version: app_version.trim().to_string(),
+                start: GameSnapshot::capture(gs, audio, true),
+                clock_sync: audio.clock_sync(),
+                clock: audio.clock_reading(),
+                tempo_trainer_last_step_ms: gs.tempo_trainer.last_step_time_ms(),
+                frames: vec![],
+                outcome: ReplayOutcome::default(),
+            },
+        }
+    }
+
+    /// records a frame, once its audio is scheduled
+    pub fn record_frame(
+        &mut self,
+        frame_time_s: f64,
+        audio: &Audio,
+        loops: &Loops,
+        inputs: &[(InputSource, Vec<Events>)],
+        attempts: &[LoopAttempt],
+    ) {
+        let inputs = inputs
+            .iter()
+            .flat_map(|(source, events)| {
+                events.iter().filter_map(|event| {
+                    let event = RecordedEvent::from_event(event, frame_time_s, loops)?;
+                    Some(RecordedInput {
+                        source: *source,
+                        event,
+                    })
+                })
+            })
+            .collect();
+        self.recording.frames.push(RecordedFrame {
+            wall_time_s: frame_time_s,
+            inputs,
+            clock: audio.clock_reading(),
+        });
+        let outcome = &mut self.recording.outcome;
+        outcome.loops.extend(attempts.iter().map(ScoredLoop::from));
+    }
+
+    pub fn finish(mut self, audio: &Audio) -> InputRecording {
+        self.recording.outcome.user_hits = audio.user_hits.iter().map(JournalHit::from).collect();
+        self.recording
+    }
+}
+
+fn recordings_dir() -> Option<PathBuf> {
+    data_dir().map(|dir| dir.join("recordings"))
+}
+
+/// a new file for a recording
+pub fn new_recording_path() -> Option<PathBuf> {
+    recordings_dir().map(|dir| dir.join(format!("recording-{}.json", current_time_millis())))
+}
+
+/// the recording named by `REPLAY_ENV_VAR`, if any
+pub fn startup_recording() -> Option<InputRecording> {
+    let path = PathBuf::from(std::env::var(REPLAY_ENV_VAR).ok()?);
+    match InputRecording::load(&path) {
+        Ok(recording) => {
+            log::info!(
+                "replaying {} inputs from {:?}",
+                recording.num_inputs(),
+                path
+            );
+            Some(recording)
+        }
+        Err(e) => {
+            log::warn!("unable to replay {:?}: {}", path, e);
+            None
+        }
+    }
+}
+
+/// settings for replaying: no input devices are connected, and nothing that's replayed saves them
+pub fn replay_config() -> AppConfig {
+    AppConfig {
+        midi_input_ports: Some(vec![]),
+        ..Default::default()
+    }
+}
+
+/// Plays a recording back, a frame at a time
+pub struct Replay<'a> {
+    recording: &'a InputRecording,
+    next_frame: usize,
+    loops: Vec<ScoredLoop>,
+}
+
+impl<'a> Replay<'a> {
+    pub fn new(recording: &'a InputRecording) -> Self {
+        Self {
+            recording,
+            next_frame: 0,
+            loops: vec![],
+        }
+    }
+
+    /// sets the game up as it was when recording started
+    pub fn start(&self, gs: &mut GameState, audio: &mut Audio) {
+        self.recording.start.restore(gs, audio);
+        gs.tempo_trainer
+            .set_last_step_time_ms(self.recording.tempo_trainer_last_step_ms);
+        audio.restore_clock(self.recording.clock_sync, self.recording.clock);
+    }
+
+    /// the next frame's time and events, with the clock set to how it read that frame. None once it's over.
+    pub fn next_frame(&mut self, audio: &mut Audio, loops: &Loops) -> Option<(f64, Vec<Events>)> {
+        let frame = self.recording.frames.get(self.next_frame)?;
+        self.next_frame += 1;
+        audio.replay_clock(frame.clock);
+        let events = frame
+            .inputs
+            .iter()
+            .filter_map(|input| input.event.to_event(frame.wall_time_s, loops))
+            .collect();
+        Some((frame.wall_time_s, events))
+    }
+
+    /// collects the loops scored in a frame. after the last frame, reports how the replay compares to the recording.
+    pub fn end_frame(&mut self, attempts: &[LoopAttempt], audio: &Audio) {
+        self.loops.extend(attempts.iter().map(ScoredLoop::from));
+        if self.next_frame != self.recording.frames.len() {
+            return;
+        }
+        let differences = self.recording.outcome.differences(&self.outcome(audio));
+        if differences.is_empty() {
+            log::info!("replay finished, with the same scores and hits as recorded");
+        }
+        for difference in differences {
+            log::warn!("replay differs from the recording: {}", difference);
+        }
+    }
+
+    /// what's been replayed so far
+    pub fn outcome(&self, audio: &Audio) -> ReplayOutcome {
+        ReplayOutcome {
+            loops: self.loops.clone(),
+            user_hits: audio.user_hits.iter().map(JournalHit::from).collect(),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::{error::Error, sync::mpsc};
+
+    use crate::{
+        audio::Audio,
+        beat::Beat,
+        config::AppConfig,
+        events::Events,
+        game::{update, GameState, Loops},
+        keyboard_input_handler::KeyboardInputHandler,
+        mic_input_handler::MicInputHandler,
+        midi_input_handler::MidiInputHandler,
+        replay::{
+            replay_config, InputRecording, InputSource, RecordedEvent, RecordedFrame,
+            RecordedInput, Replay, ReplayOutcome, RECORDING_VERSION,
+        },
+        snapshot::GameSnapshot,
+        time::{ClockReading, ClockSync},
+        voices::Instrument,
+    };
+
+    /// replays a whole recording without a window or any sound
+    pub fn replay(
+        recording: &InputRecording,
+        loops: Loops,
+    ) -> Result<ReplayOutcome, Box<dyn Error>> {
+        let (tx, rx) = mpsc::channel();
+        let mut conf = replay_config();
+        let mut audio = Audio::new_replay(&conf, tx);
+        let mut gs = GameState::new(loops);
+        let mut midi_input = MidiInputHandler::new(&conf);
+        let mut mic_input = MicInputHandler::new(&conf);
+        let mut keyboard_input = KeyboardInputHandler::new();
+
+        let mut replay = Replay::new(recording);
+        replay.start(&mut gs, &mut audio);
+        while let Some((frame_time_s, events)) = replay.next_frame(&mut audio, &gs.loops) {
+            let attempts = update(
+                &mut gs,
+                &mut audio,
+                &rx,
+                &events,
+                frame_time_s,
+                &mut conf,
+                &mut midi_input,
+                &mut mic_input,
+                &mut keyboard_input,
+                None,
+            )?;
+            replay.end_frame(&attempts, &audio);
+        }
+        Ok(replay.outcome(&audio))
+    }
+
+    /// a session at 120 bpm (4 ticks per second), with a snare on every quarter note.
+    /// the player hits each one exactly, except the last note of the second loop.
+    fn recorded_session() -> InputRecording {
+        let (tx, _rx) = mpsc::channel();
+        let mut audio = Audio::new_replay(&AppConfig::default(), tx);
+        audio.set_bpm(120.);
+        let mut gs = GameState::new(vec![]);
+        for beat in (0..16).step_by(4) {
+            gs.voices
+                .toggle_beat(Instrument::Snare, Beat::from_beats(beat));
+        }
+
+        let t0 = 1_700_000_000.;
+        let fps = 60.;
+        let tps = 4.;
+        let hit_ticks = [0., 4., 8., 12., 16., 20., 24.];
+        let reading = |wall_time_s: f64| ClockReading {
+            wall_time_s,
+            tick: (wall_time_s - t0) * tps,
+            ticking: true,
+        };
+        let start = reading(t0 - 1. / fps);
+        let mut clock_sync = ClockSync::default();
+        clock_sync.observe(start.wall_time_s, start.tick, tps);
+
+        // up to the start of the third loop, so the first two are scored
+        let frames = (0..(33. / tps * fps) as usize)
+            .map(|n| {
+                let wall_time_s = t0 + n as f64 / fps;
+                let inputs = hit_ticks
+                    .iter()
+                    .map(|tick| t0 + tick / tps)
+                    .filter(|hit_s| *hit_s <= wall_time_s && *hit_s > wall_time_s - 1. / fps)
+                    .map(|hit_s| RecordedInput {
+                        source: InputSource::Midi,
+                        event: RecordedEvent::Hit {
+                            instrument: Instrument::Snare,
+                            wall_time_s: hit_s,
+                            velocity: Some(100),
+                            device: "pad".to_string(),
+                        },
+                    })
+                    .collect();
+                RecordedFrame {
+                    wall_time_s,
+                    inputs,
+                    clock: reading(wall_time_s),
+                }
+            })
+            .collect();
+
+        InputRecording {
+            version: RECORDING_VERSION,
+            app_version: "test".to_string(),
+            start: GameSnapshot::capture(&gs, &audio, true),
+            clock_sync,
+            clock: start,
+            tempo_trainer_last_step_ms: None,
+            frames,
+            outcome: ReplayOutcome::default(),
+        }
+    }
+
+    #[test]
+    fn it_replays_a_session_to_the_same_scores_and_hits() {
+        let mut recording = recorded_session();
+        let outcome = replay(&recording, vec![]).unwrap();
+
+        let ticks: Vec<f64> = outcome.user_hits.iter().map(|h| h.clock_tick).collect();
+        assert_eq!(ticks.len(), 7);
+        for (tick, expected) in ticks.iter().zip([0., 4., 8., 12., 16., 20., 24.]) {
+            assert!((tick - expected).abs() < 1e-6, "hit at {}", tick);
+        }
+        let loop_nums: Vec<usize> = outcome.loops.iter().map(|l| l.loop_num).collect();
+        assert_eq!(loop_nums, vec![0, 1]);
+        assert!(outcome.loops[0].score > outcome.loops[1].score);
+        assert!(outcome.loops[0].grade > outcome.loops[1].grade);
+
+        // saved with what it produced, it replays the same
+        recording.outcome = outcome;
+        let saved = InputRecording::from_json(&serde_json::to_string(&recording).unwrap()).unwrap();
+        let replayed = replay(&saved, vec![]).unwrap();
+        assert_eq!(saved.outcome.differences(&replayed), Vec::<String>::new());
+
+        // a hit played 50ms late moves, and its loop scores worse
+        let mut late = saved;
+        let frame = late
+            .frames
+            .iter_mut()
+            .find(|f| !f.inputs.is_empty())
+            .unwrap();
+        if let RecordedEvent::Hit { wall_time_s, .. } = &mut frame.inputs[0].event {
+            *wall_time_s += 0.05;
+        }
+        let replayed = replay(&late, vec![]).unwrap();
+        let differences = late.outcome.differences(&replayed);
+        assert_eq!(differences.len(), 2, "{:?}", differences);
+        assert!(differences[0].starts_with("loop 0 scored"));
+        assert!(differences[1].starts_with("hit 0 was Snare at tick 0.2"));
+    }
+
+    #[test]
+    fn it_replays_tempo_changes_from_a_midi_clock_and_the_tempo_trainer() {
+        let mut recording = recorded_session();
+        // one more aced loop steps the tempo up
+        recording.start.tempo_trainer.correct_takes = 2;
+        let frame = &mut recording.frames[10];
+        frame.inputs.push(RecordedInput {
+            source: InputSource::Midi,
+            event: RecordedEvent::SyncToMidiClock {
+                bpm: Some(126.),
+                position_ticks: frame.clock.tick,
+                wall_time_s: frame.wall_time_s,
+            },
+        });
+
+        let outcome = replay(&recording, vec![]).unwrap();
+        let bpms: Vec<f64> = outcome.loops.iter().map(|l| l.bpm).collect();
+        assert_eq!(bpms, vec![126., 128.]);
+
+        recording.outcome = outcome;
+        let saved = InputRecording::from_json(&serde_json::to_string(&recording).unwrap()).unwrap();
+        let replayed = replay(&saved, vec![]).unwrap();
+        assert_eq!(saved.outcome.differences(&replayed), Vec::<String>::new());
+
+        // without the clock, the tempo (and so where hits land) differs
+        let mut unsynced = saved;
+        unsynced.frames[10].inputs.clear();
+        let replayed = replay(&unsynced, vec![]).unwrap();
+        let differences = unsynced.outcome.differences(&replayed);
+        assert_eq!(
+            differences[..2],
+            [
+                "loop 0 was played at 120 bpm, instead of 126 bpm",
+                "loop 1 was played at 122 bpm, instead of 128 bpm"
+            ]
+        );
+    }
+
+    #[test]
+    fn it_records_only_the_events_that_matter_to_a_replay() {
+        let frame_time_s = 1_700_000_000.5;
+        let loops = vec![];
+        let hit = Events::UserHit {
+            instrument: Instrument::Kick,
+            processing_delay: 0.012,
+            velocity: None,
+            device: "keyboard".to_string(),
+        };
+        let recorded = RecordedEvent::from_event(&hit, frame_time_s, &loops).unwrap();
+        let input = RecordedInput {
+            source: InputSource::Keyboard,
+            event: recorded,
+        };
+        let json = serde_json::to_string(&input).unwrap();
+        assert!(json.starts_with(r#"{"source":"keyboard","event":"hit","instrument":"kick""#));
+        let parsed: RecordedInput = serde_json::from_str(&json).unwrap();
+        let Events::UserHit {
+            processing_delay, ..
+        } = parsed.event.to_event(frame_time_s, &loops).unwrap()
+        else {
+            panic!("not a hit: {:?}", parsed);
+        };
+        assert!((processing_delay - 0.012).abs() < 1e-6);
+
+        assert_eq!(
+            RecordedEvent::from_event(&Events::SetBPM(90.), frame_time_s, &loops),
+            Some(RecordedEvent::SetBpm { bpm: 90. })
+        );
+        assert_eq!(
+            RecordedEvent::from_event(&Events::ToggleHelpVisibility, frame_time_s, &loops),
+            None
+        );
+    }
+}
@@ -2,11 +2,18 @@
   Computes score by comparing timings of user's hits vs desired hits.
 */
 
-use std::{collections::HashMap, vec};
+use std::{
+    collections::{BTreeMap, HashMap, HashSet},
+    vec,
+};
+
+use serde::{Deserialize, Serialize};
 
 use crate::{
+    beat::Beat,
     consts::UserHit,
     consts::ALL_INSTRUMENTS,
+    gap_click::GapClickConfig,
     voices::{Instrument, Voices},
 };
 
@@ -17,21 +24,126 @@ pub enum Accuracy {
     Late,
     Miss,
     Unknown,
+    /// a user hit that didn't match any desired note
+    Extra,
+    /// a desired note that was hit near the right time, but on a commonly confused instrument
+    WrongDrum,
+}
+
+/// Pairs of instruments that are easy to mix up (e.g. closed vs open hi-hat).
+/// A hit on one, where the other was written, counts as `Accuracy::WrongDrum` rather than a miss plus an extra hit.
+#[derive(Debug, PartialEq, Clone, Serialize, Deserialize)]
+pub struct ConfusionSet {
+    pub pairs: Vec<(Instrument, Instrument)>,
+}
+
+impl Default for ConfusionSet {
+    fn default() -> Self {
+        Self {
+            pairs: vec![
+                (Instrument::ClosedHihat, Instrument::OpenHihat),
+                (Instrument::ClosedHihat, Instrument::PedalHiHat),
+                (Instrument::Ride, Instrument::Crash),
+                (Instrument::Snare, Instrument::Tom1),
+                (Instrument::Tom1, Instrument::Tom2),
+                (Instrument::Tom2, Instrument::Tom3),
+            ],
+        }
+    }
 }
 
-// TODO: consider using Decimal type for exact math on beats.
-// - Floating point math has comparison/equality challenges
-// - Can't hash floating point numbers out of the gate
+impl ConfusionSet {
+    pub fn are_confusable(&self, a: Instrument, b: Instrument) -> bool {
+        self.pairs
+            .iter()
+            .any(|(x, y)| (*x == a && *y == b) || (*x == b && *y == a))
+    }
+}
 
-// TODO: Can I use cvars to tweak these vals at runtime?
-pub const CORRECT_MARGIN: f64 = 0.151; // TODO: hacky fix 0.15 -> 0.151 due to floating point comparison. let's try Decimal later
-pub const MISS_MARGIN: f64 = 0.3;
+// Scoring is done on exact beat positions. User hits are measured on a continuous clock,
+// so they're rounded to the nearest tick (well under a millisecond at typical tempos) before comparing.
+
+/// How far a hit can be from its note, at a particular tempo
+#[derive(Debug, PartialEq, Copy, Clone, Serialize, Deserialize)]
+pub struct TimingMargins {
+    pub correct: Beat,
+    pub miss: Beat,
+}
+
+/// Timing windows in milliseconds, so they're equally strict at any tempo
+#[derive(Debug, PartialEq, Copy, Clone, Serialize, Deserialize)]
+pub struct TimingWindows {
+    pub correct_ms: f64,
+    pub miss_ms: f64,
+    /// at fast tempos, keep the miss window under this fraction of a beat, so it doesn't swallow neighboring notes
+    pub max_miss_beats: Option<f64>,
+}
+
+impl Default for TimingWindows {
+    fn default() -> Self {
+        Difficulty::Normal.timing_windows()
+    }
+}
+
+impl TimingWindows {
+    pub fn margins(&self, bpm: f64) -> TimingMargins {
+        let beats_per_ms = bpm * 2. / 60_000.;
+        let mut miss = self.miss_ms * beats_per_ms;
+        if let Some(max_miss_beats) = self.max_miss_beats {
+            miss = miss.min(max_miss_beats);
+        }
+        TimingMargins {
+            correct: Beat::from_f64((self.correct_ms * beats_per_ms).min(miss)),
+            miss: Beat::from_f64(miss),
+        }
+    }
+
+    /// the preset these windows match, if any
+    pub fn difficulty(&self) -> Option<Difficulty> {
+        Difficulty::ALL
+            .into_iter()
+            .find(|d| d.timing_windows() == *self)
+    }
+}
+
+#[derive(Debug, PartialEq, Copy, Clone)]
+pub enum Difficulty {
+    Easy,
+    Normal,
+    Strict,
+}
+
+impl Difficulty {
+    pub const ALL: [Difficulty; 3] = [Difficulty::Easy, Difficulty::Normal, Difficulty::Strict];
+
+    pub fn name(&self) -> &str {
+        match self {
+            Difficulty::Easy => "Easy",
+            Difficulty::Normal => "Normal",
+            Difficulty::Strict => "Strict",
+        }
+    }
+
+    pub fn timing_windows(&self) -> TimingWindows {
+        let (correct_ms, miss_ms) = match self {
+            Difficulty::Easy => (60., 120.),
+            Difficulty::Normal => (40., 80.),
+            Difficulty::Strict => (25., 50.),
+        };
+        TimingWindows {
+            correct_ms,
+            miss_ms,
+            max_miss_beats: Some(0.5),
+        }
+    }
+}
 
 /// returns a tuple of (accuracy rating, a bool of whether not this measurement is wrapping around to the _next_ loop)
 pub fn compute_accuracy_of_single_hit(
-    user_beat_with_latency: f64,
-    desired_hits: &[f64],
+    user_beat_with_latency: Beat,
+    desired_hits: &[Beat],
     beats_per_loop: usize,
+    margins: &TimingMargins,
 ) -> (Accuracy, bool) {
     // find the nearest desired_hit
     let mut target_beat = None; // should always be a miss
@@ -39,11 +151,11 @@ pub fn compute_accuracy_of_single_hit(
         // if there's no target_beat yet, set it to the first desired hit
         match target_beat {
             None => {
-                target_beat = Some((*desired, user_beat_with_latency - desired));
+                target_beat = Some((*desired, user_beat_with_latency - *desired));
                 continue;
             }
             Some((_, prev_dist)) => {
-                let new_dist = user_beat_with_latency - desired;
+                let new_dist = user_beat_with_latency - *desired;
                 if new_dist.abs() < prev_dist.abs() {
                     target_beat = Some((*desired, new_dist));
                 }
@@ -53,8 +165,8 @@ pub fn compute_accuracy_of_single_hit(
 
     // handle end of loop wrap-around case
     let mut is_next_loop = false;
-    if desired_hits.contains(&0.) {
-        let desired = 0. + beats_per_loop as f64;
+    if desired_hits.contains(&Beat::ZERO) {
+        let desired = Beat::from_beats(beats_per_loop as i64);
         // if there's no target_beat yet, set it to the first desired hit
         match target_beat {
             None => {
@@ -75,14 +187,58 @@ pub fn compute_accuracy_of_single_hit(
         Some((b, _)) => {
             log::debug!("Target beat found: {:?}", b);
             let distance = user_beat_with_latency - b;
-            let acc = match distance {
-                d if d.abs() > MISS_MARGIN => Accuracy::Miss,
-                d if d < -CORRECT_MARGIN => Accuracy::Early,
-                d if d > CORRECT_MARGIN => Accuracy::Late,
-                _ => Accuracy::Correct,
-            };
-
-            (acc, is_next_loop)
+            (accuracy_for_distance(distance, margins), is_next_loop)
+        }
+    }
+}
+
+/// accuracy of a hit, given its signed distance from the target note
+fn accuracy_for_distance(distance: Beat, margins: &TimingMargins) -> Accuracy {
+    match distance {
+        d if d.abs() > margins.miss => Accuracy::Miss,
+        d if d < -margins.correct => Accuracy::Early,
+        d if d > margins.correct => Accuracy::Late,
+        _ => Accuracy::Correct,
+    }
+}
+
+/// Overall grade for a loop (see DESIGN.md, "Displaying accuracy"). Ordered from worst to best.
+#[derive(Debug, PartialEq, Eq, PartialOrd, Ord, Copy, Clone, Serialize, Deserialize)]
+pub enum Grade {
+    NeedsWork,
+    Good,
+    Great,
+    Ace,
+}
+
+impl Grade {
+    pub const ALL: [Grade; 4] = [Grade::Ace, Grade::Great, Grade::Good, Grade::NeedsWork];
+
+    pub fn name(&self) -> &str {
+        match self {
+            Grade::Ace => "Ace",
+            Grade::Great => "Great",
+            Grade::Good => "Good",
+            Grade::NeedsWork => "Needs Work",
+        }
+    }
+}
+
+#[derive(Debug, PartialEq, Copy, Clone, Serialize, Deserialize)]
+pub struct GradeThresholds {
+    // "great": at least this ratio correct, less than this ratio early or late, and nothing missed
+    pub great_min_correct: f64,
+    pub great_max_early_late: f64,
+    // "good": at least this score
+    pub good_min_score: f64,
+}
+
+impl Default for GradeThresholds {
+    fn default() -> Self {
+        Self {
+            great_min_correct: 0.9,
+            great_max_early_late: 0.1,
+            good_min_score: 0.7,
         }
     }
 }
@@ -97,7 +253,7 @@ impl Accuracies {
         Self { accuracies: vec![] }
     }
 
-    // score is given as a ratio, from 0 to 1
+    // score is given as a ratio, from 0 to 1. extra hits count against it like missed notes.
     pub fn score(&self) -> f64 {
         let num_correct = self
             .accuracies
@@ -112,11 +268,46 @@ impl Accuracies {
             .filter(|b| *b)
             .count();
 
+        // every entry is either a desired note or an extra hit
         let num_notes = self.accuracies.len();
+        if num_notes == 0 {
+            return 0.;
+        }
 
         // Consider near-hits as partial success instead of ONLY correct
         (1. * num_correct as f64 + 0.5 * num_close as f64) / num_notes as f64
     }
+
+    pub fn count(&self, acc: Accuracy) -> usize {
+        self.accuracies.iter().filter(|a| **a == acc).count()
+    }
+
+    pub fn grade(&self, thresholds: &GradeThresholds) -> Grade {
+        let num_correct = self.count(Accuracy::Correct);
+        let num_early_late = self.count(Accuracy::Early) + self.count(Accuracy::Late);
+        let num_wrong_drum = self.count(Accuracy::WrongDrum);
+        let num_missed = self.count(Accuracy::Miss) + self.count(Accuracy::Extra) + num_wrong_drum;
+        let num_notes = num_correct + num_early_late + self.count(Accuracy::Miss) + num_wrong_drum;
+        if num_notes == 0 {
+            return Grade::NeedsWork;
+        }
+
+        let correct_ratio = num_correct as f64 / num_notes as f64;
+        let early_late_ratio = num_early_late as f64 / num_notes as f64;
+
+        if num_correct == num_notes && num_missed == 0 {
+            Grade::Ace
+        } else if correct_ratio >= thresholds.great_min_correct
+            && early_late_ratio < thresholds.great_max_early_late
+            && num_missed == 0
+        {
+            Grade::Great
+        } else if self.score() >= thresholds.good_min_score {
+            Grade::Good
+        } else {
+            Grade::NeedsWork
+        }
+    }
 }
 
 #[derive(Debug)]
@@ -157,7 +348,7 @@ impl LastLoopSummary {
         }
     }
 
-    pub fn combined(self) -> Accuracies {
+    pub fn combined(&self) -> Accuracies {
         let mut all_acc = vec![];
 
         for ins in ALL_INSTRUMENTS.iter() {
@@ -171,6 +362,10 @@ impl LastLoopSummary {
             accuracies: all_acc,
         }
     }
+
+    pub fn grade(&self, thresholds: &GradeThresholds) -> Grade {
+        self.combined().grade(thresholds)
+    }
 }
 
 pub fn get_user_hit_timings_by_instrument(
@@ -185,63 +380,216 @@ pub fn get_user_hit_timings_by_instrument(
         .collect::<Vec<f64>>()
 }
 
-/// given timings for desired hits vs user hits, gives an accuracy for each desired hit
-/// the accuracy is based on the first user hit that's within "non miss" range of a desired hit
-/// TODO: This system doesn't work if beats are closer together than MISS_MARGIN (perhaps: 32nd notes?)
+/// positions of the user's hits, rounded to exact beats so they can be scored
+fn to_exact_beats(timings: &[f64]) -> Vec<Beat> {
+    timings.iter().map(|t| Beat::from_f64(*t)).collect()
+}
+
+/// The result of scoring a single voice
+#[derive(Debug, PartialEq)]
+pub struct VoicePerformance {
+    /// an accuracy for each desired hit, followed by an `Accuracy::Extra` for each unmatched user hit
+    pub accuracies: Vec<Accuracy>,
+    /// positions of the unmatched user hits, in the same order as their `Accuracy::Extra`s
+    pub extra_hits: Vec<Beat>,
+}
+
+/// given timings for desired hits vs user hits, gives an accuracy for each desired hit,
+/// followed by an `Accuracy::Extra` for each user hit that didn't match a desired hit.
+///
+/// Each user hit is matched to at most one desired hit, in order: a later hit never matches an earlier note.
+/// Of those matchings, the one with the most matches is used, then the one closest in total, so dense passages
+/// (e.g. 32nd notes) played consistently early or late still match each hit to its own note.
 pub fn compute_loop_performance_for_voice(
-    user_hits: &Vec<f64>,
-    desired_hits: &Vec<f64>,
-    loop_current_beat: f64,
+    user_hits: &[Beat],
+    desired_hits: &[Beat],
+    loop_current_beat: Beat,
     beats_per_loop: usize,
+    margins: &TimingMargins,
     // TODO: consider audio_latency
-) -> Vec<Accuracy> {
-    let mut out = Vec::new();
+) -> VoicePerformance {
+    let bpl = Beat::from_beats(beats_per_loop as i64);
+    let mut notes: Vec<(usize, Beat)> = desired_hits.iter().copied().enumerate().collect();
+    notes.sort_by_key(|(_, beat)| *beat);
+    let mut hits: Vec<(usize, Beat)> = user_hits
+        .iter()
+        .map(|hit| nearest_loop_position(*hit, desired_hits, bpl))
+        .enumerate()
+        .collect();
+    hits.sort_by_key(|(_, beat)| *beat);
+
+    // best[i][j] is the most matches, then the least total distance, between the first i notes and first j hits
+    let mut best = vec![vec![(0, Beat::ZERO); hits.len() + 1]; notes.len() + 1];
+    for i in 1..=notes.len() {
+        for j in 1..=hits.len() {
+            let mut candidate = better_matching(best[i - 1][j], best[i][j - 1]);
+            let distance = hits[j - 1].1 - notes[i - 1].1;
+            if distance.abs() <= margins.miss {
+                let (matches, total) = best[i - 1][j - 1];
+                candidate = better_matching(candidate, (matches + 1, total + distance.abs()));
+            }
+            best[i][j] = candidate;
+        }
+    }
+
+    // walk back through the table to find which pairs were matched
+    let mut desired_matches: Vec<Option<Beat>> = vec![None; desired_hits.len()];
+    let mut user_matched = vec![false; user_hits.len()];
+    let (mut i, mut j) = (notes.len(), hits.len());
+    while i > 0 && j > 0 {
+        let distance = hits[j - 1].1 - notes[i - 1].1;
+        let (matches, total) = best[i - 1][j - 1];
+        if distance.abs() <= margins.miss && best[i][j] == (matches + 1, total + distance.abs()) {
+            desired_matches[notes[i - 1].0] = Some(distance);
+            user_matched[hits[j - 1].0] = true;
+            i -= 1;
+            j -= 1;
+        } else if best[i][j] == best[i - 1][j] {
+            i -= 1;
+        } else {
+            j -= 1;
+        }
+    }
 
-    // compare that to desired hits for hihat
-    for desired_hit in desired_hits {
+    let mut accuracies = Vec::new();
+    for (desired_hit, matched) in desired_hits.iter().zip(desired_matches) {
         if *desired_hit > loop_current_beat {
-            out.push(Accuracy::Unknown);
+            accuracies.push(Accuracy::Unknown);
             continue;
         }
 
-        // find the first user hit that a non-miss
-        let mut was_miss = true;
-        for user_hit in user_hits {
-            let (acc, _) =
-                compute_accuracy_of_single_hit(*user_hit, &[*desired_hit], beats_per_loop);
-            if acc != Accuracy::Miss {
-                was_miss = false;
-                out.push(acc);
-                break;
-            }
-        }
-        if was_miss {
-            out.push(Accuracy::Miss);
+        accuracies.push(match matched {
+            Some(distance) => accuracy_for_distance(distance, margins),
+            None => Accuracy::Miss,
+        });
+    }
+
+    let mut extra_hits = vec![];
+    for (user_hit, is_matched) in user_hits.iter().zip(user_matched) {
+        if !is_matched {
+            accuracies.push(Accuracy::Extra);
+            extra_hits.push(*user_hit);
         }
     }
 
-    out
+    VoicePerformance {
+        accuracies,
+        extra_hits,
+    }
 }
 
-pub fn compute_last_loop_summary(
+/// more matches is better, then less total distance
+fn better_matching(a: (usize, Beat), b: (usize, Beat)) -> (usize, Beat) {
+    if b.0 > a.0 || (b.0 == a.0 && b.1 < a.1) {
+        b
+    } else {
+        a
+    }
+}
+
+/// the user hit, moved by a whole loop if that brings it nearer a desired hit (e.g. a hit just before the loop
+/// restarts, for its first note)
+fn nearest_loop_position(user_hit: Beat, desired_hits: &[Beat], bpl: Beat) -> Beat {
+    let distance_to_notes = |hit: &Beat| desired_hits.iter().map(|d| (*hit - *d).abs()).min();
+    [user_hit, user_hit - bpl, user_hit + bpl]
+        .into_iter()
+        .min_by_key(|hit| distance_to_notes(hit).unwrap_or(Beat::ZERO))
+        .unwrap_or(user_hit)
+}
+
+/// scores every voice, up to the current beat of the loop.
+/// missed notes with an extra hit nearby on a confusable instrument are marked `Accuracy::WrongDrum`, and that extra hit is dropped.
+pub fn compute_loop_summary(
     user_hits: &[UserHit],
     desired_hits: &Voices,
+    loop_current_beat: Beat,
     beats_per_loop: usize,
+    margins: &TimingMargins,
+    confusion_set: &ConfusionSet,
 ) -> LastLoopSummary {
-    let mut out = LastLoopSummary::new();
-
+    let mut performances: HashMap<Instrument, VoicePerformance> = HashMap::new();
     for instrument in ALL_INSTRUMENTS.iter() {
-        // get accuracy of hihat
-        let user_timings =
-            get_user_hit_timings_by_instrument(user_hits, *instrument, beats_per_loop);
+        let user_timings = to_exact_beats(&get_user_hit_timings_by_instrument(
+            user_hits,
+            *instrument,
+            beats_per_loop,
+        ));
         let desired_timings = desired_hits.get_instrument_beats(instrument);
 
-        let accuracies = compute_loop_performance_for_voice(
+        let performance = compute_loop_performance_for_voice(
             &user_timings,
             desired_timings,
-            beats_per_loop as f64, // "current beat" is the end of the loop
+            loop_current_beat,
             beats_per_loop,
+            margins,
         );
+        performances.insert(*instrument, performance);
+    }
+
+    // pair missed notes with extra hits on a confusable instrument, closest first
+    let mut candidates = vec![];
+    for instrument in ALL_INSTRUMENTS.iter() {
+        let desired_timings = desired_hits.get_instrument_beats(instrument);
+        let accuracies = &performances[instrument].accuracies;
+        for (desired_idx, desired_hit) in desired_timings.iter().enumerate() {
+            if accuracies[desired_idx] != Accuracy::Miss {
+                continue;
+            }
+            for other in ALL_INSTRUMENTS.iter() {
+                if !confusion_set.are_confusable(*instrument, *other) {
+                    continue;
+                }
+                for (extra_idx, extra_hit) in performances[other].extra_hits.iter().enumerate() {
+                    let distance = signed_offset_from_nearest_note(
+                        *extra_hit,
+                        &[*desired_hit],
+                        beats_per_loop,
+                    );
+                    if let Some(distance) = distance {
+                        if distance.abs() <= margins.miss {
+                            candidates.push((
+                                *instrument,
+                                desired_idx,
+                                *other,
+                                extra_idx,
+                                distance,
+                            ));
+                        }
+                    }
+                }
+            }
+        }
+    }
+    candidates.sort_by_key(|c| c.4.abs());
+
+    let mut wrong_drums: HashSet<(Instrument, usize)> = HashSet::new();
+    let mut used_extras: HashSet<(Instrument, usize)> = HashSet::new();
+    for (instrument, desired_idx, other, extra_idx, _) in candidates {
+        if wrong_drums.contains(&(instrument, desired_idx))
+            || used_extras.contains(&(other, extra_idx))
+        {
+            continue;
+        }
+        wrong_drums.insert((instrument, desired_idx));
+        used_extras.insert((other, extra_idx));
+    }
+
+    let mut out = LastLoopSummary::new();
+    for instrument in ALL_INSTRUMENTS.iter() {
+        let num_desired = desired_hits.get_instrument_beats(instrument).len();
+        let performance = &performances[instrument];
+        let mut accuracies = vec![];
+        for (idx, acc) in performance.accuracies.iter().enumerate() {
+            if idx < num_desired {
+                if wrong_drums.contains(&(*instrument, idx)) {
+                    accuracies.push(Accuracy::WrongDrum);
+                } else {
+                    accuracies.push(*acc);
+                }
+            } else if !used_extras.contains(&(*instrument, idx - num_desired)) {
+                accuracies.push(*acc);
+            }
+        }
 
         out.set_score_tracker(instrument, Accuracies { accuracies });
     }
@@ -249,17 +597,35 @@ pub fn compute_last_loop_summary(
     out
 }
 
+pub fn compute_last_loop_summary(
+    user_hits: &[UserHit],
+    desired_hits: &Voices,
+    beats_per_loop: usize,
+    margins: &TimingMargins,
+    confusion_set: &ConfusionSet,
+) -> LastLoopSummary {
+    compute_loop_summary(
+        user_hits,
+        desired_hits,
+        Beat::from_beats(beats_per_loop as i64), // "current beat" is the end of the loop
+        beats_per_loop,
+        margins,
+        confusion_set,
+    )
+}
+
 pub fn get_hits_from_nth_loop(
     user_hits: &[UserHit],
     desired_loop_idx: usize,
     beats_per_loop: usize,
+    margins: &TimingMargins,
 ) -> Vec<UserHit> {
     let last_loop_hits: Vec<UserHit> = user_hits
         .iter()
         .filter(|hit| {
             // include hits from just before start of loop (back to 0 - MISS), since those could be early or on-time hits
             let loop_num_for_hit =
-                ((hit.clock_tick + MISS_MARGIN) / beats_per_loop as f64) as usize;
+                ((hit.clock_tick + margins.miss.as_f64()) / beats_per_loop as f64) as usize;
             loop_num_for_hit == desired_loop_idx
         })
         .cloned()
@@ -267,118 +633,360 @@ pub fn get_hits_from_nth_loop(
     last_loop_hits
 }
 
+/// signed distance from the nearest desired note, considering wrap-around between loops.
+/// positive means the user was late.
+pub fn signed_offset_from_nearest_note(
+    user_beat: Beat,
+    desired_hits: &[Beat],
+    beats_per_loop: usize,
+) -> Option<Beat> {
+    let bpl = Beat::from_beats(beats_per_loop as i64);
+    desired_hits
+        .iter()
+        .flat_map(|d| [*d - bpl, *d, *d + bpl])
+        .map(|d| user_beat - d)
+        .min_by_key(|offset| offset.abs())
+}
+
+/// Timing drift of the user's hits within a single bar
+#[derive(Debug, PartialEq)]
+pub struct BarDrift {
+    pub bar: i64,
+    /// mean signed offset from the nearest desired note, in beats. positive means late.
+    pub mean_offset: f64,
+    pub num_hits: usize,
+}
+
+/// groups user hits by bar, and computes how far they drifted from the desired notes.
+/// used to reveal drift during the silent bars of a gap-click drill.
+pub fn compute_drift_per_bar(
+    user_hits: &[UserHit],
+    desired_hits: &Voices,
+    audio_latency_beats: f64,
+    beats_per_loop: usize,
+) -> Vec<BarDrift> {
+    let mut offsets_by_bar: BTreeMap<i64, Vec<f64>> = BTreeMap::new();
+    for hit in user_hits {
+        let tick = hit.clock_tick + audio_latency_beats;
+        let desired = desired_hits.get_instrument_beats(&hit.instrument);
+        let offset = signed_offset_from_nearest_note(
+            Beat::from_f64(tick % beats_per_loop as f64),
+            desired,
+            beats_per_loop,
+        );
+        if let Some(offset) = offset {
+            // attribute the hit to the bar of the note it was aiming for
+            let bar = GapClickConfig::bar_for_tick(tick - offset.as_f64());
+            offsets_by_bar.entry(bar).or_default().push(offset.as_f64());
+        }
+    }
+
+    offsets_by_bar
+        .into_iter()
+        .map(|(bar, offsets)| BarDrift {
+            bar,
+            mean_offset: offsets.iter().sum::<f64>() / offsets.len() as f64,
+            num_hits: offsets.len(),
+        })
+        .collect()
+}
+
+// within this many ms of the beat on average, a voice is "steady"
+const STEADY_MARGIN_MS: f64 = 5.;
+
+/// signed offsets (in ms) of each user hit on an instrument from its nearest desired note, along with the loop it was in.
+/// hits outside the miss margin aren't aimed at any note, so they're ignored.
+pub fn compute_hit_offsets_ms(
+    user_hits: &[UserHit],
+    desired_hits: &Voices,
+    instrument: Instrument,
+    audio_latency_beats: f64,
+    bpm: f64,
+    beats_per_loop: usize,
+    margins: &TimingMargins,
+) -> Vec<(usize, f64)> {
+    let ms_per_beat = 60. / bpm / 2. * 1000.;
+    let desired = desired_hits.get_instrument_beats(&instrument);
+    user_hits
+        .iter()
+        .filter(|hit| hit.instrument == instrument)
+        .filter_map(|hit| {
+            let tick = hit.clock_tick + audio_latency_beats;
+            let offset = signed_offset_from_nearest_note(
+                Beat::from_f64(tick % beats_per_loop as f64),
+                desired,
+                beats_per_loop,
+            )?;
+            if offset.abs() > margins.miss {
+                return None;
+            }
+            let loop_num = ((tick + margins.miss.as_f64()) / beats_per_loop as f64) as usize;
+            Some((loop_num, offset.as_f64() * ms_per_beat))
+        })
+        .collect()
+}
+
+/// Whether the user tends to play ahead of or behind the beat
+#[derive(Debug, PartialEq, Clone, Copy)]
+pub struct TimingTendency {
+    pub num_hits: usize,
+    /// positive means late (dragging), negative means early (rushing)
+    pub mean_offset_ms: f64,
+    pub std_dev_ms: f64,
+    /// change in the mean offset from one loop to the next
+    pub trend_ms_per_loop: f64,
+}
+
+impl TimingTendency {
+    /// summarizes (loop number, offset in ms) pairs
+    pub fn from_offsets(offsets: &[(usize, f64)]) -> Option<Self> {
+        if offsets.is_empty() {
+            return None;
+        }
+
+        let n = offsets.len() as f64;
+        let mean_offset_ms = offsets.iter().map(|(_, o)| o).sum::<f64>() / n;
+        let variance = offsets
+            .iter()
+            .map(|(_, o)| (o - mean_offset_ms).powi(2))
+            .sum::<f64>()
+            / n;
+
+        // least squares slope of the per-loop means
+        let mut by_loop: BTreeMap<usize, Vec<f64>> = BTreeMap::new();
+        for (loop_num, offset) in offsets {
+            by_loop.entry(*loop_num).or_default().push(*offset);
+        }
+        let points: Vec<(f64, f64)> = by_loop
+            .iter()
+            .map(|(l, os)| (*l as f64, os.iter().sum::<f64>() / os.len() as f64))
+            .collect();
+        let mean_x = points.iter().map(|p| p.0).sum::<f64>() / points.len() as f64;
+        let mean_y = points.iter().map(|p| p.1).sum::<f64>() / points.len() as f64;
+        let covariance: f64 = points.iter().map(|p| (p.0 - mean_x) * (p.1 - mean_y)).sum();
+        let variance_x: f64 = points.iter().map(|p| (p.0 - mean_x).powi(2)).sum();
+        let trend_ms_per_loop = if variance_x > 0. {
+            covariance / variance_x
+        } else {
+            0.
+        };
+
+        Some(Self {
+            num_hits: offsets.len(),
+            mean_offset_ms,
+            std_dev_ms: variance.sqrt(),
+            trend_ms_per_loop,
+        })
+    }
+
+    /// e.g. "rushing by 12 ms" or "steady"
+    pub fn describe(&self) -> String {
+        match self.mean_offset_ms {
+            m if m < -STEADY_MARGIN_MS => format!("rushing by {:.0} ms", -m),
+            m if m > STEADY_MARGIN_MS => format!("dragging by {:.0} ms", m),
+            _ => "steady".to_string(),
+        }
+    }
+}
+
 #[cfg(test)]
 mod tests {
     use crate::{
+        beat::Beat,
         consts::{UserHit, DEFAULT_BEATS_PER_LOOP},
         score::{
-            compute_accuracy_of_single_hit, compute_last_loop_summary, Accuracy, CORRECT_MARGIN,
-            MISS_MARGIN,
+            compute_accuracy_of_single_hit, compute_drift_per_bar, compute_hit_offsets_ms,
+            compute_last_loop_summary, Accuracies, Accuracy, BarDrift, ConfusionSet, Difficulty,
+            Grade, GradeThresholds, TimingMargins, TimingTendency, TimingWindows,
         },
         voices::{Instrument, Voices},
     };
 
     use super::compute_loop_performance_for_voice;
 
+    const CORRECT_MARGIN: Beat = Beat::from_ticks(144); // 0.15 beats
+    const MISS_MARGIN: Beat = Beat::from_ticks(288); // 0.3 beats
+    const MARGINS: TimingMargins = TimingMargins {
+        correct: CORRECT_MARGIN,
+        miss: MISS_MARGIN,
+    };
+    const ONE_TICK: Beat = Beat::from_ticks(1);
+
+    fn beats(positions: &[f64]) -> Vec<Beat> {
+        positions.iter().map(|p| Beat::from_f64(*p)).collect()
+    }
+
     //
     // compute_accuracy_of_single_hit
     //
 
     #[test]
     fn it_computes_accuracy_against_one_note() {
-        let compute_accuracy_legacy = |user_beat_with_latency: f64, desired_hits: &Vec<f64>| {
+        let compute_accuracy = |user_beat_with_latency: Beat, desired_hits: &[Beat]| {
             compute_accuracy_of_single_hit(
                 user_beat_with_latency,
                 desired_hits,
                 DEFAULT_BEATS_PER_LOOP,
+                &MARGINS,
             )
             .0
         };
+        let desired_hits = [Beat::ZERO];
 
         // exactly correct
-        let result = compute_accuracy_legacy(0.0, &vec![0.0]);
+        let result = compute_accuracy(Beat::ZERO, &desired_hits);
         assert_eq!(result, Accuracy::Correct);
 
         // within (at) the correct margin
-        let result = compute_accuracy_legacy(CORRECT_MARGIN, &vec![0.0]);
+        let result = compute_accuracy(CORRECT_MARGIN, &desired_hits);
         assert_eq!(result, Accuracy::Correct);
 
-        let result = compute_accuracy_legacy(-CORRECT_MARGIN, &vec![0.0]);
+        let result = compute_accuracy(-CORRECT_MARGIN, &desired_hits);
         assert_eq!(result, Accuracy::Correct);
 
         // between the correct margin and the miss margin
-        let late = CORRECT_MARGIN + (MISS_MARGIN - CORRECT_MARGIN) / 2.;
-        let result = compute_accuracy_legacy(late, &vec![0.0]);
+        let late = CORRECT_MARGIN + ONE_TICK;
+        let result = compute_accuracy(late, &desired_hits);
         assert_eq!(result, Accuracy::Late);
 
-        let result = compute_accuracy_legacy(-late, &vec![0.0]);
+        let result = compute_accuracy(-late, &desired_hits);
         assert_eq!(result, Accuracy::Early);
 
         // exactly at the mss margin
         let almost_miss = MISS_MARGIN;
-        let result = compute_accuracy_legacy(almost_miss, &vec![0.0]);
+        let result = compute_accuracy(almost_miss, &desired_hits);
         assert_eq!(result, Accuracy::Late);
 
-        let result = compute_accuracy_legacy(-almost_miss, &vec![0.0]);
+        let result = compute_accuracy(-almost_miss, &desired_hits);
         assert_eq!(result, Accuracy::Early);
 
         // beyond the miss margin
-        let miss = MISS_MARGIN + f64::EPSILON;
-        let result = compute_accuracy_legacy(miss, &vec![0.0]);
+        let miss = MISS_MARGIN + ONE_TICK;
+        let result = compute_accuracy(miss, &desired_hits);
         assert_eq!(result, Accuracy::Miss);
 
-        let result = compute_accuracy_legacy(-miss, &vec![0.0]);
+        let result = compute_accuracy(-miss, &desired_hits);
         assert_eq!(result, Accuracy::Miss);
     }
 
     #[test]
     fn it_computes_accuracy_against_correct_target_note_from_many() {
-        let compute_accuracy_legacy = |user_beat_with_latency: f64, desired_hits: &Vec<f64>| {
+        let compute_accuracy = |user_beat_with_latency: Beat, desired_hits: &[Beat]| {
             compute_accuracy_of_single_hit(
                 user_beat_with_latency,
                 desired_hits,
                 DEFAULT_BEATS_PER_LOOP,
+                &MARGINS,
             )
             .0
         };
+        let desired_hits = beats(&[0.0, 1.0]);
 
-        let beats_per_loop = DEFAULT_BEATS_PER_LOOP as f64;
+        let end_of_loop = Beat::from_beats(DEFAULT_BEATS_PER_LOOP as i64);
         // should check if it's closer to the nearest note: 0.0, not 1.0
-        let result = compute_accuracy_legacy(CORRECT_MARGIN, &vec![0.0, 1.0]);
+        let result = compute_accuracy(CORRECT_MARGIN, &desired_hits);
         assert_eq!(result, Accuracy::Correct);
 
         // handle wrap-around case
-        let result = compute_accuracy_legacy(beats_per_loop - CORRECT_MARGIN, &vec![0.0, 1.0]);
+        let result = compute_accuracy(end_of_loop - CORRECT_MARGIN, &desired_hits);
         assert_eq!(result, Accuracy::Correct);
 
-        let result = compute_accuracy_legacy(
-            beats_per_loop - CORRECT_MARGIN - f64::EPSILON * 5.,
-            &vec![0.0, 1.0],
-        );
+        let result = compute_accuracy(end_of_loop - CORRECT_MARGIN - ONE_TICK, &desired_hits);
         assert_eq!(result, Accuracy::Early);
 
-        let result = compute_accuracy_legacy(beats_per_loop - MISS_MARGIN, &vec![0.0, 1.0]);
+        let result = compute_accuracy(end_of_loop - MISS_MARGIN - ONE_TICK, &desired_hits);
         assert_eq!(result, Accuracy::Miss);
     }
 
     #[test]
     fn it_computes_accuracy_considering_is_next_loop() {
-        let beats_per_loop = DEFAULT_BEATS_PER_LOOP as f64;
+        let end_of_loop = Beat::from_beats(DEFAULT_BEATS_PER_LOOP as i64);
         let result = compute_accuracy_of_single_hit(
-            beats_per_loop - CORRECT_MARGIN,
-            &[0.0],
+            end_of_loop - CORRECT_MARGIN,
+            &[Beat::ZERO],
             DEFAULT_BEATS_PER_LOOP,
+            &MARGINS,
         );
         assert_eq!(result, (Accuracy::Correct, true));
 
         let result = compute_accuracy_of_single_hit(
-            beats_per_loop - CORRECT_MARGIN - f64::EPSILON * 5.,
-            &[0.0],
+            end_of_loop - CORRECT_MARGIN - ONE_TICK,
+            &[Beat::ZERO],
             DEFAULT_BEATS_PER_LOOP,
+            &MARGINS,
         );
         assert_eq!(result, (Accuracy::Early, true));
     }
 
+    #[test]
+    fn it_computes_accuracy_of_triplets() {
+        let triplet = Beat::from_ratio(2, 3);
+        let desired_hits = [Beat::ZERO, triplet, triplet + triplet];
+        let result = compute_accuracy_of_single_hit(
+            Beat::from_f64(2. / 3. + 0.15),
+            &desired_hits,
+            DEFAULT_BEATS_PER_LOOP,
+            &MARGINS,
+        );
+        assert_eq!(result, (Accuracy::Correct, false));
+    }
+
+    //
+    // timing windows
+    //
+
+    #[test]
+    fn it_converts_timing_windows_to_beats_at_any_tempo() {
+        let windows = TimingWindows {
+            correct_ms: 40.,
+            miss_ms: 80.,
+            max_miss_beats: None,
+        };
+
+        // at 60 bpm, a beat (8th note) is 500ms. at 120 bpm, it's 250ms.
+        let slow = windows.margins(60.);
+        assert_eq!(slow.correct, Beat::from_f64(0.08));
+        assert_eq!(slow.miss, Beat::from_f64(0.16));
+        let fast = windows.margins(120.);
+        assert_eq!(fast.correct, Beat::from_f64(0.16));
+        assert_eq!(fast.miss, Beat::from_f64(0.32));
+    }
+
+    #[test]
+    fn it_caps_the_miss_window_at_fast_tempos() {
+        let windows = TimingWindows {
+            correct_ms: 60.,
+            miss_ms: 120.,
+            max_miss_beats: Some(0.5),
+        };
+
+        // a beat is 100ms at 300 bpm
+        let margins = windows.margins(300.);
+        assert_eq!(margins.miss, Beat::from_f64(0.5));
+        assert_eq!(margins.correct, Beat::from_f64(0.5));
+
+        let margins = windows.margins(60.);
+        assert_eq!(margins.miss, Beat::from_f64(0.24));
+    }
+
+    #[test]
+    fn it_recognizes_difficulty_presets() {
+        assert_eq!(
+            TimingWindows::default().difficulty(),
+            Some(Difficulty::Normal)
+        );
+        assert_eq!(
+            Difficulty::Strict.timing_windows().difficulty(),
+            Some(Difficulty::Strict)
+        );
+
+        let custom = TimingWindows {
+            correct_ms: 33.,
+            ..Default::default()
+        };
+        assert_eq!(custom.difficulty(), None);
+    }
+
     //
     // compute_last_loop_summary
     //
@@ -387,9 +995,15 @@ mod tests {
     fn it_computes_last_loop_summary_for_correct_user_htis() {
         let user_hits = vec![UserHit::new(Instrument::Kick, 0.0)];
         let mut desired_hits = Voices::new();
-        desired_hits.toggle_beat(Instrument::Kick, 0.0);
+        desired_hits.toggle_beat(Instrument::Kick, Beat::from_f64(0.0));
 
-        let result = compute_last_loop_summary(&user_hits, &desired_hits, DEFAULT_BEATS_PER_LOOP);
+        let result = compute_last_loop_summary(
+            &user_hits,
+            &desired_hits,
+            DEFAULT_BEATS_PER_LOOP,
+            &MARGINS,
+            &ConfusionSet::default(),
+        );
         assert_eq!(
             result.get_score_tracker(&Instrument::Kick).accuracies,
             vec![Accuracy::Correct],
@@ -400,29 +1014,404 @@ mod tests {
     fn it_computes_last_loop_summary_for_incorrect_user_hits() {
         let user_hits = vec![UserHit::new(Instrument::Kick, 0.5)];
         let mut desired_hits = Voices::new();
-        desired_hits.toggle_beat(Instrument::Kick, 0.0);
+        desired_hits.toggle_beat(Instrument::Kick, Beat::from_f64(0.0));
 
-        let result = compute_last_loop_summary(&user_hits, &desired_hits, DEFAULT_BEATS_PER_LOOP);
+        let result = compute_last_loop_summary(
+            &user_hits,
+            &desired_hits,
+            DEFAULT_BEATS_PER_LOOP,
+            &MARGINS,
+            &ConfusionSet::default(),
+        );
         assert_eq!(
             result.get_score_tracker(&Instrument::Kick).accuracies,
-            vec![Accuracy::Miss],
+            vec![Accuracy::Miss, Accuracy::Extra],
+        );
+    }
+
+    #[test]
+    fn it_detects_hits_on_the_wrong_drum() {
+        let mut desired_hits = Voices::new();
+        desired_hits.toggle_beat(Instrument::Tom1, Beat::ZERO);
+        desired_hits.toggle_beat(Instrument::ClosedHihat, Beat::from_beats(2));
+
+        // snare where tom1 was written, open hi-hat where closed was written, and a kick that isn't confusable
+        let user_hits = vec![
+            UserHit::new(Instrument::Snare, 0.05),
+            UserHit::new(Instrument::OpenHihat, 2.1),
+            UserHit::new(Instrument::Kick, 2.),
+        ];
+
+        let result = compute_last_loop_summary(
+            &user_hits,
+            &desired_hits,
+            DEFAULT_BEATS_PER_LOOP,
+            &MARGINS,
+            &ConfusionSet::default(),
+        );
+        assert_eq!(
+            result.get_score_tracker(&Instrument::Tom1).accuracies,
+            vec![Accuracy::WrongDrum]
+        );
+        assert_eq!(
+            result
+                .get_score_tracker(&Instrument::ClosedHihat)
+                .accuracies,
+            vec![Accuracy::WrongDrum]
+        );
+        assert!(result
+            .get_score_tracker(&Instrument::Snare)
+            .accuracies
+            .is_empty());
+        assert!(result
+            .get_score_tracker(&Instrument::OpenHihat)
+            .accuracies
+            .is_empty());
+        assert_eq!(
+            result.get_score_tracker(&Instrument::Kick).accuracies,
+            vec![Accuracy::Extra]
+        );
+        assert_eq!(result.combined().count(Accuracy::WrongDrum), 2);
+
+        // without a confusion set, it's a miss plus an extra hit
+        let result = compute_last_loop_summary(
+            &user_hits,
+            &desired_hits,
+            DEFAULT_BEATS_PER_LOOP,
+            &MARGINS,
+            &ConfusionSet { pairs: vec![] },
+        );
+        assert_eq!(
+            result.get_score_tracker(&Instrument::Tom1).accuracies,
+            vec![Accuracy::Miss]
+        );
+        assert_eq!(
+            result.get_score_tracker(&Instrument::Snare).accuracies,
+            vec![Accuracy::Extra]
         );
     }
 
     #[test]
     fn it_computes_loop_performance_for_voice() {
-        let user_hits = vec![0.5, 0.6, 0.8];
-        let desired_hits = vec![0.0, 0.5, 1.0];
-        let loop_current_beat = 4.;
+        let user_hits = beats(&[0.5, 0.6, 0.8]);
+        let desired_hits = beats(&[0.0, 0.5, 1.0]);
+        let loop_current_beat = Beat::from_beats(4);
         let result = compute_loop_performance_for_voice(
             &user_hits,
             &desired_hits,
             loop_current_beat,
             DEFAULT_BEATS_PER_LOOP,
+            &MARGINS,
+        )
+        .accuracies;
+        assert_eq!(
+            result,
+            vec![
+                Accuracy::Miss,
+                Accuracy::Correct,
+                Accuracy::Early,
+                Accuracy::Extra
+            ]
+        );
+    }
+
+    #[test]
+    fn it_matches_each_user_hit_to_one_desired_hit() {
+        // one hit between two close notes can't satisfy both
+        let result = compute_loop_performance_for_voice(
+            &beats(&[0.1]),
+            &beats(&[0.0, 0.25]),
+            Beat::from_beats(4),
+            DEFAULT_BEATS_PER_LOOP,
+            &MARGINS,
+        )
+        .accuracies;
+        assert_eq!(result, vec![Accuracy::Correct, Accuracy::Miss]);
+    }
+
+    #[test]
+    fn it_scores_dense_16th_notes() {
+        // 16th notes (half a beat apart), played slightly late, with a flam on the last one
+        let desired_hits: Vec<f64> = (0..8).map(|i| i as f64 * 0.5).collect();
+        let mut user_hits: Vec<f64> = desired_hits.iter().map(|b| b + 0.05).collect();
+        user_hits.push(3.45);
+        let (desired_hits, user_hits) = (beats(&desired_hits), beats(&user_hits));
+
+        let result = compute_loop_performance_for_voice(
+            &user_hits,
+            &desired_hits,
+            Beat::from_beats(4),
+            DEFAULT_BEATS_PER_LOOP,
+            &MARGINS,
+        )
+        .accuracies;
+        let mut expected = vec![Accuracy::Correct; 8];
+        expected.push(Accuracy::Extra);
+        assert_eq!(result, expected);
+    }
+
+    #[test]
+    fn it_scores_dense_32nd_notes() {
+        // 32nd notes are closer together than MISS_MARGIN
+        let desired_hits: Vec<Beat> = (0..8).map(|i| Beat::from_f64(i as f64 * 0.25)).collect();
+
+        // every note played, slightly early
+        let user_hits: Vec<Beat> = desired_hits
+            .iter()
+            .map(|b| *b - Beat::from_f64(0.1))
+            .collect();
+        let result = compute_loop_performance_for_voice(
+            &user_hits,
+            &desired_hits,
+            Beat::from_beats(4),
+            DEFAULT_BEATS_PER_LOOP,
+            &MARGINS,
+        )
+        .accuracies;
+        assert_eq!(result, vec![Accuracy::Correct; 8]);
+
+        // every other note played. the skipped notes are missed, rather than credited to a neighbor's hit.
+        let user_hits: Vec<Beat> = desired_hits.iter().step_by(2).copied().collect();
+        let result = compute_loop_performance_for_voice(
+            &user_hits,
+            &desired_hits,
+            Beat::from_beats(4),
+            DEFAULT_BEATS_PER_LOOP,
+            &MARGINS,
+        )
+        .accuracies;
+        assert_eq!(
+            result,
+            [Accuracy::Correct, Accuracy::Miss]
+                .iter()
+                .cycle()
+                .take(8)
+                .copied()
+                .collect::<Vec<Accuracy>>()
+        );
+    }
+
+    #[test]
+    fn it_scores_dense_32nd_notes_played_consistently_late() {
+        let desired_hits: Vec<Beat> = (0..8).map(|i| Beat::from_f64(i as f64 * 0.25)).collect();
+
+        // each hit is nearer the next note than its own, but still inside the correct window
+        let user_hits: Vec<Beat> = desired_hits
+            .iter()
+            .map(|b| *b + Beat::from_f64(0.14))
+            .collect();
+        let result = compute_loop_performance_for_voice(
+            &user_hits,
+            &desired_hits,
+            Beat::from_beats(4),
+            DEFAULT_BEATS_PER_LOOP,
+            &MARGINS,
+        );
+        assert_eq!(result.accuracies, vec![Accuracy::Correct; 8]);
+        assert_eq!(result.extra_hits, vec![]);
+    }
+
+    #[test]
+    fn it_matches_hits_in_order_over_the_closest_pair() {
+        // 0.16 is closest to the note at 0.3, but then the note at 0 would go unmatched
+        let result = compute_loop_performance_for_voice(
+            &beats(&[0.16, 0.45]),
+            &beats(&[0., 0.3]),
+            Beat::from_beats(4),
+            DEFAULT_BEATS_PER_LOOP,
+            &MARGINS,
         );
+        assert_eq!(result.accuracies, vec![Accuracy::Late, Accuracy::Correct]);
+        assert_eq!(result.extra_hits, vec![]);
+    }
+
+    #[test]
+    fn it_grades_a_loop() {
+        let grade = |accuracies: Vec<Accuracy>| {
+            Accuracies { accuracies }.grade(&GradeThresholds::default())
+        };
+
+        assert_eq!(grade(vec![Accuracy::Correct; 10]), Grade::Ace);
+
+        let mut one_late = vec![Accuracy::Correct; 19];
+        one_late.push(Accuracy::Late);
+        assert_eq!(grade(one_late.clone()), Grade::Great);
+
+        // an extra hit means it's not great, even if all notes were hit
+        one_late.push(Accuracy::Extra);
+        assert_eq!(grade(one_late), Grade::Good);
+
+        let mut one_miss = vec![Accuracy::Correct; 9];
+        one_miss.push(Accuracy::Miss);
+        assert_eq!(grade(one_miss), Grade::Good);
+
+        assert_eq!(
+            grade(vec![Accuracy::Correct, Accuracy::Miss, Accuracy::Miss]),
+            Grade::NeedsWork
+        );
+        assert_eq!(grade(vec![]), Grade::NeedsWork);
+    }
+
+    #[test]
+    fn it_grades_with_configurable_thresholds() {
+        let mut accuracies = vec![Accuracy::Correct; 8];
+        accuracies.push(Accuracy::Early);
+        accuracies.push(Accuracy::Late);
+        let accuracies = Accuracies { accuracies };
+
+        assert_eq!(accuracies.grade(&GradeThresholds::default()), Grade::Good);
+        let lenient = GradeThresholds {
+            great_min_correct: 0.8,
+            great_max_early_late: 0.25,
+            ..Default::default()
+        };
+        assert_eq!(accuracies.grade(&lenient), Grade::Great);
+    }
+
+    #[test]
+    fn it_penalizes_extra_hits_in_score() {
+        let accuracies = Accuracies {
+            accuracies: vec![Accuracy::Correct, Accuracy::Correct, Accuracy::Extra],
+        };
+        assert_eq!(accuracies.score(), 2. / 3.);
+    }
+
+    #[test]
+    fn it_scores_a_swung_take_against_swung_positions() {
+        let mut desired_hits = Voices::new();
+        desired_hits.toggle_beat(Instrument::ClosedHihat, Beat::from_f64(0.0));
+        desired_hits.toggle_beat(Instrument::ClosedHihat, Beat::from_f64(1.0));
+
+        // off-beat played with a triplet feel
+        let user_hits = vec![
+            UserHit::new(Instrument::ClosedHihat, 0.0),
+            UserHit::new(Instrument::ClosedHihat, 1.33),
+        ];
+
+        let straight = compute_last_loop_summary(
+            &user_hits,
+            &desired_hits,
+            DEFAULT_BEATS_PER_LOOP,
+            &MARGINS,
+            &ConfusionSet::default(),
+        );
+        assert_eq!(
+            straight
+                .get_score_tracker(&Instrument::ClosedHihat)
+                .accuracies,
+            vec![Accuracy::Correct, Accuracy::Miss, Accuracy::Extra],
+        );
+
+        let swung = compute_last_loop_summary(
+            &user_hits,
+            &desired_hits.swung(2. / 3.),
+            DEFAULT_BEATS_PER_LOOP,
+            &MARGINS,
+            &ConfusionSet::default(),
+        );
+        assert_eq!(
+            swung.get_score_tracker(&Instrument::ClosedHihat).accuracies,
+            vec![Accuracy::Correct, Accuracy::Correct],
+        );
+    }
+
+    //
+    // compute_drift_per_bar
+    //
+
+    #[test]
+    fn it_computes_drift_per_bar() {
+        let mut desired_hits = Voices::new();
+        desired_hits.toggle_beat(Instrument::Kick, Beat::from_f64(0.0));
+        desired_hits.toggle_beat(Instrument::Kick, Beat::from_f64(4.0));
+        desired_hits.toggle_beat(Instrument::Kick, Beat::from_f64(8.0));
+
+        let user_hits = vec![
+            UserHit::new(Instrument::Kick, 0.0),
+            UserHit::new(Instrument::Kick, 4.25),
+            UserHit::new(Instrument::Kick, 8.5),
+            // early hit for the next loop's downbeat belongs to that loop's first bar
+            UserHit::new(Instrument::Kick, 15.75),
+        ];
+
+        let result = compute_drift_per_bar(&user_hits, &desired_hits, 0., DEFAULT_BEATS_PER_LOOP);
         assert_eq!(
             result,
-            vec![Accuracy::Miss, Accuracy::Correct, Accuracy::Early]
+            vec![
+                BarDrift {
+                    bar: 0,
+                    mean_offset: 0.125,
+                    num_hits: 2
+                },
+                BarDrift {
+                    bar: 1,
+                    mean_offset: 0.5,
+                    num_hits: 1
+                },
+                BarDrift {
+                    bar: 2,
+                    mean_offset: -0.25,
+                    num_hits: 1
+                },
+            ]
+        );
+    }
+
+    //
+    // timing tendency
+    //
+
+    #[test]
+    fn it_computes_hit_offsets_in_ms() {
+        let mut desired_hits = Voices::new();
+        desired_hits.toggle_beat(Instrument::Kick, Beat::from_f64(0.0));
+        desired_hits.toggle_beat(Instrument::Kick, Beat::from_f64(8.0));
+
+        let user_hits = vec![
+            UserHit::new(Instrument::Kick, 8.1),
+            // early for the 2nd loop's downbeat
+            UserHit::new(Instrument::Kick, 15.9),
+            // not near a kick note
+            UserHit::new(Instrument::Kick, 4.0),
+            UserHit::new(Instrument::Snare, 0.0),
+        ];
+
+        // at 60 bpm, a beat is 500ms
+        let result = compute_hit_offsets_ms(
+            &user_hits,
+            &desired_hits,
+            Instrument::Kick,
+            0.,
+            60.,
+            DEFAULT_BEATS_PER_LOOP,
+            &MARGINS,
         );
+        assert_eq!(result.len(), 2);
+        assert_eq!(result[0].0, 0);
+        assert!((result[0].1 - 50.).abs() < 1e-6);
+        assert_eq!(result[1].0, 1);
+        assert!((result[1].1 + 50.).abs() < 1e-6);
+    }
+
+    #[test]
+    fn it_computes_timing_tendency() {
+        let offsets = vec![(0, -10.), (0, -14.), (1, -12.), (1, -12.)];
+        let tendency = TimingTendency::from_offsets(&offsets).unwrap();
+        assert_eq!(tendency.num_hits, 4);
+        assert_eq!(tendency.mean_offset_ms, -12.);
+        assert!((tendency.std_dev_ms - 2_f64.sqrt()).abs() < 1e-9);
+        assert_eq!(tendency.trend_ms_per_loop, 0.);
+        assert_eq!(tendency.describe(), "rushing by 12 ms");
+
+        // gradually dragging more each loop
+        let offsets = vec![(0, 0.), (1, 5.), (2, 10.), (3, 15.)];
+        let tendency = TimingTendency::from_offsets(&offsets).unwrap();
+        assert_eq!(tendency.trend_ms_per_loop, 5.);
+        assert_eq!(tendency.describe(), "dragging by 8 ms");
+
+        let tendency = TimingTendency::from_offsets(&[(0, 3.), (0, -2.)]).unwrap();
+        assert_eq!(tendency.describe(), "steady");
+
+        assert_eq!(TimingTendency::from_offsets(&[]), None);
     }
 }
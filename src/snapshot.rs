@@ -0,0 +1,280 @@
+/*
+  Snapshot and restore of the game state.
+
+  A snapshot is the current loop (including unsaved edits) and the settings around it, saved as versioned JSON.
+  One is saved automatically on quit and restored at startup. Snapshots can also be saved on demand,
+  optionally with the session's hits, so a bug report can include an exact reproducible state.
+
+  The selected loop is saved by name, so a snapshot still applies after loops are added or removed.
+  The tempo trainer is saved with its progress, so a ramp carries on where it left off. A timed ramp's interval
+  starts over, though, since the time of its last step is from another session's clock.
+*/
+
+use std::{
+    error::Error,
+    fs,
+    path::{Path, PathBuf},
+};
+
+use serde::{Deserialize, Serialize};
+
+use crate::{
+    audio::Audio,
+    config::data_dir,
+    dynamics::DynamicsConfig,
+    game::{Flags, GameState},
+    gap_click::GapClickConfig,
+    journal::{JournalHit, JournalNote},
+    score::{ConfusionSet, GradeThresholds, TimingWindows},
+    tempo_trainer::TempoTrainer,
+    time::current_time_millis,
+    voices::Voices,
+};
+
+/// bump this when the meaning of a field changes
+pub const SNAPSHOT_VERSION: u32 = 1;
+
+/// set this to a snapshot file to start from it, instead of the last autosave
+pub const SNAPSHOT_ENV_VAR: &str = "DRUM_BREAK_SNAPSHOT";
+
+#[derive(Debug, PartialEq, Serialize, Deserialize)]
+pub struct GameSnapshot {
+    pub version: u32,
+    pub selected_loop: String,
+    pub notes: Vec<JournalNote>,
+    pub beats_per_loop: usize,
+    pub bpm: f64,
+    #[serde(alias = "swing")]
+    pub global_swing: f64,
+    #[serde(default)]
+    pub loop_swing: Option<f64>,
+    pub timing_windows: TimingWindows,
+    pub confusion_set: ConfusionSet,
+    pub dynamics_config: DynamicsConfig,
+    pub grade_thresholds: GradeThresholds,
+    pub tempo_trainer: TempoTrainer,
+    pub flags: Flags,
+    pub metronome_enabled: bool,
+    pub gap_click: GapClickConfig,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub user_hits: Option<Vec<JournalHit>>,
+}
+
+impl GameSnapshot {
+    pub fn capture(gs: &GameState, audio: &Audio, include_user_hits: bool) -> Self {
+        Self {
+            version: SNAPSHOT_VERSION,
+            selected_loop: gs.selected_loop_name().to_string(),
+            notes: JournalNote::all_from_voices(&gs.voices),
+            beats_per_loop: gs.beats_per_loop,
+            bpm: audio.get_bpm(),
+            global_swing: gs.global_swing,
+            loop_swing: gs.loop_swing,
+            timing_windows: gs.timing_windows,
+            confusion_set: gs.confusion_set.clone(),
+            dynamics_config: gs.dynamics_config,
+            grade_thresholds: gs.grade_thresholds,
+            tempo_trainer: gs.tempo_trainer.clone(),
+            flags: gs.flags.clone(),
+            metronome_enabled: audio.is_metronome_enabled(),
+            gap_click: *audio.get_gap_click(),
+            user_hits: include_user_hits
+                .then(|| audio.user_hits.iter().map(JournalHit::from).collect()),
+        }
+    }
+
+    pub fn restore(&self, gs: &mut GameState, audio: &mut Audio) {
+        match gs
+            .loops
+            .iter()
+            .position(|(name, _)| *name == self.selected_loop)
+        {
+            Some(idx) => gs.selected_loop_idx = idx,
+            None => log::warn!("the snapshot's loop {:?} doesn't exist", self.selected_loop),
+        }
+        gs.voices = voices_from_notes(&self.notes);
+        gs.beats_per_loop = self.beats_per_loop;
+        gs.global_swing = self.global_swing;
+        gs.loop_swing = self.loop_swing;
+        gs.timing_windows = self.timing_windows;
+        gs.confusion_set = self.confusion_set.clone();
+        gs.dynamics_config = self.dynamics_config;
+        gs.grade_thresholds = self.grade_thresholds;
+        gs.tempo_trainer = self.tempo_trainer.clone();
+        gs.tempo_trainer.set_last_step_time_ms(None);
+        gs.flags = self.flags.clone();
+
+        audio.set_bpm(self.bpm);
+        audio.set_beats_per_loop(self.beats_per_loop);
+        if audio.is_metronome_enabled() != self.metronome_enabled {
+            audio.toggle_metronome();
+        }
+        audio.set_gap_click(self.gap_click);
+        if let Some(hits) = &self.user_hits {
+            audio.user_hits = hits.iter().map(|h| h.to_user_hit()).collect();
+        }
+    }
+
+    pub fn from_json(data: &str) -> Result<Self, Box<dyn Error>> {
+        // check the version first, since newer snapshots may not parse
+        let SnapshotVersion { version } = serde_json::from_str(data)?;
+        if version > SNAPSHOT_VERSION {
+            return Err(format!(
+                "snapshot version {} is newer than supported version {}",
+                version, SNAPSHOT_VERSION
+            )
+            .into());
+        }
+        Ok(serde_json::from_str(data)?)
+    }
+
+    pub fn load(path: &Path) -> Result<Self, Box<dyn Error>> {
+        Self::from_json(&fs::read_to_string(path)?)
+    }
+
+    pub fn save(&self, path: &Path) -> Result<(), Box<dyn Error>> {
+        if let Some(dir) = path.parent() {
+            fs::create_dir_all(dir)?;
+        }
+        fs::write(path, serde_json::to_string_pretty(self)?)?;
+        Ok(())
+    }
+}
+
+#[derive(Deserialize)]
+struct SnapshotVersion {
+    version: u32,
+}
+
+fn voices_from_notes(notes: &[JournalNote]) -> Voices {
+    let mut voices = Voices::new();
+    for note in notes {
+        voices.toggle_beat(note.instrument, note.beat);
+        voices.set_dynamic(note.instrument, note.beat, note.dynamic);
+    }
+    voices
+}
+
+fn snapshots_dir() -> Option<PathBuf> {
+    data_dir().map(|dir| dir.join("snapshots"))
+}
+
+/// saved on quit, and restored at startup
+pub fn autosave_path() -> Option<PathBuf> {
+    snapshots_dir().map(|dir| dir.join("autosave.json"))
+}
+
+/// a new file for a snapshot saved on demand
+pub fn new_snapshot_path() -> Option<PathBuf> {
+    snapshots_dir().map(|dir| dir.join(format!("snapshot-{}.json", current_time_millis())))
+}
+
+/// the snapshot to start from: the one named by `SNAPSHOT_ENV_VAR`, otherwise the last autosave
+pub fn startup_snapshot() -> Option<GameSnapshot> {
+    let (path, is_autosave) = match std::env::var(SNAPSHOT_ENV_VAR) {
+        Ok(path) => (PathBuf::from(path), false),
+        Err(_) => (autosave_path().filter(|p| p.exists())?, true),
+    };
+    match GameSnapshot::load(&path) {
+        Ok(mut snapshot) => {
+            log::info!("restoring snapshot from {:?}", path);
+            // a new session gets its own random dropouts. a snapshot from a bug report keeps the ones it had.
+            if is_autosave {
+                snapshot.gap_click.seed = GapClickConfig::session_seed();
+            }
+            Some(snapshot)
+        }
+        Err(e) => {
+            log::warn!("unable to restore snapshot from {:?}: {}", path, e);
+            None
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::sync::mpsc;
+
+    use crate::{
+        audio::Audio,
+        beat::Beat,
+        config::AppConfig,
+        game::GameState,
+        journal::JournalNote,
+        score::Grade,
+        snapshot::{voices_from_notes, GameSnapshot},
+        tempo_trainer::RampMode,
+        voices::{Dynamic, Instrument, Loop, Voices},
+    };
+
+    #[test]
+    fn it_restores_voices_with_edits_and_dynamics() {
+        let mut voices = Voices::new();
+        voices.toggle_beat(Instrument::Tom2, Beat::from_ratio(4, 3));
+        voices.toggle_beat(Instrument::Snare, Beat::from_beats(2));
+        voices.set_dynamic(Instrument::Snare, Beat::from_beats(2), Dynamic::Accent);
+
+        let notes = JournalNote::all_from_voices(&voices);
+        let restored = voices_from_notes(&notes);
+        assert_eq!(
+            restored.get_instrument_beats(&Instrument::Tom2),
+            &vec![Beat::from_ratio(4, 3)]
+        );
+        assert_eq!(
+            restored.get_dynamic(&Instrument::Snare, Beat::from_beats(2)),
+            Dynamic::Accent
+        );
+        assert_eq!(JournalNote::all_from_voices(&restored), notes);
+    }
+
+    #[test]
+    fn it_rejects_snapshots_from_newer_versions() {
+        let err = GameSnapshot::from_json(r#"{"version": 999, "notes": "changed"}"#).unwrap_err();
+        assert!(err.to_string().contains("newer"));
+    }
+
+    #[test]
+    fn it_restores_the_loop_by_name_and_the_tempo_trainer_progress() {
+        let test_loop: Loop = serde_json::from_str(
+            r#"{"bpm": 100, "length_in_beats": 16, "voices": {"closed_hihat": [], "snare": [], "kick": [], "open_hihat": [], "ride": [], "crash": []}}"#,
+        )
+        .unwrap();
+        let (tx, _rx) = mpsc::channel();
+        let mut audio = Audio::new_replay(&AppConfig::default(), tx);
+        let mut gs = GameState::new(vec![
+            ("rock".to_string(), test_loop.clone()),
+            ("funk".to_string(), test_loop.clone()),
+        ]);
+        gs.selected_loop_idx = 1;
+        gs.tempo_trainer.config.mode = RampMode::Timed {
+            seconds_per_step: 10.,
+        };
+        gs.tempo_trainer
+            .on_loop_completed(0, 1., Grade::Ace, 100., 1_000);
+        gs.tempo_trainer
+            .on_loop_completed(1, 1., Grade::Ace, 100., 2_000);
+
+        let json = serde_json::to_string(&GameSnapshot::capture(&gs, &audio, false)).unwrap();
+        let snapshot = GameSnapshot::from_json(&json).unwrap();
+
+        // a loop was added before it since
+        let mut restored = GameState::new(vec![
+            ("blues".to_string(), test_loop.clone()),
+            ("rock".to_string(), test_loop.clone()),
+            ("funk".to_string(), test_loop),
+        ]);
+        snapshot.restore(&mut restored, &mut audio);
+        assert_eq!(restored.selected_loop_name(), "funk");
+        assert_eq!(restored.tempo_trainer.log, gs.tempo_trainer.log);
+        assert_eq!(restored.tempo_trainer.correct_takes, 2);
+
+        // the saved session's clock doesn't count toward the next timed step, even a day later
+        let next_day_ms = 86_400_000;
+        assert_eq!(
+            restored
+                .tempo_trainer
+                .on_loop_completed(2, 1., Grade::Ace, 100., next_day_ms),
+            None
+        );
+    }
+}
@@ -0,0 +1,300 @@
+/*
+  Tempo trainer. Adjusts the BPM as the user plays, based on how well each loop went (or on elapsed time).
+
+  The default configuration behaves like the original "Gold Mode": after 3 aced loops, go up by 2 BPM.
+*/
+
+use serde::{Deserialize, Serialize};
+
+use crate::score::Grade;
+
+#[derive(Debug, Clone, Copy, PartialEq, Serialize, Deserialize)]
+pub enum RampMode {
+    /// step up after enough successful loops in a row
+    Performance,
+    /// step up every N seconds, regardless of score
+    Timed { seconds_per_step: f64 },
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Serialize, Deserialize)]
+pub struct TempoTrainerConfig {
+    pub enabled: bool,
+    pub mode: RampMode,
+    pub start_bpm: f64,
+    pub target_bpm: f64,
+    pub step_bpm: f64,
+    /// a loop counts as a success if its grade is at least this
+    pub success_grade: Grade,
+    pub success_takes: i32,
+    /// how far to back off after repeated failures. 0 means never back off.
+    pub step_down_bpm: f64,
+    pub failure_takes: i32,
+}
+
+impl Default for TempoTrainerConfig {
+    fn default() -> Self {
+        Self {
+            enabled: true,
+            mode: RampMode::Performance,
+            start_bpm: 60.,
+            target_bpm: 240.,
+            step_bpm: 2.,
+            success_grade: Grade::Ace,
+            success_takes: 3,
+            step_down_bpm: 0.,
+            failure_takes: 3,
+        }
+    }
+}
+
+/// The tempo reached in a single loop
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct TempoLogEntry {
+    pub loop_num: i32,
+    pub bpm: f64,
+    pub score: f64,
+    pub grade: Grade,
+    pub system_time_ms: u128,
+}
+
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct TempoTrainer {
+    pub config: TempoTrainerConfig,
+    pub correct_takes: i32,
+    pub failed_takes: i32,
+    /// true if the last completed loop caused a step up
+    pub was_gold: bool,
+    /// wall time of the last step. not saved, since it means nothing on another day or another machine's clock.
+    #[serde(skip)]
+    last_step_time_ms: Option<u128>,
+    /// tempo reached per loop, for this session
+    pub log: Vec<TempoLogEntry>,
+}
+
+impl TempoTrainer {
+    pub fn new(config: TempoTrainerConfig) -> Self {
+        Self {
+            config,
+            correct_takes: 0,
+            failed_takes: 0,
+            was_gold: false,
+            last_step_time_ms: None,
+            log: vec![],
+        }
+    }
+
+    pub fn set_config(&mut self, config: TempoTrainerConfig) {
+        self.config = config;
+        self.reset_progress();
+    }
+
+    /// clears the progress toward the next step, and returns the BPM to start from
+    pub fn restart(&mut self) -> f64 {
+        self.reset_progress();
+        self.config.start_bpm
+    }
+
+    pub fn last_step_time_ms(&self) -> Option<u128> {
+        self.last_step_time_ms
+    }
+
+    /// with None, a timed ramp's interval starts over from the next loop
+    pub fn set_last_step_time_ms(&mut self, time_ms: Option<u128>) {
+        self.last_step_time_ms = time_ms;
+    }
+
+    fn reset_progress(&mut self) {
+        self.correct_takes = 0;
+        self.failed_takes = 0;
+        self.was_gold = false;
+        self.last_step_time_ms = None;
+    }
+
+    /// records the result of a completed loop. returns a new BPM, if the tempo should change.
+    pub fn on_loop_completed(
+        &mut self,
+        loop_num: i32,
+        score: f64,
+        grade: Grade,
+        bpm: f64,
+        now_ms: u128,
+    ) -> Option<f64> {
+        self.log.push(TempoLogEntry {
+            loop_num,
+            bpm,
+            score,
+            grade,
+            system_time_ms: now_ms,
+        });
+
+        self.was_gold = false;
+        if !self.config.enabled {
+            return None;
+        }
+
+        let last_step_time_ms = *self.last_step_time_ms.get_or_insert(now_ms);
+
+        if grade >= self.config.success_grade {
+            self.correct_takes += 1;
+            self.failed_takes = 0;
+        } else {
+            self.correct_takes = 0;
+            self.failed_takes += 1;
+        }
+
+        let should_step_up = match self.config.mode {
+            RampMode::Performance => self.correct_takes >= self.config.success_takes,
+            RampMode::Timed { seconds_per_step } => {
+                // the wall clock can go backwards, e.g. when it's corrected
+                now_ms.saturating_sub(last_step_time_ms) as f64 / 1000. >= seconds_per_step
+            }
+        };
+
+        if should_step_up {
+            self.reset_progress();
+            self.last_step_time_ms = Some(now_ms);
+            let new_bpm = (bpm + self.config.step_bpm).min(self.config.target_bpm);
+            if new_bpm > bpm {
+                self.was_gold = true;
+                return Some(new_bpm);
+            }
+            return None;
+        }
+
+        if self.config.step_down_bpm > 0. && self.failed_takes >= self.config.failure_takes {
+            self.reset_progress();
+            self.last_step_time_ms = Some(now_ms);
+            let new_bpm = (bpm - self.config.step_down_bpm).max(self.config.start_bpm);
+            if new_bpm < bpm {
+                return Some(new_bpm);
+            }
+        }
+
+        None
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::{
+        score::Grade,
+        tempo_trainer::{RampMode, TempoTrainer, TempoTrainerConfig},
+    };
+
+    #[test]
+    fn it_steps_up_after_enough_successful_loops() {
+        let mut tt = TempoTrainer::new(TempoTrainerConfig::default());
+        assert_eq!(tt.on_loop_completed(0, 1., Grade::Ace, 100., 0), None);
+        assert_eq!(tt.on_loop_completed(1, 1., Grade::Ace, 100., 0), None);
+        assert_eq!(tt.on_loop_completed(2, 1., Grade::Ace, 100., 0), Some(102.));
+        assert!(tt.was_gold);
+
+        // a failure resets the count
+        assert_eq!(tt.on_loop_completed(3, 1., Grade::Ace, 102., 0), None);
+        assert_eq!(tt.on_loop_completed(4, 0.9, Grade::Great, 102., 0), None);
+        assert_eq!(tt.on_loop_completed(5, 1., Grade::Ace, 102., 0), None);
+        assert!(!tt.was_gold);
+        assert_eq!(tt.log.len(), 6);
+    }
+
+    #[test]
+    fn it_uses_the_success_grade_and_caps_at_target() {
+        let mut tt = TempoTrainer::new(TempoTrainerConfig {
+            success_grade: Grade::Great,
+            success_takes: 1,
+            step_bpm: 5.,
+            target_bpm: 103.,
+            ..Default::default()
+        });
+        assert_eq!(tt.on_loop_completed(0, 0.7, Grade::Good, 100., 0), None);
+        assert_eq!(
+            tt.on_loop_completed(1, 0.9, Grade::Great, 100., 0),
+            Some(103.)
+        );
+        assert_eq!(tt.on_loop_completed(2, 1., Grade::Ace, 103., 0), None);
+    }
+
+    #[test]
+    fn it_steps_down_after_repeated_failures() {
+        let mut tt = TempoTrainer::new(TempoTrainerConfig {
+            start_bpm: 90.,
+            step_down_bpm: 4.,
+            failure_takes: 2,
+            ..Default::default()
+        });
+        assert_eq!(
+            tt.on_loop_completed(0, 0.5, Grade::NeedsWork, 100., 0),
+            None
+        );
+        assert_eq!(
+            tt.on_loop_completed(1, 0.5, Grade::NeedsWork, 100., 0),
+            Some(96.)
+        );
+
+        // never goes below the start bpm
+        assert_eq!(tt.on_loop_completed(2, 0.5, Grade::NeedsWork, 92., 0), None);
+        assert_eq!(
+            tt.on_loop_completed(3, 0.5, Grade::NeedsWork, 92., 0),
+            Some(90.)
+        );
+    }
+
+    #[test]
+    fn it_steps_up_over_time_in_timed_mode() {
+        let mut tt = TempoTrainer::new(TempoTrainerConfig {
+            mode: RampMode::Timed {
+                seconds_per_step: 30.,
+            },
+            ..Default::default()
+        });
+        assert_eq!(
+            tt.on_loop_completed(0, 0., Grade::NeedsWork, 100., 1_000),
+            None
+        );
+        assert_eq!(
+            tt.on_loop_completed(1, 0., Grade::NeedsWork, 100., 20_000),
+            None
+        );
+        assert_eq!(
+            tt.on_loop_completed(2, 0., Grade::NeedsWork, 100., 31_000),
+            Some(102.)
+        );
+        assert_eq!(
+            tt.on_loop_completed(3, 0., Grade::NeedsWork, 102., 40_000),
+            None
+        );
+    }
+
+    #[test]
+    fn it_waits_out_a_clock_that_went_backwards_in_timed_mode() {
+        let mut tt = TempoTrainer::new(TempoTrainerConfig {
+            mode: RampMode::Timed {
+                seconds_per_step: 30.,
+            },
+            ..Default::default()
+        });
+        assert_eq!(
+            tt.on_loop_completed(0, 0., Grade::NeedsWork, 100., 50_000),
+            None
+        );
+        assert_eq!(
+            tt.on_loop_completed(1, 0., Grade::NeedsWork, 100., 10_000),
+            None
+        );
+        assert_eq!(
+            tt.on_loop_completed(2, 0., Grade::NeedsWork, 100., 80_000),
+            Some(102.)
+        );
+    }
+
+    #[test]
+    fn it_does_nothing_when_disabled() {
+        let mut tt = TempoTrainer::new(TempoTrainerConfig {
+            enabled: false,
+            success_takes: 1,
+            ..Default::default()
+        });
+        assert_eq!(tt.on_loop_completed(0, 1., Grade::Ace, 100., 0), None);
+        assert_eq!(tt.log.len(), 1);
+    }
+}
@@ -2,8 +2,10 @@
  Helper functions for time.
 
  Notably, we have several kinds of time: Audio manager, Macroquad, and Rust time.
+ Midi devices add another: each stamps its messages in microseconds, from an arbitrary starting point.
 */
 
+use serde::{Deserialize, Serialize};
 use web_time::SystemTime;
 
 // get curent time in milliseconds
@@ -13,3 +15,168 @@ pub fn current_time_millis() -> u128 {
         .unwrap()
         .as_millis()
 }
+
+// get curent time in microseconds
+pub fn current_time_micros() -> u128 {
+    SystemTime::now()
+        .duration_since(web_time::UNIX_EPOCH)
+        .unwrap()
+        .as_micros()
+}
+
+/// wall time in seconds, which events and the audio clock are related by
+pub fn wall_time_seconds() -> f64 {
+    current_time_micros() as f64 / 1_000_000.
+}
+
+/// how much each new reading moves the estimated relation between wall time and the audio clock
+const CLOCK_SYNC_SMOOTHING: f64 = 0.05;
+
+/// readings further than this from the estimate mean the clock jumped (e.g. it restarted), rather than jitter
+const CLOCK_SYNC_MAX_ERROR_S: f64 = 0.05;
+
+/// Relates wall time to the audio clock, so that the moment something happened (e.g. a midi hit)
+/// can be converted into a clock tick.
+///
+/// The audio clock only advances once per audio buffer, so a single reading jitters by a few ms.
+/// Readings are smoothed over many frames.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Serialize, Deserialize)]
+pub struct ClockSync {
+    ticks_per_second: f64,
+    /// the estimated clock tick at wall time 0
+    offset_ticks: Option<f64>,
+}
+
+impl ClockSync {
+    /// records a reading of the audio clock. ticks_per_second is 0 while the clock is paused.
+    pub fn observe(&mut self, wall_time_s: f64, clock_tick: f64, ticks_per_second: f64) {
+        let offset = clock_tick - wall_time_s * ticks_per_second;
+        match self.offset_ticks {
+            Some(prev)
+                if ticks_per_second == self.ticks_per_second
+                    && (offset - prev).abs() <= CLOCK_SYNC_MAX_ERROR_S * ticks_per_second =>
+            {
+                self.offset_ticks = Some(prev + (offset - prev) * CLOCK_SYNC_SMOOTHING);
+            }
+            _ => {
+                // first reading, or the tempo changed or the clock jumped: start over
+                self.ticks_per_second = ticks_per_second;
+                self.offset_ticks = Some(offset);
+            }
+        }
+    }
+
+    /// the clock tick at the given wall time, if the clock has been observed
+    pub fn tick_at(&self, wall_time_s: f64) -> Option<f64> {
+        Some(self.offset_ticks? + wall_time_s * self.ticks_per_second)
+    }
+
+    /// the wall time when the clock will reach the given tick, if the clock is running
+    pub fn wall_time_at(&self, clock_tick: f64) -> Option<f64> {
+        if self.ticks_per_second == 0. {
+            return None;
+        }
+        Some((clock_tick - self.offset_ticks?) / self.ticks_per_second)
+    }
+}
+
+/// A single reading of the audio clock, and the wall time it was read at
+#[derive(Debug, Clone, Copy, Default, PartialEq, Serialize, Deserialize)]
+pub struct ClockReading {
+    pub wall_time_s: f64,
+    pub tick: f64,
+    pub ticking: bool,
+}
+
+/// how far a device's clock may drift from wall time, in microseconds per microsecond
+const MAX_TIMESTAMP_DRIFT: f64 = 0.0001;
+
+/// Relates a device's own timestamps (in microseconds, from an arbitrary start) to wall time.
+///
+/// Messages arrive some time after they were stamped, and that delay varies from message to message.
+/// The shortest delay seen is the best estimate of the fixed part of it, so it's used to convert stamps.
+#[derive(Debug, Clone, Copy, Default)]
+pub struct TimestampSync {
+    /// the shortest (arrival - stamp) seen so far, and the stamp it was seen at
+    min_offset_us: Option<(f64, u64)>,
+}
+
+impl TimestampSync {
+    /// records when a message with this stamp arrived
+    pub fn observe(&mut self, stamp_us: u64, arrival_us: u128) {
+        let offset = arrival_us as f64 - stamp_us as f64;
+        self.min_offset_us = match self.min_offset_us {
+            // allow the estimate to creep up, in case the device's clock runs slow
+            Some((min, at)) => {
+                let drift = stamp_us.saturating_sub(at) as f64 * MAX_TIMESTAMP_DRIFT;
+                if offset < min + drift {
+                    Some((offset, stamp_us))
+                } else {
+                    Some((min, at))
+                }
+            }
+            None => Some((offset, stamp_us)),
+        };
+    }
+
+    /// the wall time (in microseconds) when a message with this stamp was sent.
+    /// without any observations, that's when it arrived.
+    pub fn wall_time_us(&self, stamp_us: u64, arrival_us: u128) -> u128 {
+        match self.min_offset_us {
+            Some((min, _)) => (stamp_us as f64 + min).min(arrival_us as f64) as u128,
+            None => arrival_us,
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::time::{ClockSync, TimestampSync};
+
+    #[test]
+    fn it_converts_wall_time_to_clock_ticks() {
+        let mut sync = ClockSync::default();
+        assert_eq!(sync.tick_at(10.), None);
+
+        // 4 ticks per second, started at wall time 100s. readings lag by up to an audio buffer.
+        let tps = 4.;
+        for frame in 0..300 {
+            let wall = 100. + frame as f64 / 60.;
+            let lag_s = [0., 0.01, 0.005][frame % 3];
+            sync.observe(wall, (wall - 100. - lag_s) * tps, tps);
+        }
+        let wall = 105.;
+        let tick = sync.tick_at(wall - 0.25).unwrap();
+        // within the average lag of 5ms, despite 10ms of jitter
+        assert!((tick - 4.75 * tps).abs() < 0.006 * tps, "tick: {}", tick);
+        assert!((sync.wall_time_at(tick).unwrap() - (wall - 0.25)).abs() < 1e-6);
+
+        // the clock restarted from 0 (e.g. the audio device changed)
+        sync.observe(wall, 0., tps);
+        assert!((sync.tick_at(wall + 0.5).unwrap() - 2.).abs() < 1e-9);
+
+        // paused
+        sync.observe(wall + 1., 3., 0.);
+        assert_eq!(sync.tick_at(wall + 2.), Some(3.));
+        assert_eq!(sync.wall_time_at(4.), None);
+    }
+
+    #[test]
+    fn it_converts_device_timestamps_to_wall_time() {
+        let mut sync = TimestampSync::default();
+        assert_eq!(sync.wall_time_us(500, 2_000_000), 2_000_000);
+
+        // the device started 1s after wall time 0. messages take 1-5ms to arrive.
+        let start = 1_000_000;
+        let messages = [(10_000, 3_000), (20_000, 1_000), (30_000, 5_000)];
+        for (stamp, delay) in messages {
+            sync.observe(stamp, start + stamp as u128 + delay);
+        }
+
+        // two hits 1ms apart, where the later one arrived first
+        let late_arrival = start + 40_000 + 4_000;
+        let early_arrival = start + 41_000 + 1_000;
+        assert_eq!(sync.wall_time_us(40_000, late_arrival), start + 41_000);
+        assert_eq!(sync.wall_time_us(41_000, early_arrival), start + 42_000);
+    }
+}
@@ -1,13 +1,17 @@
 /*
   Data structures describing the notes to be played on each instrument.
 */
-use std::error::Error;
+use std::{collections::HashMap, error::Error};
 
 use serde::{Deserialize, Serialize};
 
-use crate::consts::ALL_INSTRUMENTS;
+use crate::{
+    beat::{Beat, TICKS_PER_BEAT},
+    consts::ALL_INSTRUMENTS,
+};
 
-#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash, PartialOrd, Ord, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
 pub enum Instrument {
     ClosedHihat,
     Snare,
@@ -22,11 +26,34 @@ pub enum Instrument {
     Crash,
 }
 
+/// How loud a note is written to be played
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum Dynamic {
+    Ghost,
+    Normal,
+    Accent,
+}
+
+impl Dynamic {
+    pub const ALL: [Dynamic; 3] = [Dynamic::Ghost, Dynamic::Normal, Dynamic::Accent];
+
+    pub fn name(&self) -> &str {
+        match self {
+            Dynamic::Ghost => "ghost",
+            Dynamic::Normal => "normal",
+            Dynamic::Accent => "accent",
+        }
+    }
+}
+
 /// Voice represents the notes to be played on an instrument.
 #[derive(Debug, Clone)]
 pub struct Voice {
     instrument: Instrument,
-    beat_timings: Vec<f64>,
+    beat_timings: Vec<Beat>,
+    /// notes that aren't played at normal volume
+    dynamics: HashMap<Beat, Dynamic>,
 }
 
 impl Voice {
@@ -34,38 +61,38 @@ impl Voice {
         Self {
             instrument,
             beat_timings: vec![],
+            dynamics: HashMap::new(),
         }
     }
 }
 
 /// VoicesOld represents the notes to be played on each instrument.
-#[derive(Serialize, Deserialize, Debug, Clone)]
+#[derive(Serialize, Deserialize, Debug, Clone, Default)]
+#[serde(default)]
 pub struct VoicesFromJSON {
-    closed_hihat: Vec<f64>,
-    snare: Vec<f64>,
-    kick: Vec<f64>,
-    open_hihat: Vec<f64>,
-    ride: Vec<f64>,
-    crash: Vec<f64>,
+    closed_hihat: Vec<Beat>,
+    snare: Vec<Beat>,
+    kick: Vec<Beat>,
+    open_hihat: Vec<Beat>,
+    ride: Vec<Beat>,
+    crash: Vec<Beat>,
 }
 
 impl VoicesFromJSON {
-    fn new() -> Self {
-        Self {
-            closed_hihat: vec![],
-            snare: vec![],
-            kick: vec![],
-            open_hihat: vec![],
-            ride: vec![],
-            crash: vec![],
+    fn get_instrument_beats(&self, ins: &Instrument) -> &[Beat] {
+        match ins {
+            Instrument::ClosedHihat => &self.closed_hihat,
+            Instrument::Snare => &self.snare,
+            Instrument::Kick => &self.kick,
+            Instrument::OpenHihat => &self.open_hihat,
+            Instrument::Ride => &self.ride,
+            Instrument::Crash => &self.crash,
+            Instrument::Tom1 => &[],
+            Instrument::Tom2 => &[],
+            Instrument::Tom3 => &[],
+            Instrument::PedalHiHat => &[],
         }
     }
-
-    pub fn new_mock() -> Self {
-        let mut voices = VoicesFromJSON::new();
-        voices.closed_hihat = vec![1.0, 3.0];
-        voices
-    }
 }
 
 /// Voices represents the notes to be played on each instrument.
@@ -86,51 +113,104 @@ impl Voices {
     pub fn new_from_voices_old_model(vo: &VoicesFromJSON) -> Self {
         let mut data = vec![];
         for ins in ALL_INSTRUMENTS.iter() {
-            let beat_timings = match ins {
-                Instrument::ClosedHihat => vo.closed_hihat.clone(),
-                Instrument::Snare => vo.snare.clone(),
-                Instrument::Kick => vo.kick.clone(),
-                Instrument::OpenHihat => vo.open_hihat.clone(),
-                Instrument::Ride => vo.ride.clone(),
-                Instrument::Crash => vo.crash.clone(),
-                Instrument::Tom1 => vec![],
-                Instrument::Tom2 => vec![],
-                Instrument::Tom3 => vec![],
-                Instrument::PedalHiHat => vec![],
-            };
             data.push(Voice {
                 instrument: *ins,
-                beat_timings,
+                beat_timings: vo.get_instrument_beats(ins).to_vec(),
+                dynamics: HashMap::new(),
             });
         }
         Self { data }
     }
 
-    pub fn toggle_beat(&mut self, ins: Instrument, beat: f64) {
-        let ins_vec = self.get_instrument_beats_mut(&ins);
-        if let Some(pos) = ins_vec.iter().position(|x| *x == beat) {
-            ins_vec.remove(pos);
+    /// the notes of a loop, including its written accents and ghost notes
+    pub fn new_from_loop(loop_data: &Loop) -> Self {
+        let mut voices = Self::new_from_voices_old_model(&loop_data.voices);
+        for (dynamic, notes) in [
+            (Dynamic::Accent, &loop_data.accents),
+            (Dynamic::Ghost, &loop_data.ghost_notes),
+        ] {
+            let Some(notes) = notes else {
+                continue;
+            };
+            for voice in voices.data.iter_mut() {
+                for beat in notes.get_instrument_beats(&voice.instrument) {
+                    voice.dynamics.insert(*beat, dynamic);
+                }
+            }
+        }
+        voices
+    }
+
+    pub fn toggle_beat(&mut self, ins: Instrument, beat: Beat) {
+        let voice = self.get_voice_mut(&ins);
+        if let Some(pos) = voice.beat_timings.iter().position(|x| *x == beat) {
+            voice.beat_timings.remove(pos);
+            voice.dynamics.remove(&beat);
         } else {
-            ins_vec.push(beat);
+            voice.beat_timings.push(beat);
         }
     }
 
-    pub fn get_instrument_beats(&self, ins: &Instrument) -> &Vec<f64> {
+    pub fn set_dynamic(&mut self, ins: Instrument, beat: Beat, dynamic: Dynamic) {
+        let voice = self.get_voice_mut(&ins);
+        if dynamic == Dynamic::Normal {
+            voice.dynamics.remove(&beat);
+        } else {
+            voice.dynamics.insert(beat, dynamic);
+        }
+    }
+
+    /// how loud a note is written to be played
+    pub fn get_dynamic(&self, ins: &Instrument, beat: Beat) -> Dynamic {
+        self.get_voice(ins)
+            .dynamics
+            .get(&beat)
+            .copied()
+            .unwrap_or(Dynamic::Normal)
+    }
+
+    pub fn get_instrument_beats(&self, ins: &Instrument) -> &Vec<Beat> {
+        &self.get_voice(ins).beat_timings
+    }
+
+    fn get_voice(&self, ins: &Instrument) -> &Voice {
         if let Some(pos) = self.data.iter().position(|x| x.instrument == *ins) {
-            &self.data[pos].beat_timings
+            &self.data[pos]
         } else {
             panic!("couldn't find instrument, though ALL_INSTRUMENTS should be present");
         }
     }
 
-    fn get_instrument_beats_mut(&mut self, ins: &Instrument) -> &mut Vec<f64> {
+    fn get_voice_mut(&mut self, ins: &Instrument) -> &mut Voice {
         if let Some(pos) = self.data.iter().position(|x| x.instrument == *ins) {
-            &mut self.data[pos].beat_timings
+            &mut self.data[pos]
         } else {
             panic!("couldn't find instrument, though ALL_INSTRUMENTS should be present");
         }
     }
 
+    /// returns a copy of these voices with swing applied to the off-beat subdivisions
+    pub fn swung(&self, swing: f64) -> Self {
+        let data = self
+            .data
+            .iter()
+            .map(|v| Voice {
+                instrument: v.instrument,
+                beat_timings: v
+                    .beat_timings
+                    .iter()
+                    .map(|b| swung_beat(*b, swing))
+                    .collect(),
+                dynamics: v
+                    .dynamics
+                    .iter()
+                    .map(|(b, d)| (swung_beat(*b, swing), *d))
+                    .collect(),
+            })
+            .collect();
+        Self { data }
+    }
+
     pub fn get_audio_file_for_instrument(ins: &Instrument) -> &str {
         // TODO: verify required sound files exist on startup- right now it fails during runtime
         match ins {
@@ -149,12 +229,39 @@ impl Voices {
     }
 }
 
+pub const STRAIGHT_SWING: f64 = 0.5;
+
+/// Moves a beat to its swung position.
+///
+/// Beats are 8th notes, so each pair of beats is one quarter note. `swing` is the fraction of the quarter note
+/// taken by the first 8th (0.5 is straight, ~0.67 is triplet swing). Positions in between (e.g. 16ths) are stretched to fit.
+/// Swung positions are rounded to the nearest tick.
+pub fn swung_beat(beat: Beat, swing: f64) -> Beat {
+    let quarter = 2 * TICKS_PER_BEAT;
+    let quarter_start = beat.ticks().div_euclid(quarter) * quarter;
+    let pos = (beat.ticks() - quarter_start) as f64 / TICKS_PER_BEAT as f64;
+    let swung_pos = if pos <= 1. {
+        pos * 2. * swing
+    } else {
+        2. * swing + (pos - 1.) * 2. * (1. - swing)
+    };
+    Beat::from_ticks(quarter_start) + Beat::from_f64(swung_pos)
+}
+
 /// Loop is the full information required to play a loop. It can be read/written to a file.
 #[derive(Serialize, Deserialize, Debug, Clone)]
 pub struct Loop {
     pub bpm: usize,
     pub length_in_beats: usize,
     pub voices: VoicesFromJSON,
+    // swing amount for this loop. if absent, the global swing setting is used
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub swing: Option<f64>,
+    // notes (also listed in `voices`) to be played louder or softer than normal
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub accents: Option<VoicesFromJSON>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub ghost_notes: Option<VoicesFromJSON>,
 }
 
 impl Loop {
@@ -167,7 +274,10 @@ impl Loop {
 }
 #[cfg(test)]
 mod tests {
-    use crate::voices::{Instrument, Loop, Voices};
+    use crate::{
+        beat::Beat,
+        voices::{swung_beat, Instrument, Loop, Voices, STRAIGHT_SWING},
+    };
 
     #[test]
     fn it_can_load_a_loop_from_file() {
@@ -186,4 +296,52 @@ mod tests {
         assert_eq!(voices.get_instrument_beats(&Instrument::OpenHihat).len(), 4);
         assert_eq!(voices.get_instrument_beats(&Instrument::Ride).len(), 0);
     }
+
+    #[test]
+    fn it_toggles_triplet_beats_exactly() {
+        let mut voices = Voices::new();
+        let third_triplet = Beat::from_ratio(4, 3);
+        voices.toggle_beat(Instrument::Snare, third_triplet);
+        voices.toggle_beat(
+            Instrument::Snare,
+            Beat::from_ratio(2, 3) + Beat::from_ratio(2, 3),
+        );
+        assert!(voices.get_instrument_beats(&Instrument::Snare).is_empty());
+    }
+
+    #[test]
+    fn it_swings_off_beat_subdivisions() {
+        let b = Beat::from_f64;
+
+        // straight is unchanged
+        for beat in [0., 0.5, 1., 1.5, 3., 7.25] {
+            assert_eq!(swung_beat(b(beat), STRAIGHT_SWING), b(beat));
+        }
+        let triplet = Beat::from_ratio(2, 3);
+        assert_eq!(swung_beat(triplet, STRAIGHT_SWING), triplet);
+
+        // on-beats never move
+        assert_eq!(swung_beat(b(0.), 0.75), b(0.));
+        assert_eq!(swung_beat(b(2.), 0.75), b(2.));
+
+        // off-beat 8ths are delayed
+        assert_eq!(swung_beat(b(1.), 0.75), b(1.5));
+        assert_eq!(swung_beat(b(5.), 0.75), b(5.5));
+
+        // 16ths are stretched to fit
+        assert_eq!(swung_beat(b(0.5), 0.75), b(0.75));
+        assert_eq!(swung_beat(b(1.5), 0.75), b(1.75));
+    }
+
+    #[test]
+    fn it_swings_all_voices() {
+        let mut voices = Voices::new();
+        voices.toggle_beat(Instrument::ClosedHihat, Beat::ZERO);
+        voices.toggle_beat(Instrument::ClosedHihat, Beat::from_beats(1));
+        let swung = voices.swung(0.6);
+        assert_eq!(
+            swung.get_instrument_beats(&Instrument::ClosedHihat),
+            &vec![Beat::ZERO, Beat::from_f64(1.2)]
+        );
+    }
 }